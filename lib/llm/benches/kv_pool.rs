@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the [AvailableBlocks] pool
+//!
+//! Run with `cargo bench -p dynamo-llm --features cuda_kv --bench kv_pool`, optionally followed
+//! by `-- <filter>` to run only the benchmarks whose name contains the filter. Each benchmark
+//! prints the mean time per operation of each of its variants.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dynamo_llm::kv::reuse::{AvailableBlocks, AvailableBlocksConfig, UpdateBlock};
+use dynamo_llm::kv::{BlockPriority, KvBlock};
+use dynamo_llm::tokens::{SequenceHash, Tokens};
+
+fn report(name: &str, variant: &str, ops: usize, elapsed: Duration) {
+    println!(
+        "{name:<32} {variant:<24} {:>12.1} ns/op",
+        elapsed.as_nanos() as f64 / ops as f64
+    );
+}
+
+// A pool of `blocks` cached blocks of two tokens; returns the hashes of the blocks in order
+async fn cached_pool(
+    config: AvailableBlocksConfig,
+    blocks: usize,
+) -> (AvailableBlocks, Vec<SequenceHash>) {
+    let pool = AvailableBlocks::new_with_config(config).await;
+    let (blocks, _) = Tokens::from((0..2 * blocks as u32).collect::<Vec<_>>())
+        .into_sequence(2)
+        .into_parts();
+    let hashes = blocks.iter().map(|block| block.sequence_hash()).collect();
+    pool.insert_many(blocks.into_iter().map(KvBlock::new).collect())
+        .await
+        .unwrap();
+    pool.fence().await.unwrap();
+    (pool, hashes)
+}
+
+// Match and return the blocks cached after most of the pool was checked out, with and without
+// compaction of the lookup map
+async fn post_churn_compaction() {
+    const BLOCKS: usize = 1 << 16;
+    const CHECKED_OUT: usize = BLOCKS - BLOCKS / 16;
+    const ROUNDS: usize = 16;
+
+    let variants = [
+        ("no compaction", AvailableBlocksConfig::default()),
+        (
+            "compaction",
+            AvailableBlocksConfig::default().with_compaction(4, 1024),
+        ),
+    ];
+    for (variant, config) in variants {
+        let (pool, hashes) = cached_pool(config, BLOCKS).await;
+        let _held = pool
+            .match_blocks(hashes[..CHECKED_OUT].to_vec())
+            .await
+            .unwrap();
+
+        // a fence waits for a compaction in progress
+        pool.fence().await.unwrap();
+
+        let cached = &hashes[CHECKED_OUT..];
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for &hash in cached {
+                let block = pool.match_one(hash).await.unwrap();
+                assert!(block.is_some());
+            }
+            pool.fence_returns().await.unwrap();
+        }
+        report(
+            "post_churn_compaction",
+            variant,
+            ROUNDS * cached.len(),
+            start.elapsed(),
+        );
+    }
+}
+
+// Probe the fast index of a large pool, while it is idle and while every turn of the engine
+// publishes a new snapshot
+async fn probe_fast() {
+    const BLOCKS: usize = 1 << 16;
+    const PROBE: usize = 64;
+    const ROUNDS: usize = 4096;
+
+    let config = AvailableBlocksConfig::default().with_fast_probe();
+    let (pool, hashes) = cached_pool(config, BLOCKS).await;
+    let probed = &hashes[..PROBE];
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let resident = pool.probe_fast(probed).unwrap();
+        assert!(resident.iter().all(|&cached| cached));
+    }
+    report("probe_fast", "idle", ROUNDS, start.elapsed());
+
+    // each match and each return changes the index, so every round publishes two snapshots
+    let churned = &hashes[BLOCKS - ROUNDS..];
+    let start = Instant::now();
+    for &hash in churned {
+        let block = pool.match_one(hash).await.unwrap();
+        assert!(block.is_some());
+        let resident = pool.probe_fast(probed).unwrap();
+        assert_eq!(resident.len(), PROBE);
+        drop(block);
+        pool.fence_returns().await.unwrap();
+    }
+    report("probe_fast", "publishing", ROUNDS, start.elapsed());
+}
+
+// Probe the cached prefix of a large pool from concurrent tasks while the engine churns, exactly
+// through the engine and from the fast index, and report how many times cheaper the fast probe is
+async fn probe_fast_concurrent() {
+    const BLOCKS: usize = 1 << 16;
+    const PROBE: usize = 64;
+    const PROBERS: usize = 8;
+    const ROUNDS: usize = 1024;
+
+    let config = AvailableBlocksConfig::default().with_fast_probe();
+    let (pool, hashes) = cached_pool(config, BLOCKS).await;
+    let pool = Arc::new(pool);
+    let probed = Arc::new(hashes[..PROBE].to_vec());
+
+    // match and return the blocks past the probed prefix until the probers are done
+    let done = Arc::new(AtomicBool::new(false));
+    let churn = tokio::spawn({
+        let pool = pool.clone();
+        let done = done.clone();
+        let churned = hashes[PROBE..].to_vec();
+        async move {
+            for &hash in churned.iter().cycle() {
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                drop(pool.match_one(hash).await.unwrap());
+                pool.fence_returns().await.unwrap();
+            }
+        }
+    });
+
+    let mut elapsed = Vec::new();
+    for variant in ["concurrent exact", "concurrent fast"] {
+        let exact = variant == "concurrent exact";
+        let start = Instant::now();
+        let probers: Vec<_> = (0..PROBERS)
+            .map(|_| {
+                let pool = pool.clone();
+                let probed = probed.clone();
+                tokio::spawn(async move {
+                    for _ in 0..ROUNDS {
+                        let cached = if exact {
+                            pool.cached_prefix_len(probed.to_vec()).await.unwrap()
+                        } else {
+                            let resident = pool.probe_fast(&probed).unwrap();
+                            resident.iter().take_while(|&&cached| cached).count()
+                        };
+                        assert_eq!(cached, PROBE);
+                    }
+                })
+            })
+            .collect();
+        for prober in probers {
+            prober.await.unwrap();
+        }
+        let variant_elapsed = start.elapsed();
+        report("probe_fast", variant, PROBERS * ROUNDS, variant_elapsed);
+        elapsed.push(variant_elapsed);
+    }
+
+    done.store(true, Ordering::Relaxed);
+    churn.await.unwrap();
+    println!(
+        "{:<32} {:<24} {:>12.1}x",
+        "probe_fast",
+        "exact / fast",
+        elapsed[0].as_secs_f64() / elapsed[1].as_secs_f64()
+    );
+}
+
+// Rescore every block of a large pool with one bulk rebalance, in chunks, and with per-entry
+// updates
+async fn rebalance() {
+    const BLOCKS: usize = 50_000;
+
+    let variants = [
+        ("rebalance", AvailableBlocksConfig::default()),
+        (
+            "rebalance chunked",
+            AvailableBlocksConfig::default().with_handler_chunk_size(1024),
+        ),
+        ("update_multiple", AvailableBlocksConfig::default()),
+    ];
+    for (variant, config) in variants {
+        let (pool, hashes) = cached_pool(config, BLOCKS).await;
+        let scores: Vec<_> = hashes
+            .iter()
+            .enumerate()
+            .map(|(index, &hash)| (hash, BlockPriority::new(0, index as u32 % 97)))
+            .collect();
+
+        let start = Instant::now();
+        if variant == "update_multiple" {
+            let updates = scores
+                .into_iter()
+                .map(|(hash, priority)| UpdateBlock::new(hash, priority))
+                .collect();
+            pool.update_multiple(updates).await.unwrap();
+        } else {
+            let outcome = pool.rebalance(scores).await.unwrap();
+            assert_eq!(outcome.applied, BLOCKS);
+        }
+        report("rebalance", variant, BLOCKS, start.elapsed());
+    }
+}
+
+// Take blocks from a large pool and return them with new contents, with the eviction order kept
+// in the ordered map and in the uniform queue
+async fn uniform_priority() {
+    const BLOCKS: usize = 1 << 16;
+    const BATCH: usize = 256;
+    const ROUNDS: usize = 64;
+
+    let (contents, _) = Tokens::from((0..2 * (BLOCKS + ROUNDS * BATCH) as u32).collect::<Vec<_>>())
+        .into_sequence(2)
+        .into_parts();
+    let returned = &contents[BLOCKS..];
+
+    let variants = [
+        ("ordered", AvailableBlocksConfig::default()),
+        (
+            "uniform",
+            AvailableBlocksConfig::default().with_uniform_priority(),
+        ),
+    ];
+    for (variant, config) in variants {
+        let (pool, _) = cached_pool(config, BLOCKS).await;
+
+        let start = Instant::now();
+        for round in returned.chunks(BATCH) {
+            let mut taken = pool.take_blocks(BATCH as u32).await.unwrap();
+            for (block, token_block) in taken.iter_mut().zip(round) {
+                block.update_token_block(token_block.clone());
+            }
+            drop(taken);
+            pool.fence_returns().await.unwrap();
+        }
+        report("uniform_priority", variant, ROUNDS * BATCH, start.elapsed());
+    }
+}
+
+fn main() {
+    let filter = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_default();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        if "post_churn_compaction".contains(&filter) {
+            post_churn_compaction().await;
+        }
+        if "probe_fast".contains(&filter) {
+            probe_fast().await;
+        }
+        if "rebalance".contains(&filter) {
+            rebalance().await;
+        }
+        if "uniform_priority".contains(&filter) {
+            uniform_priority().await;
+        }
+    });
+
+    // the probers run in parallel with the engine and with each other
+    if "probe_fast".contains(&filter) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(probe_fast_concurrent());
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of splitting [Tokens] into blocks
+//!
+//! Run with `cargo bench -p dynamo-llm --bench tokens`, optionally followed by `-- <filter>` to
+//! run only the benchmarks whose name contains the filter. Each benchmark prints the mean time
+//! per iteration of each of its variants.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use dynamo_llm::tokens::{SequenceHash, Token, TokenBlock, TokenSequence, Tokens};
+
+const BLOCK_SIZE: usize = 16;
+const ITERATIONS: usize = 20;
+
+fn report(name: &str, variant: &str, iterations: usize, elapsed: Duration) {
+    println!(
+        "{name:<32} {variant:<24} {:>12.1} us/iter",
+        elapsed.as_secs_f64() * 1e6 / iterations as f64
+    );
+}
+
+// A 128k token prompt
+fn prompt() -> Tokens {
+    (0..128 * 1024).collect::<Vec<u32>>().into()
+}
+
+fn time(iterations: usize, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed()
+}
+
+// Split a 128k token prompt with the default and the fast path
+fn into_sequence_128k() {
+    let tokens = prompt();
+
+    let variants: [(&str, fn(Tokens, usize) -> TokenSequence); 2] = [
+        ("into_sequence", Tokens::into_sequence),
+        ("into_sequence_fast", Tokens::into_sequence_fast),
+    ];
+    for (variant, split) in variants {
+        let elapsed = time(ITERATIONS, || {
+            black_box(split(tokens.clone(), BLOCK_SIZE));
+        });
+        report("into_sequence_128k", variant, ITERATIONS, elapsed);
+    }
+
+    // both paths must agree, or the timings compare different work
+    let expected = tokens.clone().into_sequence(BLOCK_SIZE);
+    let actual = tokens.into_sequence_fast(BLOCK_SIZE);
+    assert!(expected
+        .blocks()
+        .iter()
+        .map(|block| block.sequence_hash())
+        .eq(actual.blocks().iter().map(|block| block.sequence_hash())));
+}
+
+// Rebuild the blocks of a 128k token prompt received over the wire, rehashing the tokens and
+// trusting the precomputed hashes
+fn precomputed_hash_128k() {
+    let tokens = prompt();
+    let (blocks, _) = tokens.clone().into_sequence(BLOCK_SIZE).into_parts();
+    let wire: Vec<(Vec<Token>, SequenceHash, Option<SequenceHash>)> = blocks
+        .iter()
+        .map(|block| {
+            (
+                block.tokens().to_vec(),
+                block.sequence_hash(),
+                block.parent_sequence_hash(),
+            )
+        })
+        .collect();
+
+    let elapsed = time(ITERATIONS, || {
+        black_box(tokens.clone().into_sequence(BLOCK_SIZE));
+    });
+    report("precomputed_hash_128k", "computed", ITERATIONS, elapsed);
+
+    let elapsed = time(ITERATIONS, || {
+        let blocks: Vec<TokenBlock> = wire
+            .iter()
+            .map(|(tokens, hash, parent)| {
+                TokenBlock::with_precomputed_hash(tokens.clone().into(), *hash, *parent)
+            })
+            .collect();
+        black_box(blocks);
+    });
+    report("precomputed_hash_128k", "precomputed", ITERATIONS, elapsed);
+}
+
+fn main() {
+    let filter = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_default();
+
+    if "into_sequence_128k".contains(&filter) {
+        into_sequence_128k();
+    }
+    if "precomputed_hash_128k".contains(&filter) {
+        precomputed_hash_128k();
+    }
+}
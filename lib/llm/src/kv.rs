@@ -13,12 +13,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod block_pool;
+pub mod compat;
+pub mod descriptor;
+pub mod federation;
 pub mod layer;
 pub mod manager;
+pub mod metrics;
+pub mod migrate;
 pub mod reserved;
 pub mod reuse;
 pub mod sequence;
+pub mod shadow;
+pub mod simulate;
 pub mod storage;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+#[cfg(feature = "trace-record")]
+pub mod trace;
 
 // #[cfg(feature = "cuda_kv")]
 // pub mod storage;
@@ -26,7 +38,9 @@ pub mod storage;
 use reserved::*;
 
 use std::{
+    any::Any,
     collections::{BTreeMap, HashMap, VecDeque},
+    ops::RangeInclusive,
     sync::{atomic::AtomicU64, Arc, RwLock},
 };
 
@@ -37,19 +51,143 @@ use dynamo_runtime::{
     utils::pool::{PoolExt, PoolItem, PoolValue, Returnable, SharedPoolItem},
     Result,
 };
+use tokio::time::Instant;
 
-use crate::tokens::{PartialTokenBlock, SequenceHash, TokenBlock, Tokens};
+use crate::tokens::{PartialTokenBlock, SequenceHash, Token, TokenBlock, Tokens};
 
 use tracing as log;
 
 pub type UniqueBlock = PoolItem<KvBlock>;
 pub type SharedBlock = SharedPoolItem<KvBlock>;
 
+/// Caller data attached to a block; see [KvBlock::set_metadata]
+pub type BlockMetadata = Arc<dyn Any + Send + Sync>;
+
+/// Eviction priority of a block: a retention class and a score within the class
+///
+/// Blocks are evicted by class first, then by score, lowest first. The class and the score are
+/// kept apart, so raising a score never carries a block into the next class.
+///
+/// `From<u32>` is the migration shim for single value priorities: the class is `value >> 24` and
+/// the score `value & LEGACY_SCORE_MASK`. Converted values keep the order of the single values,
+/// so blocks keyed by single value priorities are evicted in the same order as before.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct BlockPriority {
+    class: u8,
+    score: u32,
+}
+
+impl BlockPriority {
+    /// Bits of a single value priority holding the score; see the `From<u32>` shim
+    pub const LEGACY_SCORE_MASK: u32 = (1 << 24) - 1;
+
+    /// The priority of `score` within `class`
+    pub const fn new(class: u8, score: u32) -> Self {
+        Self { class, score }
+    }
+
+    /// Retention class; blocks of a lower class are evicted first
+    pub fn class(self) -> u8 {
+        self.class
+    }
+
+    /// Score within the class; blocks with a lower score are evicted first
+    pub fn score(self) -> u32 {
+        self.score
+    }
+
+    /// Raise the score by `delta` without leaving the class
+    pub fn bump(self, delta: u32) -> Self {
+        Self::new(self.class, self.score.saturating_add(delta))
+    }
+
+    /// Lower the score by `delta` without leaving the class
+    pub fn lower(self, delta: u32) -> Self {
+        Self::new(self.class, self.score.saturating_sub(delta))
+    }
+
+    /// Every score of `class`, e.g. for [reuse::AvailableBlocksConfig::with_reserved_band]
+    pub fn class_range(class: u8) -> RangeInclusive<BlockPriority> {
+        Self::new(class, 0)..=Self::new(class, u32::MAX)
+    }
+}
+
+impl From<u32> for BlockPriority {
+    fn from(value: u32) -> Self {
+        Self::new((value >> 24) as u8, value & Self::LEGACY_SCORE_MASK)
+    }
+}
+
+impl std::fmt::Display for BlockPriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.class, self.score)
+    }
+}
+
 #[derive(Default)]
 pub struct KvBlock {
     token_block: TokenBlock,
-    priority: u32,
+    priority: BlockPriority,
     return_tick: u64,
+
+    // key set by a [reuse::HashExtender]; the sequence hash is used when unset
+    extended_key: Option<SequenceHash>,
+
+    // retention deadline stamped by a [reuse::PoolSession]; consumed when the block enters the pool
+    deadline: Option<Instant>,
+
+    // set by [reuse::AvailableBlocks::match_blocks_single_use]; the block is reset when returned
+    single_use: bool,
+
+    // bumped by every change to the block's contents; survives resets
+    version: u64,
+
+    // set by the pool while a sticky session holds the block; see [reuse::AvailableBlocks::stick]
+    sticky: bool,
+
+    // checksum of the block's device data, set by the engine integration after writing it
+    checksum: Option<u64>,
+
+    // affinity group set by the scheduler; see [reuse::AvailableBlocksConfig::with_affinity_grouping]
+    affinity: Option<u64>,
+
+    // newest return tick of the block's affinity group, maintained by the pool
+    affinity_tick: u64,
+
+    // exempts the slot from [reuse::AvailableBlocksConfig::with_uninitialized_ttl]; survives resets
+    preallocated: bool,
+
+    // when the pool last added the block to its uninitialized set
+    blank_since: Option<Instant>,
+
+    // set by the pool while the block is checked out by
+    // [reuse::AvailableBlocks::checkout_for_maintenance]
+    maintenance: Option<MaintenanceCheckout>,
+
+    // caller data kept with the block's contents; never read by the pool
+    metadata: Option<BlockMetadata>,
+
+    // weak reference to the pool which last inserted the block; survives resets
+    pool: Option<reuse::PoolRef>,
+}
+
+// The key a block was checked out for maintenance under, and whether its return bumps its recency
+#[derive(Debug, Clone, Copy)]
+struct MaintenanceCheckout {
+    sequence_hash: SequenceHash,
+    bump_recency: bool,
 }
 
 // pub struct KvStorage {
@@ -68,22 +206,199 @@ impl KvBlock {
     pub fn new(token_block: TokenBlock) -> Self {
         Self {
             token_block,
-            priority: 0,
+            priority: BlockPriority::default(),
             return_tick: 0,
+            extended_key: None,
+            deadline: None,
+            single_use: false,
+            version: 0,
+            sticky: false,
+            checksum: None,
+            affinity: None,
+            affinity_tick: 0,
+            preallocated: false,
+            blank_since: None,
+            maintenance: None,
+            metadata: None,
+            pool: None,
             // storage: None,
         }
     }
 
-    /// Updates the token block
+    /// Creates a block at `priority` for each full block of `block_size` tokens of `tokens`
+    ///
+    /// The blocks are chained into a sequence, so each carries the sequence hash a match for the
+    /// same tokens looks up; trailing tokens which do not fill a block are dropped.
+    pub fn from_tokens(
+        tokens: &[Token],
+        block_size: usize,
+        priority: impl Into<BlockPriority>,
+    ) -> Vec<KvBlock> {
+        let priority = priority.into();
+        let (blocks, _) = Tokens::from(tokens.to_vec())
+            .into_sequence(block_size)
+            .into_parts();
+        blocks
+            .into_iter()
+            .map(|token_block| {
+                let mut block = KvBlock::new(token_block);
+                block.priority = priority;
+                block
+            })
+            .collect()
+    }
+
+    /// Updates the token block; any extended key is cleared
     pub fn update_token_block(&mut self, token_block: TokenBlock) {
         self.token_block = token_block;
+        self.extended_key = None;
+        self.checksum = None;
+        self.metadata = None;
+        self.mark_modified();
+    }
+
+    /// Content version of the block, bumped whenever its contents change
+    ///
+    /// A caller which sees a different version for the same sequence hash across two matches
+    /// knows the contents were rewritten in between; see
+    /// [reuse::AvailableBlocks::match_if_version].
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
-    /// Resets the block to its initial state
+    /// Bumps the version after the block's KV memory was written
+    pub fn mark_modified(&mut self) {
+        self.version = self.version.wrapping_add(1);
+    }
+
+    /// Key the block with the extended sequence hash; blocks without a sequence hash stay unkeyed
+    pub fn extend_key(&mut self, extender: &dyn reuse::HashExtender) {
+        let sequence_hash = self.token_block.sequence_hash();
+        self.extended_key = (sequence_hash != 0).then(|| extender.extend(sequence_hash));
+    }
+
+    /// Eviction priority of the block
+    pub fn priority(&self) -> BlockPriority {
+        self.priority
+    }
+
+    /// Sets the eviction priority applied when the block is returned to the pool; a `u32` is
+    /// converted by the [BlockPriority] shim
+    pub fn set_priority(&mut self, priority: impl Into<BlockPriority>) {
+        self.priority = priority.into();
+    }
+
+    /// Retention class of the block
+    pub fn class(&self) -> u8 {
+        self.priority.class()
+    }
+
+    /// Score of the block within its class
+    pub fn score(&self) -> u32 {
+        self.priority.score()
+    }
+
+    /// Deadline until which the pool protects the block from eviction once it is returned
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Sets the retention deadline applied when the block is returned to the pool
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Checksum of the block's device data, kept with the block while it is cached
+    pub fn checksum(&self) -> Option<u64> {
+        self.checksum
+    }
+
+    /// Sets the checksum of the block's device data; see
+    /// [reuse::AvailableBlocksConfig::with_checksum_verifier]
+    pub fn set_checksum(&mut self, checksum: Option<u64>) {
+        self.checksum = checksum;
+    }
+
+    /// Affinity group of the block
+    pub fn affinity(&self) -> Option<u64> {
+        self.affinity
+    }
+
+    /// Sets the affinity group the block joins when it enters the pool; see
+    /// [reuse::AvailableBlocksConfig::with_affinity_grouping]
+    pub fn set_affinity(&mut self, group: Option<u64>) {
+        self.affinity = group;
+    }
+
+    /// Caller data attached by [KvBlock::set_metadata]
+    pub fn metadata(&self) -> Option<&BlockMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// The attached caller data, if it is a `T`
+    pub fn metadata_as<T: Any>(&self) -> Option<&T> {
+        self.metadata.as_deref()?.downcast_ref()
+    }
+
+    /// Attaches caller data, such as a request id or a model revision, to the block
+    ///
+    /// The pool keeps the data with the block's contents and hands it back unchanged on a match;
+    /// it is dropped when the block is reset or its token block is replaced.
+    pub fn set_metadata(&mut self, metadata: Option<BlockMetadata>) {
+        self.metadata = metadata;
+    }
+
+    /// Operations on the pool which last held the block, scoped to the block's current key
+    ///
+    /// The pool is referenced weakly, so a block does not keep its pool alive; the operations
+    /// fail once the pool has shut down. `None` for a block which never entered a pool.
+    pub fn pool_ops(&self) -> Option<reuse::BlockOps> {
+        let pool = self.pool.clone()?;
+        Some(reuse::BlockOps::new(pool, self.lookup_key()))
+    }
+
+    pub(crate) fn set_pool(&mut self, pool: reuse::PoolRef) {
+        self.pool = Some(pool);
+    }
+
+    /// Whether the slot was allocated up front; see [KvBlock::set_preallocated]
+    pub fn is_preallocated(&self) -> bool {
+        self.preallocated
+    }
+
+    /// Marks the slot as allocated up front, so it is never dropped by
+    /// [reuse::AvailableBlocksConfig::with_uninitialized_ttl]
+    pub fn set_preallocated(&mut self, preallocated: bool) {
+        self.preallocated = preallocated;
+    }
+
+    /// The key the block is stored under in the pool
+    pub fn lookup_key(&self) -> SequenceHash {
+        self.extended_key
+            .unwrap_or_else(|| self.token_block.sequence_hash())
+    }
+
+    /// Resets the block to its initial state, freeing its token storage
     pub(crate) fn reset(&mut self) {
-        self.token_block = TokenBlock::default();
-        self.priority = 0;
+        self.reset_keeping_storage();
+        self.token_block.release_storage();
+    }
+
+    /// Resets the block to its initial state, keeping its token storage for the next use
+    pub(crate) fn reset_keeping_storage(&mut self) {
+        self.token_block.clear();
+        self.priority = BlockPriority::default();
         self.return_tick = 0;
+        self.extended_key = None;
+        self.deadline = None;
+        self.single_use = false;
+        self.sticky = false;
+        self.checksum = None;
+        self.affinity = None;
+        self.affinity_tick = 0;
+        self.maintenance = None;
+        self.metadata = None;
+        self.mark_modified();
         // self.storage = None;
         // self.storage_state = StorageState::Absent;
     }
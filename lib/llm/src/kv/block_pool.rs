@@ -0,0 +1,425 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Block Pool Trait
+//!
+//! [BlockPool] is the subset of [AvailableBlocks] a scheduler needs to place requests: matching
+//! cached blocks, taking blocks, probing for a cached prefix and reading the counters. Code which
+//! accepts an `Arc<dyn BlockPool>` can be tested against the [MockBlockPool] of the `test-utils`
+//! feature, which serves blocks from memory without an engine task or channels, records every
+//! call and checks them against expectations.
+
+use async_trait::async_trait;
+use dynamo_runtime::Result;
+
+use super::reuse::{AvailableBlocks, CounterSnapshot};
+use super::UniqueBlock;
+use crate::tokens::SequenceHash;
+
+/// Operations of a KV block pool used to place requests
+#[async_trait]
+pub trait BlockPool: Send + Sync {
+    /// Blocks for the longest cached prefix of `hashes`, see [AvailableBlocks::match_blocks]
+    async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<UniqueBlock>>;
+
+    /// Up to `count` blocks to fill, see [AvailableBlocks::take_blocks]
+    async fn take_blocks(&self, count: u32) -> Result<Vec<UniqueBlock>>;
+
+    /// Number of leading hashes which are cached, without acquiring the blocks; see
+    /// [AvailableBlocks::cached_prefix_len]
+    async fn probe(&self, hashes: Vec<SequenceHash>) -> Result<usize>;
+
+    /// Counters since the last drain, see [AvailableBlocks::drain_counters]
+    async fn drain_counters(&self) -> Result<CounterSnapshot>;
+
+    fn total_blocks(&self) -> u64;
+
+    fn available_blocks(&self) -> u64;
+}
+
+#[async_trait]
+impl BlockPool for AvailableBlocks {
+    async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<UniqueBlock>> {
+        AvailableBlocks::match_blocks(self, hashes).await
+    }
+
+    async fn take_blocks(&self, count: u32) -> Result<Vec<UniqueBlock>> {
+        AvailableBlocks::take_blocks(self, count).await
+    }
+
+    async fn probe(&self, hashes: Vec<SequenceHash>) -> Result<usize> {
+        self.cached_prefix_len(hashes).await
+    }
+
+    async fn drain_counters(&self) -> Result<CounterSnapshot> {
+        AvailableBlocks::drain_counters(self).await
+    }
+
+    fn total_blocks(&self) -> u64 {
+        AvailableBlocks::total_blocks(self)
+    }
+
+    fn available_blocks(&self) -> u64 {
+        AvailableBlocks::available_blocks(self)
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock::{BlockPoolCall, BlockPoolOp, MockBlockPool};
+
+#[cfg(any(test, feature = "test-utils"))]
+mod mock {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    use dynamo_runtime::{
+        raise,
+        utils::pool::{PoolExt, PoolValue, ReturnHandle},
+    };
+
+    use super::*;
+    use crate::kv::KvBlock;
+
+    /// Kind of a [BlockPoolCall], to script failures with [MockBlockPool::fail_next]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum BlockPoolOp {
+        Match,
+        Take,
+        Probe,
+        DrainCounters,
+    }
+
+    /// A call recorded by a [MockBlockPool]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum BlockPoolCall {
+        Match(Vec<SequenceHash>),
+        Take(u32),
+        Probe(Vec<SequenceHash>),
+        DrainCounters,
+    }
+
+    impl BlockPoolCall {
+        pub fn op(&self) -> BlockPoolOp {
+            match self {
+                Self::Match(_) => BlockPoolOp::Match,
+                Self::Take(_) => BlockPoolOp::Take,
+                Self::Probe(_) => BlockPoolOp::Probe,
+                Self::DrainCounters => BlockPoolOp::DrainCounters,
+            }
+        }
+    }
+
+    /// An in-memory [BlockPool] for tests
+    ///
+    /// Cached blocks are matched by their lookup key and taken oldest first after the empty
+    /// blocks, like an [AvailableBlocks] pool with a single priority. Dropped blocks return to the
+    /// mock on the spot, so its counts are current without a fence.
+    ///
+    /// Every call is recorded. Calls expected with [MockBlockPool::expect] must be made in order,
+    /// and a call which differs from the next expectation panics; [MockBlockPool::verify] panics
+    /// if expectations are left. Calls made once the expectations are used up are only recorded.
+    #[derive(Clone, Default)]
+    pub struct MockBlockPool {
+        state: Arc<MockState>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        inner: Mutex<MockInner>,
+    }
+
+    #[derive(Default)]
+    struct MockInner {
+        total_blocks: u64,
+        blanks: Vec<KvBlock>,
+
+        // cached blocks, oldest first
+        cached: VecDeque<KvBlock>,
+
+        counters: CounterSnapshot,
+        calls: Vec<BlockPoolCall>,
+        expected: VecDeque<BlockPoolCall>,
+        failures: HashMap<BlockPoolOp, VecDeque<String>>,
+    }
+
+    impl MockInner {
+        fn add(&mut self, block: KvBlock) {
+            if block.lookup_key() == 0 {
+                self.blanks.push(block);
+            } else {
+                self.cached.push_back(block);
+            }
+        }
+
+        fn position(&self, hash: SequenceHash) -> Option<usize> {
+            self.cached
+                .iter()
+                .position(|block| block.lookup_key() == hash)
+        }
+    }
+
+    impl MockState {
+        // record the call and check it against the expectations; returns the scripted failure.
+        // The check runs once the lock is released, so a failed expectation does not poison it
+        // for the blocks returned while unwinding
+        fn call(&self, call: BlockPoolCall) -> Option<String> {
+            let (expected, failure) = {
+                let mut inner = self.inner.lock().unwrap();
+                let expected = inner.expected.pop_front();
+                let failure = inner
+                    .failures
+                    .get_mut(&call.op())
+                    .and_then(|failures| failures.pop_front());
+                inner.calls.push(call.clone());
+                (expected, failure)
+            };
+            if let Some(expected) = expected {
+                assert_eq!(call, expected, "unexpected call to the mock block pool");
+            }
+            failure
+        }
+    }
+
+    impl ReturnHandle<KvBlock> for MockState {
+        fn return_to_pool(&self, block: PoolValue<KvBlock>) {
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            inner.counters.returns += 1;
+            let block = match block {
+                PoolValue::Boxed(block) => *block,
+                PoolValue::Direct(block) => block,
+            };
+            inner.add(block);
+        }
+    }
+
+    impl PoolExt<KvBlock> for MockState {}
+
+    impl MockBlockPool {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Add blocks to the mock; blocks without a sequence hash are empty
+        pub fn insert_many(&self, blocks: Vec<KvBlock>) {
+            let mut inner = self.state.inner.lock().unwrap();
+            inner.total_blocks += blocks.len() as u64;
+            inner.counters.inserts += blocks.len() as u64;
+            for block in blocks {
+                inner.add(block);
+            }
+        }
+
+        /// Expect `call` after the calls already expected
+        pub fn expect(&self, call: BlockPoolCall) {
+            self.state.inner.lock().unwrap().expected.push_back(call);
+        }
+
+        /// Fail the next call of `op` with `message` instead of serving it
+        pub fn fail_next(&self, op: BlockPoolOp, message: impl Into<String>) {
+            self.state
+                .inner
+                .lock()
+                .unwrap()
+                .failures
+                .entry(op)
+                .or_default()
+                .push_back(message.into());
+        }
+
+        /// Calls made so far, oldest first
+        pub fn calls(&self) -> Vec<BlockPoolCall> {
+            self.state.inner.lock().unwrap().calls.clone()
+        }
+
+        /// Panic if an expected call was not made
+        pub fn verify(&self) {
+            let expected = self.state.inner.lock().unwrap().expected.clone();
+            assert!(
+                expected.is_empty(),
+                "expected calls not made to the mock block pool: {:?}",
+                expected
+            );
+        }
+
+        fn hand_out(&self, blocks: Vec<KvBlock>) -> Vec<UniqueBlock> {
+            let handle: Arc<dyn ReturnHandle<KvBlock>> = self.state.clone();
+            blocks
+                .into_iter()
+                .map(|block| {
+                    self.state
+                        .create_pool_item(PoolValue::Direct(block), handle.clone())
+                })
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl BlockPool for MockBlockPool {
+        async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<UniqueBlock>> {
+            if let Some(message) = self.state.call(BlockPoolCall::Match(hashes.clone())) {
+                raise!("{}", message);
+            }
+            let matched = {
+                let mut inner = self.state.inner.lock().unwrap();
+
+                let mut matched = Vec::new();
+                for hash in hashes {
+                    let Some(position) = inner.position(hash) else {
+                        break;
+                    };
+                    matched.extend(inner.cached.remove(position));
+                }
+                inner.counters.matches += matched.len() as u64;
+                matched
+            };
+            Ok(self.hand_out(matched))
+        }
+
+        async fn take_blocks(&self, count: u32) -> Result<Vec<UniqueBlock>> {
+            if let Some(message) = self.state.call(BlockPoolCall::Take(count)) {
+                raise!("{}", message);
+            }
+            let taken = {
+                let mut inner = self.state.inner.lock().unwrap();
+
+                let mut taken = Vec::new();
+                while taken.len() < count as usize {
+                    let block = match inner.blanks.pop() {
+                        Some(block) => block,
+                        None => match inner.cached.pop_front() {
+                            Some(mut block) => {
+                                inner.counters.evictions += 1;
+                                block.reset();
+                                block
+                            }
+                            None => break,
+                        },
+                    };
+                    taken.push(block);
+                }
+                inner.counters.takes += taken.len() as u64;
+                taken
+            };
+            Ok(self.hand_out(taken))
+        }
+
+        async fn probe(&self, hashes: Vec<SequenceHash>) -> Result<usize> {
+            if let Some(message) = self.state.call(BlockPoolCall::Probe(hashes.clone())) {
+                raise!("{}", message);
+            }
+            let inner = self.state.inner.lock().unwrap();
+            Ok(hashes
+                .iter()
+                .take_while(|&&hash| inner.position(hash).is_some())
+                .count())
+        }
+
+        async fn drain_counters(&self) -> Result<CounterSnapshot> {
+            if let Some(message) = self.state.call(BlockPoolCall::DrainCounters) {
+                raise!("{}", message);
+            }
+            let mut inner = self.state.inner.lock().unwrap();
+            Ok(std::mem::take(&mut inner.counters))
+        }
+
+        fn total_blocks(&self) -> u64 {
+            self.state.inner.lock().unwrap().total_blocks
+        }
+
+        fn available_blocks(&self) -> u64 {
+            let inner = self.state.inner.lock().unwrap();
+            (inner.blanks.len() + inner.cached.len()) as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::kv::reuse::tests::{block_hashes, create_blocks, create_token_sequence};
+    use crate::kv::KvBlock;
+
+    // a scheduler step written against the trait: reuse the cached prefix and take the rest
+    async fn admit(pool: Arc<dyn BlockPool>, hashes: Vec<SequenceHash>) -> Result<usize> {
+        let cached = pool.probe(hashes.clone()).await?;
+        let mut blocks = pool.match_blocks(hashes[..cached].to_vec()).await?;
+        let needed = hashes.len() - blocks.len();
+        if needed > 0 {
+            blocks.extend(pool.take_blocks(needed as u32).await?);
+        }
+        Ok(blocks.len())
+    }
+
+    fn blocks() -> (Vec<KvBlock>, Vec<SequenceHash>) {
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        (blocks, hashes)
+    }
+
+    #[tokio::test]
+    async fn test_admit_with_mock_pool() {
+        let (mut blocks, hashes) = blocks();
+        blocks.truncate(2);
+        let mock = MockBlockPool::new();
+        mock.insert_many(blocks);
+        mock.insert_many(vec![KvBlock::default()]);
+
+        mock.expect(BlockPoolCall::Probe(hashes.clone()));
+        mock.expect(BlockPoolCall::Match(hashes[..2].to_vec()));
+        mock.expect(BlockPoolCall::Take(1));
+
+        let pool: Arc<dyn BlockPool> = Arc::new(mock.clone());
+        assert_eq!(admit(pool.clone(), hashes.clone()).await.unwrap(), 3);
+        mock.verify();
+        assert_eq!(pool.available_blocks(), 3);
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.matches, 2);
+        assert_eq!(counters.takes, 1);
+        assert_eq!(counters.returns, 3);
+        assert_eq!(mock.calls().len(), 4);
+
+        // scripted failures surface as errors of the call
+        mock.fail_next(BlockPoolOp::Take, "out of blocks");
+        let err = admit(pool, hashes).await.unwrap_err();
+        assert_eq!(err.to_string(), "out of blocks");
+    }
+
+    // the blocks held while the failed expectation unwinds still make it back to the mock
+    #[tokio::test]
+    #[should_panic(expected = "unexpected call to the mock block pool")]
+    async fn test_unexpected_call_with_blocks_out() {
+        let mock = MockBlockPool::new();
+        mock.insert_many(vec![KvBlock::default(), KvBlock::default()]);
+        let _taken = mock.take_blocks(1).await.unwrap();
+
+        mock.expect(BlockPoolCall::Take(1));
+        let _ = mock.probe(vec![1]).await;
+    }
+
+    #[tokio::test]
+    async fn test_admit_with_available_blocks() {
+        let (blocks, hashes) = blocks();
+        let pool = AvailableBlocks::new().await;
+        pool.insert_many(blocks).await.unwrap();
+        pool.fence().await.unwrap();
+
+        let pool: Arc<dyn BlockPool> = Arc::new(pool);
+        assert_eq!(admit(pool.clone(), hashes).await.unwrap(), 3);
+        assert_eq!(pool.total_blocks(), 3);
+    }
+}
@@ -0,0 +1,289 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Compatibility Manifest
+//!
+//! Components which exchange cache state, such as the pools of a [federation][super::federation],
+//! a primary and its [warm standby][super::shadow] or the consumers of an exported
+//! [PoolManifest][super::reuse::PoolManifest], must agree on how blocks are hashed and encoded.
+//! A mismatch does not fail; it shows up as a hit rate of zero. A [CompatManifest] describes
+//! these settings of a pool, see [AvailableBlocks::manifest][super::reuse::AvailableBlocks::manifest],
+//! and [CompatManifest::compatible_with] names the first one which differs.
+
+use serde::{Deserialize, Serialize};
+
+use super::descriptor;
+use crate::kv_router::indexer::XXH3_SEED;
+use crate::tokens::SequenceHash;
+
+/// Version of the block descriptor encoding used by exported manifests and snapshots
+pub const SNAPSHOT_FORMAT_VERSION: u8 = descriptor::MAX_SUPPORTED_VERSION;
+
+/// How sequence hashes are computed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashConfig {
+    pub algorithm: String,
+    pub seed: u64,
+}
+
+impl Default for HashConfig {
+    /// The hash of [compute_hash][crate::kv_router::indexer::compute_hash]
+    fn default() -> Self {
+        Self {
+            algorithm: "xxh3_64".to_string(),
+            seed: XXH3_SEED,
+        }
+    }
+}
+
+/// Settings a component must share with the components it exchanges cache state with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompatManifest {
+    /// Version of this crate
+    pub crate_version: String,
+
+    pub hash_config: HashConfig,
+
+    /// Tokens per block, if known; see [AvailableBlocksConfig::with_block_size][super::reuse::AvailableBlocksConfig::with_block_size]
+    pub block_size: Option<usize>,
+
+    /// Bits of a sequence hash
+    pub hash_width: u32,
+
+    pub snapshot_format_version: u8,
+
+    /// Optional capabilities which change the cache state the component exchanges, sorted
+    ///
+    /// Capabilities local to a component, such as trace recording or a cold tier, are not listed:
+    /// they do not change what its peers receive.
+    pub features: Vec<String>,
+}
+
+impl Default for CompatManifest {
+    /// The settings of this build with the default hash, an unknown block size and no optional
+    /// capabilities
+    fn default() -> Self {
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            hash_config: HashConfig::default(),
+            block_size: None,
+            hash_width: SequenceHash::BITS,
+            snapshot_format_version: SNAPSHOT_FORMAT_VERSION,
+            features: Vec::new(),
+        }
+    }
+}
+
+/// Why two components can not exchange cache state, see [CompatManifest::compatible_with]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IncompatibilityReason {
+    #[error("hash width {theirs} differs from {ours}")]
+    HashWidth { ours: u32, theirs: u32 },
+
+    #[error("hash config {theirs:?} differs from {ours:?}; sequence hashes will never match")]
+    HashConfig {
+        ours: HashConfig,
+        theirs: HashConfig,
+    },
+
+    #[error("block size {theirs} differs from {ours}; rechunk the cache with kv::migrate first")]
+    BlockSize { ours: usize, theirs: usize },
+
+    #[error("snapshot format {theirs} is newer than the supported format {ours}; upgrade this component")]
+    SnapshotFormat { ours: u8, theirs: u8 },
+
+    #[error("crate version {theirs} is not compatible with {ours}")]
+    CrateVersion { ours: String, theirs: String },
+
+    #[error("feature {0} of the other component is not enabled on this one")]
+    MissingFeature(String),
+}
+
+impl CompatManifest {
+    /// Check that this component can consume the cache state of the component described by
+    /// `other`; returns the first setting which differs
+    ///
+    /// An unknown block size on either side is not checked. Crate versions are compatible within
+    /// a release line: the same major version, or the same minor version before 1.0. The check is
+    /// directional for snapshot formats and features, so peers which exchange state both ways
+    /// check both directions.
+    pub fn compatible_with(&self, other: &CompatManifest) -> Result<(), IncompatibilityReason> {
+        if self.hash_width != other.hash_width {
+            return Err(IncompatibilityReason::HashWidth {
+                ours: self.hash_width,
+                theirs: other.hash_width,
+            });
+        }
+
+        if self.hash_config != other.hash_config {
+            return Err(IncompatibilityReason::HashConfig {
+                ours: self.hash_config.clone(),
+                theirs: other.hash_config.clone(),
+            });
+        }
+
+        if let (Some(ours), Some(theirs)) = (self.block_size, other.block_size) {
+            if ours != theirs {
+                return Err(IncompatibilityReason::BlockSize { ours, theirs });
+            }
+        }
+
+        if other.snapshot_format_version > self.snapshot_format_version {
+            return Err(IncompatibilityReason::SnapshotFormat {
+                ours: self.snapshot_format_version,
+                theirs: other.snapshot_format_version,
+            });
+        }
+
+        if release_line(&self.crate_version) != release_line(&other.crate_version) {
+            return Err(IncompatibilityReason::CrateVersion {
+                ours: self.crate_version.clone(),
+                theirs: other.crate_version.clone(),
+            });
+        }
+
+        if let Some(feature) = other
+            .features
+            .iter()
+            .find(|feature| !self.features.contains(feature))
+        {
+            return Err(IncompatibilityReason::MissingFeature(feature.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+// The semver-compatible prefix of a version; a version which does not parse is its own line
+fn release_line(version: &str) -> Result<(u64, u64), &str> {
+    let mut parts = version.split('.').map(str::parse::<u64>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(0)), Some(Ok(minor))) => Ok((0, minor)),
+        (Some(Ok(major)), Some(Ok(_))) => Ok((major, 0)),
+        _ => Err(version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatible_with() {
+        let ours = CompatManifest {
+            crate_version: "0.3.1".to_string(),
+            block_size: Some(16),
+            features: Vec::new(),
+            ..Default::default()
+        };
+        let check = |theirs: CompatManifest| ours.compatible_with(&theirs);
+
+        assert_eq!(check(ours.clone()), Ok(()));
+
+        // patch releases and unknown block sizes are compatible
+        let theirs = CompatManifest {
+            crate_version: "0.3.4".to_string(),
+            block_size: None,
+            ..ours.clone()
+        };
+        assert_eq!(check(theirs), Ok(()));
+
+        let theirs = CompatManifest {
+            hash_width: 32,
+            ..ours.clone()
+        };
+        assert_eq!(
+            check(theirs),
+            Err(IncompatibilityReason::HashWidth {
+                ours: 64,
+                theirs: 32
+            })
+        );
+
+        let hash_config = HashConfig {
+            seed: 7,
+            ..Default::default()
+        };
+        let theirs = CompatManifest {
+            hash_config: hash_config.clone(),
+            ..ours.clone()
+        };
+        assert_eq!(
+            check(theirs),
+            Err(IncompatibilityReason::HashConfig {
+                ours: HashConfig::default(),
+                theirs: hash_config
+            })
+        );
+
+        let theirs = CompatManifest {
+            block_size: Some(32),
+            ..ours.clone()
+        };
+        assert_eq!(
+            check(theirs),
+            Err(IncompatibilityReason::BlockSize {
+                ours: 16,
+                theirs: 32
+            })
+        );
+
+        // older snapshot formats are still read
+        let theirs = CompatManifest {
+            snapshot_format_version: SNAPSHOT_FORMAT_VERSION + 1,
+            ..ours.clone()
+        };
+        assert_eq!(
+            check(theirs.clone()),
+            Err(IncompatibilityReason::SnapshotFormat {
+                ours: SNAPSHOT_FORMAT_VERSION,
+                theirs: SNAPSHOT_FORMAT_VERSION + 1
+            })
+        );
+        assert_eq!(theirs.compatible_with(&ours), Ok(()));
+
+        let theirs = CompatManifest {
+            crate_version: "0.4.0".to_string(),
+            ..ours.clone()
+        };
+        assert_eq!(
+            check(theirs),
+            Err(IncompatibilityReason::CrateVersion {
+                ours: "0.3.1".to_string(),
+                theirs: "0.4.0".to_string()
+            })
+        );
+
+        let theirs = CompatManifest {
+            features: vec!["descriptor-v2".to_string()],
+            ..ours.clone()
+        };
+        assert_eq!(
+            check(theirs.clone()),
+            Err(IncompatibilityReason::MissingFeature(
+                "descriptor-v2".to_string()
+            ))
+        );
+        assert_eq!(theirs.compatible_with(&ours), Ok(()));
+    }
+
+    #[test]
+    fn test_release_line() {
+        assert_eq!(release_line("1.2.3"), release_line("1.9.0"));
+        assert_ne!(release_line("1.2.3"), release_line("2.0.0"));
+        assert_ne!(release_line("0.2.3"), release_line("0.3.0"));
+        assert_eq!(release_line("dev"), Err("dev"));
+    }
+}
@@ -0,0 +1,374 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Block Descriptors
+//!
+//! [BlockDescriptor] is the one description of a KV block shared by every component which ships
+//! blocks over the wire.
+//!
+//! ## Wire format
+//!
+//! ```text
+//! version: u8 | field* where field = tag: u8 | len: u8 | value: [u8; len]
+//! ```
+//!
+//! All integers are little endian. Decoders skip fields with unknown tags, so newer writers may
+//! append fields without breaking older readers. The version byte is only bumped for changes an
+//! older reader can not safely ignore; a reader rejects versions newer than [MAX_SUPPORTED_VERSION].
+//!
+//! The priority is written twice: as a class and score field, and as a single value field for
+//! readers which predate classes. The single value field packs the class into the top 8 bits and
+//! saturates the score at [BlockPriority::LEGACY_SCORE_MASK]; a reader which finds the class and
+//! score field ignores it.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::{BlockPriority, KvBlock};
+use crate::{kv_router::indexer::WorkerId, tokens::SequenceHash};
+
+/// Version written by [BlockDescriptor::encode]
+pub const VERSION: u8 = 1;
+
+/// Newest version [BlockDescriptor::decode] understands
+pub const MAX_SUPPORTED_VERSION: u8 = 1;
+
+const TAG_SEQUENCE_HASH: u8 = 1;
+const TAG_PARENT_SEQUENCE_HASH: u8 = 2;
+const TAG_PRIORITY: u8 = 3;
+const TAG_TOKEN_COUNT: u8 = 4;
+const TAG_WORKER_ID: u8 = 5;
+const TAG_CLASS_PRIORITY: u8 = 6;
+
+/// Errors that can occur while decoding a [BlockDescriptor]
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum DescriptorError {
+    #[error("payload is empty")]
+    Empty,
+
+    #[error("unsupported descriptor version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("payload truncated")]
+    Truncated,
+
+    #[error("field {tag} has invalid length {len}")]
+    InvalidLength { tag: u8, len: u8 },
+
+    #[error("required field {0} is missing")]
+    MissingField(u8),
+}
+
+/// Compact, versioned description of a KV block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockDescriptor {
+    pub sequence_hash: SequenceHash,
+    pub parent_sequence_hash: Option<SequenceHash>,
+    pub priority: BlockPriority,
+    pub token_count: u32,
+    pub worker_id: Option<WorkerId>,
+}
+
+impl BlockDescriptor {
+    /// Set the worker which owns the block
+    pub fn with_worker_id(mut self, worker_id: WorkerId) -> Self {
+        self.worker_id = Some(worker_id);
+        self
+    }
+
+    /// Encode the descriptor at the current [VERSION]
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(48);
+        buf.put_u8(VERSION);
+
+        put_field(
+            &mut buf,
+            TAG_SEQUENCE_HASH,
+            &self.sequence_hash.to_le_bytes(),
+        );
+        if let Some(parent) = self.parent_sequence_hash {
+            put_field(&mut buf, TAG_PARENT_SEQUENCE_HASH, &parent.to_le_bytes());
+        }
+        let mut class_priority = [0; 5];
+        class_priority[0] = self.priority.class();
+        class_priority[1..].copy_from_slice(&self.priority.score().to_le_bytes());
+        put_field(&mut buf, TAG_CLASS_PRIORITY, &class_priority);
+        put_field(
+            &mut buf,
+            TAG_PRIORITY,
+            &legacy_priority(self.priority).to_le_bytes(),
+        );
+        put_field(&mut buf, TAG_TOKEN_COUNT, &self.token_count.to_le_bytes());
+        if let Some(worker_id) = self.worker_id {
+            put_field(&mut buf, TAG_WORKER_ID, &worker_id.to_le_bytes());
+        }
+
+        buf.freeze()
+    }
+
+    /// Decode a descriptor, skipping fields this version does not know about
+    pub fn decode(payload: &[u8]) -> Result<Self, DescriptorError> {
+        let mut buf = payload;
+        if !buf.has_remaining() {
+            return Err(DescriptorError::Empty);
+        }
+
+        let version = buf.get_u8();
+        if version == 0 || version > MAX_SUPPORTED_VERSION {
+            return Err(DescriptorError::UnsupportedVersion(version));
+        }
+
+        let mut sequence_hash = None;
+        let mut class_priority = None;
+        let mut descriptor = BlockDescriptor {
+            sequence_hash: 0,
+            parent_sequence_hash: None,
+            priority: BlockPriority::default(),
+            token_count: 0,
+            worker_id: None,
+        };
+
+        while buf.has_remaining() {
+            if buf.remaining() < 2 {
+                return Err(DescriptorError::Truncated);
+            }
+            let tag = buf.get_u8();
+            let len = buf.get_u8();
+            if buf.remaining() < len as usize {
+                return Err(DescriptorError::Truncated);
+            }
+            let (value, rest) = buf.split_at(len as usize);
+            buf = rest;
+
+            match tag {
+                TAG_SEQUENCE_HASH => sequence_hash = Some(u64::from_le_bytes(fixed(tag, value)?)),
+                TAG_PARENT_SEQUENCE_HASH => {
+                    descriptor.parent_sequence_hash = Some(u64::from_le_bytes(fixed(tag, value)?))
+                }
+                TAG_PRIORITY => descriptor.priority = u32::from_le_bytes(fixed(tag, value)?).into(),
+                TAG_CLASS_PRIORITY => {
+                    let [class, score @ ..] = fixed::<5>(tag, value)?;
+                    class_priority = Some(BlockPriority::new(class, u32::from_le_bytes(score)));
+                }
+                TAG_TOKEN_COUNT => descriptor.token_count = u32::from_le_bytes(fixed(tag, value)?),
+                TAG_WORKER_ID => {
+                    descriptor.worker_id = Some(WorkerId::from_le_bytes(fixed(tag, value)?))
+                }
+                // written by a newer version; safe to ignore
+                _ => {}
+            }
+        }
+
+        descriptor.sequence_hash =
+            sequence_hash.ok_or(DescriptorError::MissingField(TAG_SEQUENCE_HASH))?;
+        if let Some(priority) = class_priority {
+            descriptor.priority = priority;
+        }
+        Ok(descriptor)
+    }
+}
+
+impl From<&KvBlock> for BlockDescriptor {
+    fn from(block: &KvBlock) -> Self {
+        Self {
+            sequence_hash: block.token_block.sequence_hash(),
+            parent_sequence_hash: block.token_block.parent_sequence_hash(),
+            priority: block.priority,
+            token_count: block.token_block.tokens().len() as u32,
+            worker_id: None,
+        }
+    }
+}
+
+// single value priority for readers which predate classes
+fn legacy_priority(priority: BlockPriority) -> u32 {
+    ((priority.class() as u32) << 24) | priority.score().min(BlockPriority::LEGACY_SCORE_MASK)
+}
+
+fn put_field(buf: &mut BytesMut, tag: u8, value: &[u8]) {
+    buf.put_u8(tag);
+    buf.put_u8(value.len() as u8);
+    buf.put_slice(value);
+}
+
+fn fixed<const N: usize>(tag: u8, value: &[u8]) -> Result<[u8; N], DescriptorError> {
+    value
+        .try_into()
+        .map_err(|_| DescriptorError::InvalidLength {
+            tag,
+            len: value.len() as u8,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::kv::reuse::tests::{create_blocks, create_token_sequence};
+
+    fn descriptor() -> BlockDescriptor {
+        BlockDescriptor {
+            sequence_hash: 0x0123_4567_89ab_cdef,
+            parent_sequence_hash: Some(42),
+            priority: BlockPriority::new(2, 7),
+            token_count: 16,
+            worker_id: Some(-3),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let descriptor = descriptor();
+        assert_eq!(
+            BlockDescriptor::decode(&descriptor.encode()).unwrap(),
+            descriptor
+        );
+
+        let root = BlockDescriptor {
+            parent_sequence_hash: None,
+            worker_id: None,
+            ..descriptor
+        };
+        assert_eq!(BlockDescriptor::decode(&root.encode()).unwrap(), root);
+    }
+
+    #[test]
+    fn test_from_kv_block() {
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let descriptor = BlockDescriptor::from(&blocks[1]).with_worker_id(1);
+
+        assert_eq!(
+            descriptor.sequence_hash,
+            blocks[1].token_block.sequence_hash()
+        );
+        assert_eq!(
+            descriptor.parent_sequence_hash,
+            Some(blocks[0].token_block.sequence_hash())
+        );
+        assert_eq!(descriptor.token_count, 2);
+        assert_eq!(descriptor.worker_id, Some(1));
+    }
+
+    #[test]
+    fn test_decode_v1_payload() {
+        // hand written v1 payload with the fields in an unusual order
+        let mut payload = vec![1];
+        payload.extend([TAG_PRIORITY, 4]);
+        payload.extend(9u32.to_le_bytes());
+        payload.extend([TAG_SEQUENCE_HASH, 8]);
+        payload.extend(5u64.to_le_bytes());
+
+        let descriptor = BlockDescriptor::decode(&payload).unwrap();
+        assert_eq!(descriptor.sequence_hash, 5);
+        assert_eq!(descriptor.priority, BlockPriority::new(0, 9));
+        assert_eq!(descriptor.parent_sequence_hash, None);
+        assert_eq!(descriptor.token_count, 0);
+    }
+
+    #[test]
+    fn test_unknown_fields_are_skipped() {
+        // a newer writer appending fields keeps the version and adds new tags
+        let mut payload = descriptor().encode().to_vec();
+        payload.extend([200, 3, 0xaa, 0xbb, 0xcc]);
+        payload.extend([201, 0]);
+
+        assert_eq!(BlockDescriptor::decode(&payload).unwrap(), descriptor());
+    }
+
+    #[test]
+    fn test_class_priority_field() {
+        // a score past the single value range survives the class and score field
+        let descriptor = BlockDescriptor {
+            priority: BlockPriority::new(255, u32::MAX),
+            ..descriptor()
+        };
+        let payload = descriptor.encode();
+        assert_eq!(BlockDescriptor::decode(&payload).unwrap(), descriptor);
+
+        // a reader which predates classes sees the saturated single value field
+        let (mut buf, mut legacy) = (&payload[1..], None);
+        while buf.has_remaining() {
+            let (tag, len) = (buf.get_u8(), buf.get_u8() as usize);
+            if tag == TAG_PRIORITY {
+                legacy = Some(u32::from_le_bytes(buf[..len].try_into().unwrap()));
+            }
+            buf.advance(len);
+        }
+        assert_eq!(legacy, Some(u32::MAX));
+
+        // a single value field from an older writer goes through the shim
+        let mut payload = vec![1];
+        payload.extend([TAG_SEQUENCE_HASH, 8]);
+        payload.extend(5u64.to_le_bytes());
+        payload.extend([TAG_PRIORITY, 4]);
+        payload.extend(0x0200_0005u32.to_le_bytes());
+        assert_eq!(
+            BlockDescriptor::decode(&payload).unwrap().priority,
+            BlockPriority::new(2, 5)
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(BlockDescriptor::decode(&[]), Err(DescriptorError::Empty));
+        assert_eq!(
+            BlockDescriptor::decode(&[MAX_SUPPORTED_VERSION + 1]),
+            Err(DescriptorError::UnsupportedVersion(
+                MAX_SUPPORTED_VERSION + 1
+            ))
+        );
+        assert_eq!(
+            BlockDescriptor::decode(&[1]),
+            Err(DescriptorError::MissingField(TAG_SEQUENCE_HASH))
+        );
+        assert_eq!(
+            BlockDescriptor::decode(&[1, TAG_SEQUENCE_HASH, 8, 0]),
+            Err(DescriptorError::Truncated)
+        );
+        assert_eq!(
+            BlockDescriptor::decode(&[1, TAG_PRIORITY, 2, 0, 0]),
+            Err(DescriptorError::InvalidLength {
+                tag: TAG_PRIORITY,
+                len: 2
+            })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_never_panics(payload in proptest::collection::vec(any::<u8>(), 0..128)) {
+            let _ = BlockDescriptor::decode(&payload);
+        }
+
+        #[test]
+        fn test_round_trip_any(
+            sequence_hash in any::<u64>(),
+            parent_sequence_hash in any::<Option<u64>>(),
+            class in any::<u8>(),
+            score in any::<u32>(),
+            token_count in any::<u32>(),
+            worker_id in any::<Option<i64>>(),
+        ) {
+            let descriptor = BlockDescriptor {
+                sequence_hash,
+                parent_sequence_hash,
+                priority: BlockPriority::new(class, score),
+                token_count,
+                worker_id,
+            };
+            prop_assert_eq!(BlockDescriptor::decode(&descriptor.encode()).unwrap(), descriptor);
+        }
+    }
+}
@@ -0,0 +1,487 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Pool Federation
+//!
+//! A worker with several devices runs one [AvailableBlocks] per device. [FederatedPools] gives the
+//! scheduler a single view over them: it probes every pool for the cached prefix of a request,
+//! places the request on the pool with the largest overlap and aggregates counters and events.
+//!
+//! This is a routing facade only; blocks never move between pools. [FederatedPools::try_new]
+//! checks the [manifests][AvailableBlocks::manifest] of the pools, so a request is never placed on
+//! a pool which hashes it differently.
+//!
+//! Pools sharing a [SharedContentIndex] record which of them holds each hash, so a pool which
+//! misses a prefix cached by a peer can find it through [AvailableBlocks::match_or_fetch] and the
+//! caller can arrange a transfer instead of computing the prefix again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use dynamo_runtime::utils::pool::PoolItem;
+use dynamo_runtime::Result;
+use futures::{future::try_join_all, stream, Stream};
+use tokio::sync::broadcast;
+
+use super::compat::IncompatibilityReason;
+use super::reuse::{Allocation, AvailableBlocks, CounterSnapshot, OpCompleted};
+use super::KvBlock;
+use crate::tokens::SequenceHash;
+
+/// Index of a pool in a [FederatedPools]
+pub type PoolId = usize;
+
+/// Cached prefix of a request in one pool, see [FederatedPools::probe_all]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerPoolOverlap {
+    pub pool: PoolId,
+
+    /// Number of leading hashes cached in the pool
+    pub matched: usize,
+
+    /// Available blocks of the pool at probe time
+    pub available_blocks: u64,
+}
+
+/// Blocks acquired by [FederatedPools::allocate_on_best]
+pub struct FederatedAllocation {
+    /// Pool the blocks were acquired from
+    pub pool: PoolId,
+    pub allocation: Allocation,
+}
+
+/// An [OpCompleted] event tagged with the pool which reported it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOpCompleted {
+    pub pool: PoolId,
+    pub event: OpCompleted,
+}
+
+/// Which pools hold each sequence hash, shared by the pools configured with
+/// [AvailableBlocksConfig::with_shared_content_index][super::reuse::AvailableBlocksConfig::with_shared_content_index]
+///
+/// A pool records the hashes which entered it, cached or checked out, and forgets those which
+/// left it at the end of the engine turn which made the change, so the index lags every pool by
+/// at most one turn. A pool which is dropped leaves its hashes behind until
+/// [SharedContentIndex::remove_pool] is called.
+#[derive(Debug, Clone, Default)]
+pub struct SharedContentIndex {
+    holders: Arc<Mutex<HashMap<SequenceHash, Vec<PoolId>>>>,
+}
+
+impl SharedContentIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pools holding `hash`, in the order they recorded it
+    pub fn holders(&self, hash: SequenceHash) -> Vec<PoolId> {
+        self.holders
+            .lock()
+            .unwrap()
+            .get(&hash)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Leading `hashes` held by a single peer of `pool`; the peer is the first other pool which
+    /// recorded the first hash
+    pub fn peer_prefix(&self, pool: PoolId, hashes: &[SequenceHash]) -> Option<PeerFetch> {
+        let holders = self.holders.lock().unwrap();
+        let first = hashes.first()?;
+        let peer = *holders.get(first)?.iter().find(|&&holder| holder != pool)?;
+        let held = hashes
+            .iter()
+            .take_while(|hash| holders.get(hash).is_some_and(|pools| pools.contains(&peer)))
+            .copied()
+            .collect();
+        Some(PeerFetch {
+            pool: peer,
+            hashes: held,
+        })
+    }
+
+    /// Forget every hash recorded by `pool`
+    pub fn remove_pool(&self, pool: PoolId) {
+        self.holders.lock().unwrap().retain(|_, pools| {
+            pools.retain(|&holder| holder != pool);
+            !pools.is_empty()
+        });
+    }
+
+    // Record the hashes which entered (true) or left (false) `pool`, in order
+    pub(crate) fn apply(
+        &self,
+        pool: PoolId,
+        changes: impl IntoIterator<Item = (SequenceHash, bool)>,
+    ) {
+        let mut holders = self.holders.lock().unwrap();
+        for (hash, held) in changes {
+            if held {
+                let pools = holders.entry(hash).or_default();
+                if !pools.contains(&pool) {
+                    pools.push(pool);
+                }
+            } else if let Some(pools) = holders.get_mut(&hash) {
+                pools.retain(|&holder| holder != pool);
+                if pools.is_empty() {
+                    holders.remove(&hash);
+                }
+            }
+        }
+    }
+}
+
+/// Leading hashes a peer pool holds, see [SharedContentIndex::peer_prefix]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerFetch {
+    pub pool: PoolId,
+    pub hashes: Vec<SequenceHash>,
+}
+
+/// Outcome of [AvailableBlocks::match_or_fetch]
+pub struct MatchOrFetch {
+    /// Blocks matched locally, a prefix of the requested hashes
+    pub matched: Vec<PoolItem<KvBlock>>,
+
+    /// Peer holding the hashes which follow the local match, if any
+    pub fetch_from: Option<PeerFetch>,
+}
+
+/// Single view over the pools of one worker
+pub struct FederatedPools {
+    pools: Vec<AvailableBlocks>,
+}
+
+impl FederatedPools {
+    /// Federate `pools`; the [PoolId] of a pool is its index
+    pub fn new(pools: Vec<AvailableBlocks>) -> Self {
+        Self { pools }
+    }
+
+    /// Federate `pools` after checking that every pool is compatible with the first, both ways
+    pub fn try_new(pools: Vec<AvailableBlocks>) -> Result<Self, (PoolId, IncompatibilityReason)> {
+        if let Some((first, rest)) = pools.split_first() {
+            let manifest = first.manifest();
+            for (id, pool) in rest.iter().enumerate() {
+                let other = pool.manifest();
+                manifest
+                    .compatible_with(&other)
+                    .and_then(|()| other.compatible_with(&manifest))
+                    .map_err(|reason| (id + 1, reason))?;
+            }
+        }
+        Ok(Self::new(pools))
+    }
+
+    pub fn pools(&self) -> &[AvailableBlocks] {
+        &self.pools
+    }
+
+    pub fn pool(&self, pool: PoolId) -> Option<&AvailableBlocks> {
+        self.pools.get(pool)
+    }
+
+    pub fn total_blocks(&self) -> u64 {
+        self.pools.iter().map(|pool| pool.total_blocks()).sum()
+    }
+
+    pub fn available_blocks(&self) -> u64 {
+        self.pools.iter().map(|pool| pool.available_blocks()).sum()
+    }
+
+    /// Counters of every pool since the last drain, summed
+    pub async fn drain_counters(&self) -> Result<CounterSnapshot> {
+        let counters = try_join_all(self.pools.iter().map(|pool| pool.drain_counters())).await?;
+        let mut total = CounterSnapshot::default();
+        for counters in counters {
+            total += counters;
+        }
+        Ok(total)
+    }
+
+    /// Cached prefix of `hashes` in every pool, in pool order
+    ///
+    /// This is a read-only query; the pools are probed concurrently, so the overlaps may reflect
+    /// slightly different points in time.
+    pub async fn probe_all(&self, hashes: Vec<SequenceHash>) -> Result<Vec<PerPoolOverlap>> {
+        let probes = self.pools.iter().enumerate().map(|(id, pool)| {
+            let hashes = hashes.clone();
+            async move {
+                let matched = pool.cached_prefix_len(hashes).await;
+                matched.map(|matched| PerPoolOverlap {
+                    pool: id,
+                    matched,
+                    available_blocks: pool.available_blocks(),
+                })
+            }
+        });
+        try_join_all(probes).await
+    }
+
+    /// Acquire `needed` blocks for a request on the pool holding the longest cached prefix of
+    /// `hashes`, preferring the pool with more available blocks on a tie
+    ///
+    /// The blocks for the first `needed` hashes are acquired as by [AvailableBlocks::allocate];
+    /// blocks needed beyond the known hashes are taken from the same pool in the same engine turn,
+    /// see [AvailableBlocks::allocate_extra]. The pools are only probed beforehand, so the choice
+    /// may be stale by the time the allocation is served. Returns `None` if there are no pools.
+    pub async fn allocate_on_best(
+        &self,
+        mut hashes: Vec<SequenceHash>,
+        needed: usize,
+    ) -> Result<Option<FederatedAllocation>> {
+        let best = self
+            .probe_all(hashes.clone())
+            .await?
+            .into_iter()
+            .max_by_key(|overlap| (overlap.matched, overlap.available_blocks));
+        let Some(best) = best else {
+            return Ok(None);
+        };
+
+        let pool = &self.pools[best.pool];
+        hashes.truncate(needed);
+        let extra = needed - hashes.len();
+
+        let allocation = pool.allocate_extra(hashes, extra as u32).await?;
+
+        Ok(Some(FederatedAllocation {
+            pool: best.pool,
+            allocation,
+        }))
+    }
+
+    /// Merged [OpCompleted] events of every pool, tagged with their pool
+    ///
+    /// Events a slow subscriber missed are skipped, as by [AvailableBlocks::subscribe_op_events].
+    pub fn subscribe_op_events(&self) -> impl Stream<Item = PoolOpCompleted> {
+        stream::select_all(self.pools.iter().enumerate().map(|(id, pool)| {
+            Box::pin(stream::unfold(
+                pool.subscribe_op_events(),
+                move |mut rx| async move {
+                    loop {
+                        match rx.recv().await {
+                            Ok(event) => return Some((PoolOpCompleted { pool: id, event }, rx)),
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                },
+            ))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::reuse::{
+        tests::{create_blocks, create_token_sequence},
+        AvailableBlocksConfig, SessionOptions,
+    };
+    use crate::kv::KvBlock;
+
+    async fn seeded_pool(tokens: &[u32], blanks: usize) -> AvailableBlocks {
+        let pool = AvailableBlocks::new().await;
+        pool.insert_many(create_blocks(create_token_sequence(tokens), 2))
+            .await
+            .unwrap();
+        for _ in 0..blanks {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+        pool.fence().await.unwrap();
+        pool
+    }
+
+    fn hashes(tokens: &[u32]) -> Vec<SequenceHash> {
+        create_blocks(create_token_sequence(tokens), 2)
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_allocate_on_best() {
+        // pool 0 caches one block of the request, pool 1 caches two
+        let pools = FederatedPools::new(vec![
+            seeded_pool(&[1, 2, 9, 9], 4).await,
+            seeded_pool(&[1, 2, 3, 4], 2).await,
+        ]);
+        let request = hashes(&[1, 2, 3, 4, 5, 6]);
+
+        let overlaps = pools.probe_all(request.clone()).await.unwrap();
+        assert_eq!(
+            overlaps,
+            vec![
+                PerPoolOverlap {
+                    pool: 0,
+                    matched: 1,
+                    available_blocks: 6,
+                },
+                PerPoolOverlap {
+                    pool: 1,
+                    matched: 2,
+                    available_blocks: 4,
+                },
+            ]
+        );
+
+        let placed = pools.allocate_on_best(request, 4).await.unwrap().unwrap();
+        assert_eq!(placed.pool, 1);
+        assert_eq!(placed.allocation.matched.len(), 2);
+        assert_eq!(placed.allocation.taken.len(), 2);
+        assert_eq!(pools.available_blocks(), 6);
+        drop(placed);
+        for pool in pools.pools() {
+            pool.fence().await.unwrap();
+        }
+
+        // with equal overlaps, the pool with more available blocks wins
+        let placed = pools
+            .allocate_on_best(hashes(&[7, 8]), 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(placed.pool, 0);
+        assert_eq!(placed.allocation.taken.len(), 1);
+        drop(placed);
+        for pool in pools.pools() {
+            pool.fence().await.unwrap();
+        }
+
+        // the blocks needed past the known hashes come from the same pool
+        let placed = pools
+            .allocate_on_best(hashes(&[1, 2, 3, 4]), 4)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(placed.pool, 1);
+        assert_eq!(placed.allocation.matched.len(), 2);
+        assert_eq!(placed.allocation.taken.len(), 2);
+        assert_eq!(pools.pools()[1].available_blocks(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_match_or_fetch_from_peer() {
+        let index = SharedContentIndex::new();
+        let pool = |id| {
+            AvailableBlocks::new_with_config(
+                AvailableBlocksConfig::default().with_shared_content_index(index.clone(), id),
+            )
+        };
+        let (local, peer) = (pool(0).await, pool(1).await);
+        local
+            .insert_many(create_blocks(create_token_sequence(&[1, 2]), 2))
+            .await
+            .unwrap();
+        peer.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2))
+            .await
+            .unwrap();
+        local.fence().await.unwrap();
+        peer.fence().await.unwrap();
+
+        let request = hashes(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(index.holders(request[0]), vec![0, 1]);
+
+        // the local pool holds the first block, the peer the next two
+        let outcome = local.match_or_fetch(request.clone()).await.unwrap();
+        assert_eq!(outcome.matched.len(), 1);
+        assert_eq!(
+            outcome.fetch_from,
+            Some(PeerFetch {
+                pool: 1,
+                hashes: request[1..3].to_vec()
+            })
+        );
+        drop(outcome);
+        local.fence_returns().await.unwrap();
+
+        // a block checked out of the peer is still held by it
+        let held = peer.match_blocks(request[..2].to_vec()).await.unwrap();
+        peer.fence().await.unwrap();
+        assert_eq!(index.holders(request[1]), vec![1]);
+        drop(held);
+        peer.fence_returns().await.unwrap();
+
+        // evicted blocks leave the index
+        drop(peer.take_blocks(3).await.unwrap());
+        peer.fence().await.unwrap();
+        assert!(index.holders(request[1]).is_empty());
+        let outcome = local.match_or_fetch(request.clone()).await.unwrap();
+        assert_eq!(outcome.matched.len(), 1);
+        assert_eq!(outcome.fetch_from, None);
+
+        index.remove_pool(0);
+        assert!(index.holders(request[0]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_aggregated_counters_and_events() {
+        let pools = FederatedPools::new(vec![
+            seeded_pool(&[1, 2], 0).await,
+            seeded_pool(&[3, 4], 0).await,
+        ]);
+        assert_eq!(pools.total_blocks(), 2);
+
+        let counters = pools.drain_counters().await.unwrap();
+        assert_eq!(counters.inserts, 2);
+
+        let mut events = Box::pin(pools.subscribe_op_events());
+        let session = pools.pool(1).unwrap().session(SessionOptions {
+            request_id: Some(7),
+            ..Default::default()
+        });
+        drop(session.match_blocks(hashes(&[3, 4])).await.unwrap());
+
+        let event = futures::StreamExt::next(&mut events).await.unwrap();
+        assert_eq!(event.pool, 1);
+        assert_eq!(event.event.request_id, 7);
+        assert_eq!(event.event.hits, 1);
+
+        for pool in pools.pools() {
+            pool.fence().await.unwrap();
+        }
+        let counters = pools.drain_counters().await.unwrap();
+        assert_eq!(counters.matches, 1);
+        assert_eq!(counters.returns, 1);
+    }
+
+    #[tokio::test]
+    async fn test_try_new_checks_manifests() {
+        let pool = |block_size| {
+            AvailableBlocks::new_with_config(
+                AvailableBlocksConfig::default().with_block_size(block_size),
+            )
+        };
+
+        let pools = FederatedPools::try_new(vec![pool(16).await, pool(16).await]).unwrap();
+        assert_eq!(pools.pools().len(), 2);
+
+        let Err((id, reason)) =
+            FederatedPools::try_new(vec![pool(16).await, pool(16).await, pool(32).await])
+        else {
+            panic!("pools with different block sizes were federated");
+        };
+        assert_eq!(id, 2);
+        assert_eq!(
+            reason,
+            IncompatibilityReason::BlockSize {
+                ours: 16,
+                theirs: 32
+            }
+        );
+    }
+}
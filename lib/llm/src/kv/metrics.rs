@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Pool Metrics
+//!
+//! Prometheus metrics fed from the [TokenStats] of the pools of a worker, labeled by pool name.
+
+use prometheus::{GaugeVec, IntCounterVec, Opts, Registry};
+use tokio::sync::watch;
+
+use super::reuse::TokenStats;
+
+/// Prometheus metrics of the token accounting of [AvailableBlocks][super::reuse::AvailableBlocks]
+/// pools
+pub struct TokenMetrics {
+    tokens_saved: IntCounterVec,
+    tokens_recomputed: IntCounterVec,
+    token_hit_ratio: GaugeVec,
+}
+
+impl TokenMetrics {
+    /// Create TokenMetrics with the given prefix
+    /// The following metrics will be created:
+    /// - `{prefix}_kv_pool_tokens_saved_total` - IntCounterVec for the prefill tokens served from the pool
+    /// - `{prefix}_kv_pool_tokens_recomputed_total` - IntCounterVec for the request tokens the pool did not cover
+    /// - `{prefix}_kv_pool_token_hit_ratio` - GaugeVec for the hit ratio by tokens over the window
+    pub fn new(prefix: &str) -> Self {
+        let tokens_saved = IntCounterVec::new(
+            Opts::new(
+                format!("{}_kv_pool_tokens_saved_total", prefix),
+                "Prefill tokens served from the KV pool",
+            ),
+            &["pool"],
+        )
+        .unwrap();
+
+        let tokens_recomputed = IntCounterVec::new(
+            Opts::new(
+                format!("{}_kv_pool_tokens_recomputed_total", prefix),
+                "Request tokens not covered by the KV pool",
+            ),
+            &["pool"],
+        )
+        .unwrap();
+
+        let token_hit_ratio = GaugeVec::new(
+            Opts::new(
+                format!("{}_kv_pool_token_hit_ratio", prefix),
+                "Fraction of the request tokens served from the KV pool over the hit window",
+            ),
+            &["pool"],
+        )
+        .unwrap();
+
+        TokenMetrics {
+            tokens_saved,
+            tokens_recomputed,
+            token_hit_ratio,
+        }
+    }
+
+    pub fn register(&self, registry: &Registry) -> Result<(), prometheus::Error> {
+        registry.register(Box::new(self.tokens_saved.clone()))?;
+        registry.register(Box::new(self.tokens_recomputed.clone()))?;
+        registry.register(Box::new(self.token_hit_ratio.clone()))?;
+        Ok(())
+    }
+
+    /// Bring the metrics of `pool` up to date with `stats`
+    ///
+    /// The totals of [TokenStats] only grow, so the counters advance by the difference to their
+    /// current value. The hit ratio is left as is while no request is within the window.
+    pub fn observe(&self, pool: &str, stats: &TokenStats) {
+        let saved = self.tokens_saved.with_label_values(&[pool]);
+        saved.inc_by(stats.tokens_saved.saturating_sub(saved.get()));
+
+        let recomputed = self.tokens_recomputed.with_label_values(&[pool]);
+        recomputed.inc_by(stats.tokens_recomputed.saturating_sub(recomputed.get()));
+
+        if let Some(ratio) = stats.window_hit_ratio() {
+            self.token_hit_ratio.with_label_values(&[pool]).set(ratio);
+        }
+    }
+
+    /// Observe every update of `stats`, as returned by
+    /// [super::reuse::AvailableBlocks::watch_token_stats], until the pool is dropped
+    pub async fn follow(&self, pool: &str, mut stats: watch::Receiver<TokenStats>) {
+        loop {
+            let current = *stats.borrow_and_update();
+            self.observe(pool, &current);
+            if stats.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let metrics = TokenMetrics::new("test");
+        let registry = Registry::new();
+        metrics.register(&registry).unwrap();
+
+        let mut stats = TokenStats {
+            tokens_saved: 10,
+            tokens_recomputed: 3,
+            window_tokens_saved: 10,
+            window_tokens_requested: 13,
+        };
+        metrics.observe("gpu0", &stats);
+        stats.tokens_saved = 14;
+        stats.window_tokens_requested = 20;
+        metrics.observe("gpu0", &stats);
+
+        assert_eq!(metrics.tokens_saved.with_label_values(&["gpu0"]).get(), 14);
+        assert_eq!(
+            metrics.tokens_recomputed.with_label_values(&["gpu0"]).get(),
+            3
+        );
+        assert_eq!(
+            metrics.token_hit_ratio.with_label_values(&["gpu0"]).get(),
+            0.5
+        );
+
+        // an empty window keeps the last ratio
+        stats.window_tokens_saved = 0;
+        stats.window_tokens_requested = 0;
+        metrics.observe("gpu0", &stats);
+        assert_eq!(
+            metrics.token_hit_ratio.with_label_values(&["gpu0"]).get(),
+            0.5
+        );
+    }
+}
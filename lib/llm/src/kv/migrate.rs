@@ -0,0 +1,474 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Block Size Migration
+//!
+//! Offline tooling to move a cache index to a new block size. [rechunk] walks every chain of a
+//! [PoolSnapshot] taken at the old block size and recomputes the sequence hashes at the new block
+//! size wherever the token content of the prefix is known. Each new block records the segments of
+//! old blocks holding its tokens, so it can be registered as a cold descriptor pointing at the old
+//! data layout; the old blocks which are not fully covered by new blocks must be recomputed.
+
+use std::collections::{HashMap, HashSet};
+
+use bytemuck::cast_slice;
+
+use super::KvBlock;
+use crate::{
+    kv_router::indexer::compute_hash,
+    tokens::{BlockHash, SequenceHash, Token, Tokens},
+};
+
+/// Errors that can occur while planning a migration
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MigrationError {
+    #[error("block size must be non-zero")]
+    ZeroBlockSize,
+}
+
+/// A cached block of a [PoolSnapshot]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotBlock {
+    pub sequence_hash: SequenceHash,
+    pub parent_sequence_hash: Option<SequenceHash>,
+
+    /// Token content of the block, if known
+    pub tokens: Option<Tokens>,
+}
+
+/// Cached chains of a pool at one block size
+#[derive(Debug, Clone, Default)]
+pub struct PoolSnapshot {
+    pub block_size: usize,
+    pub blocks: Vec<SnapshotBlock>,
+}
+
+impl PoolSnapshot {
+    /// Snapshot of blocks whose token content is known
+    pub fn from_blocks(block_size: usize, blocks: &[KvBlock]) -> Self {
+        let blocks = blocks
+            .iter()
+            .map(|block| SnapshotBlock {
+                sequence_hash: block.token_block.sequence_hash(),
+                parent_sequence_hash: block.token_block.parent_sequence_hash(),
+                tokens: Some(block.token_block.tokens().clone()),
+            })
+            .collect();
+        Self { block_size, blocks }
+    }
+}
+
+/// Tokens `offset..offset + len` of an old block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSegment {
+    pub sequence_hash: SequenceHash,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// A block at the new block size whose tokens are held by old blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigratedBlock {
+    pub sequence_hash: SequenceHash,
+    pub parent_sequence_hash: Option<SequenceHash>,
+
+    /// Where the tokens of the block live in the old layout, in token order
+    pub segments: Vec<SourceSegment>,
+}
+
+/// Why an old block can not be carried over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecomputeReason {
+    /// The tokens of the block or of one of its ancestors are unknown or do not fill a block
+    UnknownTokens,
+
+    /// An ancestor of the block is missing from the snapshot
+    MissingParent,
+
+    /// Some tokens of the block fall into a trailing partial block at the new block size
+    PartialBlock,
+}
+
+/// An old block which must be recomputed after the migration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecomputeBlock {
+    pub sequence_hash: SequenceHash,
+    pub reason: RecomputeReason,
+}
+
+/// Result of [rechunk]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationPlan {
+    pub block_size: usize,
+
+    /// New blocks, each after its parent
+    pub blocks: Vec<MigratedBlock>,
+
+    /// Old blocks not fully covered by [MigrationPlan::blocks], in snapshot order
+    pub recompute: Vec<RecomputeBlock>,
+}
+
+/// Plan the migration of `snapshot` to `block_size`
+pub fn rechunk(
+    snapshot: &PoolSnapshot,
+    block_size: usize,
+) -> Result<MigrationPlan, MigrationError> {
+    if snapshot.block_size == 0 || block_size == 0 {
+        return Err(MigrationError::ZeroBlockSize);
+    }
+
+    let mut children: HashMap<SequenceHash, Vec<&SnapshotBlock>> = HashMap::new();
+    let mut roots = Vec::new();
+    for block in &snapshot.blocks {
+        match block.parent_sequence_hash {
+            None => roots.push(block),
+            Some(parent) => children.entry(parent).or_default().push(block),
+        }
+    }
+
+    let mut planner = Planner {
+        old_block_size: snapshot.block_size,
+        block_size,
+        children,
+        blocks: Vec::new(),
+        visited: HashSet::new(),
+        covered: HashSet::new(),
+        unknown: HashSet::new(),
+    };
+    for root in roots {
+        planner.visit(root, ChainState::default());
+    }
+
+    let recompute = snapshot
+        .blocks
+        .iter()
+        .filter(|block| !planner.covered.contains(&block.sequence_hash))
+        .map(|block| {
+            let reason = if planner.unknown.contains(&block.sequence_hash) {
+                RecomputeReason::UnknownTokens
+            } else if planner.visited.contains(&block.sequence_hash) {
+                RecomputeReason::PartialBlock
+            } else {
+                RecomputeReason::MissingParent
+            };
+            RecomputeBlock {
+                sequence_hash: block.sequence_hash,
+                reason,
+            }
+        })
+        .collect();
+
+    Ok(MigrationPlan {
+        block_size,
+        blocks: planner.blocks,
+        recompute,
+    })
+}
+
+// Sequence hash chained like [crate::tokens::TokenSequence::split_tokens]
+fn chain_hash(parent: Option<SequenceHash>, block_hash: BlockHash) -> SequenceHash {
+    match parent {
+        Some(parent) => compute_hash(cast_slice(&[parent, block_hash])),
+        None => block_hash,
+    }
+}
+
+// Progress of the new chain along one path of old blocks
+#[derive(Debug, Clone, Default)]
+struct ChainState {
+    parent: Option<SequenceHash>,
+
+    // tokens not yet in a new block, with the old blocks holding them
+    pending: Vec<Token>,
+    segments: Vec<SourceSegment>,
+}
+
+struct Planner<'a> {
+    old_block_size: usize,
+    block_size: usize,
+    children: HashMap<SequenceHash, Vec<&'a SnapshotBlock>>,
+    blocks: Vec<MigratedBlock>,
+
+    // old blocks reached from a root through blocks with known tokens
+    visited: HashSet<SequenceHash>,
+
+    // old blocks whose last tokens made it into a new block on some path
+    covered: HashSet<SequenceHash>,
+    unknown: HashSet<SequenceHash>,
+}
+
+impl<'a> Planner<'a> {
+    // Depth first from `root` with an explicit stack, as chains can be arbitrarily long
+    fn visit(&mut self, root: &'a SnapshotBlock, state: ChainState) {
+        let mut stack = vec![(root, state)];
+        while let Some((block, mut state)) = stack.pop() {
+            let tokens = match &block.tokens {
+                Some(tokens) if tokens.len() == self.old_block_size => tokens,
+                _ => {
+                    self.mark_unknown(block);
+                    continue;
+                }
+            };
+
+            self.visited.insert(block.sequence_hash);
+            state.pending.extend_from_slice(tokens);
+            state.segments.push(SourceSegment {
+                sequence_hash: block.sequence_hash,
+                offset: 0,
+                len: tokens.len(),
+            });
+
+            while state.pending.len() >= self.block_size {
+                self.emit(&mut state);
+            }
+
+            // pushed in reverse, so the children are visited in order
+            if let Some(children) = self.children.get(&block.sequence_hash) {
+                for child in children.iter().rev() {
+                    stack.push((*child, state.clone()));
+                }
+            }
+        }
+    }
+
+    // Turn the first `block_size` pending tokens into a new block
+    fn emit(&mut self, state: &mut ChainState) {
+        let tokens: Vec<Token> = state.pending.drain(..self.block_size).collect();
+        let sequence_hash = chain_hash(state.parent, compute_hash(cast_slice(&tokens)));
+
+        let mut segments = Vec::new();
+        let mut needed = self.block_size;
+        while needed > 0 {
+            let front = &mut state.segments[0];
+            let len = front.len.min(needed);
+            segments.push(SourceSegment { len, ..*front });
+            needed -= len;
+
+            if len == front.len {
+                // the remaining tokens of the old block are all in new blocks now
+                self.covered.insert(front.sequence_hash);
+                state.segments.remove(0);
+            } else {
+                front.offset += len;
+                front.len -= len;
+            }
+        }
+
+        self.blocks.push(MigratedBlock {
+            sequence_hash,
+            parent_sequence_hash: state.parent,
+            segments,
+        });
+        state.parent = Some(sequence_hash);
+    }
+
+    fn mark_unknown(&mut self, block: &'a SnapshotBlock) {
+        let mut stack = vec![block];
+        while let Some(block) = stack.pop() {
+            if !self.unknown.insert(block.sequence_hash) {
+                continue;
+            }
+            if let Some(children) = self.children.get(&block.sequence_hash) {
+                stack.extend(children.iter().copied());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::reuse::tests::{block_hashes, create_blocks, create_token_sequence};
+
+    fn snapshot(values: &[u32], block_size: usize) -> (PoolSnapshot, Vec<SequenceHash>) {
+        let blocks = create_blocks(create_token_sequence(values), block_size);
+        let hashes = block_hashes(&blocks);
+        (PoolSnapshot::from_blocks(block_size, &blocks), hashes)
+    }
+
+    fn expected_hashes(values: &[u32], block_size: usize) -> Vec<SequenceHash> {
+        let (blocks, _) = create_token_sequence(values)
+            .into_sequence_fast(block_size)
+            .into_parts();
+        blocks.iter().map(|block| block.sequence_hash()).collect()
+    }
+
+    fn plan_hashes(plan: &MigrationPlan) -> Vec<SequenceHash> {
+        plan.blocks
+            .iter()
+            .map(|block| block.sequence_hash)
+            .collect()
+    }
+
+    #[test]
+    fn test_rechunk_to_smaller_divisor() {
+        let values: Vec<u32> = (0..12).collect();
+        let (snapshot, old) = snapshot(&values, 4);
+
+        let plan = rechunk(&snapshot, 2).unwrap();
+        assert_eq!(plan_hashes(&plan), expected_hashes(&values, 2));
+        assert!(plan.recompute.is_empty());
+
+        assert_eq!(plan.blocks[0].parent_sequence_hash, None);
+        assert_eq!(
+            plan.blocks[3].parent_sequence_hash,
+            Some(plan.blocks[2].sequence_hash)
+        );
+        assert_eq!(
+            plan.blocks[3].segments,
+            vec![SourceSegment {
+                sequence_hash: old[1],
+                offset: 2,
+                len: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rechunk_to_non_divisor() {
+        // 10 tokens at block size 3 leave a partial block in the last old block
+        let values: Vec<u32> = (0..10).collect();
+        let (snapshot, old) = snapshot(&values, 5);
+
+        let plan = rechunk(&snapshot, 3).unwrap();
+        assert_eq!(plan_hashes(&plan), expected_hashes(&values, 3));
+        assert_eq!(
+            plan.blocks[1].segments,
+            vec![
+                SourceSegment {
+                    sequence_hash: old[0],
+                    offset: 3,
+                    len: 2,
+                },
+                SourceSegment {
+                    sequence_hash: old[1],
+                    offset: 0,
+                    len: 1,
+                },
+            ]
+        );
+        assert_eq!(
+            plan.recompute,
+            vec![RecomputeBlock {
+                sequence_hash: old[1],
+                reason: RecomputeReason::PartialBlock,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rechunk_to_larger() {
+        // 3 blocks of 2 tokens hold one block of 4 and a partial block
+        let values: Vec<u32> = (0..6).collect();
+        let (snapshot, old) = snapshot(&values, 2);
+
+        let plan = rechunk(&snapshot, 4).unwrap();
+        assert_eq!(plan_hashes(&plan), expected_hashes(&values, 4));
+        assert_eq!(plan.blocks[0].segments.len(), 2);
+        assert_eq!(
+            plan.recompute,
+            vec![RecomputeBlock {
+                sequence_hash: old[2],
+                reason: RecomputeReason::PartialBlock,
+            }]
+        );
+
+        // a chain shorter than one new block is recomputed entirely
+        let plan = rechunk(&snapshot, 8).unwrap();
+        assert!(plan.blocks.is_empty());
+        assert_eq!(plan.recompute.len(), 3);
+    }
+
+    #[test]
+    fn test_rechunk_long_chain() {
+        // deep enough to overflow the stack of a test thread if each block took a frame
+        let values: Vec<u32> = (0..100_000).collect();
+        let (snapshot, _) = snapshot(&values, 1);
+
+        let plan = rechunk(&snapshot, 2).unwrap();
+        assert_eq!(plan_hashes(&plan), expected_hashes(&values, 2));
+        assert!(plan.recompute.is_empty());
+    }
+
+    #[test]
+    fn test_rechunk_branches() {
+        let (trunk, _) = snapshot(&[1, 2, 3], 3);
+        let (left, _) = snapshot(&[1, 2, 3, 4, 5, 6], 3);
+        let (right, _) = snapshot(&[1, 2, 3, 7, 8, 9], 3);
+
+        let mut blocks = trunk.blocks.clone();
+        blocks.push(left.blocks[1].clone());
+        blocks.push(right.blocks[1].clone());
+        let snapshot = PoolSnapshot {
+            block_size: 3,
+            blocks,
+        };
+
+        // the trunk's last token is shared by the first new block of each branch
+        let plan = rechunk(&snapshot, 2).unwrap();
+        let hashes: HashSet<SequenceHash> = plan_hashes(&plan).into_iter().collect();
+        assert_eq!(plan.blocks.len(), 5);
+        for values in [[1, 2, 3, 4, 5, 6], [1, 2, 3, 7, 8, 9]] {
+            assert!(expected_hashes(&values, 2)
+                .iter()
+                .all(|hash| hashes.contains(hash)));
+        }
+        assert!(plan.recompute.is_empty());
+    }
+
+    #[test]
+    fn test_rechunk_unknown_tokens_and_missing_parents() {
+        let values: Vec<u32> = (0..8).collect();
+        let (mut snapshot, old) = snapshot(&values, 2);
+
+        // the second block has unknown tokens and the third block's parent is gone
+        snapshot.blocks[1].tokens = None;
+        let (orphans, orphan_old) = snapshot_without_root(&[9, 9, 8, 8], 2);
+        snapshot.blocks.extend(orphans);
+
+        let plan = rechunk(&snapshot, 1).unwrap();
+        assert_eq!(plan.blocks.len(), 2);
+
+        let reasons: Vec<(SequenceHash, RecomputeReason)> = plan
+            .recompute
+            .iter()
+            .map(|block| (block.sequence_hash, block.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (old[1], RecomputeReason::UnknownTokens),
+                (old[2], RecomputeReason::UnknownTokens),
+                (old[3], RecomputeReason::UnknownTokens),
+                (orphan_old[1], RecomputeReason::MissingParent),
+            ]
+        );
+    }
+
+    // snapshot of a chain whose root block is missing
+    fn snapshot_without_root(
+        values: &[u32],
+        block_size: usize,
+    ) -> (Vec<SnapshotBlock>, Vec<SequenceHash>) {
+        let (snapshot, hashes) = snapshot(values, block_size);
+        (snapshot.blocks[1..].to_vec(), hashes)
+    }
+
+    #[test]
+    fn test_rechunk_zero_block_size() {
+        let (snapshot, _) = snapshot(&[1, 2], 2);
+        assert_eq!(rechunk(&snapshot, 0), Err(MigrationError::ZeroBlockSize));
+    }
+}
@@ -36,1027 +36,15749 @@
 //! - **Synchronization**: Fence operations ensure all higher priority operations have completed
 //!   before proceeding. Note that this is not a true fence - higher priority operations issued
 //!   after the fence will still be processed before the fence completes.
-
-use std::sync::atomic::Ordering;
+//!
+//! ## Match ordering
+//!
+//! Every match delivers a contiguous prefix of the requested hashes, in request order: block `i`
+//! of the result is the block for hash `i`, and the first hash which misses ends the match even if
+//! later hashes are cached. This holds for plain, streamed, tiered and chunked matches and for the
+//! matched blocks of an allocation. A chunked or streamed match walks the hashes in order across
+//! its chunks and never revisits a hash, so the guarantee holds however the engine splits it.
+//!
+//! A match or allocation which lists the same hash twice is rejected with
+//! [KvPoolError::InvalidRequest]: the first occurrence would check the block out and the second
+//! would always miss, ending the match there. Matching a hash again in a later request is allowed
+//! and misses while the block is checked out. Likewise every take of zero blocks fails with
+//! [KvPoolError::InvalidRequest], and panics in debug builds.
+//!
+//! ## Request ordering
+//!
+//! Requests reach the progress engine on separate channels, each handled in the order it was sent.
+//! Between channels the engine is biased: whenever several are ready it serves matches and takes
+//! first, then returns, returns spilled to the overflow buffer, control requests, janitor ticks
+//! and fences last. A block dropped right before a take is therefore not guaranteed to be
+//! visible to that take, which may be served from the blocks available before the drop while the
+//! return waits behind it. [AvailableBlocks::available_blocks] likewise counts a dropped block
+//! only once the engine absorbed it.
+//!
+//! [AvailableBlocks::fence_returns] is the synchronization point: it resolves once every block
+//! dropped before the call was absorbed, so a match or take issued after it sees those blocks.
+//! An [AvailableBlocks::fence] also resolves only after the returns queued before it, but waits
+//! behind match and control requests as well. While the lookup map is being compacted, see
+//! [AvailableBlocksConfig::with_compaction], returns and fences both wait for the compaction to
+//! finish, so neither guarantee is weakened by it.
+//!
+//! ## Deterministic mode
+//!
+//! With [AvailableBlocksConfig::with_deterministic_mode], the same sequence of requests leaves the
+//! pool in the same state and emits the same events on every run. The lookup map then uses a
+//! fixed-key hasher, so walks over it, such as a reconcile, visit the blocks in the same order.
+//! The rest of the engine is ordered in every mode:
+//!
+//! - Eviction takes blocks by `(class, score, sticky, affinity tick, return tick, sequence hash)`,
+//!   lowest first; see [BlockPriority]. Return ticks are unique, so the sequence hash only orders
+//!   lookup keys.
+//! - [AvailableBlocks::export_manifest] lists the blocks in eviction order and
+//!   [AvailableBlocks::dump_state] lists the lookup keys by sequence hash.
+//! - Takes and matches waiting under [UnderSupply::WaitUpTo], [OnSoftHold::Wait] or
+//!   [OnMaintenance::Wait] are woken together by the turn which may serve them and are served in
+//!   the order their retries reach the engine; with
+//!   [AvailableBlocksConfig::with_strict_sequencing], in the order they were issued.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashSet},
+    future::Future,
+    hash::{BuildHasher, DefaultHasher, RandomState},
+    ops::{Bound, RangeBounds, RangeInclusive},
+    sync::{atomic::Ordering, Mutex},
+};
 
 use dynamo_runtime::utils::pool::ReturnHandle;
+use futures::{Stream, StreamExt};
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        oneshot, watch, Notify,
+    },
     task::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
+
+#[cfg(feature = "trace-record")]
+use super::trace::{RecordedTrace, TraceOp, TraceRecorder, TraceRecorderConfig};
+use super::{
+    compat::{CompatManifest, HashConfig},
+    descriptor::BlockDescriptor,
+    federation::{MatchOrFetch, PoolId, SharedContentIndex},
+    *,
 };
 
-use super::*;
+/// Errors returned by the [AvailableBlocks] pool
+#[derive(Debug, thiserror::Error)]
+pub enum KvPoolError {
+    #[error("pool is not ready; initial population has not completed")]
+    NotReady,
+
+    #[error("pool is shutting down")]
+    ShuttingDown,
+
+    #[error("block has a zero sequence hash")]
+    ZeroHash,
+
+    /// A request which can only come from a caller bug; debug builds panic with the description
+    /// instead for the cheap checks, see [AvailableBlocks::take_blocks]
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("caller {0:?} has reached its quota of outstanding blocks")]
+    QuotaExceeded(CallerId),
+
+    #[error("{requested} blocks requested but only {available} can be taken")]
+    InsufficientBlocks {
+        requested: u32,
+        available: u64,
+        shortfall: ShortfallReason,
+    },
+
+    #[error("the last {reserve} free blocks are reserved for takes backing cache hits")]
+    ReserveForHits { reserve: u64 },
+
+    #[error("{max} blocks are already checked out of the pool")]
+    InUseLimitReached { max: u64 },
+
+    #[error("block {0} is checked out for maintenance")]
+    UnderMaintenance(SequenceHash),
+
+    #[error(
+        "{requested} blocks requested but only {available} can be taken; {pinned} more are pinned"
+    )]
+    OnlyPinnedRemaining {
+        requested: u32,
+        available: u64,
+        pinned: u64,
+    },
+
+    #[error("priority {priority} of block {sequence_hash} exceeds its parent's priority {parent_priority}")]
+    InvalidPriorityChain {
+        sequence_hash: SequenceHash,
+        priority: BlockPriority,
+        parent_priority: BlockPriority,
+    },
+}
 
-pub struct AvailableBlocks {
-    match_tx: mpsc::UnboundedSender<MatchRequest>,
-    control_tx: mpsc::UnboundedSender<ControlRequest>,
-    fence_tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
-    return_handle: Arc<ReturnHandleImpl>,
-    total_blocks: Arc<AtomicU64>,
-    available_blocks: Arc<AtomicU64>,
-    join_handle: JoinHandle<()>,
+/// Handling of priority updates which would rank a block above its parent; see
+/// [AvailableBlocksConfig::with_priority_chain_policy]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityChainPolicy {
+    /// Leave the priority unchanged and fail with [KvPoolError::InvalidPriorityChain]
+    Reject,
+
+    /// Lower the priority to the parent's priority
+    Clamp,
 }
 
-impl AvailableBlocks {
-    pub fn total_blocks(&self) -> u64 {
-        self.total_blocks.load(Ordering::SeqCst)
-    }
+/// Behavior of a partial take which falls short because the remaining reusable blocks are
+/// pinned; see [AvailableBlocksConfig::with_pinned_exhaustion]
+///
+/// Blocks are pinned while their reserved band holds no more than its reservation, see
+/// [AvailableBlocksConfig::with_reserved_band]; no take can evict them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PinnedExhaustion {
+    /// Return as many blocks as can be taken
+    #[default]
+    Partial,
+
+    /// Take nothing and fail with [KvPoolError::OnlyPinnedRemaining]
+    Fail,
+}
 
-    pub fn available_blocks(&self) -> u64 {
-        self.available_blocks.load(Ordering::SeqCst)
-    }
+/// Behavior of a take which can not be fully satisfied; see
+/// [AvailableBlocks::take_blocks_with_policy]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnderSupply {
+    /// Return as many blocks as can be taken
+    #[default]
+    ReturnPartial,
 
-    pub fn is_active(&self) -> bool {
-        !self.join_handle.is_finished()
-    }
+    /// Take nothing and fail with [KvPoolError::InsufficientBlocks]
+    Fail,
 
-    pub async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<PoolItem<KvBlock>>> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .match_tx
-            .send(MatchRequest::MatchMultiple(MatchMultiple {
-                hashes,
-                return_handle: self.return_handle.clone(),
-                tx,
-            }))
-            .is_err()
-        {
-            raise!("failed to send match request; channel closed");
-        }
+    /// Wait up to the duration for enough blocks to be returned or inserted, then fail with
+    /// [KvPoolError::InsufficientBlocks]
+    WaitUpTo(Duration),
+}
 
-        let matched_blocks = rx.await?;
-        Ok(matched_blocks)
-    }
+/// Behavior of a match reaching a block soft held by another holder; see
+/// [AvailableBlocks::match_blocks_with_options]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnSoftHold {
+    /// Match the block anyway; the hold is dropped
+    #[default]
+    Steal,
 
-    pub async fn match_token_blocks(
-        &self,
-        token_blocks: &[TokenBlock],
-    ) -> Result<Vec<PoolItem<KvBlock>>> {
-        let hashes: Vec<u64> = token_blocks.iter().map(|b| b.sequence_hash()).collect();
-        self.match_blocks(hashes).await
-    }
+    /// End the matched prefix before the held block
+    Miss,
 
-    pub async fn take_blocks(&self, count: u32) -> Result<Vec<PoolItem<KvBlock>>> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .match_tx
-            .send(MatchRequest::Take(Take {
-                count,
-                return_handle: self.return_handle.clone(),
-                tx,
-            }))
-            .is_err()
-        {
-            raise!("failed to send take request; channel closed");
-        }
+    /// Wait up to the duration for the hold to expire or be claimed or released, then end the
+    /// matched prefix before the held block
+    Wait(Duration),
+}
 
-        let matched_blocks = rx.await?;
-        Ok(matched_blocks)
-    }
+/// Behavior of a match reaching a block checked out by [AvailableBlocks::checkout_for_maintenance];
+/// see [AvailableBlocksConfig::with_maintenance_conflict]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnMaintenance {
+    /// End the matched prefix before the block
+    #[default]
+    Miss,
+
+    /// Wait up to the duration for the block to be re-published, then end the matched prefix
+    /// before it
+    Wait(Duration),
+}
 
-    pub async fn insert(&self, block: KvBlock) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .control_tx
-            .send(ControlRequest::Insert(InsertControl { block, tx }))
-            .is_err()
-        {
-            raise!("failed to send insert request; channel closed");
-        }
-        rx.await?;
-        Ok(())
-    }
+/// Identity of a caller whose outstanding blocks are capped by
+/// [AvailableBlocksConfig::with_per_caller_quota]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CallerId(pub u64);
 
-    pub async fn update_single(&self, update: UpdateBlock) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .control_tx
-            .send(ControlRequest::UpdateSingle(UpdateSingleControl {
-                update,
-                tx,
-            }))
-            .is_err()
-        {
-            raise!("failed to send update single request; channel closed");
-        }
-        rx.await?;
-        Ok(())
-    }
+/// Lifecycle of an [AvailableBlocks] pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolLifecycle {
+    /// Blocks are still being registered; match and take requests are gated
+    Initializing,
 
-    pub async fn update_multiple(&self, updates: Vec<UpdateBlock>) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .control_tx
-            .send(ControlRequest::UpdateMultiple(UpdateMultipleControl {
-                updates,
-                tx,
-            }))
-            .is_err()
-        {
-            raise!("failed to send update multiple request; channel closed");
-        }
-        rx.await?;
-        Ok(())
-    }
+    /// The pool is serving match and take requests
+    Ready,
 
-    pub async fn reset(&self, sequence_hashes: Vec<SequenceHash>) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .control_tx
-            .send(ControlRequest::Reset(ResetControl {
-                sequence_hashes,
-                tx,
-            }))
-            .is_err()
-        {
-            raise!("failed to send reset request; channel closed");
-        }
-        rx.await?;
-        Ok(())
-    }
+    /// [AvailableBlocks::shutdown] was called; the engine stops once every block is back and
+    /// nothing is queued; match and take requests are handled according to the [ShutdownMode]
+    ShuttingDown,
+}
 
-    pub async fn reset_all(&self) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .control_tx
-            .send(ControlRequest::ResetAll(ResetAllControl { tx }))
-            .is_err()
-        {
-            raise!("failed to send reset all request; channel closed");
-        }
-        rx.await?;
-        Ok(())
-    }
+/// Behavior of match and take requests issued while the pool is [PoolLifecycle::ShuttingDown];
+/// see [AvailableBlocksConfig::with_shutdown_mode]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Fail immediately with [KvPoolError::ShuttingDown], including requests queued before the
+    /// shutdown began but not handled yet; a [streamed match][AvailableBlocks::match_blocks_stream]
+    /// ends without blocks instead
+    RejectNew,
+
+    /// Serve requests until the engine stops, then fail with [KvPoolError::ShuttingDown]
+    #[default]
+    DrainThenReject,
+}
 
-    pub async fn fence(&self) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        if self.fence_tx.send(tx).is_err() {
-            raise!("failed to send fence request; channel closed");
-        }
-        rx.await?;
-        Ok(())
-    }
+/// Behavior of match and take requests issued while the pool is [PoolLifecycle::Initializing]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotReadyPolicy {
+    /// Fail immediately with [KvPoolError::NotReady]
+    Reject,
+
+    /// Wait until [AvailableBlocks::mark_ready] is called
+    Queue,
 }
 
-struct ReturnHandleImpl {
-    return_tx: mpsc::UnboundedSender<PoolValue<KvBlock>>,
+/// Point-in-time health of an [AvailableBlocks] pool
+#[derive(Debug, Clone)]
+pub struct PoolHealth {
+    pub lifecycle: PoolLifecycle,
+    pub active: bool,
+    pub total_blocks: u64,
+    pub available_blocks: u64,
+
+    /// Returned blocks the progress engine has not absorbed yet
+    pub return_queue_depth: u64,
+
+    /// Blocks handed to an offload worker which has not completed yet; see
+    /// [AvailableBlocks::begin_offload]
+    pub offloading_blocks: u64,
+
+    /// Latest anomaly found by the self check; see [AvailableBlocksConfig::with_self_check]
+    pub anomaly: Option<PoolAnomaly>,
+
+    /// Report of the latest integrity audit, requested by the self check or through
+    /// [AvailableBlocks::verify_integrity]
+    pub last_audit: Option<IntegrityReport>,
 }
 
-impl ReturnHandle<KvBlock> for ReturnHandleImpl {
-    fn return_to_pool(&self, value: PoolValue<KvBlock>) {
-        if self.return_tx.send(value).is_err() {
-            log::trace!("Failed to return block to pool");
-        }
-    }
+/// Disagreement between the counters of an [AvailableBlocks] pool and its progress engine, found
+/// by the self check; see [AvailableBlocksConfig::with_self_check]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolAnomaly {
+    /// More blocks are counted available or offloading than the pool holds
+    CounterDrift {
+        total: u64,
+        available: u64,
+        offloading: u64,
+    },
+
+    /// Returns are queued but the progress engine has not finished a turn for longer than the
+    /// staleness threshold
+    StaleHeartbeat {
+        since_last_turn: Duration,
+        queued_returns: u64,
+    },
+
+    /// The progress engine is no longer running
+    EngineStopped,
 }
 
-impl AvailableBlocks {
-    pub async fn new() -> Self {
-        let (match_tx, match_rx) = mpsc::unbounded_channel();
-        let (return_tx, return_rx) = mpsc::unbounded_channel();
-        let (control_tx, control_rx) = mpsc::unbounded_channel();
-        let (fence_tx, fence_rx) = mpsc::unbounded_channel();
+/// State of a sequence hash as seen by an [AvailableBlocks] pool; see [AvailableBlocks::watch_sequence]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceState {
+    /// No block with this hash is held by the pool
+    Absent,
 
-        let total_blocks = Arc::new(AtomicU64::new(0));
-        let available_blocks = Arc::new(AtomicU64::new(0));
+    /// A block with this hash is available for matching
+    Cached,
 
-        let return_tx_clone = return_tx.clone();
-        let return_handle = Arc::new(ReturnHandleImpl {
-            return_tx: return_tx_clone,
-        });
+    /// The block with this hash was matched and is checked out
+    InUse,
+}
 
-        let join_handle = tokio::spawn(progress_engine(
-            match_rx,
-            return_rx,
-            control_rx,
-            fence_rx,
-            total_blocks.clone(),
-            available_blocks.clone(),
-        ));
+/// Outcome of [AvailableBlocks::rebalance]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RebalanceReport {
+    /// Cached blocks whose priority was set
+    pub applied: usize,
 
-        Self {
-            match_tx,
-            control_tx,
-            fence_tx,
-            return_handle,
-            total_blocks,
-            available_blocks,
-            join_handle,
-        }
-    }
+    /// Hashes which were not cached in the pool
+    pub missing: usize,
 }
 
+/// Point-in-time accounting of the uninitialized blocks of an [AvailableBlocks] pool
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct PriorityKey {
-    priority: u32,
-    return_tick: u64,
-    sequence_hash: SequenceHash,
+pub struct BlankSetStats {
+    /// Number of uninitialized blocks
+    pub blocks: usize,
+
+    /// Bytes of token storage currently held by uninitialized blocks
+    pub storage_bytes: usize,
+
+    /// Bytes of token storage released so far by the blank storage cap
+    pub released_bytes: u64,
 }
 
-// customize ord and partial ord for to store first by priority (lowest to highest), then by return_tick (lowest to highest)
-impl PartialOrd for PriorityKey {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
+/// Read-only view of a block held by an [AvailableBlocks] pool; see
+/// [AvailableBlocks::list_uninitialized]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvBlockView {
+    /// Sequence hash the block still carries; zero for an empty slot
+    pub sequence_hash: SequenceHash,
+
+    /// Number of tokens the block still holds
+    pub token_count: usize,
 }
 
-impl Ord for PriorityKey {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.priority
-            .cmp(&other.priority)
-            .then(self.return_tick.cmp(&other.return_tick))
+impl KvBlockView {
+    /// Whether the block is an empty slot rather than one demoted with its contents, such as a
+    /// duplicate of a cached block
+    pub fn is_empty_slot(&self) -> bool {
+        self.sequence_hash == 0 && self.token_count == 0
     }
 }
 
-impl From<&KvBlock> for PriorityKey {
+impl From<&KvBlock> for KvBlockView {
     fn from(block: &KvBlock) -> Self {
         Self {
-            priority: block.priority,
-            return_tick: block.return_tick,
             sequence_hash: block.token_block.sequence_hash(),
+            token_count: block.token_block.tokens().len(),
         }
     }
 }
 
-#[derive(Default)]
-struct AvailableBlocksState {
-    // Direct lookup by sequence_hash
-    lookup_map: HashMap<SequenceHash, PoolValue<KvBlock>>,
+/// Blocks a chunked request handles between two checks of the watchdog's hard threshold
+const WATCHDOG_STEP: usize = 64;
 
-    // // Ordered by timestamp (oldest first)
-    priority_set: BTreeMap<PriorityKey, SequenceHash>,
+// Watchers below which dropped watchers are only pruned by the transitions of their hash
+const WATCHER_PRUNE_MIN: usize = 64;
 
-    // Fully Uninitialized
-    uninitialized_set: VecDeque<PoolValue<KvBlock>>,
+/// Slots below which the lookup map is never compacted; see [AvailableBlocksConfig::with_compaction]
+pub const COMPACTION_MIN_CAPACITY: usize = 1024;
 
-    // Return Tick
-    return_tick: u64,
+#[derive(Debug, Clone, Copy)]
+struct CompactionPolicy {
+    max_slack: usize,
+    budget: usize,
+}
 
-    // Total blocks
-    total_blocks: Arc<AtomicU64>,
+/// Shortest interval between two warnings about a return queue past its soft limit
+const RETURN_QUEUE_WARNING_INTERVAL: Duration = Duration::from_secs(1);
 
-    // Available blocks
-    available_blocks: Arc<AtomicU64>,
+/// Shortest interval between two self check warnings, each of which requests an audit
+const SELF_CHECK_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct SelfCheck {
+    every: u64,
+    stale_after: Duration,
 }
 
-impl AvailableBlocksState {
-    fn new(total_blocks: Arc<AtomicU64>, available_blocks: Arc<AtomicU64>) -> Self {
-        Self {
-            lookup_map: HashMap::new(),
-            priority_set: BTreeMap::new(),
-            uninitialized_set: VecDeque::new(),
-            return_tick: 0,
-            total_blocks,
-            available_blocks,
+#[derive(Debug, Clone, Copy)]
+struct ReturnQueueLimits {
+    soft: u64,
+    hard: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TurnWatchdog {
+    soft: Duration,
+    hard: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct ReservedBand {
+    priorities: RangeInclusive<BlockPriority>,
+    fraction: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenHitWindow {
+    bucket: Duration,
+    buckets: usize,
+}
+
+// Tokens of the requests allocated within one interval of the token hit window
+struct TokenBucket {
+    start: Instant,
+    saved: u64,
+    requested: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlankStorageCap {
+    cap: usize,
+    block_size: usize,
+}
+
+/// Where an evicted block was offloaded to; an opaque handle assigned by the eviction hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ColdLocation(pub u64);
+
+/// Result of the eviction hook set by [AvailableBlocksConfig::with_eviction_hook]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictOutcome {
+    /// The block was not offloaded; its hash is forgotten
+    Forget,
+
+    /// The block was offloaded; matches report its location until the cold entry expires
+    Cold(ColdLocation),
+}
+
+/// A block matched by [AvailableBlocks::match_blocks_tiered] which only exists in cold storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColdHit {
+    pub sequence_hash: SequenceHash,
+    pub location: ColdLocation,
+}
+
+/// Result of [AvailableBlocks::match_blocks_tiered]
+pub struct TieredMatch {
+    /// The prefix matched in the pool
+    pub blocks: Vec<PoolItem<KvBlock>>,
+
+    /// The blocks following the matched prefix which were offloaded on eviction
+    pub cold: Vec<ColdHit>,
+}
+
+type EvictionHookFn = Arc<dyn Fn(&KvBlock) -> EvictOutcome + Send + Sync>;
+
+#[derive(Clone)]
+struct EvictionHook {
+    hook: EvictionHookFn,
+    ttl: Duration,
+    cap: usize,
+}
+
+impl std::fmt::Debug for EvictionHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EvictionHook")
+            .field("ttl", &self.ttl)
+            .field("cap", &self.cap)
+            .finish_non_exhaustive()
+    }
+}
+
+struct ColdEntry {
+    location: ColdLocation,
+    expiry: Instant,
+    parent_sequence_hash: Option<SequenceHash>,
+    tokens: ColdTokens,
+}
+
+// Tokens of an offloaded block, kept so [AvailableBlocks::promote_cold] can rebuild it
+enum ColdTokens {
+    Plain(Tokens),
+
+    // set by [AvailableBlocksConfig::with_cold_compression]; see [pack_tokens]
+    Packed(Vec<u8>),
+}
+
+impl ColdTokens {
+    fn new(tokens: &[u32], compress: bool) -> Self {
+        if compress {
+            ColdTokens::Packed(pack_tokens(tokens))
+        } else {
+            ColdTokens::Plain(Tokens::from(tokens.to_vec()))
         }
     }
-    // Insert an item with a given key and sequence_hash
-    fn insert(&mut self, block: PoolValue<KvBlock>) {
-        let sequence_hash = block.token_block.sequence_hash();
-        log::debug!(sequence_hash, "inserting block into available blocks");
 
-        // If we already have an entry for this sequence hash, we need to move it to the uninitialized set
-        // the lookup map has only one entry per sequence hash
-        if self.lookup_map.contains_key(&sequence_hash) || sequence_hash == 0u64 {
-            log::debug!(sequence_hash, "inserted block to uninitialized set");
-            self.uninitialized_set.push_back(block);
-            return;
+    fn to_tokens(&self) -> Tokens {
+        match self {
+            ColdTokens::Plain(tokens) => tokens.clone(),
+            ColdTokens::Packed(packed) => Tokens::from(unpack_tokens(packed)),
         }
+    }
+}
 
-        // Insert into timestamp set
-        let key = PriorityKey::from(&*block);
+// Each token is written as the zigzag LEB128 varint of its difference to the previous token, so
+// runs of nearby token ids take one or two bytes instead of four.
+fn pack_tokens(tokens: &[u32]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(tokens.len() * 2);
+    let mut previous = 0u32;
+    for &token in tokens {
+        let delta = token.wrapping_sub(previous) as i32;
+        let mut zigzag = ((delta << 1) ^ (delta >> 31)) as u32;
+        while zigzag >= 0x80 {
+            packed.push((zigzag as u8) | 0x80);
+            zigzag >>= 7;
+        }
+        packed.push(zigzag as u8);
+        previous = token;
+    }
+    packed
+}
+
+fn unpack_tokens(packed: &[u8]) -> Vec<u32> {
+    let mut tokens = Vec::with_capacity(packed.len());
+    let mut previous = 0u32;
+    let mut zigzag = 0u32;
+    let mut shift = 0;
+    for &byte in packed {
+        zigzag |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 != 0 {
+            shift += 7;
+            continue;
+        }
+        let delta = ((zigzag >> 1) as i32) ^ -((zigzag & 1) as i32);
+        previous = previous.wrapping_add(delta as u32);
+        tokens.push(previous);
+        zigzag = 0;
+        shift = 0;
+    }
+    tokens
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AdaptivePriority {
+    promote_threshold: u32,
+    demote_threshold: u32,
+    interval: Duration,
+    min: BlockPriority,
+    max: BlockPriority,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MissTracking {
+    capacity: usize,
+    decay_interval: Duration,
+}
+
+// Space-saving counter of missed hashes in fixed memory: a hash which is not tracked replaces the
+// least counted one and inherits its count, so counts are overestimated by at most the smallest
+// tracked count. Counts are halved once per decay interval.
+struct MissCounter {
+    tracking: MissTracking,
+    next_decay: Instant,
+    counts: HashMap<SequenceHash, u32>,
+
+    // tracked hashes ordered by count, least counted first
+    order: BTreeSet<(u32, SequenceHash)>,
+}
+
+impl MissCounter {
+    const ENTRY_BYTES: usize = 2 * std::mem::size_of::<(u32, SequenceHash)>();
+
+    fn new(tracking: MissTracking) -> Self {
+        Self {
+            tracking,
+            next_decay: Instant::now() + tracking.decay_interval,
+            counts: HashMap::with_capacity(tracking.capacity),
+            order: BTreeSet::new(),
+        }
+    }
+
+    fn record(&mut self, sequence_hash: SequenceHash) {
+        self.decay();
+
+        if let Some(count) = self.counts.get_mut(&sequence_hash) {
+            self.order.remove(&(*count, sequence_hash));
+            *count = count.saturating_add(1);
+            self.order.insert((*count, sequence_hash));
+            return;
+        }
+
+        let count = if self.counts.len() >= self.tracking.capacity {
+            let Some((min, replaced)) = self.order.pop_first() else {
+                return;
+            };
+            self.counts.remove(&replaced);
+            min.saturating_add(1)
+        } else {
+            1
+        };
+        self.counts.insert(sequence_hash, count);
+        self.order.insert((count, sequence_hash));
+    }
+
+    fn top(&mut self, k: usize) -> Vec<(SequenceHash, u32)> {
+        self.decay();
+        self.order
+            .iter()
+            .rev()
+            .take(k)
+            .map(|(count, sequence_hash)| (*sequence_hash, *count))
+            .collect()
+    }
+
+    // Halve the counts once for every decay interval which has passed
+    fn decay(&mut self) {
+        let interval = self.tracking.decay_interval;
+        let now = Instant::now();
+        if interval.is_zero() || now < self.next_decay {
+            return;
+        }
+
+        let periods = (now - self.next_decay).as_nanos() / interval.as_nanos() + 1;
+        self.next_decay += interval * periods.min(u32::MAX as u128) as u32;
+
+        let shift = periods.min(u32::BITS as u128) as u32;
+        self.counts.retain(|_, count| {
+            *count = count.checked_shr(shift).unwrap_or(0);
+            *count > 0
+        });
+        self.order = self
+            .counts
+            .iter()
+            .map(|(sequence_hash, count)| (*count, *sequence_hash))
+            .collect();
+    }
+}
+
+impl AuxMemory for MissCounter {
+    fn aux_bytes(&self) -> usize {
+        self.counts.len() * Self::ENTRY_BYTES
+    }
+
+    // the least counted hashes are the ones a new miss would replace next
+    fn shed_oldest(&mut self, bytes: usize) -> usize {
+        let entries = bytes.div_ceil(Self::ENTRY_BYTES).min(self.counts.len());
+        for _ in 0..entries {
+            if let Some((_, sequence_hash)) = self.order.pop_first() {
+                self.counts.remove(&sequence_hash);
+            }
+        }
+        entries
+    }
+}
+
+// History the engine keeps beside the cached blocks, capped by with_aux_budget; shedding it makes
+// the pool less informed but never changes which blocks it holds
+trait AuxMemory {
+    // Approximate bytes held
+    fn aux_bytes(&self) -> usize;
+
+    // Drop the oldest entries until at least `bytes` were freed or none are left; returns the
+    // number of entries dropped
+    fn shed_oldest(&mut self, bytes: usize) -> usize;
+
+    // Shed the `over` bytes past the budget, adding the entries dropped to `shed`; returns the
+    // bytes still over
+    fn shed_over(&mut self, over: usize, shed: &mut u64) -> usize {
+        if over == 0 {
+            return 0;
+        }
+        let before = self.aux_bytes();
+        *shed += self.shed_oldest(over) as u64;
+        over.saturating_sub(before - self.aux_bytes())
+    }
+}
+
+// Operation ids applied by insert_many_once, oldest first in the order; not auxiliary memory,
+// as forgetting an id early would apply its redelivery twice
+#[derive(Default)]
+struct DedupeWindow {
+    ids: HashSet<u128>,
+    order: VecDeque<u128>,
+}
+
+impl DedupeWindow {
+    fn contains(&self, op_id: u128) -> bool {
+        self.ids.contains(&op_id)
+    }
+
+    fn insert(&mut self, op_id: u128, window: usize) {
+        while self.order.len() >= window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(op_id);
+        self.order.push_back(op_id);
+    }
+}
+
+// Tagged operations handled but not yet observed by a fence_ops, oldest first in the order; an
+// id observed by a fence stays in the order until it falls out of the window
+#[derive(Default)]
+struct CompletedOps {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl CompletedOps {
+    // Forget the id if it was remembered; returns whether it was
+    fn observe(&mut self, op_id: u64) -> bool {
+        self.ids.remove(&op_id)
+    }
+
+    fn insert(&mut self, op_id: u64, window: usize) {
+        while self.order.len() >= window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(op_id);
+        self.order.push_back(op_id);
+    }
+}
+
+// Hashes evicted within the hysteresis window with the tick of their eviction, oldest first in
+// the order; a hash evicted again keeps its older entries in the order until they expire
+#[derive(Default)]
+struct EvictionHistory {
+    ticks: HashMap<SequenceHash, u64>,
+    order: VecDeque<(u64, SequenceHash)>,
+}
+
+impl EvictionHistory {
+    const ENTRY_BYTES: usize = 2 * std::mem::size_of::<(u64, SequenceHash)>();
+
+    fn record(&mut self, sequence_hash: SequenceHash, tick: u64) {
+        self.ticks.insert(sequence_hash, tick);
+        self.order.push_back((tick, sequence_hash));
+    }
+
+    fn contains(&self, sequence_hash: SequenceHash) -> bool {
+        self.ticks.contains_key(&sequence_hash)
+    }
+
+    // Expire the evictions which fell out of `window` ticks before `tick`
+    fn expire(&mut self, window: u64, tick: u64) {
+        while let Some(&(evicted, _)) = self.order.front() {
+            if evicted.saturating_add(window) >= tick {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        let Some((tick, sequence_hash)) = self.order.pop_front() else {
+            return;
+        };
+
+        // the hash may have been evicted again since
+        if self.ticks.get(&sequence_hash) == Some(&tick) {
+            self.ticks.remove(&sequence_hash);
+        }
+    }
+}
+
+impl AuxMemory for EvictionHistory {
+    fn aux_bytes(&self) -> usize {
+        self.order.len() * Self::ENTRY_BYTES
+    }
+
+    fn shed_oldest(&mut self, bytes: usize) -> usize {
+        let entries = bytes.div_ceil(Self::ENTRY_BYTES).min(self.order.len());
+        for _ in 0..entries {
+            self.pop_oldest();
+        }
+        entries
+    }
+}
+
+/// Cumulative counters of an [AvailableBlocks] pool since the last [AvailableBlocks::drain_counters]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CounterSnapshot {
+    /// Blocks matched by sequence hash
+    pub matches: u64,
+
+    /// Blocks handed out by takes
+    pub takes: u64,
+
+    /// Blocks inserted into the pool
+    pub inserts: u64,
+
+    /// Reusable blocks evicted to serve takes
+    pub evictions: u64,
+
+    /// Blocks returned to the pool
+    pub returns: u64,
+
+    /// Blocks reset by reset requests
+    pub resets: u64,
+
+    /// Shielded blocks evicted because no unshielded block was left; see [AvailableBlocks::expect]
+    pub shield_breaches: u64,
+
+    /// Engine turns which ran past the soft threshold of [AvailableBlocksConfig::with_turn_watchdog]
+    pub slow_turns: u64,
+
+    /// Chunked requests cut short by the hard threshold of [AvailableBlocksConfig::with_turn_watchdog]
+    pub split_turns: u64,
+
+    /// Returns absorbed from the spill buffer rather than the return channel; see
+    /// [AvailableBlocksConfig::with_return_queue_limits]
+    pub spilled_returns: u64,
+
+    /// Batches of returns absorbed in a single engine turn; see [AvailableBlocks::return_batch_scope]
+    pub return_batches: u64,
+
+    /// Rebuilds of the eviction order after it diverged from the lookup map; see
+    /// [AvailableBlocksConfig::with_periodic_integrity_check]
+    pub integrity_repairs: u64,
+
+    /// Offloads reclaimed by the janitor after their worker failed to complete them; see
+    /// [AvailableBlocksConfig::with_offload_janitor]
+    pub offload_abandoned: u64,
+
+    /// Uninitialized blocks dropped after sitting unused; see
+    /// [AvailableBlocksConfig::with_uninitialized_ttl]
+    pub uninitialized_expired: u64,
+
+    /// Priority raises and cuts of cached blocks by their hit frequency; see
+    /// [AvailableBlocksConfig::with_adaptive_priority]
+    pub adaptive_promotions: u64,
+    pub adaptive_demotions: u64,
+
+    /// Blocks reported by [AvailableBlocks::report_corrupt]
+    pub corrupt_blocks: u64,
+
+    /// Takes without cache hits rejected by [AvailableBlocksConfig::with_hit_reserve]
+    pub reserve_rejections: u64,
+
+    /// Blocks handed out of the hit reserve to takes backing cache hits
+    pub reserve_takes: u64,
+
+    /// Redelivered inserts acknowledged without inserting; see [AvailableBlocks::insert_many_once]
+    pub duplicate_inserts: u64,
+
+    /// Compactions of the lookup map completed; see [AvailableBlocksConfig::with_compaction]
+    pub compactions: u64,
+
+    /// Entries moved by compactions
+    pub compacted_entries: u64,
+
+    /// Matches which took a block soft held by another holder under [OnSoftHold::Steal]
+    pub soft_hold_steals: u64,
+
+    /// Matches cut short at a block soft held by another holder, including waits which timed out
+    pub soft_hold_misses: u64,
+
+    /// Matches served after waiting for a soft hold under [OnSoftHold::Wait]
+    pub soft_hold_waits: u64,
+
+    /// Blocks checked out by [AvailableBlocks::checkout_for_maintenance]; these are not counted
+    /// as matches or returns
+    pub maintenance_checkouts: u64,
+
+    /// Entries of the auxiliary history shed to stay within [AvailableBlocksConfig::with_aux_budget]:
+    /// recent evictions of the hysteresis window and tracked misses
+    pub aux_shed_evictions: u64,
+    pub aux_shed_misses: u64,
+
+    /// Mirrored entries dropped past [AvailableBlocksConfig::with_mirror_cap]
+    pub mirror_overflow: u64,
+}
+
+impl std::ops::AddAssign for CounterSnapshot {
+    fn add_assign(&mut self, other: Self) {
+        self.matches += other.matches;
+        self.takes += other.takes;
+        self.inserts += other.inserts;
+        self.evictions += other.evictions;
+        self.returns += other.returns;
+        self.resets += other.resets;
+        self.shield_breaches += other.shield_breaches;
+        self.slow_turns += other.slow_turns;
+        self.split_turns += other.split_turns;
+        self.spilled_returns += other.spilled_returns;
+        self.return_batches += other.return_batches;
+        self.integrity_repairs += other.integrity_repairs;
+        self.offload_abandoned += other.offload_abandoned;
+        self.uninitialized_expired += other.uninitialized_expired;
+        self.adaptive_promotions += other.adaptive_promotions;
+        self.adaptive_demotions += other.adaptive_demotions;
+        self.corrupt_blocks += other.corrupt_blocks;
+        self.reserve_rejections += other.reserve_rejections;
+        self.reserve_takes += other.reserve_takes;
+        self.duplicate_inserts += other.duplicate_inserts;
+        self.compactions += other.compactions;
+        self.compacted_entries += other.compacted_entries;
+        self.soft_hold_steals += other.soft_hold_steals;
+        self.soft_hold_misses += other.soft_hold_misses;
+        self.soft_hold_waits += other.soft_hold_waits;
+        self.maintenance_checkouts += other.maintenance_checkouts;
+        self.aux_shed_evictions += other.aux_shed_evictions;
+        self.aux_shed_misses += other.aux_shed_misses;
+        self.mirror_overflow += other.mirror_overflow;
+    }
+}
+
+impl CounterSnapshot {
+    // Blocks touched by matches, takes, inserts, evictions, returns and resets
+    fn touched(&self) -> u64 {
+        self.matches + self.takes + self.inserts + self.evictions + self.returns + self.resets
+    }
+}
+
+/// Point-in-time sizes of an [AvailableBlocks] pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Name set by [AvailableBlocksConfig::with_name]
+    pub name: Option<String>,
+
+    pub total_blocks: u64,
+    pub available_blocks: u64,
+
+    /// Available blocks without reusable state
+    pub uninitialized_blocks: u64,
+
+    /// Available blocks which can be matched by sequence hash
+    pub reusable_blocks: u64,
+
+    /// Reusable blocks currently shielded from eviction; see [AvailableBlocks::expect]
+    pub active_shields: u64,
+
+    /// Reusable blocks in each band set by [AvailableBlocksConfig::with_reserved_band], in the
+    /// order the bands were configured
+    pub reserved_band_blocks: Vec<u64>,
+
+    /// Reusable blocks of each retention class holding any, by class; see [BlockPriority]
+    pub class_blocks: Vec<(u8, u64)>,
+
+    /// Latency percentiles of the operations reported as [OpCompleted] events, by kind
+    pub op_latencies: Vec<OpLatency>,
+
+    /// Returned blocks the progress engine has not absorbed yet
+    pub return_queue_depth: u64,
+
+    /// Slots allocated by the lookup map of reusable blocks; see
+    /// [AvailableBlocksConfig::with_compaction]
+    pub lookup_capacity: u64,
+
+    /// Approximate bytes of auxiliary history held; see [AvailableBlocksConfig::with_aux_budget]
+    pub aux_bytes: u64,
+
+    /// The last [CAPACITY_LOG_LEN] changes of the total blocks, oldest first
+    pub capacity_log: Vec<CapacityChange>,
+
+    pub tokens: TokenStats,
+}
+
+/// Prefill tokens served by an [AvailableBlocks] pool; see [AvailableBlocks::watch_token_stats]
+///
+/// Blocks are counted by the tokens they hold, so partial tail blocks and blocks of different
+/// sizes are accounted exactly. The totals count since the pool started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenStats {
+    /// Tokens of every matched block
+    pub tokens_saved: u64,
+
+    /// Tokens of the requests allocated by [AvailableBlocks::allocate_request] which their matched
+    /// blocks did not cover
+    pub tokens_recomputed: u64,
+
+    /// Tokens saved by the requests allocated within the window set by
+    /// [AvailableBlocksConfig::with_token_hit_window]
+    pub window_tokens_saved: u64,
+
+    /// Tokens of the requests allocated within the window
+    pub window_tokens_requested: u64,
+}
+
+impl TokenStats {
+    /// Fraction of the request tokens within the window served from the pool; `None` if no
+    /// request was allocated within the window
+    pub fn window_hit_ratio(&self) -> Option<f64> {
+        (self.window_tokens_requested > 0)
+            .then(|| self.window_tokens_saved as f64 / self.window_tokens_requested as f64)
+    }
+}
+
+/// Reusable contents of an [AvailableBlocks] pool, see [AvailableBlocks::export_manifest]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolManifest {
+    /// Settings of the exporting pool, to check before consuming the blocks; see
+    /// [AvailableBlocks::manifest]
+    pub compat: CompatManifest,
+
+    /// Descriptors of the reusable blocks, in eviction order
+    pub blocks: Vec<BlockDescriptor>,
+}
+
+/// Engine state written by [AvailableBlocks::dump_state]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StateDump {
+    /// Name set by [AvailableBlocksConfig::with_name]
+    pub name: Option<String>,
+
+    pub total_blocks: u64,
+    pub available_blocks: u64,
+    pub return_tick: u64,
+    pub uninitialized_blocks: u64,
+
+    /// Keys of the lookup map, sorted
+    pub lookup_keys: Vec<SequenceHash>,
+
+    /// Reusable blocks in eviction order, as exported by [AvailableBlocks::export_manifest]
+    pub eviction_order: Vec<DumpedBlock>,
+}
+
+/// A reusable block in a [StateDump]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DumpedBlock {
+    pub sequence_hash: SequenceHash,
+    pub parent_sequence_hash: Option<SequenceHash>,
+    pub priority: BlockPriority,
+    pub token_count: u32,
+}
+
+impl From<BlockDescriptor> for DumpedBlock {
+    fn from(descriptor: BlockDescriptor) -> Self {
+        Self {
+            sequence_hash: descriptor.sequence_hash,
+            parent_sequence_hash: descriptor.parent_sequence_hash,
+            priority: descriptor.priority,
+            token_count: descriptor.token_count,
+        }
+    }
+}
+
+/// Engine invariants checked by [AvailableBlocks::verify_integrity]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Reusable blocks in the lookup map
+    pub lookup_entries: u64,
+
+    /// Entries of the eviction order
+    pub priority_entries: u64,
+
+    pub uninitialized_blocks: u64,
+
+    /// [AvailableBlocks::available_blocks] at the time of the check
+    pub available_blocks: u64,
+
+    /// Eviction order entries without a block in the lookup map, or keyed differently from it
+    pub orphaned_priorities: u64,
+
+    /// Blocks stored in the lookup map under a key other than their lookup key
+    pub misfiled_blocks: u64,
+}
+
+impl IntegrityReport {
+    /// Whether every idle block is accounted for exactly once
+    pub fn is_consistent(&self) -> bool {
+        self.lookup_entries == self.priority_entries
+            && self.orphaned_priorities == 0
+            && self.misfiled_blocks == 0
+            && self.available_blocks == self.lookup_entries + self.uninitialized_blocks
+    }
+}
+
+/// Largest batch of blocks [AvailableBlocks::match_blocks_stream] sends to the caller at once
+pub const MATCH_STREAM_BATCH_SIZE: usize = 256;
+
+/// Number of blocks [AvailableBlocks::insert_stream] sends to the engine per request
+pub const INSERT_STREAM_BATCH_SIZE: usize = 256;
+
+/// Default number of operation ids [AvailableBlocks::insert_many_once] remembers; see
+/// [AvailableBlocksConfig::with_insert_dedupe_window]
+pub const INSERT_DEDUPE_WINDOW: usize = 4096;
+
+/// Default number of handled tagged operations remembered for [AvailableBlocks::fence_ops]; see
+/// [AvailableBlocksConfig::with_completed_ops_window]
+pub const COMPLETED_OPS_WINDOW: usize = 4096;
+
+/// Default number of entries [AvailableBlocks::mirror_cold] keeps; see
+/// [AvailableBlocksConfig::with_mirror_cap]
+pub const MIRROR_COLD_CAP: usize = 1 << 20;
+
+/// Result of [AvailableBlocks::insert_many_once]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The blocks were inserted
+    Applied,
+
+    /// The operation id was already applied; nothing was inserted
+    Duplicate,
+}
+
+/// Eviction ranks at or beyond this are reported as `None` by [AvailableBlocks::match_blocks_detailed]
+pub const EVICTION_RANK_HORIZON: usize = 64;
+
+/// A block matched by [AvailableBlocks::match_blocks_detailed], with its state at match time
+pub struct MatchedBlock {
+    pub block: PoolItem<KvBlock>,
+
+    /// Priority the block was cached at
+    pub priority: BlockPriority,
+
+    /// Return ticks since the block was last returned or inserted
+    pub age: u64,
+
+    /// Number of reusable blocks ahead of this one in eviction order; `None` if there were at
+    /// least [EVICTION_RANK_HORIZON]. Uninitialized blocks are always taken first and shields
+    /// are not accounted for.
+    pub rank: Option<usize>,
+
+    /// Content version of the block at match time; see [KvBlock::version]
+    pub version: u64,
+}
+
+impl MatchedBlock {
+    /// True if the block would have been the next reusable block evicted
+    pub fn was_next_victim(&self) -> bool {
+        self.rank == Some(0)
+    }
+}
+
+/// Verdict of [AvailableBlocks::reconcile] for a single block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockFate {
+    /// The block is still valid
+    Keep,
+
+    /// The device data is gone; keep the slot as an uninitialized block
+    Blank,
+
+    /// The slot is gone; remove it from the pool
+    Remove,
+}
+
+/// Number of blocks per [BlockFate] applied by [AvailableBlocks::reconcile]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    pub kept: u64,
+    pub blanked: u64,
+    pub removed: u64,
+
+    /// Checked out blocks; their fate is decided when they are returned
+    pub in_flight: u64,
+}
+
+type BlockVerdict = Arc<dyn Fn(&KvBlock) -> BlockFate + Send + Sync>;
+
+/// Folds extra context, such as a model or LoRA adapter id, into the key a block is stored under
+///
+/// Blocks with the same tokens but different context get different keys, so they never match
+/// each other. See [AvailableBlocks::with_hash_extender].
+pub trait HashExtender: Send + Sync {
+    fn extend(&self, sequence_hash: SequenceHash) -> SequenceHash;
+}
+
+/// [HashExtender] keying blocks by LoRA adapter id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdapterHashExtender {
+    adapter_id: u64,
+}
+
+impl AdapterHashExtender {
+    pub fn new(adapter_id: u64) -> Self {
+        Self { adapter_id }
+    }
+}
+
+impl HashExtender for AdapterHashExtender {
+    fn extend(&self, sequence_hash: SequenceHash) -> SequenceHash {
+        let vals = [sequence_hash, self.adapter_id];
+        crate::kv_router::indexer::compute_hash(bytemuck::cast_slice(&vals))
+    }
+}
+
+/// View of an [AvailableBlocks] pool which stores and matches blocks under extended keys
+///
+/// Blocks taken or matched through the view and refilled by the caller must be re-keyed with
+/// [KvBlock::extend_key] before they are returned.
+pub struct ExtendedPool<'a> {
+    pool: &'a AvailableBlocks,
+    extender: &'a dyn HashExtender,
+}
+
+impl ExtendedPool<'_> {
+    fn extend_hashes(&self, hashes: Vec<SequenceHash>) -> Vec<SequenceHash> {
+        hashes
+            .into_iter()
+            .map(|hash| self.extender.extend(hash))
+            .collect()
+    }
+
+    pub async fn insert(&self, mut block: KvBlock) -> Result<()> {
+        block.extend_key(self.extender);
+        self.pool.insert(block).await
+    }
+
+    pub async fn insert_many(&self, mut blocks: Vec<KvBlock>) -> Result<()> {
+        for block in blocks.iter_mut() {
+            block.extend_key(self.extender);
+        }
+        self.pool.insert_many(blocks).await
+    }
+
+    pub async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<PoolItem<KvBlock>>> {
+        self.pool.match_blocks(self.extend_hashes(hashes)).await
+    }
+
+    pub async fn is_fully_cached(&self, hashes: Vec<SequenceHash>) -> Result<bool> {
+        self.pool.is_fully_cached(self.extend_hashes(hashes)).await
+    }
+}
+
+/// Stamps applied to every block acquired or committed through a [PoolSession]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// Once in the pool, blocks are shielded from eviction until this deadline, as by
+    /// [AvailableBlocks::expect]
+    pub deadline: Option<Instant>,
+
+    /// Priority the blocks are stored at once in the pool
+    pub priority: BlockPriority,
+
+    /// Request the session's matches, takes and allocations are reported under as
+    /// [OpCompleted] events; see [AvailableBlocks::subscribe_op_events]
+    pub request_id: Option<u64>,
+}
+
+/// Metadata applied to a block as it is returned by [AvailableBlocks::return_with_meta]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockMeta {
+    /// Priority the block is stored at; keeps its current priority if `None`
+    pub priority: Option<BlockPriority>,
+
+    /// Shields the block from eviction until this deadline, as by [AvailableBlocks::expect]
+    pub deadline: Option<Instant>,
+}
+
+/// A block checked out by [AvailableBlocks::checkout_for_maintenance]
+///
+/// Dereferences to the block for in-place mutation. Dropping the guard re-publishes the block
+/// under the sequence hash it was checked out for, keeping its place in the eviction order unless
+/// [MaintenanceGuard::bump_recency] was called. A block whose token block was changed to another
+/// sequence hash is reset and returned as a blank instead.
+pub struct MaintenanceGuard {
+    block: PoolItem<KvBlock>,
+}
+
+impl MaintenanceGuard {
+    /// Re-publish the block as most recently used, as a normal return would
+    pub fn bump_recency(&mut self) {
+        if let Some(checkout) = self.block.maintenance.as_mut() {
+            checkout.bump_recency = true;
+        }
+    }
+}
+
+impl std::ops::Deref for MaintenanceGuard {
+    type Target = KvBlock;
+
+    fn deref(&self) -> &KvBlock {
+        &self.block
+    }
+}
+
+impl std::ops::DerefMut for MaintenanceGuard {
+    fn deref_mut(&mut self) -> &mut KvBlock {
+        &mut self.block
+    }
+}
+
+/// Options of [AvailableBlocks::take_blocks_with_options]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TakeOptions {
+    /// Number of blocks the request matched in the pool before taking; see
+    /// [AvailableBlocksConfig::with_hit_reserve]
+    pub hits: usize,
+}
+
+/// Options of [AvailableBlocks::match_blocks_with_options]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchOptions {
+    /// Holder the match is made for; its own holds from [AvailableBlocks::peek_hold] are claimed
+    /// rather than treated as a conflict
+    pub holder: Option<u64>,
+
+    pub on_soft_hold: OnSoftHold,
+
+    /// Most blocks to match, or no bound; the match stops there even if more would match
+    pub max_blocks: Option<usize>,
+}
+
+impl MatchOptions {
+    /// Stop the match after `max_blocks` hits even if more would match, at least one
+    ///
+    /// Bounds the time the engine spends on a very long prefix and the size of the plan handed
+    /// to the caller. [AvailableBlocks::match_prefix] and [AvailableBlocks::allocate_with_options]
+    /// report where to resume the prefix on a later call.
+    pub fn max_blocks(mut self, max_blocks: usize) -> Self {
+        self.max_blocks = Some(max_blocks.max(1));
+        self
+    }
+}
+
+/// Blocks matched by [AvailableBlocks::match_prefix]
+pub struct PrefixMatch {
+    pub blocks: Vec<PoolItem<KvBlock>>,
+
+    /// Whether the match stopped at [MatchOptions::max_blocks] with hashes left to match
+    pub truncated: bool,
+
+    /// Hash to resume the prefix from, the one after the last block matched, when truncated
+    pub resume_from: Option<SequenceHash>,
+}
+
+/// Capacity of the [OpCompleted] event stream; slower subscribers miss the oldest events
+pub const OP_EVENT_CAPACITY: usize = 1024;
+
+/// Capacity of the [EvictionEvent] stream; slower subscribers miss the oldest events
+pub const EVICTION_EVENT_CAPACITY: usize = 1024;
+
+/// Capacity of the [PoolEvent] stream; slower subscribers miss the oldest events
+pub const POOL_EVENT_CAPACITY: usize = 1024;
+
+/// Number of most recent capacity changes kept in [PoolStats::capacity_log]
+pub const CAPACITY_LOG_LEN: usize = 64;
+
+/// Why the total blocks of a pool changed; see [PoolEvent::CapacityChanged]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapacityChangeReason {
+    /// Blocks inserted into the pool
+    Insert,
+
+    /// Idle blocks removed or checked out slots retired by [AvailableBlocks::shrink]
+    Shrink,
+
+    /// Uninitialized blocks dropped under [AvailableBlocksConfig::with_uninitialized_ttl]
+    UninitializedExpired,
+
+    /// Blocks removed by the verdict of [AvailableBlocks::reconcile], including blocks checked
+    /// out when it started and removed once returned
+    Reconcile,
+
+    /// Returns of a previous generation dropped under [StaleReturnPolicy::Drop]
+    StaleReturn,
+}
+
+/// Lifecycle event of an [AvailableBlocks] pool; see [AvailableBlocks::subscribe_pool_events]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolEvent {
+    /// The total blocks of the pool changed by `delta` to `new_total`
+    ///
+    /// Changes for the same reason within one engine turn are reported as one event, so an
+    /// [AvailableBlocks::insert_many] is confirmed by a single event.
+    CapacityChanged {
+        delta: i64,
+        new_total: u64,
+        reason: CapacityChangeReason,
+    },
+}
+
+/// A change of the total blocks of a pool, as kept in [PoolStats::capacity_log]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityChange {
+    /// When the engine made the change
+    pub at: Instant,
+
+    pub delta: i64,
+    pub new_total: u64,
+    pub reason: CapacityChangeReason,
+}
+
+/// A cached block evicted from the pool, see [AvailableBlocks::subscribe_evictions]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvictionEvent {
+    pub sequence_hash: SequenceHash,
+
+    /// Caller of the take which forced the eviction, if it was made by
+    /// [AvailableBlocks::take_blocks_for]
+    pub caller: Option<CallerId>,
+
+    /// Request of the operation which forced the eviction, if it was issued through a
+    /// [PoolSession] with a request id
+    pub request_id: Option<u64>,
+}
+
+/// Number of most recent operations of each kind the latency percentiles in [PoolStats] cover
+pub const OP_LATENCY_WINDOW: usize = 1024;
+
+/// Kind of operation reported by [OpCompleted]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OpKind {
+    /// [PoolSession::match_blocks]
+    Match,
+
+    /// [PoolSession::take_blocks]
+    Take,
+
+    /// [PoolSession::allocate]
+    Allocate,
+}
+
+/// Outcome of an operation issued through a [PoolSession] with a request id, measured by the
+/// progress engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpCompleted {
+    pub request_id: u64,
+    pub op: OpKind,
+
+    /// Time from the call until the engine started handling the operation, including any wait
+    /// for the pool to become ready
+    pub queued: Duration,
+
+    /// Time the engine spent handling the operation until the response was sent
+    pub handled: Duration,
+
+    /// Blocks served: matched blocks for a match, taken blocks for a take and matched blocks
+    /// for an allocation
+    pub hits: usize,
+
+    /// Blocks not served from the pool: hashes not matched for a match or an allocation and the
+    /// shortfall of a take
+    pub misses: usize,
+}
+
+/// Latency percentiles of the queued plus handled time of one kind of operation, over the last
+/// [OP_LATENCY_WINDOW] operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpLatency {
+    pub op: OpKind,
+    pub samples: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+/// Blocks taken by [AvailableBlocks::take_blocks_reporting_evictions]
+pub struct TakeOutcome {
+    pub taken: Vec<PoolItem<KvBlock>>,
+
+    /// Cached blocks evicted to serve the take, in eviction order, as they were before their
+    /// slots were handed out
+    pub evicted: Vec<KvBlockView>,
+
+    /// Where the remaining blocks of the pool were, if fewer blocks were taken than requested
+    pub shortfall: Option<ShortfallReason>,
+}
+
+/// Blocks of a pool by what keeps a take short from getting them, when a take falls short
+///
+/// A pool which is truly empty reports zeros everywhere. Blocks still blank or evictable were
+/// held back by the cap of [AvailableBlocksConfig::with_max_in_use]; protected blocks need a
+/// different kind of take, and transitional blocks come back on their own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShortfallReason {
+    /// Idle uninitialized blocks the take could have had
+    pub blank: u64,
+
+    /// Idle reusable blocks the take could have evicted
+    pub evictable: u64,
+
+    /// Idle blocks the take may not have: pinned by a reserved band or left as the headroom of
+    /// emergency takes
+    pub protected: u64,
+
+    /// Checked out blocks on their way back: being offloaded, quarantined or under maintenance
+    pub transitional: u64,
+}
+
+/// Blocks acquired by [AvailableBlocks::allocate]
+pub struct Allocation {
+    /// Blocks matched for the longest cached prefix of the hashes
+    pub matched: Vec<PoolItem<KvBlock>>,
+
+    /// Blocks taken for the rest of the hashes; fewer than asked for if the pool ran out
+    pub taken: Vec<PoolItem<KvBlock>>,
+
+    /// Whether the match stopped at [MatchOptions::max_blocks] with hashes left to match, or at
+    /// a block failing [AvailableBlocksConfig::with_checksum_verifier]; nothing is taken then,
+    /// see [AvailableBlocks::allocate_with_options]
+    pub truncated: bool,
+
+    /// Hash to resume the allocation from, the one after the last block matched, when truncated
+    pub resume_from: Option<SequenceHash>,
+}
+
+/// One request of a batch allocated by [AvailableBlocks::allocate_batch]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllocateSpec {
+    /// Hashes to acquire one block each for, as by [AvailableBlocks::allocate]
+    pub hashes: Vec<SequenceHash>,
+}
+
+/// How [AvailableBlocks::allocate_batch] handles a batch the pool can not fully serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Serve every request of the batch or none of them
+    AllOrNothing,
+
+    /// Serve the requests in the given order until one does not fit; it and all later requests
+    /// are not served
+    BestEffortRanked,
+}
+
+// Request id and enqueue time stamped onto an operation at the public API
+#[derive(Debug, Clone, Copy)]
+struct OpContext {
+    request_id: u64,
+    enqueued: Instant,
+}
+
+// Operation with a context which is being handled by the engine
+#[derive(Debug, Clone, Copy)]
+struct ActiveOp {
+    context: OpContext,
+    dispatched: Instant,
+}
+
+/// View of an [AvailableBlocks] pool acting on behalf of one request
+///
+/// The stamps are consumed when a block enters the pool; blocks taken back out of the pool carry
+/// no deadline.
+pub struct PoolSession<'a> {
+    pool: &'a AvailableBlocks,
+    options: SessionOptions,
+}
+
+impl PoolSession<'_> {
+    fn stamp(&self, block: &mut KvBlock) {
+        block.set_priority(self.options.priority);
+        block.set_deadline(self.options.deadline);
+    }
+
+    // stamped when the operation is called, so the wait for readiness counts as queued
+    fn context(&self) -> Option<OpContext> {
+        self.options.request_id.map(|request_id| OpContext {
+            request_id,
+            enqueued: Instant::now(),
+        })
+    }
+
+    pub fn options(&self) -> SessionOptions {
+        self.options
+    }
+
+    pub async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<PoolItem<KvBlock>>> {
+        let mut blocks = self
+            .pool
+            .match_blocks_with(hashes, false, self.context())
+            .await?;
+        for block in blocks.iter_mut() {
+            self.stamp(block);
+        }
+        Ok(blocks)
+    }
+
+    pub async fn take_blocks(&self, count: u32) -> Result<Vec<PoolItem<KvBlock>>> {
+        let mut blocks = self
+            .pool
+            .take_blocks_with(count, self.pool.config.under_supply, self.context())
+            .await?;
+        for block in blocks.iter_mut() {
+            self.stamp(block);
+        }
+        Ok(blocks)
+    }
+
+    /// Acquire blocks for `hashes` like [AvailableBlocks::allocate]
+    pub async fn allocate(&self, hashes: Vec<SequenceHash>) -> Result<Allocation> {
+        let mut allocation = self
+            .pool
+            .allocate_with(hashes, 0, None, None, self.context())
+            .await?;
+        for block in allocation
+            .matched
+            .iter_mut()
+            .chain(allocation.taken.iter_mut())
+        {
+            self.stamp(block);
+        }
+        Ok(allocation)
+    }
+
+    pub async fn insert(&self, mut block: KvBlock) -> Result<()> {
+        self.stamp(&mut block);
+        self.pool.insert(block).await
+    }
+
+    pub async fn insert_many(&self, mut blocks: Vec<KvBlock>) -> Result<()> {
+        for block in blocks.iter_mut() {
+            self.stamp(block);
+        }
+        self.pool.insert_many(blocks).await
+    }
+
+    /// Return the session's blocks to the pool, resolving once their stamps have been applied
+    pub async fn finish(self, blocks: Vec<PoolItem<KvBlock>>) -> Result<()> {
+        drop(blocks);
+        self.pool.fence().await
+    }
+}
+
+/// Number of buckets in the eviction age histogram; see [AgeBucket]
+pub const EVICTION_AGE_BUCKETS: usize = 16;
+
+/// Range of block ages, measured in return ticks, covered by one eviction histogram bucket.
+///
+/// Bucket 0 holds age 0, bucket `i` holds ages in `[2^(i-1), 2^i)` and the last bucket is unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgeBucket {
+    /// Inclusive lower bound
+    pub min: u64,
+
+    /// Exclusive upper bound; `None` for the last bucket
+    pub max: Option<u64>,
+}
+
+impl AgeBucket {
+    fn index(age: u64) -> usize {
+        let index = (u64::BITS - age.leading_zeros()) as usize;
+        index.min(EVICTION_AGE_BUCKETS - 1)
+    }
+
+    fn from_index(index: usize) -> Self {
+        let min = match index {
+            0 => 0,
+            i => 1 << (i - 1),
+        };
+        let max = match index {
+            i if i == EVICTION_AGE_BUCKETS - 1 => None,
+            i => Some(1 << i),
+        };
+        Self { min, max }
+    }
+}
+
+/// Handling of blocks returned to the pool by checkouts from a previous pool generation
+///
+/// The generation is bumped whenever [AvailableBlocks::shrink] retires slots which are still checked out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StaleReturnPolicy {
+    /// Reset the block and re-admit it as an uninitialized block
+    #[default]
+    ReadmitAsBlank,
+
+    /// Drop the block and remove its slot from the pool
+    Drop,
+}
+
+/// Outcome of [AvailableBlocks::shrink]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkReport {
+    /// Idle blocks removed from the pool immediately
+    pub removed_idle: u64,
+
+    /// Checked out slots removed from the totals; they are dropped when returned
+    pub retired_in_flight: u64,
+}
+
+/// Outcome of [AvailableBlocks::evict_chains]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvictionReport {
+    /// Chains evicted whole
+    pub chains: u64,
+
+    /// Cached blocks evicted across those chains
+    pub blocks: u64,
+}
+
+/// Options for the engine thread of [AvailableBlocks::new_dedicated_thread_with]
+#[derive(Debug, Clone, Default)]
+pub struct EngineThreadOptions {
+    name: Option<String>,
+    core: Option<usize>,
+}
+
+impl EngineThreadOptions {
+    /// Name the engine thread
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Ask for the engine thread to be pinned to `core`; only a hint, which is ignored where
+    /// affinity can not be set
+    pub fn with_core(mut self, core: usize) -> Self {
+        self.core = Some(core);
+        self
+    }
+}
+
+// Thread running the single threaded runtime of a dedicated engine; dropping it stops the
+// runtime, which cancels the engine, and joins the thread
+struct EngineThread {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for EngineThread {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn!("progress engine thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) {
+    if core >= libc::CPU_SETSIZE as usize {
+        log::warn!(core, "core out of range; engine thread is not pinned");
+        return;
+    }
+
+    // SAFETY: the cpu set is plain data, zeroed before use and only read by sched_setaffinity
+    let pinned = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    };
+    if !pinned {
+        log::warn!(core, "failed to pin engine thread");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(core: usize) {
+    log::debug!(
+        core,
+        "core affinity is not supported; engine thread is not pinned"
+    );
+}
+
+/// Configuration for [AvailableBlocks]
+#[derive(Debug, Clone, Default)]
+pub struct AvailableBlocksConfig {
+    return_channel_capacity: Option<usize>,
+    not_ready_policy: Option<NotReadyPolicy>,
+    shutdown_mode: ShutdownMode,
+    reject_zero_hash: bool,
+    stale_return_policy: StaleReturnPolicy,
+    strict_sequencing: bool,
+    deterministic_mode: bool,
+    uniform_priority: bool,
+    blank_storage_cap: Option<BlankStorageCap>,
+    eviction_hysteresis: Option<u64>,
+    handler_chunk_size: Option<usize>,
+    name: Option<String>,
+    eviction_hook: Option<EvictionHook>,
+    per_caller_quota: Option<u32>,
+    priority_chain_policy: Option<PriorityChainPolicy>,
+    reserved_bands: Vec<ReservedBand>,
+    turn_watchdog: Option<TurnWatchdog>,
+    miss_tracking: Option<MissTracking>,
+    under_supply: UnderSupply,
+    pinned_exhaustion: PinnedExhaustion,
+    return_queue_limits: Option<ReturnQueueLimits>,
+    integrity_check_interval: Option<u64>,
+    self_check: Option<SelfCheck>,
+    self_check_repair: bool,
+    offload_timeout: Option<Duration>,
+    uninitialized_ttl: Option<Duration>,
+    adaptive_priority: Option<AdaptivePriority>,
+    token_hit_window: Option<TokenHitWindow>,
+    sticky_cap: Option<usize>,
+    affinity_grouping: bool,
+    cold_compression: bool,
+    checksum_verifier: Option<fn(&KvBlock) -> bool>,
+    uninitialized_rule: Option<fn(&KvBlock) -> bool>,
+    insert_dedupe_window: Option<usize>,
+    completed_ops_window: Option<usize>,
+    mirror_cap: Option<usize>,
+    aux_budget: Option<usize>,
+    compaction: Option<CompactionPolicy>,
+    block_size: Option<usize>,
+    hash_config: Option<HashConfig>,
+    fast_probe: bool,
+    shared_content_index: Option<(SharedContentIndex, PoolId)>,
+    max_in_use: Option<u64>,
+    on_maintenance: OnMaintenance,
+    hit_reserve: Option<f64>,
+    reserved_headroom: u64,
+    #[cfg(feature = "trace-record")]
+    trace_recorder: Option<TraceRecorderConfig>,
+}
+
+impl AvailableBlocksConfig {
+    /// Use a bounded return channel with the given capacity instead of an unbounded one.
+    ///
+    /// Returns that do not fit in the channel are parked in an overflow buffer which is
+    /// drained by the progress engine, so a dropped [PoolItem] is never lost.
+    pub fn with_bounded_returns(mut self, capacity: usize) -> Self {
+        self.return_channel_capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Start the pool in [PoolLifecycle::Initializing] until [AvailableBlocks::mark_ready] is called.
+    ///
+    /// Inserts and control requests are always allowed; match and take requests are handled
+    /// according to the given policy. By default the pool is ready as soon as it is created.
+    pub fn with_readiness_gate(mut self, policy: NotReadyPolicy) -> Self {
+        self.not_ready_policy = Some(policy);
+        self
+    }
+
+    /// Handle match and take requests issued after [AvailableBlocks::shutdown] according to the
+    /// given mode; by default they are served until the engine stops
+    pub fn with_shutdown_mode(mut self, mode: ShutdownMode) -> Self {
+        self.shutdown_mode = mode;
+        self
+    }
+
+    /// Reject inserts of blocks with a zero sequence hash with [KvPoolError::ZeroHash].
+    ///
+    /// By default such blocks are silently accepted as uninitialized, which hides callers that
+    /// forgot to compute the hash.
+    pub fn with_reject_zero_hash(mut self, reject: bool) -> Self {
+        self.reject_zero_hash = reject;
+        self
+    }
+
+    /// Set how returns from checkouts of a previous pool generation are handled
+    pub fn with_stale_return_policy(mut self, policy: StaleReturnPolicy) -> Self {
+        self.stale_return_policy = policy;
+        self
+    }
+
+    /// Process match and control requests strictly in the order they were issued by the handle
+    ///
+    /// Matches and control requests travel on separate channels and matches are preferred, so by
+    /// default a match issued right after an update may be processed first. With strict sequencing
+    /// each request is stamped and the engine holds back any request whose predecessors have not
+    /// been processed yet, at the cost of some throughput.
+    pub fn with_strict_sequencing(mut self, strict: bool) -> Self {
+        self.strict_sequencing = strict;
+        self
+    }
+
+    /// Make runs of the same workload reproducible, for tests comparing scheduling decisions
+    ///
+    /// The lookup map is hashed with fixed keys instead of per-process random keys; see the
+    /// module documentation for the order of everything else. Off by default, as fixed keys
+    /// give up the protection of random keys against crafted collisions.
+    pub fn with_deterministic_mode(mut self, deterministic: bool) -> Self {
+        self.deterministic_mode = deterministic;
+        self
+    }
+
+    /// Declare that every block is stored at priority 0, outside sticky sessions and affinity
+    /// groups
+    ///
+    /// The eviction order is then kept in a queue by return order instead of an ordered map,
+    /// which makes inserts, returns and takes cheaper. The first block stored with another
+    /// priority, sticky or in an affinity group moves the pool to the ordered map for good, so
+    /// a wrong declaration costs one rebuild and nothing else.
+    pub fn with_uniform_priority(mut self) -> Self {
+        self.uniform_priority = true;
+        self
+    }
+
+    /// Only the first `cap` uninitialized blocks keep their token storage
+    ///
+    /// Without a cap, a block which is reset or evicted into the uninitialized set frees its
+    /// token storage. Under a cap, the first `cap` uninitialized blocks keep theirs for their
+    /// next use; blocks beyond the cap are kept, but their token storage is released. Storage
+    /// for `block_size` tokens is re-allocated when a blank block is handed out by a take.
+    pub fn with_blank_storage_cap(mut self, cap: usize, block_size: usize) -> Self {
+        self.blank_storage_cap = Some(BlankStorageCap { cap, block_size });
+        self
+    }
+
+    /// Suppress re-admitting an evicted sequence hash for `ticks` return ticks
+    ///
+    /// A block inserted or returned with a hash evicted within the window is added to the
+    /// uninitialized set instead of being made matchable, which keeps oscillating load from
+    /// repeatedly evicting and re-admitting the same blocks.
+    pub fn with_eviction_hysteresis(mut self, ticks: u64) -> Self {
+        self.eviction_hysteresis = Some(ticks);
+        self
+    }
+
+    /// Process multi-block matches and bulk updates at most `chunk_size` elements at a time
+    ///
+    /// Between chunks the engine yields and handles returns and fences, while new match and
+    /// control requests wait until the chunked request has finished. A chunked match only matches
+    /// blocks which were in the pool when the match started, so stopping at the first miss has the
+    /// same meaning as for an unchunked match.
+    pub fn with_handler_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.handler_chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// Call `hook` with every block evicted to make room for a take
+    ///
+    /// If the hook offloaded the block and returns [EvictOutcome::Cold], the pool remembers the
+    /// location of the hash for `ttl`, up to `cap` entries with the oldest dropped first, and
+    /// reports it from [AvailableBlocks::match_blocks_tiered]. The hook runs on the engine task
+    /// and must not block.
+    pub fn with_eviction_hook(
+        mut self,
+        hook: impl Fn(&KvBlock) -> EvictOutcome + Send + Sync + 'static,
+        ttl: Duration,
+        cap: usize,
+    ) -> Self {
+        self.eviction_hook = Some(EvictionHook {
+            hook: Arc::new(hook),
+            ttl,
+            cap,
+        });
+        self
+    }
+
+    /// Keep the blocks of an affinity group together in eviction order, see [KvBlock::set_affinity]
+    ///
+    /// Within a priority, a group is ordered by the newest return of any of its cached members,
+    /// so a hit on one member keeps the whole group resident and the group is evicted as a unit
+    /// once it is the oldest. This is a preference only; priorities, stickiness and shields still
+    /// take precedence.
+    pub fn with_affinity_grouping(mut self, enabled: bool) -> Self {
+        self.affinity_grouping = enabled;
+        self
+    }
+
+    /// Compress the tokens kept for cold entries, see [AvailableBlocksConfig::with_eviction_hook]
+    ///
+    /// Trades CPU on offload and [AvailableBlocks::promote_cold] for memory in deployments with
+    /// large cold caches; promoted blocks are the same either way.
+    pub fn with_cold_compression(mut self, enabled: bool) -> Self {
+        self.cold_compression = enabled;
+        self
+    }
+
+    /// Default [UnderSupply] policy of [AvailableBlocks::take_blocks]
+    pub fn with_under_supply(mut self, policy: UnderSupply) -> Self {
+        self.under_supply = policy;
+        self
+    }
+
+    /// Behavior of a take under [UnderSupply::ReturnPartial] which can not be fully satisfied
+    /// because the rest of the pool is pinned
+    ///
+    /// With [PinnedExhaustion::Fail], such a take fails instead of silently under-delivering. A
+    /// take which falls short with nothing pinned still returns what it could take.
+    pub fn with_pinned_exhaustion(mut self, policy: PinnedExhaustion) -> Self {
+        self.pinned_exhaustion = policy;
+        self
+    }
+
+    /// Watch the number of returned blocks waiting for the progress engine
+    ///
+    /// Past `soft` a warning is logged, at most once a second. Past `hard` returns bypass the
+    /// return channel and are parked in a spill buffer which the engine absorbs in bulk, so a
+    /// stalled engine does not grow the channel without bound and a dropper never does more than
+    /// a push under a mutex.
+    pub fn with_return_queue_limits(mut self, soft: u64, hard: u64) -> Self {
+        self.return_queue_limits = Some(ReturnQueueLimits {
+            soft,
+            hard: hard.max(soft),
+        });
+        self
+    }
+
+    /// Label the pool in engine logs and in [PoolStats], to tell several pools apart
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Cap the blocks taken by each caller through [AvailableBlocks::take_blocks_for] and not yet
+    /// returned at `max`
+    ///
+    /// Takes are cut down to the remaining quota; once the quota is used up they fail with
+    /// [KvPoolError::QuotaExceeded] until the caller returns blocks.
+    pub fn with_per_caller_quota(mut self, max: u32) -> Self {
+        self.per_caller_quota = Some(max);
+        self
+    }
+
+    /// Keep a child's priority at or below its parent's when priorities are updated
+    ///
+    /// Children are evicted before their parents only if they do not outrank them. Updates are
+    /// checked against the parent's priority if the parent is cached in the pool; other updates
+    /// in the same request are still applied when one of them is rejected.
+    pub fn with_priority_chain_policy(mut self, policy: PriorityChainPolicy) -> Self {
+        self.priority_chain_policy = Some(policy);
+        self
+    }
+
+    /// Reserve `fraction` of the pool's blocks for reusable blocks with a priority in `priorities`
+    ///
+    /// Blocks in the band are never evicted by takes while the band holds no more than its
+    /// reservation, so other traffic can not push them out. The reservation is also the band's
+    /// cap: a block cached into a full band evicts the band's own first block in eviction order.
+    /// The reservation is recomputed from the current total on every insert. Bands should not
+    /// overlap; a priority belongs to the first band containing it. A `u32` bound is converted by
+    /// the [BlockPriority] shim.
+    pub fn with_reserved_band<P: Into<BlockPriority>>(
+        mut self,
+        priorities: RangeInclusive<P>,
+        fraction: f64,
+    ) -> Self {
+        let (start, end) = priorities.into_inner();
+        self.reserved_bands.push(ReservedBand {
+            priorities: start.into()..=end.into(),
+            fraction: fraction.clamp(0.0, 1.0),
+        });
+        self
+    }
+
+    /// Reserve `fraction` of the pool's blocks for every score of a retention class, as by
+    /// [AvailableBlocksConfig::with_reserved_band]; see [BlockPriority]
+    pub fn with_reserved_class(self, class: u8, fraction: f64) -> Self {
+        self.with_reserved_band(BlockPriority::class_range(class), fraction)
+    }
+
+    /// Time every turn of the progress engine
+    ///
+    /// A turn running past `soft` is logged as a warning with the kind of request and the number
+    /// of blocks it touched. Requests which can be chunked are handled as in
+    /// [AvailableBlocksConfig::with_handler_chunk_size] and yield once they have run for `hard`,
+    /// resuming on a later turn; other requests can only be reported.
+    pub fn with_turn_watchdog(mut self, soft: Duration, hard: Duration) -> Self {
+        self.turn_watchdog = Some(TurnWatchdog { soft, hard });
+        self
+    }
+
+    /// Count the hashes at which matches miss, for [AvailableBlocks::top_misses]
+    ///
+    /// At most `capacity` hashes are tracked; a new hash replaces the least missed one, so counts
+    /// are approximate for all but the most missed hashes. Counts are halved every
+    /// `decay_interval` so old misses fade; a zero interval never decays.
+    pub fn with_miss_tracking(mut self, capacity: usize, decay_interval: Duration) -> Self {
+        self.miss_tracking = Some(MissTracking {
+            capacity,
+            decay_interval,
+        });
+        self
+    }
+
+    /// Check every `every_n_ops` requests and returns that the eviction order and the lookup map
+    /// hold the same number of blocks
+    ///
+    /// On a mismatch the divergence is logged and the eviction order is rebuilt from the lookup
+    /// map, so an eviction order entry without a block is dropped before it can be popped.
+    pub fn with_periodic_integrity_check(mut self, every_n_ops: u64) -> Self {
+        self.integrity_check_interval = Some(every_n_ops.max(1));
+        self
+    }
+
+    /// Check the counters of the pool on every `every_n_calls` match and control request
+    ///
+    /// The caller compares the available and offloading counts against the total, and, while
+    /// returns are queued, the time since the progress engine last finished a turn against
+    /// `stale_after`. An anomaly is kept in [PoolHealth::anomaly] until a later check finds the
+    /// counters clean or an audit finds the engine state consistent; it is also logged and an
+    /// integrity audit requested, at most once per ten seconds. The audit report lands in
+    /// [PoolHealth::last_audit]. The counters are read outside the engine, so a check racing a
+    /// turn can flag drift the audit does not confirm.
+    pub fn with_self_check(mut self, every_n_calls: u64, stale_after: Duration) -> Self {
+        self.self_check = Some(SelfCheck {
+            every: every_n_calls.max(1),
+            stale_after,
+        });
+        self
+    }
+
+    /// Let an inconsistent audit requested by the self check repair the engine state
+    ///
+    /// The eviction order is rebuilt from the lookup map if they diverged, and the available
+    /// count is reset to the blocks held by the engine. Repairs are counted in
+    /// [CounterSnapshot::integrity_repairs]. Off by default; without
+    /// [AvailableBlocksConfig::with_self_check] it has no effect.
+    pub fn with_self_check_repair(mut self, repair: bool) -> Self {
+        self.self_check_repair = repair;
+        self
+    }
+
+    /// Reclaim blocks whose offload has not completed within `timeout`
+    ///
+    /// The engine sweeps the offloads in progress every `timeout`; an offload past its timeout is
+    /// abandoned and its block returns to the pool blank, as if the offload had completed. A
+    /// sweep reclaims a bounded number of blocks per turn, leaving the rest for the next sweep.
+    pub fn with_offload_janitor(mut self, timeout: Duration) -> Self {
+        self.offload_timeout = Some(timeout);
+        self
+    }
+
+    /// Drop uninitialized blocks which sat unused for longer than `ttl`
+    ///
+    /// An idle blank slot is over-provisioned memory, so the janitor drops the blanks past their
+    /// TTL, reducing the total blocks, and the pool shrinks back once a load spike subsides. Slots
+    /// marked with [KvBlock::set_preallocated] are kept. The janitor runs every `ttl`, so a blank
+    /// may linger for up to twice its TTL.
+    pub fn with_uninitialized_ttl(mut self, ttl: Duration) -> Self {
+        self.uninitialized_ttl = Some(ttl);
+        self
+    }
+
+    /// Adjust the priorities of cached blocks by how often they were matched
+    ///
+    /// Every `interval` the janitor raises the priority of each cached block matched at least
+    /// `promote_threshold` times since the last adjustment by one, and lowers the priority of
+    /// each block matched at most `demote_threshold` times by one, re-keying them in bulk as
+    /// [AvailableBlocks::rebalance] does. Only blocks whose priority lies within the bounds of
+    /// [AvailableBlocksConfig::with_adaptive_priority_bounds] are adjusted, and never past them,
+    /// so priorities managed by the caller outside the bounds are left alone.
+    pub fn with_adaptive_priority(
+        mut self,
+        promote_threshold: u32,
+        demote_threshold: u32,
+        interval: Duration,
+    ) -> Self {
+        let (min, max) = self
+            .adaptive_priority
+            .map_or(BlockPriority::class_range(0).into_inner(), |adaptive| {
+                (adaptive.min, adaptive.max)
+            });
+        self.adaptive_priority = Some(AdaptivePriority {
+            promote_threshold,
+            demote_threshold,
+            interval: interval.max(Duration::from_millis(1)),
+            min,
+            max,
+        });
+        self
+    }
+
+    /// Bounds of the priorities [AvailableBlocksConfig::with_adaptive_priority] adjusts; by
+    /// default every score of the lowest class, `BlockPriority::class_range(0)`
+    pub fn with_adaptive_priority_bounds<P: Into<BlockPriority>>(
+        mut self,
+        bounds: RangeInclusive<P>,
+    ) -> Self {
+        if let Some(adaptive) = self.adaptive_priority.as_mut() {
+            let (min, max) = bounds.into_inner();
+            adaptive.min = min.into();
+            adaptive.max = max.into();
+        }
+        self
+    }
+
+    /// Track the hit ratio by tokens of [AvailableBlocks::allocate_request] over the last
+    /// `buckets` intervals of `bucket`; see [TokenStats]
+    pub fn with_token_hit_window(mut self, bucket: Duration, buckets: usize) -> Self {
+        self.token_hit_window = Some(TokenHitWindow {
+            bucket,
+            buckets: buckets.max(1),
+        });
+        self
+    }
+
+    /// Cap the number of blocks a single session can hold through [AvailableBlocks::stick]
+    pub fn with_sticky_cap(mut self, max_blocks_per_session: usize) -> Self {
+        self.sticky_cap = Some(max_blocks_per_session);
+        self
+    }
+
+    /// Verify the checksum of every matched block which carries one, see [KvBlock::set_checksum]
+    ///
+    /// `verify` runs on the caller's task after [AvailableBlocks::match_blocks] resolves, so it
+    /// may read device memory without stalling the engine. A match is cut short at the first
+    /// block which fails verification, and that block is reported to
+    /// [AvailableBlocks::report_corrupt].
+    pub fn with_checksum_verifier(mut self, verify: fn(&KvBlock) -> bool) -> Self {
+        self.checksum_verifier = Some(verify);
+        self
+    }
+
+    /// Record one in every `sample_every` matches, takes and returns in a ring buffer of
+    /// `capacity` operations, see [AvailableBlocks::export_trace]
+    ///
+    /// Every match and take is recorded where the engine serves it, whichever call made it, so an
+    /// allocation records its match and its take. Operations issued through a [PoolSession] with
+    /// a request id are sampled by request: one in every `sample_every` requests has all of its
+    /// operations recorded. Other operations, and returns, are sampled one in every
+    /// `sample_every`. Matches split by [AvailableBlocksConfig::with_handler_chunk_size] are not
+    /// recorded.
+    #[cfg(feature = "trace-record")]
+    pub fn with_trace_recorder(mut self, capacity: usize, sample_every: u64) -> Self {
+        self.trace_recorder = Some(TraceRecorderConfig {
+            capacity: capacity.max(1),
+            sample_every: sample_every.max(1),
+        });
+        self
+    }
+
+    /// Reserve the last `fraction` of the pool's capacity for takes backing cache hits
+    ///
+    /// Takes through [AvailableBlocks::take_blocks_with_options] which report no hits in their
+    /// [TakeOptions] are cut down so they leave the reserve free, and fail with
+    /// [KvPoolError::ReserveForHits] once only the reserve is left. Takes reporting hits, and
+    /// takes without options, are served as before. A request of [AvailableBlocks::allocate_batch]
+    /// which matches nothing is only served if its takes leave the reserve free.
+    pub fn with_hit_reserve(mut self, fraction: f64) -> Self {
+        self.hit_reserve = Some(fraction.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Keep the last `blocks` takeable blocks free for [AvailableBlocks::take_blocks_emergency]
+    ///
+    /// Every other take, including the takes of an allocation, only takes down to the headroom,
+    /// so a low priority batch can not consume the slots a latency critical
+    /// request needs.
+    pub fn with_reserved_headroom(mut self, blocks: u64) -> Self {
+        self.reserved_headroom = blocks;
+        self
+    }
+
+    /// Decide which inserted blocks are demoted to the uninitialized set
+    ///
+    /// By default a block with a zero sequence hash is uninitialized. Callers which use hash 0 for
+    /// a real prefix, such as an empty prompt, can supply a rule under which it is cached and
+    /// matchable like any other block. Blocks whose hash is already cached are always demoted.
+    pub fn with_uninitialized_rule(mut self, is_uninitialized: fn(&KvBlock) -> bool) -> Self {
+        self.uninitialized_rule = Some(is_uninitialized);
+        self
+    }
+
+    /// Remember the ids of the last `op_ids` operations of [AvailableBlocks::insert_many_once]
+    ///
+    /// A redelivery older than the window is applied again. Defaults to [INSERT_DEDUPE_WINDOW].
+    pub fn with_insert_dedupe_window(mut self, op_ids: usize) -> Self {
+        self.insert_dedupe_window = Some(op_ids.max(1));
+        self
+    }
+
+    /// Remember the ids of the last `op_ids` tagged operations handled for [AvailableBlocks::fence_ops]
+    ///
+    /// A fence listing an op id which fell out of the window waits until an operation with that id
+    /// is handled again. Defaults to [COMPLETED_OPS_WINDOW].
+    pub fn with_completed_ops_window(mut self, op_ids: usize) -> Self {
+        self.completed_ops_window = Some(op_ids.max(1));
+        self
+    }
+
+    /// Keep at most `entries` cold entries recorded by [AvailableBlocks::mirror_cold]
+    ///
+    /// Mirrored entries never expire and are not counted against the cap of the
+    /// [eviction hook][AvailableBlocksConfig::with_eviction_hook]. Entries added past the cap are
+    /// dropped and counted in [CounterSnapshot::mirror_overflow] until entries are removed or the
+    /// mirror is replaced. Defaults to [MIRROR_COLD_CAP].
+    pub fn with_mirror_cap(mut self, entries: usize) -> Self {
+        self.mirror_cap = Some(entries);
+        self
+    }
+
+    /// Cap the auxiliary history the engine keeps beside the cached blocks at about `max_bytes`
+    ///
+    /// The history is the recent evictions of [AvailableBlocksConfig::with_eviction_hysteresis]
+    /// and the counts of [AvailableBlocksConfig::with_miss_tracking]. A turn which leaves it over
+    /// budget sheds the oldest entries in that order: recent evictions first, then the least
+    /// missed hashes. A shed entry behaves as one which expired; the cached blocks are never
+    /// touched. Shed entries are counted in [CounterSnapshot] and the size held is reported in
+    /// [PoolStats::aux_bytes].
+    ///
+    /// The rest of the engine state is bounded on its own and not part of the budget:
+    ///
+    /// - the dedupe window of [AvailableBlocks::insert_many_once] decides whether a redelivery is
+    ///   applied, so it is never shed; [AvailableBlocksConfig::with_insert_dedupe_window] bounds it
+    /// - tagged operations not yet observed by a fence are capped by
+    ///   [AvailableBlocksConfig::with_completed_ops_window]
+    /// - pending priorities and quarantined hashes are kept only for checked out blocks
+    /// - cold entries and their expiry order are capped by
+    ///   [AvailableBlocksConfig::with_eviction_hook]
+    /// - hits counted for [AvailableBlocksConfig::with_adaptive_priority] are cleared every
+    ///   adaptation interval
+    /// - watchers of [AvailableBlocks::watch_sequence] are pruned once their receivers are dropped
+    pub fn with_aux_budget(mut self, max_bytes: usize) -> Self {
+        self.aux_budget = Some(max_bytes);
+        self
+    }
+
+    /// Compact the lookup map of reusable blocks in the background once it has `max_slack` times
+    /// more slots than entries, moving at most `budget` entries per engine turn
+    ///
+    /// After heavy churn, such as a mass reset or a generation invalidation, the map keeps the
+    /// slots of its peak size. Compaction rehashes it into a map sized for its current entries
+    /// with a quarter of headroom; maps of fewer than [COMPACTION_MIN_CAPACITY] slots are left
+    /// alone. While a compaction is in progress, returns and fences wait along with new requests,
+    /// as for a chunked request; the eviction order is not touched.
+    pub fn with_compaction(mut self, max_slack: usize, budget: usize) -> Self {
+        self.compaction = Some(CompactionPolicy {
+            max_slack: max_slack.max(2),
+            budget: budget.max(1),
+        });
+        self
+    }
+
+    /// Record the tokens per block of the pool's blocks in its [CompatManifest]
+    ///
+    /// The pool does not check inserted blocks against it; it lets components exchanging cache
+    /// state detect a mismatch, see [AvailableBlocks::manifest].
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Record how the sequence hashes of the pool's blocks are computed in its [CompatManifest]
+    ///
+    /// The pool does not hash tokens itself; set this when the blocks are not hashed by
+    /// [compute_hash][crate::kv_router::indexer::compute_hash] with its default seed.
+    pub fn with_hash_config(mut self, hash_config: HashConfig) -> Self {
+        self.hash_config = Some(hash_config);
+        self
+    }
+
+    /// Publish the hashes of the cached blocks in an index read by [AvailableBlocks::probe_fast]
+    ///
+    /// The engine publishes the index as an immutable snapshot at the end of each turn, so a
+    /// probe never waits on the engine and the index costs one publish per turn rather than one
+    /// write per block. Two snapshots alternate: the one replaced by a publish is updated in
+    /// place for the next turn, and is only copied if a probe still holds it.
+    pub fn with_fast_probe(mut self) -> Self {
+        self.fast_probe = true;
+        self
+    }
+
+    /// Record the hashes held by the pool as `pool` in an `index` shared with its peers, for
+    /// [AvailableBlocks::match_or_fetch]
+    ///
+    /// The changes of each engine turn are applied to the index under a single lock at the end of
+    /// the turn. The id must be unique among the pools sharing the index.
+    pub fn with_shared_content_index(mut self, index: SharedContentIndex, pool: PoolId) -> Self {
+        self.shared_content_index = Some((index, pool));
+        self
+    }
+
+    /// Cap the blocks checked out of the pool at any time at `max_in_use`
+    ///
+    /// For a pool which is a logical view over a device budget shared with other consumers. Every
+    /// match, take and allocation is served only up to the cap; once
+    /// `total_blocks - available_blocks` reaches it, matches, takes, cold promotions and
+    /// allocations fail with [KvPoolError::InUseLimitReached] until blocks return. A take which
+    /// must be served in full fails if it would cross the cap.
+    ///
+    /// The paths which cannot fail once started end at the cap instead: a chunked or streamed
+    /// match stops when the cap is reached between its chunks, and
+    /// [AvailableBlocks::allocate_batch] leaves the requests past the cap unserved.
+    pub fn with_max_in_use(mut self, max_in_use: u64) -> Self {
+        self.max_in_use = Some(max_in_use);
+        self
+    }
+
+    /// How [AvailableBlocks::match_blocks] treats a block checked out by
+    /// [AvailableBlocks::checkout_for_maintenance]; by default the match misses it
+    pub fn with_maintenance_conflict(mut self, on_maintenance: OnMaintenance) -> Self {
+        self.on_maintenance = on_maintenance;
+        self
+    }
+
+    fn compat_manifest(&self) -> CompatManifest {
+        CompatManifest {
+            hash_config: self.hash_config.clone().unwrap_or_default(),
+            block_size: self.block_size,
+            ..Default::default()
+        }
+    }
+}
+
+pub struct AvailableBlocks {
+    match_tx: mpsc::UnboundedSender<Sequenced<MatchRequest>>,
+    control_tx: mpsc::UnboundedSender<Sequenced<ControlRequest>>,
+    fence_tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    return_tx: ReturnSender,
+    next_seq: AtomicU64,
+    total_blocks: Arc<AtomicU64>,
+    available_blocks: Arc<AtomicU64>,
+    offloading_blocks: Arc<AtomicU64>,
+    lifecycle_rx: watch::Receiver<PoolLifecycle>,
+    token_stats_rx: watch::Receiver<TokenStats>,
+    available_rx: watch::Receiver<u64>,
+    resident_rx: watch::Receiver<Arc<HashSet<SequenceHash>>>,
+    config: AvailableBlocksConfig,
+    join_handle: JoinHandle<()>,
+
+    op_events: broadcast::Sender<OpCompleted>,
+    eviction_events: broadcast::Sender<EvictionEvent>,
+    pool_events: broadcast::Sender<PoolEvent>,
+
+    // notified by the engine whenever blocks are returned or inserted
+    availability: Arc<Notify>,
+
+    // notified by the engine whenever a soft hold is claimed or released, or a block checked out
+    // for maintenance is re-published
+    holds: Arc<Notify>,
+
+    // shared with every return handle; tracks the depth of the return queue
+    overflow: Arc<ReturnOverflow>,
+
+    // shared with the engine, which stamps its turns and caches the audits
+    self_check: Arc<SelfCheckState>,
+
+    // set when the engine runs on its own thread; dropped last to tear the thread down
+    engine_thread: Option<EngineThread>,
+}
+
+impl AvailableBlocks {
+    /// Settings other components must share to exchange cache state with the pool; check them
+    /// with [CompatManifest::compatible_with]
+    pub fn manifest(&self) -> CompatManifest {
+        self.config.compat_manifest()
+    }
+
+    pub fn total_blocks(&self) -> u64 {
+        self.total_blocks.load(Ordering::SeqCst)
+    }
+
+    pub fn available_blocks(&self) -> u64 {
+        self.available_blocks.load(Ordering::SeqCst)
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.join_handle.is_finished()
+    }
+
+    pub fn lifecycle(&self) -> PoolLifecycle {
+        *self.lifecycle_rx.borrow()
+    }
+
+    /// [TokenStats] of the pool, published at the end of every engine turn which changed them
+    pub fn watch_token_stats(&self) -> watch::Receiver<TokenStats> {
+        self.token_stats_rx.clone()
+    }
+
+    /// Available blocks of the pool, published at the end of every engine turn which changed them
+    pub fn subscribe_availability(&self) -> watch::Receiver<u64> {
+        self.available_rx.clone()
+    }
+
+    /// Available blocks of the pool like [AvailableBlocks::subscribe_availability], emitted at most
+    /// once per `window`
+    ///
+    /// After a change the stream waits for `window` and emits the value the pool settled on, so
+    /// a burst of returns within the window is coalesced into a single item. The stream ends when
+    /// the pool is dropped.
+    pub fn subscribe_availability_debounced(&self, window: Duration) -> impl Stream<Item = u64> {
+        futures::stream::unfold(self.subscribe_availability(), move |mut rx| async move {
+            rx.changed().await.ok()?;
+            tokio::time::sleep(window).await;
+            let available = *rx.borrow_and_update();
+            Some((available, rx))
+        })
+    }
+
+    pub fn health(&self) -> PoolHealth {
+        PoolHealth {
+            lifecycle: self.lifecycle(),
+            active: self.is_active(),
+            total_blocks: self.total_blocks(),
+            available_blocks: self.available_blocks(),
+            return_queue_depth: self.return_queue_depth(),
+            offloading_blocks: self.offloading_blocks.load(Ordering::SeqCst),
+            anomaly: *self.self_check.anomaly.lock().unwrap(),
+            last_audit: *self.self_check.last_audit.lock().unwrap(),
+        }
+    }
+
+    // Check the counters on every nth call under AvailableBlocksConfig::with_self_check
+    fn sample_self_check(&self) {
+        let Some(check) = self.config.self_check else {
+            return;
+        };
+        if self.self_check.calls.fetch_add(1, Ordering::Relaxed) % check.every != 0 {
+            return;
+        }
+        let Some(anomaly) = self.detect_anomaly(check) else {
+            *self.self_check.anomaly.lock().unwrap() = None;
+            return;
+        };
+
+        *self.self_check.anomaly.lock().unwrap() = Some(anomaly);
+        if !self.self_check.should_warn() {
+            return;
+        }
+        log::warn!(
+            ?anomaly,
+            "pool counters disagree with the engine; requesting an audit"
+        );
+
+        // sent directly rather than through send_control, which samples again
+        let (tx, _rx) = oneshot::channel();
+        let audit = ControlRequest::VerifyIntegrity(VerifyIntegrityControl {
+            repair: self.config.self_check_repair,
+            tx,
+        });
+        if self.control_tx.send(self.sequenced(audit)).is_err() {
+            log::trace!("Failed to send self check audit; channel closed");
+        }
+    }
+
+    fn detect_anomaly(&self, check: SelfCheck) -> Option<PoolAnomaly> {
+        if !self.is_active() {
+            return Some(PoolAnomaly::EngineStopped);
+        }
+
+        let total = self.total_blocks();
+        let available = self.available_blocks();
+        let offloading = self.offloading_blocks.load(Ordering::SeqCst);
+        if available + offloading > total {
+            return Some(PoolAnomaly::CounterDrift {
+                total,
+                available,
+                offloading,
+            });
+        }
+
+        let queued_returns = self.return_queue_depth();
+        let since_last_turn = self.self_check.since_last_turn();
+        if queued_returns > 0 && since_last_turn > check.stale_after {
+            return Some(PoolAnomaly::StaleHeartbeat {
+                since_last_turn,
+                queued_returns,
+            });
+        }
+        None
+    }
+
+    #[cfg(test)]
+    fn inject_available_drift(&self, blocks: u64) {
+        self.available_blocks.fetch_add(blocks, Ordering::SeqCst);
+    }
+
+    /// Returned blocks the progress engine has not absorbed yet
+    pub fn return_queue_depth(&self) -> u64 {
+        self.overflow.depth.load(Ordering::SeqCst)
+    }
+
+    // Stamp a request with the next sequence number if strict sequencing is enabled
+    fn sequenced<T>(&self, request: T) -> Sequenced<T> {
+        let seq = self
+            .config
+            .strict_sequencing
+            .then(|| self.next_seq.fetch_add(1, Ordering::SeqCst));
+        Sequenced {
+            seq,
+            context: None,
+            request,
+        }
+    }
+
+    fn send_match(
+        &self,
+        request: MatchRequest,
+    ) -> std::result::Result<(), mpsc::error::SendError<Sequenced<MatchRequest>>> {
+        self.send_match_in(request, None)
+    }
+
+    fn send_match_in(
+        &self,
+        request: MatchRequest,
+        context: Option<OpContext>,
+    ) -> std::result::Result<(), mpsc::error::SendError<Sequenced<MatchRequest>>> {
+        self.sample_self_check();
+        let mut request = self.sequenced(request);
+        request.context = context;
+        self.match_tx.send(request)
+    }
+
+    /// Subscribe to the [OpCompleted] events of operations issued through a [PoolSession] with
+    /// a request id
+    pub fn subscribe_op_events(&self) -> broadcast::Receiver<OpCompleted> {
+        self.op_events.subscribe()
+    }
+
+    /// Subscribe to an [EvictionEvent] for every cached block evicted, attributed to the caller
+    /// and request which forced it
+    ///
+    /// Takes made through [AvailableBlocks::take_blocks_for] are attributed to their caller;
+    /// without [AvailableBlocksConfig::with_per_caller_quota] this only tags the take.
+    pub fn subscribe_evictions(&self) -> broadcast::Receiver<EvictionEvent> {
+        self.eviction_events.subscribe()
+    }
+
+    /// Subscribe to the [PoolEvent]s of the pool, such as a [PoolEvent::CapacityChanged] once the
+    /// engine has absorbed or released blocks
+    pub fn subscribe_pool_events(&self) -> broadcast::Receiver<PoolEvent> {
+        self.pool_events.subscribe()
+    }
+
+    fn send_control(
+        &self,
+        request: ControlRequest,
+    ) -> std::result::Result<(), mpsc::error::SendError<Sequenced<ControlRequest>>> {
+        self.sample_self_check();
+        self.control_tx.send(self.sequenced(request))
+    }
+
+    /// Wait until every listed tagged operation has been handled
+    ///
+    /// Operations are tagged by the `_tagged` forms of [AvailableBlocks::insert],
+    /// [AvailableBlocks::insert_many], [AvailableBlocks::update_multiple],
+    /// [AvailableBlocks::rebalance] and [AvailableBlocks::reset]. Unlike [AvailableBlocks::fence],
+    /// operations which are not listed are not waited for. Op ids are chosen by the caller and
+    /// should not be reused; a handled op id is remembered until a fence listing it resolves, for
+    /// at most [AvailableBlocksConfig::with_completed_ops_window] operations.
+    pub async fn fence_ops(&self, ops: Vec<u64>) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::FenceOps(FenceOpsControl { ops, tx }))
+            .is_err()
+        {
+            raise!("failed to send fence ops request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Transition the pool to [PoolLifecycle::Ready]
+    ///
+    /// The transition is ordered after all previously issued inserts and control requests.
+    pub async fn mark_ready(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::MarkReady(MarkReadyControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send mark ready request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    async fn wait_until_ready(&self) -> Result<()> {
+        match self.lifecycle() {
+            PoolLifecycle::Ready => return Ok(()),
+            PoolLifecycle::ShuttingDown => return self.admit_during_shutdown(),
+            PoolLifecycle::Initializing => {}
+        }
+
+        match self
+            .config
+            .not_ready_policy
+            .unwrap_or(NotReadyPolicy::Reject)
+        {
+            NotReadyPolicy::Reject => raise!(KvPoolError::NotReady),
+            NotReadyPolicy::Queue => {
+                let mut lifecycle_rx = self.lifecycle_rx.clone();
+                if lifecycle_rx
+                    .wait_for(|lifecycle| *lifecycle != PoolLifecycle::Initializing)
+                    .await
+                    .is_err()
+                {
+                    raise!("failed to wait for pool readiness; progress engine stopped");
+                }
+                if self.lifecycle() == PoolLifecycle::ShuttingDown {
+                    return self.admit_during_shutdown();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn admit_during_shutdown(&self) -> Result<()> {
+        match self.config.shutdown_mode {
+            ShutdownMode::DrainThenReject if self.is_active() => Ok(()),
+            _ => raise!(KvPoolError::ShuttingDown),
+        }
+    }
+
+    /// Start shutting the pool down; resolves once the engine has begun draining
+    ///
+    /// The shutdown is ordered after all previously issued inserts and control requests. The
+    /// engine keeps handling returns and control requests, and match and take requests according
+    /// to [AvailableBlocksConfig::with_shutdown_mode], until every checked out block is back and
+    /// nothing is queued; then it stops.
+    pub async fn begin_shutdown(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Shutdown(ShutdownControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send shutdown request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Shut the pool down like [AvailableBlocks::begin_shutdown] and wait until the engine has
+    /// stopped
+    pub async fn shutdown(&self) -> Result<()> {
+        let (tx, _rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Shutdown(ShutdownControl { tx }))
+            .is_err()
+        {
+            log::trace!("shutdown request not sent; progress engine already stopped");
+        }
+
+        // the lifecycle sender is dropped with the engine state
+        let mut lifecycle_rx = self.lifecycle_rx.clone();
+        while lifecycle_rx.changed().await.is_ok() {}
+        Ok(())
+    }
+
+    /// Match the longest cached prefix of `hashes`; see [match ordering](self#match-ordering)
+    pub async fn match_blocks(&self, hashes: Vec<SequenceHash>) -> Result<Vec<PoolItem<KvBlock>>> {
+        self.match_blocks_with(hashes, false, None).await
+    }
+
+    /// Match a single block, without the allocations of a multi-hash match
+    pub async fn match_one(&self, hash: SequenceHash) -> Result<Option<PoolItem<KvBlock>>> {
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::MatchSingle(MatchSingle { hash, tx }))
+            .is_err()
+        {
+            raise!("failed to send match request; channel closed");
+        }
+
+        let Some(matched_block) = rx.await?? else {
+            return Ok(None);
+        };
+        Ok(self.verify_checksums(vec![matched_block]).await?.pop())
+    }
+
+    /// Check out the block cached under `hash` to mutate it in place and re-publish it
+    ///
+    /// The block leaves the pool as for a match, but is not counted as a match, a return or
+    /// saved tokens, and is not charged to a caller quota. While it is checked out, matches
+    /// reaching it behave as set by [AvailableBlocksConfig::with_maintenance_conflict]. Returns
+    /// `None` if no block is cached under `hash`.
+    pub async fn checkout_for_maintenance(
+        &self,
+        hash: SequenceHash,
+    ) -> Result<Option<MaintenanceGuard>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::CheckoutForMaintenance(
+                CheckoutForMaintenanceControl { hash, tx },
+            ))
+            .is_err()
+        {
+            raise!("failed to send maintenance checkout request; channel closed");
+        }
+        let block = rx.await?;
+        Ok(block.map(|block| MaintenanceGuard { block }))
+    }
+
+    /// Match blocks like [AvailableBlocks::match_blocks], streaming the matched blocks in batches
+    ///
+    /// The engine fulfills the match in chunks of at most [MATCH_STREAM_BATCH_SIZE] blocks,
+    /// yielding in between, and the stream yields the blocks in the order of `hashes` until the
+    /// first miss. As for a chunked match, only
+    /// blocks in the pool when the match started are matched. Blocks not yet consumed when the
+    /// stream is dropped are returned to the pool.
+    ///
+    /// At most one batch waits for the caller: while it is unconsumed the engine matches no
+    /// further blocks for the stream and serves other requests in the meantime.
+    pub async fn match_blocks_stream(
+        &self,
+        hashes: Vec<SequenceHash>,
+    ) -> Result<impl Stream<Item = PoolItem<KvBlock>>> {
+        validate_match(&hashes)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = mpsc::channel(1);
+        if self
+            .send_match(MatchRequest::MatchStream(MatchStream { hashes, tx }))
+            .is_err()
+        {
+            raise!("failed to send match request; channel closed");
+        }
+
+        // the stream ends at the first block failing the checksum verifier
+        let verify = self.config.checksum_verifier;
+        Ok(tokio_stream::wrappers::ReceiverStream::new(rx)
+            .flat_map(futures::stream::iter)
+            .take_while(move |block| {
+                let corrupt =
+                    verify.is_some_and(|verify| block.checksum().is_some() && !verify(block));
+                let ops = corrupt.then(|| block.pool_ops()).flatten();
+                async move {
+                    // reported before the block is dropped, so it is quarantined on return
+                    if let Some(ops) = ops {
+                        if ops.report_corrupt().await.is_err() {
+                            log::trace!("corrupt block not reported; pool shut down");
+                        }
+                    }
+                    !corrupt
+                }
+            }))
+    }
+
+    /// Match blocks like [AvailableBlocks::match_blocks], then continue the match through the
+    /// blocks offloaded by the eviction hook
+    ///
+    /// See [AvailableBlocksConfig::with_eviction_hook]; without a hook, no cold hits are reported.
+    pub async fn match_blocks_tiered(&self, hashes: Vec<SequenceHash>) -> Result<TieredMatch> {
+        validate_match(&hashes)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::MatchTiered(MatchTiered { hashes, tx }))
+            .is_err()
+        {
+            raise!("failed to send match request; channel closed");
+        }
+
+        let tiered_match = rx.await??;
+        Ok(tiered_match)
+    }
+
+    /// Bring a block offloaded by the eviction hook back into a block taken from the pool
+    ///
+    /// The returned block holds the tokens of the cold entry and is cached under its hash again
+    /// once dropped; the caller restores the block's KV data from the entry's [ColdLocation].
+    /// Returns `None` if the hash has no cold entry, is cached in the pool or no block can be
+    /// taken.
+    pub async fn promote_cold(&self, hash: SequenceHash) -> Result<Option<UniqueBlock>> {
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::PromoteCold(PromoteCold { hash, tx }))
+            .is_err()
+        {
+            raise!("failed to send promote request; channel closed");
+        }
+
+        let promoted = rx.await??;
+        Ok(promoted)
+    }
+
+    /// Record cold entries for blocks held outside this pool, e.g. the index of a primary mirrored
+    /// by a [standby][super::shadow::ShadowStandby]
+    ///
+    /// Drops the mirrored entries in `removed`, then records `added`; with `replace`, every
+    /// mirrored entry is dropped first. Mirrored entries never expire and are reported by
+    /// [AvailableBlocks::match_blocks_tiered] like offloaded blocks, but can not be promoted since
+    /// the pool has no tokens for them. Blocks cached or offloaded by this pool are left as is.
+    /// Mirrored entries are kept apart from offloaded ones, up to
+    /// [AvailableBlocksConfig::with_mirror_cap], and never count against the eviction hook's cap.
+    pub async fn mirror_cold(
+        &self,
+        added: Vec<ColdHit>,
+        removed: Vec<SequenceHash>,
+        replace: bool,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::MirrorCold(MirrorColdControl {
+                added,
+                removed,
+                replace,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send mirror cold request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Match blocks like [AvailableBlocks::match_blocks], reporting how close each block was to
+    /// eviction
+    pub async fn match_blocks_detailed(
+        &self,
+        hashes: Vec<SequenceHash>,
+    ) -> Result<Vec<MatchedBlock>> {
+        validate_match(&hashes)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::MatchDetailed(MatchDetailed { hashes, tx }))
+            .is_err()
+        {
+            raise!("failed to send match request; channel closed");
+        }
+
+        let matched_blocks = rx.await??;
+        Ok(matched_blocks)
+    }
+
+    /// Match a single block only if its content version is still `expected_version`
+    ///
+    /// A block whose contents were rewritten since the caller last saw it is not matched and
+    /// counts as a miss, so stale contents are never served as a hit; see [KvBlock::version].
+    pub async fn match_if_version(
+        &self,
+        hash: SequenceHash,
+        expected_version: u64,
+    ) -> Result<Option<PoolItem<KvBlock>>> {
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::MatchIfVersion(MatchIfVersion {
+                hash,
+                version: expected_version,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send match request; channel closed");
+        }
+
+        let matched = rx.await??;
+        Ok(matched)
+    }
+
+    /// Match blocks like [AvailableBlocks::match_blocks] for a prefix which is served only once
+    ///
+    /// When the matched blocks are returned they are reset into uninitialized blocks instead of
+    /// being cached again under their sequence hash.
+    pub async fn match_blocks_single_use(
+        &self,
+        hashes: Vec<SequenceHash>,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        self.match_blocks_with(hashes, true, None).await
+    }
+
+    async fn match_blocks_with(
+        &self,
+        hashes: Vec<SequenceHash>,
+        single_use: bool,
+        context: Option<OpContext>,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_match(&hashes)?;
+        self.wait_until_ready().await?;
+
+        let deadline = match self.config.on_maintenance {
+            OnMaintenance::Wait(wait) => Some(Instant::now() + wait),
+            OnMaintenance::Miss => None,
+        };
+        let mut hashes = hashes;
+        loop {
+            // registered before the attempt so a block re-published during it is not missed
+            let republished = self.holds.notified();
+            tokio::pin!(republished);
+            republished.as_mut().enable();
+
+            // the hashes are only kept for a retry while there is time left to wait
+            let wait = deadline.filter(|deadline| Instant::now() < *deadline);
+            let attempt = match wait {
+                Some(_) => hashes.clone(),
+                None => std::mem::take(&mut hashes),
+            };
+
+            let (tx, rx) = oneshot::channel();
+            if self
+                .send_match_in(
+                    MatchRequest::MatchMultiple(MatchMultiple {
+                        hashes: attempt,
+                        single_use,
+                        wait_for_maintenance: wait.is_some(),
+                        tx,
+                    }),
+                    context,
+                )
+                .is_err()
+            {
+                raise!("failed to send match request; channel closed");
+            }
+
+            match (rx.await?, wait) {
+                (Err(KvPoolError::UnderMaintenance(_)), Some(deadline)) => {
+                    let _ = tokio::time::timeout_at(deadline, republished).await;
+                }
+                (matched_blocks, _) => return self.verify_checksums(matched_blocks?).await,
+            }
+        }
+    }
+
+    // Cut a match short at the first block failing the checksum verifier
+    async fn verify_checksums(
+        &self,
+        mut blocks: Vec<PoolItem<KvBlock>>,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        let Some(verify) = self.config.checksum_verifier else {
+            return Ok(blocks);
+        };
+
+        let corrupt = blocks
+            .iter()
+            .position(|block| block.checksum().is_some() && !verify(block));
+        if let Some(index) = corrupt {
+            // reported before the block is dropped, so it is quarantined on return
+            self.report_corrupt(blocks[index].lookup_key()).await?;
+            blocks.truncate(index);
+        }
+        Ok(blocks)
+    }
+
+    pub async fn match_token_blocks(
+        &self,
+        token_blocks: &[TokenBlock],
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        let hashes: Vec<u64> = token_blocks.iter().map(|b| b.sequence_hash()).collect();
+        self.match_blocks(hashes).await
+    }
+
+    /// Take up to `count` blocks, under the default [UnderSupply] policy of the pool
+    ///
+    /// A take of zero blocks fails with [KvPoolError::InvalidRequest] and panics in debug builds.
+    pub async fn take_blocks(&self, count: u32) -> Result<Vec<PoolItem<KvBlock>>> {
+        self.take_blocks_with(count, self.config.under_supply, None)
+            .await
+    }
+
+    /// Take up to `count` blocks like [AvailableBlocks::take_blocks], dipping into the headroom
+    /// set by [AvailableBlocksConfig::with_reserved_headroom]
+    pub async fn take_blocks_emergency(&self, count: u32) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_take(count)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::Take(Take {
+                count,
+                emergency: true,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send take request; channel closed");
+        }
+
+        let taken_blocks = rx.await??;
+        Ok(taken_blocks)
+    }
+
+    /// Take up to `count` blocks like [AvailableBlocks::take_blocks] for a request described by
+    /// `options`; see [AvailableBlocksConfig::with_hit_reserve]
+    pub async fn take_blocks_with_options(
+        &self,
+        count: u32,
+        options: TakeOptions,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_take(count)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::TakeWithOptions(TakeWithOptions {
+                count,
+                options,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send take request; channel closed");
+        }
+
+        let taken_blocks = rx.await??;
+        Ok(taken_blocks)
+    }
+
+    /// Take `count` blocks, applying `policy` if fewer can be taken
+    pub async fn take_blocks_with_policy(
+        &self,
+        count: u32,
+        policy: UnderSupply,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        self.take_blocks_with(count, policy, None).await
+    }
+
+    async fn take_blocks_with(
+        &self,
+        count: u32,
+        policy: UnderSupply,
+        context: Option<OpContext>,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_take(count)?;
+        self.wait_until_ready().await?;
+
+        let wait = match policy {
+            UnderSupply::ReturnPartial => {
+                let (tx, rx) = oneshot::channel();
+                if self
+                    .send_match_in(
+                        MatchRequest::Take(Take {
+                            count,
+                            emergency: false,
+                            tx,
+                        }),
+                        context,
+                    )
+                    .is_err()
+                {
+                    raise!("failed to send take request; channel closed");
+                }
+
+                let matched_blocks = rx.await??;
+                return Ok(matched_blocks);
+            }
+            UnderSupply::Fail => Duration::ZERO,
+            UnderSupply::WaitUpTo(wait) => wait,
+        };
+
+        let deadline = Instant::now() + wait;
+        loop {
+            // registered before the attempt so an availability change during it is not missed
+            let available = self.availability.notified();
+            tokio::pin!(available);
+            available.as_mut().enable();
+
+            let (tx, rx) = oneshot::channel();
+            if self
+                .send_match_in(MatchRequest::TakeExact(TakeExact { count, tx }), context)
+                .is_err()
+            {
+                raise!("failed to send take request; channel closed");
+            }
+
+            match rx.await? {
+                Ok(blocks) => return Ok(blocks),
+                Err(err) => {
+                    if tokio::time::timeout_at(deadline, available).await.is_err() {
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Match `match_hashes` like [AvailableBlocks::match_blocks] and, in the same engine turn,
+    /// lower the priority of the cached `demote_tail` blocks to `new_priority`
+    ///
+    /// Meant for a sequence shorter than the cached prefix: the cached tail beyond it is re-keyed
+    /// to be evicted earlier before any other request can observe the pool. Blocks already at or
+    /// below `new_priority` and blocks not in the pool are left alone.
+    pub async fn acquire_and_demote(
+        &self,
+        match_hashes: Vec<SequenceHash>,
+        demote_tail: Vec<SequenceHash>,
+        new_priority: impl Into<BlockPriority>,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_match(&match_hashes)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::AcquireAndDemote(AcquireAndDemote {
+                hashes: match_hashes,
+                demote: demote_tail,
+                priority: new_priority.into(),
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send acquire and demote request; channel closed");
+        }
+
+        let matched_blocks = rx.await??;
+        Ok(matched_blocks)
+    }
+
+    /// Acquire one block for each of `hashes` in a single engine turn: blocks are matched for the
+    /// longest cached prefix and taken, evicting if needed, for the rest
+    pub async fn allocate(&self, hashes: Vec<SequenceHash>) -> Result<Allocation> {
+        self.allocate_with(hashes, 0, None, None, None).await
+    }
+
+    /// Allocate blocks for `hashes` like [AvailableBlocks::allocate], along with `extra` blocks
+    /// for the tokens of the request past its known hashes, all in the same engine turn
+    ///
+    /// The extra blocks are appended to [Allocation::taken] after the blocks taken for the
+    /// hashes; fewer blocks are taken if the pool runs out.
+    pub async fn allocate_extra(
+        &self,
+        hashes: Vec<SequenceHash>,
+        extra: u32,
+    ) -> Result<Allocation> {
+        self.allocate_with(hashes, extra, None, None, None).await
+    }
+
+    /// Allocate blocks for `hashes` like [AvailableBlocks::allocate], matching at most
+    /// [MatchOptions::max_blocks] blocks
+    ///
+    /// A match cut short by the limit takes nothing, since the hashes past it may still be
+    /// cached; the allocation is truncated and the caller resumes from
+    /// [Allocation::resume_from] on a later call, holding the blocks matched so far. A call
+    /// which misses before the limit takes blocks for every hash left, as a plain allocation.
+    /// Only the limit of the options applies; allocations do not consider soft holds.
+    pub async fn allocate_with_options(
+        &self,
+        hashes: Vec<SequenceHash>,
+        options: MatchOptions,
+    ) -> Result<Allocation> {
+        self.allocate_with(hashes, 0, None, options.max_blocks, None)
+            .await
+    }
+
+    /// Allocate blocks for a request of `request_tokens` prompt tokens like
+    /// [AvailableBlocks::allocate]
+    ///
+    /// The request tokens not covered by the matched blocks are accounted as recomputed in the
+    /// [TokenStats] of the pool.
+    pub async fn allocate_request(
+        &self,
+        hashes: Vec<SequenceHash>,
+        request_tokens: u64,
+    ) -> Result<Allocation> {
+        self.allocate_with(hashes, 0, Some(request_tokens), None, None)
+            .await
+    }
+
+    async fn allocate_with(
+        &self,
+        hashes: Vec<SequenceHash>,
+        extra: u32,
+        request_tokens: Option<u64>,
+        max_blocks: Option<usize>,
+        context: Option<OpContext>,
+    ) -> Result<Allocation> {
+        validate_match(&hashes)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match_in(
+                MatchRequest::Allocate(Allocate {
+                    hashes,
+                    extra,
+                    request_tokens,
+                    max_blocks,
+                    tx,
+                }),
+                context,
+            )
+            .is_err()
+        {
+            raise!("failed to send allocate request; channel closed");
+        }
+
+        let allocation = rx.await??;
+        self.verify_allocation(allocation).await
+    }
+
+    // Truncate an allocation at the first matched block failing the checksum verifier; the
+    // blocks taken for the hashes past it are dropped along with the rest of the match
+    async fn verify_allocation(&self, mut allocation: Allocation) -> Result<Allocation> {
+        let Some(verify) = self.config.checksum_verifier else {
+            return Ok(allocation);
+        };
+
+        let corrupt = allocation
+            .matched
+            .iter()
+            .position(|block| block.checksum().is_some() && !verify(block));
+        if let Some(index) = corrupt {
+            let hash = allocation.matched[index].lookup_key();
+            // reported before the block is dropped, so it is quarantined on return
+            self.report_corrupt(hash).await?;
+            allocation.matched.truncate(index);
+            allocation.taken.clear();
+            allocation.truncated = true;
+            allocation.resume_from = Some(hash);
+        }
+        Ok(allocation)
+    }
+
+    /// Allocate a batch of requests in a single engine turn
+    ///
+    /// Each request served gets an [Allocation] as from [AvailableBlocks::allocate], except that a
+    /// request is only served if all of its blocks can be acquired; a request is never handed a
+    /// partial allocation. Requests are planned in order: a block matched by an earlier request
+    /// is not matched again, and no take evicts a block matched by the batch. The batch is
+    /// planned before anything is acquired, so requests which are not served leave the pool
+    /// untouched.
+    pub async fn allocate_batch(
+        &self,
+        requests: Vec<AllocateSpec>,
+        mode: BatchMode,
+    ) -> Result<Vec<Option<Allocation>>> {
+        for request in &requests {
+            validate_match(&request.hashes)?;
+        }
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::AllocateBatch(AllocateBatch {
+                requests,
+                mode,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send allocate request; channel closed");
+        }
+
+        let allocations = rx.await??;
+        Ok(allocations)
+    }
+
+    /// Take up to `count` blocks like [AvailableBlocks::take_blocks], reporting the cached blocks
+    /// evicted to serve the take
+    ///
+    /// Lets the caller keep an external index of cached prefixes in sync with the pool.
+    pub async fn take_blocks_reporting_evictions(&self, count: u32) -> Result<TakeOutcome> {
+        validate_take(count)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::TakeReportingEvictions(
+                TakeReportingEvictions { count, tx },
+            ))
+            .is_err()
+        {
+            raise!("failed to send take request; channel closed");
+        }
+
+        let outcome = rx.await??;
+        Ok(outcome)
+    }
+
+    /// Take up to `count` blocks like [AvailableBlocks::take_blocks] on behalf of `caller`
+    ///
+    /// The blocks count against the caller's quota until they are returned; see
+    /// [AvailableBlocksConfig::with_per_caller_quota].
+    pub async fn take_blocks_for(
+        &self,
+        caller: CallerId,
+        count: u32,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_take(count)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::TakeFor(TakeFor { caller, count, tx }))
+            .is_err()
+        {
+            raise!("failed to send take request; channel closed");
+        }
+
+        let taken_blocks = rx.await??;
+        Ok(taken_blocks)
+    }
+
+    /// Take up to `count` blocks like [AvailableBlocks::take_blocks], grouped by the priority
+    /// they were taken at
+    ///
+    /// Uninitialized blocks are grouped under `None`, apart from every priority, and come first.
+    pub async fn take_grouped(
+        &self,
+        count: u32,
+    ) -> Result<BTreeMap<Option<BlockPriority>, Vec<PoolItem<KvBlock>>>> {
+        validate_take(count)?;
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::TakeGrouped(TakeGrouped { count, tx }))
+            .is_err()
+        {
+            raise!("failed to send take request; channel closed");
+        }
+
+        let grouped_blocks = rx.await??;
+        Ok(grouped_blocks)
+    }
+
+    /// Take a single reusable block at exactly `priority`, the oldest returned first
+    ///
+    /// Unlike [AvailableBlocks::take_blocks], uninitialized blocks and blocks at other priorities
+    /// are never touched; `None` is returned if there is no reusable block at this priority.
+    pub async fn take_one_at_priority(
+        &self,
+        priority: impl Into<BlockPriority>,
+    ) -> Result<Option<PoolItem<KvBlock>>> {
+        self.wait_until_ready().await?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_match(MatchRequest::TakeAtPriority(TakeAtPriority {
+                priority: priority.into(),
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send take request; channel closed");
+        }
+
+        let taken_block = rx.await??;
+        Ok(taken_block)
+    }
+
+    fn validate_insert(&self, block: &KvBlock) -> Result<()> {
+        let sequence_hash = block.token_block.sequence_hash();
+        if self.config.reject_zero_hash && sequence_hash == 0 {
+            raise!(KvPoolError::ZeroHash);
+        }
+        if sequence_hash != 0 && block.token_block.tokens().is_empty() {
+            return invalid_request(format!(
+                "block {sequence_hash} has a sequence hash but no tokens"
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn insert(&self, block: KvBlock) -> Result<()> {
+        self.insert_tagged(block, None).await
+    }
+
+    /// Same as [AvailableBlocks::insert], tagged with `op_id` for [AvailableBlocks::fence_ops]
+    pub async fn insert_tagged(&self, block: KvBlock, op_id: Option<u64>) -> Result<()> {
+        self.validate_insert(&block)?;
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Insert(InsertControl { block, op_id, tx }))
+            .is_err()
+        {
+            raise!("failed to send insert request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Insert a batch of blocks in a single engine turn
+    ///
+    /// The batch is validated as a whole; if any block is rejected, none are inserted.
+    pub async fn insert_many(&self, blocks: Vec<KvBlock>) -> Result<()> {
+        self.insert_many_tagged(blocks, None).await
+    }
+
+    /// Same as [AvailableBlocks::insert_many], tagged with `op_id` for [AvailableBlocks::fence_ops]
+    pub async fn insert_many_tagged(&self, blocks: Vec<KvBlock>, op_id: Option<u64>) -> Result<()> {
+        for block in &blocks {
+            self.validate_insert(block)?;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::InsertMultiple(InsertMultipleControl {
+                blocks,
+                op_id,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send insert multiple request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Insert `blocks` as the operation `op_id`, at most once
+    ///
+    /// Meant for control planes with at-least-once delivery, such as replayed onboarding
+    /// descriptors: a redelivery of an operation still in the dedupe window is acknowledged as
+    /// [InsertOutcome::Duplicate] without inserting, so it adds no phantom capacity.
+    pub async fn insert_many_once(
+        &self,
+        op_id: u128,
+        blocks: Vec<KvBlock>,
+    ) -> Result<InsertOutcome> {
+        for block in &blocks {
+            self.validate_insert(block)?;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::InsertOnce(InsertOnceControl {
+                op_id,
+                blocks,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send insert once request; channel closed");
+        }
+        let outcome = rx.await?;
+        Ok(outcome)
+    }
+
+    /// Insert every block of `stream`, returning the number of blocks inserted
+    ///
+    /// Blocks are sent in batches of [INSERT_STREAM_BATCH_SIZE] and the next batch is only sent
+    /// once the previous one has been acknowledged, so at most two batches are held at a time and
+    /// a slow engine slows down the consumption of the stream. On error, the batches sent so far
+    /// stay inserted.
+    pub async fn insert_stream(&self, stream: impl Stream<Item = KvBlock>) -> Result<usize> {
+        let mut batches = std::pin::pin!(stream.chunks(INSERT_STREAM_BATCH_SIZE));
+        let mut in_flight: Option<oneshot::Receiver<()>> = None;
+        let mut inserted = 0;
+
+        while let Some(blocks) = batches.next().await {
+            for block in &blocks {
+                self.validate_insert(block)?;
+            }
+
+            if let Some(rx) = in_flight.take() {
+                rx.await?;
+            }
+
+            let count = blocks.len();
+            let (tx, rx) = oneshot::channel();
+            if self
+                .send_control(ControlRequest::InsertMultiple(InsertMultipleControl {
+                    blocks,
+                    op_id: None,
+                    tx,
+                }))
+                .is_err()
+            {
+                raise!("failed to send insert multiple request; channel closed");
+            }
+            in_flight = Some(rx);
+            inserted += count;
+        }
+
+        if let Some(rx) = in_flight {
+            rx.await?;
+        }
+        Ok(inserted)
+    }
+
+    pub async fn update_single(&self, update: UpdateBlock) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::UpdateSingle(UpdateSingleControl {
+                update,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send update single request; channel closed");
+        }
+        rx.await??;
+        Ok(())
+    }
+
+    pub async fn update_multiple(&self, updates: Vec<UpdateBlock>) -> Result<()> {
+        self.update_multiple_tagged(updates, None).await
+    }
+
+    /// Same as [AvailableBlocks::update_multiple], tagged with `op_id` for
+    /// [AvailableBlocks::fence_ops]
+    pub async fn update_multiple_tagged(
+        &self,
+        updates: Vec<UpdateBlock>,
+        op_id: Option<u64>,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::UpdateMultiple(UpdateMultipleControl {
+                updates,
+                op_id,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send update multiple request; channel closed");
+        }
+        rx.await??;
+        Ok(())
+    }
+
+    /// Set the priority of the block cached under `hash` to `new` only if it is currently
+    /// `expected`; returns whether the priority was swapped
+    ///
+    /// The comparison and the update happen in one engine turn, so an external scheduler can
+    /// manage priorities optimistically without racing other updates. The new priority is still
+    /// subject to the priority chain policy. A hash not in the pool is never swapped.
+    pub async fn update_priority_if(
+        &self,
+        hash: SequenceHash,
+        expected: impl Into<BlockPriority>,
+        new: impl Into<BlockPriority>,
+    ) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::UpdatePriorityIf(UpdatePriorityIfControl {
+                hash,
+                expected: expected.into(),
+                new: new.into(),
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send update priority if request; channel closed");
+        }
+        let swapped = rx.await??;
+        Ok(swapped)
+    }
+
+    /// Set the priorities of many cached blocks at once from externally computed scores
+    ///
+    /// Applied in one engine turn, or in chunks with [AvailableBlocksConfig::with_handler_chunk_size]
+    /// or a turn watchdog; when the scores touch a large part of the pool the eviction order is
+    /// rebuilt once instead of re-keying every block. A chunk only rebuilds it when the pool is
+    /// small next to the chunk, so a chunked rebalance of a large pool re-keys block by block. A hash listed more than once gets
+    /// its last score. Scores are applied as given, without the priority chain policy; reserved
+    /// bands are trimmed back to their caps afterwards.
+    pub async fn rebalance(
+        &self,
+        scores: Vec<(SequenceHash, BlockPriority)>,
+    ) -> Result<RebalanceReport> {
+        self.rebalance_tagged(scores, None).await
+    }
+
+    /// Same as [AvailableBlocks::rebalance], tagged with `op_id` for [AvailableBlocks::fence_ops]
+    pub async fn rebalance_tagged(
+        &self,
+        scores: Vec<(SequenceHash, BlockPriority)>,
+        op_id: Option<u64>,
+    ) -> Result<RebalanceReport> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Rebalance(RebalanceControl {
+                scores,
+                op_id,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send rebalance request; channel closed");
+        }
+        let report = rx.await?;
+        Ok(report)
+    }
+
+    /// Mark the listed cached blocks as just used, without taking them out of the pool
+    ///
+    /// Each block moves to the back of its priority tier, as if it had just been returned, so it
+    /// is evicted after its peers; priorities are left as they are. Touching is not a hit and
+    /// does not start a retention window. Returns the number of hashes which were cached; absent
+    /// and checked out blocks are skipped. Hashes are touched in order, so the last one listed
+    /// ends up the most recent.
+    pub async fn touch(&self, hashes: Vec<SequenceHash>) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Touch(TouchControl { hashes, tx }))
+            .is_err()
+        {
+            raise!("failed to send touch request; channel closed");
+        }
+        let touched = rx.await?;
+        Ok(touched)
+    }
+
+    pub async fn reset(&self, sequence_hashes: Vec<SequenceHash>) -> Result<()> {
+        self.reset_tagged(sequence_hashes, None).await
+    }
+
+    /// Same as [AvailableBlocks::reset], tagged with `op_id` for [AvailableBlocks::fence_ops]
+    pub async fn reset_tagged(
+        &self,
+        sequence_hashes: Vec<SequenceHash>,
+        op_id: Option<u64>,
+    ) -> Result<()> {
+        if sequence_hashes.is_empty() {
+            return invalid_request("reset of no hashes".to_string());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Reset(ResetControl {
+                sequence_hashes,
+                op_id,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send reset request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Reset the listed blocks and set their priority in the same engine turn
+    ///
+    /// Each block returns to the pool blank, carrying its new priority to the slot's next use.
+    /// Returns the number of hashes which were cached; absent hashes are skipped.
+    pub async fn reset_and_reprioritize(
+        &self,
+        entries: Vec<(SequenceHash, BlockPriority)>,
+    ) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ResetAndReprioritize(
+                ResetAndReprioritizeControl { entries, tx },
+            ))
+            .is_err()
+        {
+            raise!("failed to send reset request; channel closed");
+        }
+        let processed = rx.await?;
+        Ok(processed)
+    }
+
+    pub async fn reset_all(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ResetAll(ResetAllControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send reset all request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Drain the operations recorded since the previous export
+    ///
+    /// Returns an empty trace without [AvailableBlocksConfig::with_trace_recorder].
+    #[cfg(feature = "trace-record")]
+    pub async fn export_trace(&self) -> Result<RecordedTrace> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ExportTrace(ExportTraceControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send export trace request; channel closed");
+        }
+        let trace = rx.await?;
+        Ok(trace)
+    }
+
+    /// Describe the reusable blocks currently in the pool
+    pub async fn export_manifest(&self) -> Result<PoolManifest> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ExportManifest(ExportManifestControl {
+                reset: false,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send export manifest request; channel closed");
+        }
+        let manifest = rx.await?;
+        Ok(manifest)
+    }
+
+    /// [AvailableBlocks::export_manifest] followed by [AvailableBlocks::reset_all] in a single
+    /// engine turn, so no insert or return lands between the snapshot and the reset
+    pub async fn snapshot_and_reset_all(&self) -> Result<PoolManifest> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ExportManifest(ExportManifestControl {
+                reset: true,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send snapshot and reset request; channel closed");
+        }
+        let manifest = rx.await?;
+        Ok(manifest)
+    }
+
+    /// Check that the lookup map, the eviction order and the available count agree, in a
+    /// single engine turn; see [IntegrityReport]
+    ///
+    /// Unlike [AvailableBlocksConfig::with_periodic_integrity_check], nothing is repaired. Fence
+    /// the returns first for an exact available count. The report is also kept as
+    /// [PoolHealth::last_audit].
+    pub async fn verify_integrity(&self) -> Result<IntegrityReport> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::VerifyIntegrity(VerifyIntegrityControl {
+                repair: false,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send verify integrity request; channel closed");
+        }
+        let report = rx.await?;
+        Ok(report)
+    }
+
+    /// Write the engine state, captured in a single engine turn, to `writer` as pretty printed
+    /// JSON; see [StateDump]
+    ///
+    /// Meant for bug reports and support bundles; the dump lists every cached hash, so it is as
+    /// large as the pool.
+    pub async fn dump_state(&self, mut writer: impl std::io::Write) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::DumpState(DumpStateControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send dump state request; channel closed");
+        }
+        let dump = rx.await?;
+        serde_json::to_writer_pretty(&mut writer, &dump)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Returns true if every hash is present in the pool; vacuously true for an empty list.
+    ///
+    /// This is a read-only query, no blocks are removed from the pool.
+    pub async fn is_fully_cached(&self, hashes: Vec<SequenceHash>) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::IsFullyCached(IsFullyCachedControl {
+                hashes,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send is fully cached request; channel closed");
+        }
+        let fully_cached = rx.await?;
+        Ok(fully_cached)
+    }
+
+    /// Number of leading hashes which are cached in the pool
+    ///
+    /// Like [AvailableBlocks::is_fully_cached], this is a read-only query.
+    pub async fn cached_prefix_len(&self, hashes: Vec<SequenceHash>) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::CachedPrefixLen(CachedPrefixLenControl {
+                hashes,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send cached prefix request; channel closed");
+        }
+        let len = rx.await?;
+        Ok(len)
+    }
+
+    /// Whether a block is cached for each of `hashes`, read from the index of
+    /// [AvailableBlocksConfig::with_fast_probe] without a round trip to the engine
+    ///
+    /// The index is updated at the end of every engine turn, so it lags the engine by at most
+    /// one turn: a change handled before a [AvailableBlocks::fence] is visible once the fence
+    /// resolves. A block checked out by a match is not cached. For an exact answer use
+    /// [AvailableBlocks::is_fully_cached] or [AvailableBlocks::cached_prefix_len].
+    pub fn probe_fast(&self, hashes: &[SequenceHash]) -> Result<Vec<bool>> {
+        if !self.config.fast_probe {
+            raise!("fast probe is not enabled; see AvailableBlocksConfig::with_fast_probe");
+        }
+        let resident = Arc::clone(&self.resident_rx.borrow());
+        Ok(hashes.iter().map(|hash| resident.contains(hash)).collect())
+    }
+
+    /// Match `hashes`, and on a miss find the peer holding the hashes which follow the match in the
+    /// index of [AvailableBlocksConfig::with_shared_content_index]
+    ///
+    /// The caller arranges the transfer from the peer; the index may lag the peer by a turn, so
+    /// the peer can miss blocks it was reported to hold. Without an index this is a plain match.
+    pub async fn match_or_fetch(&self, hashes: Vec<SequenceHash>) -> Result<MatchOrFetch> {
+        let matched = self.match_blocks(hashes.clone()).await?;
+        let fetch_from = self
+            .config
+            .shared_content_index
+            .as_ref()
+            .and_then(|(index, pool)| index.peer_prefix(*pool, &hashes[matched.len()..]));
+        Ok(MatchOrFetch {
+            matched,
+            fetch_from,
+        })
+    }
+
+    /// Hand a reusable block to an offload worker
+    ///
+    /// The block leaves the eviction order and can not be matched until the worker calls
+    /// [AvailableBlocks::complete_offload], after which it returns to the pool blank. Returns
+    /// the descriptor of the block, or `None` if the hash is not cached. Without
+    /// [AvailableBlocksConfig::with_offload_janitor], a worker which never completes strands the
+    /// block until the pool is dropped.
+    pub async fn begin_offload(&self, hash: SequenceHash) -> Result<Option<BlockDescriptor>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::BeginOffload(BeginOffloadControl {
+                hash,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send begin offload request; channel closed");
+        }
+        let descriptor = rx.await?;
+        Ok(descriptor)
+    }
+
+    /// Complete the offload of a block, returning it to the pool blank
+    ///
+    /// Returns false if the block was not being offloaded, e.g. because the janitor already
+    /// abandoned the offload.
+    pub async fn complete_offload(&self, hash: SequenceHash) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::CompleteOffload(CompleteOffloadControl {
+                hash,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send complete offload request; channel closed");
+        }
+        let completed = rx.await?;
+        Ok(completed)
+    }
+
+    /// Subscribe to the [SequenceState] of a sequence hash
+    ///
+    /// The receiver starts at [SequenceState::Cached] if the hash is in the pool and is updated as
+    /// the hash is inserted, matched, returned, evicted or reset. A hash matched before the first
+    /// watcher was registered reports [SequenceState::Absent] until its block is returned.
+    pub async fn watch_sequence(
+        &self,
+        hash: SequenceHash,
+    ) -> Result<watch::Receiver<SequenceState>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::WatchSequence(WatchSequenceControl {
+                hash,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send watch sequence request; channel closed");
+        }
+        let watcher = rx.await?;
+        Ok(watcher)
+    }
+
+    /// Number and token storage of the uninitialized blocks; see [AvailableBlocksConfig::with_blank_storage_cap]
+    pub async fn blank_set_stats(&self) -> Result<BlankSetStats> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::BlankSetStats(BlankSetStatsControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send blank set stats request; channel closed");
+        }
+        let stats = rx.await?;
+        Ok(stats)
+    }
+
+    /// Views of up to `limit` uninitialized blocks, in the order they will be taken
+    ///
+    /// Blocks inserted with the hash of a block already cached are demoted to the uninitialized
+    /// set with their contents; a listing with many of them points at callers inserting
+    /// duplicates rather than at a lack of reusable blocks.
+    pub async fn list_uninitialized(&self, limit: usize) -> Result<Vec<KvBlockView>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ListUninitialized(
+                ListUninitializedControl { limit, tx },
+            ))
+            .is_err()
+        {
+            raise!("failed to send list uninitialized request; channel closed");
+        }
+        let views = rx.await?;
+        Ok(views)
+    }
+
+    /// Views of the blocks [AvailableBlocks::take_blocks] would hand out for `count`, in the
+    /// order it would take them, without taking them
+    ///
+    /// Lets a scheduler inspect the eviction set, for instance for high priority blocks, before
+    /// committing to the take. The preview follows the same selection as a take, including
+    /// reserved bands, shields and the caps on the count; a take issued right after it takes
+    /// the same blocks unless other requests land in between.
+    pub async fn preview_take(&self, count: u32) -> Result<Vec<KvBlockView>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::PreviewTake(PreviewTakeControl {
+                count,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send preview take request; channel closed");
+        }
+        let views = rx.await??;
+        Ok(views)
+    }
+
+    /// Return blocks to the pool with a new priority and deadline each, resolving once they are
+    /// back in the pool
+    ///
+    /// Both stamps travel with the block and are applied by the engine as it re-inserts it, so
+    /// no match or take can observe the block with only one of them applied.
+    pub async fn return_with_meta(&self, items: Vec<(PoolItem<KvBlock>, BlockMeta)>) -> Result<()> {
+        for (mut item, meta) in items {
+            if let Some(priority) = meta.priority {
+                item.set_priority(priority);
+            }
+            item.set_deadline(meta.deadline);
+        }
+        self.fence().await
+    }
+
+    /// View of the pool which stamps `options` onto every block it acquires or commits
+    pub fn session(&self, options: SessionOptions) -> PoolSession<'_> {
+        PoolSession {
+            pool: self,
+            options,
+        }
+    }
+
+    /// View of the pool which applies `extender` to the sequence hashes of inserts and matches
+    pub fn with_hash_extender<'a>(&'a self, extender: &'a dyn HashExtender) -> ExtendedPool<'a> {
+        ExtendedPool {
+            pool: self,
+            extender,
+        }
+    }
+
+    /// Reconcile the pool against an external source of truth, such as the engine's device blocks
+    ///
+    /// `verdict` is applied to every reusable and uninitialized block, chunked like other long
+    /// handlers when [AvailableBlocksConfig::with_handler_chunk_size] is set. Blocks checked out
+    /// when the reconcile starts are counted as in flight and get their verdict when returned;
+    /// if several reconciles overlap, the latest verdict applies.
+    pub async fn reconcile(
+        &self,
+        verdict: impl Fn(&KvBlock) -> BlockFate + Send + Sync + 'static,
+    ) -> Result<ReconcileReport> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Reconcile(ReconcileControl {
+                verdict: Arc::new(verdict),
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send reconcile request; channel closed");
+        }
+        let report = rx.await?;
+        Ok(report)
+    }
+
+    /// Current [PoolStats], as seen by the engine
+    pub async fn stats(&self) -> Result<PoolStats> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Stats(StatsControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send stats request; channel closed");
+        }
+        let stats = rx.await?;
+        Ok(stats)
+    }
+
+    /// Declare that the sequence hashes are expected to be matched within `ttl`
+    ///
+    /// The resident blocks with these hashes are shielded from eviction until they are matched
+    /// or the ttl expires; returns the number of blocks shielded. Shielded blocks are evicted last:
+    /// a take which finds only shielded blocks still evicts one, counted as a shield breach.
+    pub async fn expect(&self, hashes: Vec<SequenceHash>, ttl: Duration) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Expect(ExpectControl { hashes, ttl, tx }))
+            .is_err()
+        {
+            raise!("failed to send expect request; channel closed");
+        }
+        let shielded = rx.await?;
+        Ok(shielded)
+    }
+
+    /// Soft hold the cached prefix of `hashes` for `holder` until `ttl` passes
+    ///
+    /// Held blocks are shielded from eviction as by [AvailableBlocks::expect]; in addition, a
+    /// match of another holder through [AvailableBlocks::match_blocks_with_options] resolves the
+    /// conflict by its [OnSoftHold]. The held prefix ends at the first block not cached or held by
+    /// another holder; returns its length. A match of the holder claims its holds.
+    pub async fn peek_hold(
+        &self,
+        holder: u64,
+        hashes: Vec<SequenceHash>,
+        ttl: Duration,
+    ) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::PeekHold(PeekHoldControl {
+                holder,
+                hashes,
+                ttl,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send peek hold request; channel closed");
+        }
+        let held = rx.await?;
+        Ok(held)
+    }
+
+    /// Release every soft hold of `holder`; returns the number of blocks released
+    ///
+    /// A block also shielded by [AvailableBlocks::expect] stays shielded until that shield
+    /// expires.
+    pub async fn release_holds(&self, holder: u64) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ReleaseHolds(ReleaseHoldsControl {
+                holder,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send release holds request; channel closed");
+        }
+        let released = rx.await?;
+        Ok(released)
+    }
+
+    /// Match the longest cached prefix of `hashes` like [AvailableBlocks::match_blocks], resolving
+    /// blocks soft held by another holder by [MatchOptions::on_soft_hold]
+    ///
+    /// Shields from [AvailableBlocks::expect] and session deadlines have no holder and never
+    /// conflict. Under [OnSoftHold::Wait] nothing is matched until the hold expires, is claimed
+    /// or released, or the wait runs out; the match is then retried.
+    pub async fn match_blocks_with_options(
+        &self,
+        hashes: Vec<SequenceHash>,
+        options: MatchOptions,
+    ) -> Result<Vec<PoolItem<KvBlock>>> {
+        validate_match(&hashes)?;
+        self.wait_until_ready().await?;
+
+        let deadline = match options.on_soft_hold {
+            OnSoftHold::Wait(wait) => Some(Instant::now() + wait),
+            OnSoftHold::Steal | OnSoftHold::Miss => None,
+        };
+        let mut waited = false;
+        loop {
+            // registered before the attempt so a hold released during it is not missed
+            let released = self.holds.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let final_attempt = deadline.is_none_or(|deadline| Instant::now() >= deadline);
+            let (tx, rx) = oneshot::channel();
+            if self
+                .send_match(MatchRequest::MatchWithOptions(MatchWithOptions {
+                    hashes: hashes.clone(),
+                    options,
+                    waited,
+                    final_attempt,
+                    tx,
+                }))
+                .is_err()
+            {
+                raise!("failed to send match request; channel closed");
+            }
+
+            match rx.await?? {
+                HeldMatch::Matched(blocks) => return self.verify_checksums(blocks).await,
+                HeldMatch::Held(expiry) => {
+                    let wake = deadline.map_or(expiry, |deadline| deadline.min(expiry));
+                    let _ = tokio::time::timeout_at(wake, released).await;
+                    waited = true;
+                }
+            }
+        }
+    }
+
+    /// Match the cached prefix of `hashes` like [AvailableBlocks::match_blocks_with_options],
+    /// reporting whether the match stopped at [MatchOptions::max_blocks]
+    ///
+    /// A truncated match is continued by calling again with the hashes from
+    /// [PrefixMatch::resume_from] on, while holding the blocks matched so far; the prefix still
+    /// ends at its first miss.
+    pub async fn match_prefix(
+        &self,
+        hashes: Vec<SequenceHash>,
+        options: MatchOptions,
+    ) -> Result<PrefixMatch> {
+        let resume_from = options
+            .max_blocks
+            .and_then(|max_blocks| hashes.get(max_blocks).copied());
+        let blocks = self.match_blocks_with_options(hashes, options).await?;
+
+        let resume_from = resume_from.filter(|_| Some(blocks.len()) == options.max_blocks);
+        Ok(PrefixMatch {
+            blocks,
+            truncated: resume_from.is_some(),
+            resume_from,
+        })
+    }
+
+    /// Keep the blocks of a live session cached in preference to other blocks of their priority
+    ///
+    /// Until `ttl` passes or [AvailableBlocks::unstick] is called, the blocks with these hashes
+    /// are evicted after every non-sticky block of the same priority; priorities still take
+    /// precedence. Blocks which are checked out become sticky when they are returned. Sticking a
+    /// session again replaces its hashes and ttl. Returns the number of hashes registered, at
+    /// most the cap set by [AvailableBlocksConfig::with_sticky_cap].
+    pub async fn stick(
+        &self,
+        hashes: Vec<SequenceHash>,
+        session_id: u64,
+        ttl: Duration,
+    ) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Stick(StickControl {
+                hashes,
+                session_id,
+                ttl,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send stick request; channel closed");
+        }
+        let stuck = rx.await?;
+        Ok(stuck)
+    }
+
+    /// Quarantine the block cached under `hash` after its device data failed verification
+    ///
+    /// A block in the pool is reset to blank right away; a block checked out is reset when it
+    /// is returned, so the hash is never matched again until a new block is inserted for it.
+    /// A hash with no block cached or checked out, and the blank hash 0, are ignored.
+    pub async fn report_corrupt(&self, hash: SequenceHash) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::ReportCorrupt(ReportCorruptControl {
+                hash,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send report corrupt request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// End a sticky session before its ttl; returns the number of hashes it held
+    pub async fn unstick(&self, session_id: u64) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Unstick(UnstickControl { session_id, tx }))
+            .is_err()
+        {
+            raise!("failed to send unstick request; channel closed");
+        }
+        let released = rx.await?;
+        Ok(released)
+    }
+
+    /// Returns the [CounterSnapshot] accumulated since the previous call and resets the counters
+    ///
+    /// Counters are reset inside the engine, so consecutive drains report disjoint deltas.
+    pub async fn drain_counters(&self) -> Result<CounterSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::DrainCounters(DrainCountersControl { tx }))
+            .is_err()
+        {
+            raise!("failed to send drain counters request; channel closed");
+        }
+        let counters = rx.await?;
+        Ok(counters)
+    }
+
+    /// The `k` most missed sequence hashes with their approximate miss counts, most missed first
+    ///
+    /// A match which stops at a hash counts one miss for that hash. Empty unless
+    /// [AvailableBlocksConfig::with_miss_tracking] is set.
+    pub async fn top_misses(&self, k: usize) -> Result<Vec<(SequenceHash, u32)>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::TopMisses(TopMissesControl { k, tx }))
+            .is_err()
+        {
+            raise!("failed to send top misses request; channel closed");
+        }
+        let misses = rx.await?;
+        Ok(misses)
+    }
+
+    /// Histogram of the ages of blocks evicted by `take`, oldest buckets last.
+    ///
+    /// The age of a block is the number of return ticks since it was inserted or returned.
+    /// A pool which evicts mostly young blocks is likely undersized.
+    pub async fn eviction_age_histogram(&self) -> Result<Vec<(AgeBucket, u64)>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::EvictionAgeHistogram(
+                EvictionAgeHistogramControl { class: None, tx },
+            ))
+            .is_err()
+        {
+            raise!("failed to send eviction age histogram request; channel closed");
+        }
+        let histogram = rx.await?;
+        Ok(histogram)
+    }
+
+    /// Histogram of the ages of the blocks of retention `class` evicted by `take`, as by
+    /// [AvailableBlocks::eviction_age_histogram]; see [BlockPriority]
+    ///
+    /// A class whose blocks are evicted young is short of room, e.g. of a reservation by
+    /// [AvailableBlocksConfig::with_reserved_class].
+    pub async fn eviction_age_histogram_of_class(
+        &self,
+        class: u8,
+    ) -> Result<Vec<(AgeBucket, u64)>> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::EvictionAgeHistogram(
+                EvictionAgeHistogramControl {
+                    class: Some(class),
+                    tx,
+                },
+            ))
+            .is_err()
+        {
+            raise!("failed to send eviction age histogram request; channel closed");
+        }
+        let histogram = rx.await?;
+        Ok(histogram)
+    }
+
+    /// Shrink the pool to at most `target_total` blocks
+    ///
+    /// Idle blocks are removed first, uninitialized before reusable ones in eviction order.
+    /// If that is not enough, checked out slots are retired: they are removed from the totals
+    /// immediately and the pool generation is bumped, so the first returns from the previous
+    /// generation are dropped rather than re-admitted. Any further stale returns are handled
+    /// according to the [StaleReturnPolicy].
+    pub async fn shrink(&self, target_total: u64) -> Result<ShrinkReport> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::Shrink(ShrinkControl { target_total, tx }))
+            .is_err()
+        {
+            raise!("failed to send shrink request; channel closed");
+        }
+        let report = rx.await?;
+        Ok(report)
+    }
+
+    /// Evict whole cold chains until at least `target_blocks` cached blocks were evicted
+    ///
+    /// A chain is a cached block whose parent is not cached together with every cached
+    /// descendant. Chains are evicted coldest first, a chain being as hot as its hottest member in
+    /// eviction order, and each one leaves the pool at once, leaves first, so no child is left
+    /// cached without its parent. Chains with a shielded member or a member in a protected
+    /// reserved band are skipped. The last chain may take the report past the target; slots
+    /// stay in the pool as uninitialized blocks.
+    pub async fn evict_chains(&self, target_blocks: u64) -> Result<EvictionReport> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .send_control(ControlRequest::EvictChains(EvictChainsControl {
+                target_blocks,
+                tx,
+            }))
+            .is_err()
+        {
+            raise!("failed to send evict chains request; channel closed");
+        }
+        let report = rx.await?;
+        Ok(report)
+    }
+
+    pub async fn fence(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self.fence_tx.send(tx).is_err() {
+            raise!("failed to send fence request; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Resolve once every block dropped before the call has been handled by the engine
+    ///
+    /// Unlike [AvailableBlocks::fence], the fence travels on the return channel, so it settles
+    /// [AvailableBlocks::available_blocks] after drops without waiting behind queued match and
+    /// control requests. Blocks spilled to the overflow buffer are absorbed before the fence is
+    /// acknowledged.
+    pub async fn fence_returns(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let sent = match &self.return_tx {
+            ReturnSender::Unbounded(return_tx) => return_tx.send(ReturnMessage::Fence(tx)).is_ok(),
+            ReturnSender::Bounded(return_tx) => {
+                return_tx.send(ReturnMessage::Fence(tx)).await.is_ok()
+            }
+        };
+        if !sent {
+            raise!("failed to send return fence; channel closed");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Hold blocks until they are returned together, on [ReturnBatchScope::flush] or when the
+    /// scope is dropped
+    pub fn return_batch_scope(&self) -> ReturnBatchScope<'_> {
+        ReturnBatchScope {
+            pool: self,
+            blocks: Vec::new(),
+        }
+    }
+}
+
+/// Blocks held to be returned to an [AvailableBlocks] pool in a single batch; see
+/// [AvailableBlocks::return_batch_scope]
+///
+/// Unlike dropping the blocks one by one, the held blocks reach the engine as one message which
+/// it absorbs in a single turn. [ReturnBatchScope::flush] waits until they are absorbed; dropping
+/// the scope sends the batch without waiting. Blocks of another pool are returned to it one by
+/// one. A batch which would take the return queue past the hard limit of
+/// [AvailableBlocksConfig::with_return_queue_limits] is spilled like single returns are.
+pub struct ReturnBatchScope<'a> {
+    pool: &'a AvailableBlocks,
+    blocks: Vec<PoolItem<KvBlock>>,
+}
+
+impl ReturnBatchScope<'_> {
+    pub fn push(&mut self, block: PoolItem<KvBlock>) {
+        self.blocks.push(block);
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Return the held blocks and wait until the engine has absorbed them
+    pub async fn flush(&mut self) -> Result<()> {
+        let batch = self.collect();
+        if !batch.is_empty() {
+            let sent = match &self.pool.return_tx {
+                ReturnSender::Unbounded(tx) => tx.send(ReturnMessage::Batch(batch)).is_ok(),
+                ReturnSender::Bounded(tx) => tx.send(ReturnMessage::Batch(batch)).await.is_ok(),
+            };
+            if !sent {
+                raise!("failed to send return batch; channel closed");
+            }
+        }
+        self.pool.fence_returns().await
+    }
+
+    // Drop the held blocks, collecting the returns to this pool into a batch; a batch past the
+    // hard limit of the return queue is spilled instead, and the returned batch is empty
+    fn collect(&mut self) -> Vec<ReturnedBlock> {
+        let overflow = &self.pool.overflow;
+        let batch = ReturnBatch::collect_drops(overflow, std::mem::take(&mut self.blocks));
+        if batch.is_empty() {
+            return batch;
+        }
+
+        let depth = overflow
+            .depth
+            .fetch_add(batch.len() as u64, Ordering::SeqCst)
+            + batch.len() as u64;
+        if let Some(limits) = overflow.limits {
+            if depth > limits.soft {
+                overflow.warn_depth(depth);
+            }
+            if depth > limits.hard {
+                for block in batch {
+                    overflow.push(block);
+                }
+                return Vec::new();
+            }
+        }
+        batch
+    }
+}
+
+impl Extend<PoolItem<KvBlock>> for ReturnBatchScope<'_> {
+    fn extend<I: IntoIterator<Item = PoolItem<KvBlock>>>(&mut self, blocks: I) {
+        self.blocks.extend(blocks);
+    }
+}
+
+impl Drop for ReturnBatchScope<'_> {
+    fn drop(&mut self) {
+        let batch = self.collect();
+        if batch.is_empty() {
+            return;
+        }
+        match &self.pool.return_tx {
+            ReturnSender::Unbounded(tx) => {
+                if tx.send(ReturnMessage::Batch(batch)).is_err() {
+                    log::trace!("Failed to return block batch to pool");
+                }
+            }
+            ReturnSender::Bounded(tx) => match tx.try_send(ReturnMessage::Batch(batch)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(ReturnMessage::Batch(batch))) => {
+                    log::trace!("return channel full; parking block batch in overflow buffer");
+                    for block in batch {
+                        self.pool.overflow.push(block);
+                    }
+                }
+                Err(TrySendError::Full(_) | TrySendError::Closed(_)) => {
+                    log::trace!("Failed to return block batch to pool");
+                }
+            },
+        }
+    }
+}
+
+thread_local! {
+    // Set while a ReturnBatchScope drops its blocks on this thread
+    static RETURN_BATCH: RefCell<Option<ReturnBatch>> = const { RefCell::new(None) };
+}
+
+// Returns to the pool of a ReturnBatchScope collected while it drops its blocks
+struct ReturnBatch {
+    overflow: Arc<ReturnOverflow>,
+    blocks: Vec<ReturnedBlock>,
+}
+
+impl ReturnBatch {
+    // Drop `blocks`, collecting the returns to the pool which owns `overflow` instead of sending them
+    fn collect_drops(
+        overflow: &Arc<ReturnOverflow>,
+        blocks: Vec<PoolItem<KvBlock>>,
+    ) -> Vec<ReturnedBlock> {
+        let batch = ReturnBatch {
+            overflow: overflow.clone(),
+            blocks: Vec::with_capacity(blocks.len()),
+        };
+        let outer = RETURN_BATCH.replace(Some(batch));
+        drop(blocks);
+        let batch = RETURN_BATCH.replace(outer);
+        batch.map(|batch| batch.blocks).unwrap_or_default()
+    }
+
+    // Add a return to the batch being collected for its pool; hand it back otherwise
+    fn join(overflow: &Arc<ReturnOverflow>, block: ReturnedBlock) -> Option<ReturnedBlock> {
+        RETURN_BATCH.with_borrow_mut(|batch| match batch {
+            Some(batch) if Arc::ptr_eq(&batch.overflow, overflow) => {
+                batch.blocks.push(block);
+                None
+            }
+            _ => Some(block),
+        })
+    }
+}
+
+/// Weak reference from a block to the control channel of its pool; see [KvBlock::pool_ops]
+///
+/// Blocks are held by the engine, so a strong sender would keep the channel open after the last
+/// handle is dropped.
+#[derive(Clone)]
+pub(crate) struct PoolRef(mpsc::WeakUnboundedSender<Sequenced<ControlRequest>>);
+
+/// Operations on the pool of a block, for code holding the block but not the pool; see
+/// [KvBlock::pool_ops]
+///
+/// The operations are not ordered with the requests of a handle under
+/// [AvailableBlocksConfig::with_strict_sequencing], and fail once the pool has shut down.
+#[derive(Clone)]
+pub struct BlockOps {
+    pool: PoolRef,
+    hash: SequenceHash,
+}
+
+impl BlockOps {
+    pub(crate) fn new(pool: PoolRef, hash: SequenceHash) -> Self {
+        Self { pool, hash }
+    }
+
+    /// Key of the block the operations apply to
+    pub fn hash(&self) -> SequenceHash {
+        self.hash
+    }
+
+    /// Quarantine the block; see [AvailableBlocks::report_corrupt]
+    pub async fn report_corrupt(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let request = ControlRequest::ReportCorrupt(ReportCorruptControl {
+            hash: self.hash,
+            tx,
+        });
+        if !self.send(request) {
+            raise!("failed to send report corrupt request; pool shut down");
+        }
+        rx.await?;
+        Ok(())
+    }
+
+    /// Set the priority of the block; a `u32` is converted by the [BlockPriority] shim
+    ///
+    /// A cached block is re-keyed right away, like [AvailableBlocks::update_single]. A block
+    /// checked out takes the priority when it is returned, in place of the priority it is
+    /// returned with, unless the pool is reset first. A block neither cached nor checked out is
+    /// left alone, and a blank block fails with [KvPoolError::ZeroHash].
+    pub async fn set_priority(&self, priority: impl Into<BlockPriority>) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let request = ControlRequest::SetBlockPriority(SetBlockPriorityControl {
+            hash: self.hash,
+            priority: priority.into(),
+            tx,
+        });
+        if !self.send(request) {
+            raise!("failed to send set priority request; pool shut down");
+        }
+        rx.await??;
+        Ok(())
+    }
+
+    fn send(&self, request: ControlRequest) -> bool {
+        let Some(control_tx) = self.pool.0.upgrade() else {
+            return false;
+        };
+        control_tx
+            .send(Sequenced {
+                seq: None,
+                context: None,
+                request,
+            })
+            .is_ok()
+    }
+}
+
+/// Return handle for the checkouts of a single pool generation
+struct ReturnHandleImpl {
+    return_tx: ReturnSender,
+    overflow: Arc<ReturnOverflow>,
+    generation: u64,
+
+    // bumped by each reconcile, so returns of earlier checkouts get the deferred verdict
+    reconcile_epoch: u64,
+
+    // set on the handles of blocks taken on behalf of a caller, to release its quota on return
+    caller: Option<CallerId>,
+}
+
+impl ReturnHandleImpl {
+    fn with_generation(&self, generation: u64) -> Self {
+        Self {
+            return_tx: self.return_tx.clone(),
+            overflow: self.overflow.clone(),
+            generation,
+            reconcile_epoch: self.reconcile_epoch,
+            caller: self.caller,
+        }
+    }
+
+    fn with_reconcile_epoch(&self, reconcile_epoch: u64) -> Self {
+        Self {
+            return_tx: self.return_tx.clone(),
+            overflow: self.overflow.clone(),
+            generation: self.generation,
+            reconcile_epoch,
+            caller: self.caller,
+        }
+    }
+
+    fn with_caller(&self, caller: CallerId) -> Self {
+        Self {
+            return_tx: self.return_tx.clone(),
+            overflow: self.overflow.clone(),
+            generation: self.generation,
+            reconcile_epoch: self.reconcile_epoch,
+            caller: Some(caller),
+        }
+    }
+}
+
+impl ReturnHandle<KvBlock> for ReturnHandleImpl {
+    fn return_to_pool(&self, block: PoolValue<KvBlock>) {
+        let value = ReturnedBlock {
+            generation: self.generation,
+            reconcile_epoch: self.reconcile_epoch,
+            caller: self.caller,
+            block,
+        };
+
+        // a block dropped by a ReturnBatchScope of this pool travels with the batch
+        let Some(value) = ReturnBatch::join(&self.overflow, value) else {
+            return;
+        };
+
+        let depth = self.overflow.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(limits) = self.overflow.limits {
+            if depth > limits.soft {
+                self.overflow.warn_depth(depth);
+            }
+            if depth > limits.hard {
+                // the engine is falling behind; keep the channel from growing and let the
+                // engine absorb the spill buffer in one go
+                self.overflow.push(value);
+                return;
+            }
+        }
+
+        match &self.return_tx {
+            ReturnSender::Unbounded(tx) => {
+                if tx.send(ReturnMessage::Block(value)).is_err() {
+                    log::trace!("Failed to return block to pool");
+                }
+            }
+            ReturnSender::Bounded(tx) => match tx.try_send(ReturnMessage::Block(value)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(ReturnMessage::Block(value))) => {
+                    // drop is not async, so we cannot wait for capacity; park the block in the
+                    // overflow buffer and wake the progress engine to drain it
+                    log::trace!("return channel full; parking block in overflow buffer");
+                    self.overflow.push(value);
+                }
+                Err(TrySendError::Full(_) | TrySendError::Closed(_)) => {
+                    log::trace!("Failed to return block to pool");
+                }
+            },
+        }
+    }
+}
+
+/// A block on its way back to the pool, stamped with the generation it was checked out from
+struct ReturnedBlock {
+    generation: u64,
+    reconcile_epoch: u64,
+    caller: Option<CallerId>,
+    block: PoolValue<KvBlock>,
+}
+
+// A fence on the return channel is acknowledged once every return sent before it is handled
+enum ReturnMessage {
+    Block(ReturnedBlock),
+    Batch(Vec<ReturnedBlock>),
+    Fence(oneshot::Sender<()>),
+}
+
+#[derive(Clone)]
+enum ReturnSender {
+    Unbounded(mpsc::UnboundedSender<ReturnMessage>),
+    Bounded(mpsc::Sender<ReturnMessage>),
+}
+
+enum ReturnReceiver {
+    Unbounded(mpsc::UnboundedReceiver<ReturnMessage>),
+    Bounded(mpsc::Receiver<ReturnMessage>),
+}
+
+impl ReturnReceiver {
+    async fn recv(&mut self) -> Option<ReturnMessage> {
+        match self {
+            ReturnReceiver::Unbounded(rx) => rx.recv().await,
+            ReturnReceiver::Bounded(rx) => rx.recv().await,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            ReturnReceiver::Unbounded(rx) => rx.is_closed(),
+            ReturnReceiver::Bounded(rx) => rx.is_closed(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            ReturnReceiver::Unbounded(rx) => rx.is_empty(),
+            ReturnReceiver::Bounded(rx) => rx.is_empty(),
+        }
+    }
+}
+
+/// Blocks which could not be placed on a full bounded return channel
+#[derive(Default)]
+struct ReturnOverflow {
+    blocks: Mutex<VecDeque<ReturnedBlock>>,
+    notify: Notify,
+
+    // returns sent but not yet handled by the engine, across the channel and the buffer
+    depth: AtomicU64,
+    limits: Option<ReturnQueueLimits>,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl ReturnOverflow {
+    fn new(limits: Option<ReturnQueueLimits>) -> Self {
+        Self {
+            blocks: Mutex::default(),
+            notify: Notify::new(),
+            depth: AtomicU64::new(0),
+            limits,
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    fn warn_depth(&self, depth: u64) {
+        let now = Instant::now();
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|last| now < last + RETURN_QUEUE_WARNING_INTERVAL) {
+            return;
+        }
+        *last_warning = Some(now);
+        log::warn!(
+            depth,
+            "return queue past its soft limit; the progress engine is falling behind"
+        );
+    }
+
+    fn push(&self, block: ReturnedBlock) {
+        self.blocks.lock().unwrap().push_back(block);
+        self.notify.notify_one();
+    }
+
+    fn drain(&self) -> VecDeque<ReturnedBlock> {
+        std::mem::take(&mut *self.blocks.lock().unwrap())
+    }
+}
+
+/// State of the self check shared by the handle and the engine; see
+/// [AvailableBlocksConfig::with_self_check]
+struct SelfCheckState {
+    started: Instant,
+
+    // milliseconds since started at the end of the latest engine turn
+    last_turn_ms: AtomicU64,
+
+    // sampled calls and warnings issued
+    calls: AtomicU64,
+    warnings: AtomicU64,
+    last_warning: Mutex<Option<Instant>>,
+
+    anomaly: Mutex<Option<PoolAnomaly>>,
+    last_audit: Mutex<Option<IntegrityReport>>,
+}
+
+impl SelfCheckState {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_turn_ms: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            warnings: AtomicU64::new(0),
+            last_warning: Mutex::new(None),
+            anomaly: Mutex::new(None),
+            last_audit: Mutex::new(None),
+        }
+    }
+
+    fn stamp_turn(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_turn_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn since_last_turn(&self) -> Duration {
+        let last_turn = Duration::from_millis(self.last_turn_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_turn)
+    }
+
+    // Whether a warning is due, at most once per SELF_CHECK_WARNING_INTERVAL
+    fn should_warn(&self) -> bool {
+        let now = Instant::now();
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|last| now < last + SELF_CHECK_WARNING_INTERVAL) {
+            return false;
+        }
+        *last_warning = Some(now);
+        self.warnings.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+impl AvailableBlocks {
+    pub async fn new() -> Self {
+        Self::new_with_config(AvailableBlocksConfig::default()).await
+    }
+
+    pub async fn new_with_config(config: AvailableBlocksConfig) -> Self {
+        Self::new_with_config_on(config, tokio::runtime::Handle::current())
+    }
+
+    /// Create a pool whose progress engine runs on the given runtime instead of the current one
+    pub fn new_on(handle: tokio::runtime::Handle) -> Self {
+        Self::new_with_config_on(AvailableBlocksConfig::default(), handle)
+    }
+
+    /// Create a pool whose progress engine runs on its own OS thread, on a single threaded runtime
+    pub fn new_dedicated_thread() -> Result<Self> {
+        Self::new_dedicated_thread_with(
+            AvailableBlocksConfig::default(),
+            EngineThreadOptions::default(),
+        )
+    }
+
+    /// Create a pool whose progress engine runs on its own OS thread, on a single threaded runtime
+    ///
+    /// The engine is isolated from the worker threads of the runtimes using the pool. Dropping
+    /// the pool stops the engine and joins the thread.
+    pub fn new_dedicated_thread_with(
+        config: AvailableBlocksConfig,
+        options: EngineThreadOptions,
+    ) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let mut builder = std::thread::Builder::new();
+        if let Some(name) = options.name {
+            builder = builder.name(name);
+        }
+        let core = options.core;
+        let thread = builder.spawn(move || {
+            if let Some(core) = core {
+                pin_current_thread(core);
+            }
+            // the engine makes progress while the runtime is driven; dropping the runtime
+            // afterwards cancels it
+            let _ = runtime.block_on(shutdown_rx);
+        })?;
+
+        let mut pool = Self::new_with_config_on(config, handle);
+        pool.engine_thread = Some(EngineThread {
+            shutdown_tx: Some(shutdown_tx),
+            thread: Some(thread),
+        });
+        Ok(pool)
+    }
+
+    /// Create a pool with the given configuration whose progress engine runs on the given runtime
+    pub fn new_with_config_on(
+        config: AvailableBlocksConfig,
+        handle: tokio::runtime::Handle,
+    ) -> Self {
+        let (match_tx, match_rx) = mpsc::unbounded_channel();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (fence_tx, fence_rx) = mpsc::unbounded_channel();
+
+        let (return_tx, return_rx) = match config.return_channel_capacity {
+            Some(capacity) => {
+                let (tx, rx) = mpsc::channel(capacity);
+                (ReturnSender::Bounded(tx), ReturnReceiver::Bounded(rx))
+            }
+            None => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                (ReturnSender::Unbounded(tx), ReturnReceiver::Unbounded(rx))
+            }
+        };
+        let overflow = Arc::new(ReturnOverflow::new(config.return_queue_limits));
+
+        let total_blocks = Arc::new(AtomicU64::new(0));
+        let available_blocks = Arc::new(AtomicU64::new(0));
+        let offloading_blocks = Arc::new(AtomicU64::new(0));
+
+        let (lifecycle_tx, lifecycle_rx) = watch::channel(match config.not_ready_policy {
+            Some(_) => PoolLifecycle::Initializing,
+            None => PoolLifecycle::Ready,
+        });
+        let (token_stats_tx, token_stats_rx) = watch::channel(TokenStats::default());
+        let (available_tx, available_rx) = watch::channel(0);
+        let (resident_tx, resident_rx) = watch::channel(Arc::default());
+
+        let return_handle = Arc::new(ReturnHandleImpl {
+            return_tx: return_tx.clone(),
+            overflow: overflow.clone(),
+            generation: 0,
+            reconcile_epoch: 0,
+            caller: None,
+        });
+
+        let (op_events, _) = broadcast::channel(OP_EVENT_CAPACITY);
+        let (eviction_events, _) = broadcast::channel(EVICTION_EVENT_CAPACITY);
+        let (pool_events, _) = broadcast::channel(POOL_EVENT_CAPACITY);
+        let availability = Arc::new(Notify::new());
+        let holds = Arc::new(Notify::new());
+        let self_check = Arc::new(SelfCheckState::new());
+
+        let state = AvailableBlocksState::new(
+            config.clone(),
+            return_handle,
+            total_blocks.clone(),
+            available_blocks.clone(),
+            offloading_blocks.clone(),
+            lifecycle_tx,
+            token_stats_tx,
+            available_tx,
+            resident_tx,
+            op_events.clone(),
+            eviction_events.clone(),
+            pool_events.clone(),
+            availability.clone(),
+            holds.clone(),
+            self_check.clone(),
+            PoolRef(control_tx.downgrade()),
+        );
+
+        // every log line of the engine carries the pool name
+        let span = match &config.name {
+            Some(name) => log::info_span!("available_blocks", pool = %name),
+            None => log::Span::none(),
+        };
+
+        let handle_overflow = overflow.clone();
+        let join_handle = handle.spawn(
+            progress_engine(state, match_rx, return_rx, overflow, control_rx, fence_rx)
+                .instrument(span),
+        );
+
+        Self {
+            match_tx,
+            control_tx,
+            fence_tx,
+            return_tx,
+            next_seq: AtomicU64::new(0),
+            total_blocks,
+            available_blocks,
+            offloading_blocks,
+            lifecycle_rx,
+            token_stats_rx,
+            available_rx,
+            resident_rx,
+            config,
+            join_handle,
+            op_events,
+            eviction_events,
+            pool_events,
+            availability,
+            holds,
+            overflow: handle_overflow,
+            self_check,
+            engine_thread: None,
+        }
+    }
+}
+
+// An eviction shield; a shield with a holder is a soft hold, see OnSoftHold
+#[derive(Debug, Clone, Copy)]
+struct Shield {
+    expiry: Instant,
+    holder: Option<u64>,
+
+    // Expiry of the holder-less shield under a soft hold, which remains once the hold is released
+    unheld_expiry: Option<Instant>,
+}
+
+impl Shield {
+    fn new(expiry: Instant, holder: Option<u64>) -> Self {
+        Self {
+            expiry,
+            holder,
+            unheld_expiry: None,
+        }
+    }
+
+    // Extend the holder-less shielding of the block to `expiry`
+    fn shield_until(&mut self, expiry: Instant) {
+        self.expiry = self.expiry.max(expiry);
+        if self.holder.is_some() {
+            self.unheld_expiry = self.unheld_expiry.max(Some(expiry));
+        }
+    }
+}
+
+// Hasher of the lookup map; fixed keys under AvailableBlocksConfig::with_deterministic_mode
+#[derive(Debug, Clone)]
+enum LookupHasher {
+    Random(RandomState),
+    Fixed,
+}
+
+impl LookupHasher {
+    fn new(deterministic: bool) -> Self {
+        if deterministic {
+            Self::Fixed
+        } else {
+            Self::Random(RandomState::new())
+        }
+    }
+}
+
+impl BuildHasher for LookupHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        match self {
+            Self::Random(state) => state.build_hasher(),
+            Self::Fixed => DefaultHasher::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PriorityKey {
+    priority: BlockPriority,
+    sticky: bool,
+    affinity_tick: u64,
+    return_tick: u64,
+    sequence_hash: SequenceHash,
+}
+
+// customize ord and partial ord for to store first by priority (lowest to highest), i.e. by the
+// class and then the score of the [BlockPriority], then non-sticky before sticky, then by affinity
+// group tick and return_tick (lowest to highest), then by sequence hash
+impl PartialOrd for PriorityKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PriorityKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(self.sticky.cmp(&other.sticky))
+            .then(self.affinity_tick.cmp(&other.affinity_tick))
+            .then(self.return_tick.cmp(&other.return_tick))
+            .then(self.sequence_hash.cmp(&other.sequence_hash))
+    }
+}
+
+impl From<&KvBlock> for PriorityKey {
+    fn from(block: &KvBlock) -> Self {
+        Self {
+            priority: block.priority,
+            sticky: block.sticky,
+            affinity_tick: block.affinity_tick,
+            return_tick: block.return_tick,
+            sequence_hash: block.lookup_key(),
+        }
+    }
+}
+
+impl PriorityKey {
+    // Whether the key orders by return tick alone, so it can be kept in a UniformQueue
+    fn is_uniform(&self) -> bool {
+        self.priority == BlockPriority::default() && !self.sticky && self.affinity_tick == 0
+    }
+}
+
+// Entries of the uniform eviction order allowed beyond twice the live ones before the tombstones
+// are swept
+const UNIFORM_QUEUE_SLACK: usize = 64;
+
+// Eviction order of the reusable blocks, with the blocks of each reserved band counted as they
+// enter and leave it
+struct PrioritySet {
+    order: PriorityOrder,
+    bands: Vec<RangeInclusive<BlockPriority>>,
+    band_lens: Vec<usize>,
+
+    // blocks of each retention class, indexed by class
+    class_lens: Vec<usize>,
+}
+
+// Representation of the eviction order; see AvailableBlocksConfig::with_uniform_priority
+enum PriorityOrder {
+    Ordered(BTreeMap<PriorityKey, SequenceHash>),
+    Uniform(UniformQueue),
+}
+
+// Uniform keys in ascending order. Blocks are returned in tick order, so nearly every key is
+// appended to the queue; a key below the back of the queue, such as a block re-keyed under its old
+// tick, goes to the ordered map of late keys instead, so no insert shifts the queue. A key removed
+// from the queue stays behind as a tombstone until it reaches the front or the tombstones are swept.
+#[derive(Default)]
+struct UniformQueue {
+    entries: VecDeque<(PriorityKey, Option<SequenceHash>)>,
+    late: BTreeMap<PriorityKey, SequenceHash>,
+
+    // live entries of the queue
+    live: usize,
+}
+
+impl PrioritySet {
+    fn new(uniform: bool, bands: Vec<RangeInclusive<BlockPriority>>) -> Self {
+        Self {
+            order: PriorityOrder::new(uniform),
+            band_lens: vec![0; bands.len()],
+            bands,
+            class_lens: vec![0; u8::MAX as usize + 1],
+        }
+    }
+
+    fn is_uniform(&self) -> bool {
+        self.order.is_uniform()
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    // Blocks in the eviction order with a priority in the reserved band at `index`
+    fn band_len(&self, index: usize) -> usize {
+        self.band_lens[index]
+    }
+
+    // Blocks of each retention class holding any, by class
+    fn class_lens(&self) -> Vec<(u8, u64)> {
+        (0..=u8::MAX)
+            .zip(&self.class_lens)
+            .filter(|(_, len)| **len > 0)
+            .map(|(class, len)| (class, *len as u64))
+            .collect()
+    }
+
+    // A priority belongs to the first band containing it
+    fn count_band(&mut self, priority: BlockPriority, entered: bool) {
+        let class = priority.class() as usize;
+        if entered {
+            self.class_lens[class] += 1;
+        } else {
+            self.class_lens[class] -= 1;
+        }
+
+        if let Some(index) = self.bands.iter().position(|band| band.contains(&priority)) {
+            if entered {
+                self.band_lens[index] += 1;
+            } else {
+                self.band_lens[index] -= 1;
+            }
+        }
+    }
+
+    fn insert(&mut self, key: PriorityKey, sequence_hash: SequenceHash) -> Option<SequenceHash> {
+        let previous = self.order.insert(key, sequence_hash);
+        if previous.is_none() {
+            self.count_band(key.priority, true);
+        }
+        previous
+    }
+
+    fn remove(&mut self, key: &PriorityKey) -> Option<SequenceHash> {
+        let removed = self.order.remove(key);
+        if removed.is_some() {
+            self.count_band(key.priority, false);
+        }
+        removed
+    }
+
+    fn pop_first(&mut self) -> Option<(PriorityKey, SequenceHash)> {
+        let (key, sequence_hash) = self.order.pop_first()?;
+        self.count_band(key.priority, false);
+        Some((key, sequence_hash))
+    }
+
+    fn range(&self, range: impl RangeBounds<PriorityKey>) -> PrioritySetIter<'_> {
+        self.order.range(range)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &SequenceHash> {
+        self.order.values()
+    }
+
+    // A set of `entries` in the representation of this one, with the bands recounted
+    fn rebuilt(&self, entries: impl Iterator<Item = (PriorityKey, SequenceHash)>) -> Self {
+        let mut set = Self {
+            order: self.order.rebuilt(entries),
+            bands: self.bands.clone(),
+            band_lens: vec![0; self.bands.len()],
+            class_lens: vec![0; u8::MAX as usize + 1],
+        };
+        let priorities: Vec<BlockPriority> =
+            set.order.range(..).map(|(key, _)| key.priority).collect();
+        for priority in priorities {
+            set.count_band(priority, true);
+        }
+        set
+    }
+}
+
+impl PriorityOrder {
+    fn new(uniform: bool) -> Self {
+        if uniform {
+            Self::Uniform(UniformQueue::default())
+        } else {
+            Self::Ordered(BTreeMap::new())
+        }
+    }
+
+    fn is_uniform(&self) -> bool {
+        matches!(self, Self::Uniform(_))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Ordered(map) => map.len(),
+            Self::Uniform(queue) => queue.len(),
+        }
+    }
+
+    fn insert(&mut self, key: PriorityKey, sequence_hash: SequenceHash) -> Option<SequenceHash> {
+        match self {
+            Self::Uniform(queue) if key.is_uniform() => queue.insert(key, sequence_hash),
+            Self::Uniform(queue) => {
+                log::debug!(
+                    priority = %key.priority,
+                    "non-uniform block; moving the eviction order to an ordered map"
+                );
+                let mut map: BTreeMap<_, _> = queue
+                    .range(..)
+                    .map(|(key, sequence_hash)| (*key, *sequence_hash))
+                    .collect();
+                let previous = map.insert(key, sequence_hash);
+                *self = Self::Ordered(map);
+                previous
+            }
+            Self::Ordered(map) => map.insert(key, sequence_hash),
+        }
+    }
+
+    fn remove(&mut self, key: &PriorityKey) -> Option<SequenceHash> {
+        match self {
+            Self::Ordered(map) => map.remove(key),
+            Self::Uniform(queue) => queue.remove(key),
+        }
+    }
+
+    fn pop_first(&mut self) -> Option<(PriorityKey, SequenceHash)> {
+        match self {
+            Self::Ordered(map) => map.pop_first(),
+            Self::Uniform(queue) => queue.pop_first(),
+        }
+    }
+
+    fn range(&self, range: impl RangeBounds<PriorityKey>) -> PrioritySetIter<'_> {
+        match self {
+            Self::Ordered(map) => PrioritySetIter::Ordered(map.range(range)),
+            Self::Uniform(queue) => queue.range(range),
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = &SequenceHash> {
+        self.range(..).map(|(_, sequence_hash)| sequence_hash)
+    }
+
+    // A set of `entries` in the representation of this one
+    fn rebuilt(&self, entries: impl Iterator<Item = (PriorityKey, SequenceHash)>) -> Self {
+        match self {
+            Self::Ordered(_) => Self::Ordered(entries.collect()),
+            Self::Uniform(_) => {
+                let mut entries: Vec<_> = entries.collect();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                let mut set = Self::new(true);
+                for (key, sequence_hash) in entries {
+                    set.insert(key, sequence_hash);
+                }
+                set
+            }
+        }
+    }
+}
+
+impl UniformQueue {
+    fn len(&self) -> usize {
+        self.live + self.late.len()
+    }
+
+    // Index of the first entry not below `key`
+    fn position(&self, key: &PriorityKey) -> usize {
+        self.entries.partition_point(|(entry, _)| entry < key)
+    }
+
+    fn insert(&mut self, key: PriorityKey, sequence_hash: SequenceHash) -> Option<SequenceHash> {
+        if let Some(previous) = self.late.get_mut(&key) {
+            return Some(std::mem::replace(previous, sequence_hash));
+        }
+        if self.entries.back().is_none_or(|(last, _)| *last < key) {
+            self.entries.push_back((key, Some(sequence_hash)));
+            self.live += 1;
+            return None;
+        }
+
+        let position = self.position(&key);
+        match self.entries.get_mut(position) {
+            Some((entry, slot)) if *entry == key => {
+                let previous = slot.replace(sequence_hash);
+                if previous.is_none() {
+                    self.live += 1;
+                }
+                previous
+            }
+            _ => self.late.insert(key, sequence_hash),
+        }
+    }
+
+    fn remove(&mut self, key: &PriorityKey) -> Option<SequenceHash> {
+        if !key.is_uniform() {
+            return None;
+        }
+        if let Some(removed) = self.late.remove(key) {
+            return Some(removed);
+        }
+        let position = self.position(key);
+        let (entry, slot) = self.entries.get_mut(position)?;
+        if entry != key {
+            return None;
+        }
+        let removed = slot.take()?;
+        self.live -= 1;
+        self.sweep();
+        Some(removed)
+    }
+
+    fn pop_first(&mut self) -> Option<(PriorityKey, SequenceHash)> {
+        self.pop_tombstones();
+        let late_first = match (self.entries.front(), self.late.first_key_value()) {
+            (Some((queued, _)), Some((late, _))) => late < queued,
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+        if late_first {
+            return self.late.pop_first();
+        }
+
+        let (key, slot) = self.entries.pop_front()?;
+        self.live -= 1;
+        Some((key, slot.expect("tombstones popped from the front")))
+    }
+
+    fn range(&self, range: impl RangeBounds<PriorityKey>) -> PrioritySetIter<'_> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.entries.partition_point(|(entry, _)| entry < key),
+            Bound::Excluded(key) => self.entries.partition_point(|(entry, _)| entry <= key),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.entries.partition_point(|(entry, _)| entry <= key),
+            Bound::Excluded(key) => self.entries.partition_point(|(entry, _)| entry < key),
+            Bound::Unbounded => self.entries.len(),
+        };
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        PrioritySetIter::Uniform {
+            queued: self
+                .entries
+                .range(start..end.max(start))
+                .filter_map(live_entry as LiveEntry)
+                .peekable(),
+            late: self.late.range(bounds).peekable(),
+        }
+    }
+
+    fn pop_tombstones(&mut self) {
+        while self.entries.front().is_some_and(|(_, slot)| slot.is_none()) {
+            self.entries.pop_front();
+        }
+    }
+
+    // Drop the tombstones at the front, and all of them once they outnumber the live entries
+    fn sweep(&mut self) {
+        self.pop_tombstones();
+        if self.entries.len() > 2 * self.live + UNIFORM_QUEUE_SLACK {
+            self.entries.retain(|(_, slot)| slot.is_some());
+        }
+    }
+}
+
+type LiveEntry = for<'a> fn(
+    &'a (PriorityKey, Option<SequenceHash>),
+) -> Option<(&'a PriorityKey, &'a SequenceHash)>;
+
+// The key and hash of a queue entry which is not a tombstone
+fn live_entry(
+    (key, slot): &(PriorityKey, Option<SequenceHash>),
+) -> Option<(&PriorityKey, &SequenceHash)> {
+    slot.as_ref().map(|sequence_hash| (key, sequence_hash))
+}
+
+enum PrioritySetIter<'a> {
+    Ordered(std::collections::btree_map::Range<'a, PriorityKey, SequenceHash>),
+
+    // the queue and the late keys, merged in key order
+    Uniform {
+        queued: std::iter::Peekable<
+            std::iter::FilterMap<
+                std::collections::vec_deque::Iter<'a, (PriorityKey, Option<SequenceHash>)>,
+                LiveEntry,
+            >,
+        >,
+        late:
+            std::iter::Peekable<std::collections::btree_map::Range<'a, PriorityKey, SequenceHash>>,
+    },
+}
+
+impl<'a> Iterator for PrioritySetIter<'a> {
+    type Item = (&'a PriorityKey, &'a SequenceHash);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Ordered(range) => range.next(),
+            Self::Uniform { queued, late } => {
+                let late_first = match (queued.peek(), late.peek()) {
+                    (Some((queued, _)), Some((late, _))) => late < queued,
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                };
+                if late_first {
+                    late.next()
+                } else {
+                    queued.next()
+                }
+            }
+        }
+    }
+}
+
+struct StickySession {
+    hashes: Vec<SequenceHash>,
+    expiry: Instant,
+}
+
+#[derive(Default)]
+struct AffinityGroup {
+    tick: u64,
+    members: HashSet<SequenceHash>,
+}
+
+struct AvailableBlocksState {
+    config: AvailableBlocksConfig,
+
+    // Return handle for checkouts of the current generation
+    return_handle: Arc<ReturnHandleImpl>,
+
+    // Checked out slots already removed from the totals by a shrink
+    retired_in_flight: u64,
+
+    // Direct lookup by sequence_hash
+    lookup_map: HashMap<SequenceHash, PoolValue<KvBlock>, LookupHasher>,
+
+    // // Ordered by timestamp (oldest first)
+    priority_set: PrioritySet,
+
+    // Fully Uninitialized
+    uninitialized_set: VecDeque<PoolValue<KvBlock>>,
+
+    // Token storage held by the uninitialized set and released by the blank storage cap
+    blank_bytes: usize,
+    blank_released_bytes: u64,
+
+    // Return Tick
+    return_tick: u64,
+
+    // Total blocks
+    total_blocks: Arc<AtomicU64>,
+
+    // Available blocks
+    available_blocks: Arc<AtomicU64>,
+
+    // Lifecycle published to the handles
+    lifecycle_tx: watch::Sender<PoolLifecycle>,
+
+    // Token totals; the window fields are derived from the buckets when published
+    token_stats: TokenStats,
+    token_buckets: VecDeque<TokenBucket>,
+    token_stats_tx: watch::Sender<TokenStats>,
+
+    // Available blocks published to the handles
+    available_tx: watch::Sender<u64>,
+
+    // Hashes of the cached blocks published for probe_fast, and the state changes of the current
+    // turn for it and the shared content index
+    resident_tx: watch::Sender<Arc<HashSet<SequenceHash>>>,
+    residency_changes: Vec<(SequenceHash, SequenceState)>,
+
+    // The snapshot replaced by the last publish, and the changes of that turn it lacks
+    resident_spare: Arc<HashSet<SequenceHash>>,
+    spare_changes: Vec<(SequenceHash, SequenceState)>,
+
+    // Number of evictions per age bucket, in total and by retention class
+    eviction_ages: [u64; EVICTION_AGE_BUCKETS],
+    class_eviction_ages: BTreeMap<u8, [u64; EVICTION_AGE_BUCKETS]>,
+
+    // Cumulative counters since the last drain
+    counters: CounterSnapshot,
+
+    // Eviction shields registered through expect, session deadlines and peek holds
+    shields: HashMap<SequenceHash, Shield>,
+
+    // Sequence hashes of the blocks checked out for maintenance
+    maintenance: HashSet<SequenceHash>,
+
+    // Verdict of the latest reconcile, applied to blocks checked out before it started
+    deferred_verdict: Option<BlockVerdict>,
+
+    // Hashes evicted within the hysteresis window
+    recently_evicted: EvictionHistory,
+
+    // Operation ids applied by insert_many_once
+    applied_ops: DedupeWindow,
+
+    // Evicted blocks offloaded by the eviction hook, with their expiry in insertion order
+    cold_entries: HashMap<SequenceHash, ColdEntry>,
+    cold_order: VecDeque<(Instant, SequenceHash)>,
+
+    // Locations of blocks held outside this pool, recorded by mirror_cold; never expire
+    mirrored: HashMap<SequenceHash, ColdLocation>,
+
+    // Sticky sessions with their hashes and expiry, the sessions in expiry order and the number
+    // of sessions holding each hash
+    sticky_sessions: HashMap<u64, StickySession>,
+    sticky_expiry: BTreeSet<(Instant, u64)>,
+    sticky_refs: HashMap<SequenceHash, u32>,
+
+    // Hashes reported corrupt while their block was checked out; reset once returned
+    quarantined: HashSet<SequenceHash>,
+
+    // Cached blocks checked out by a match or a maintenance checkout, counted by hash until they
+    // are returned
+    checked_out: HashMap<SequenceHash, u32>,
+
+    // Cached members of each affinity group with the group's newest return tick; members which
+    // left the pool other than by eviction or match are purged lazily
+    affinity_groups: HashMap<u64, AffinityGroup>,
+
+    // Cached blocks by the hash of their parent, maintained as blocks enter and leave the lookup
+    // map; the chains of evict_chains are walked from it
+    chain_children: HashMap<SequenceHash, Vec<SequenceHash>>,
+
+    // Blocks taken on behalf of each caller and not yet returned
+    outstanding: HashMap<CallerId, u32>,
+
+    // Hashes at which matches missed, when miss tracking is enabled
+    misses: Option<MissCounter>,
+
+    // Matches of each hash since the last priority adjustment, and when the next one is due,
+    // under with_adaptive_priority
+    adaptive_hits: HashMap<SequenceHash, u32>,
+    next_adaptation: Option<Instant>,
+
+    // Set by with_trace_recorder, and whether the request being dispatched is recorded once
+    // sampled, see trace_op
+    #[cfg(feature = "trace-record")]
+    recorder: Option<TraceRecorder>,
+    #[cfg(feature = "trace-record")]
+    trace_op: Option<bool>,
+
+    // Watchers registered through watch_sequence, and the number of watchers at which the ones
+    // whose receivers were dropped are pruned next
+    sequence_watchers: HashMap<SequenceHash, watch::Sender<SequenceState>>,
+    watcher_prune_at: usize,
+
+    // Set while a take reporting its evictions is handled, to collect the evicted blocks
+    evicted_views: Option<Vec<KvBlockView>>,
+
+    // Next sequence number to process and the requests which arrived ahead of it
+    next_seq: u64,
+    reorder_buffer: BTreeMap<u64, Sequenced<SequencedRequest>>,
+
+    // Remainder of a chunked request; at most one is in progress at a time
+    continuation: Option<Continuation>,
+
+    // Streamed matches waiting for their caller to consume the last batch; they resume as a
+    // continuation once their channel has room, see parked_stream_ready
+    parked_streams: Vec<Continuation>,
+
+    // Start of the current engine turn and the touched counter at that point, when watched
+    turn: Option<(Instant, u64)>,
+
+    // Operation with a context being dispatched, its completion events and recent latencies
+    op_context: Option<ActiveOp>,
+    op_events: broadcast::Sender<OpCompleted>,
+    op_latencies: HashMap<OpKind, VecDeque<Duration>>,
+
+    // Evictions of cached blocks, and the caller of the take being handled, if any
+    eviction_events: broadcast::Sender<EvictionEvent>,
+    evicting_for: Option<CallerId>,
+
+    // Capacity events, the change of the current turn not yet published and the latest changes
+    pool_events: broadcast::Sender<PoolEvent>,
+    pending_capacity: Option<CapacityChange>,
+    capacity_log: VecDeque<CapacityChange>,
+
+    // Wakes takes waiting for enough blocks under UnderSupply::WaitUpTo
+    availability: Arc<Notify>,
+
+    // Wakes matches waiting for a soft hold under OnSoftHold::Wait or for a block under
+    // maintenance under OnMaintenance::Wait
+    holds: Arc<Notify>,
+
+    // Tagged operations handled but not yet observed by a fence_ops, and the fences still waiting
+    completed_ops: CompletedOps,
+    op_fences: Vec<OpFence>,
+
+    // requests and returns handled since the last periodic integrity check
+    ops_since_integrity_check: u64,
+
+    // Blocks handed to an offload worker with the time the offload began; under a janitor, the
+    // offloads are also kept in the order they began
+    offloading: HashMap<SequenceHash, (PoolValue<KvBlock>, Instant)>,
+    offload_order: VecDeque<(Instant, SequenceHash)>,
+    offloading_blocks: Arc<AtomicU64>,
+
+    // Turn stamps and audits read by the self check of the handles
+    self_check: Arc<SelfCheckState>,
+
+    // Stamped on every block inserted, for the block's pool_ops
+    pool_ref: PoolRef,
+
+    // Priorities set through BlockOps while their block was checked out; applied on return
+    pending_priorities: HashMap<SequenceHash, BlockPriority>,
+
+    // Set by a shutdown request; the engine stops once drained
+    shutting_down: bool,
+}
+
+impl AvailableBlocksState {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        config: AvailableBlocksConfig,
+        return_handle: Arc<ReturnHandleImpl>,
+        total_blocks: Arc<AtomicU64>,
+        available_blocks: Arc<AtomicU64>,
+        offloading_blocks: Arc<AtomicU64>,
+        lifecycle_tx: watch::Sender<PoolLifecycle>,
+        token_stats_tx: watch::Sender<TokenStats>,
+        available_tx: watch::Sender<u64>,
+        resident_tx: watch::Sender<Arc<HashSet<SequenceHash>>>,
+        op_events: broadcast::Sender<OpCompleted>,
+        eviction_events: broadcast::Sender<EvictionEvent>,
+        pool_events: broadcast::Sender<PoolEvent>,
+        availability: Arc<Notify>,
+        holds: Arc<Notify>,
+        self_check: Arc<SelfCheckState>,
+        pool_ref: PoolRef,
+    ) -> Self {
+        let misses = config.miss_tracking.map(MissCounter::new);
+        let next_adaptation = config
+            .adaptive_priority
+            .map(|adaptive| Instant::now() + adaptive.interval);
+        let lookup_hasher = LookupHasher::new(config.deterministic_mode);
+        let priority_set = PrioritySet::new(
+            config.uniform_priority,
+            config
+                .reserved_bands
+                .iter()
+                .map(|band| band.priorities.clone())
+                .collect(),
+        );
+        #[cfg(feature = "trace-record")]
+        let recorder = config.trace_recorder.map(TraceRecorder::new);
+        Self {
+            config,
+            return_handle,
+            retired_in_flight: 0,
+            lookup_map: HashMap::with_hasher(lookup_hasher),
+            priority_set,
+            uninitialized_set: VecDeque::new(),
+            blank_bytes: 0,
+            blank_released_bytes: 0,
+            return_tick: 0,
+            total_blocks,
+            available_blocks,
+            lifecycle_tx,
+            token_stats: TokenStats::default(),
+            token_buckets: VecDeque::new(),
+            token_stats_tx,
+            available_tx,
+            resident_tx,
+            residency_changes: Vec::new(),
+            resident_spare: Arc::default(),
+            spare_changes: Vec::new(),
+            eviction_ages: [0; EVICTION_AGE_BUCKETS],
+            class_eviction_ages: BTreeMap::new(),
+            counters: CounterSnapshot::default(),
+            shields: HashMap::new(),
+            maintenance: HashSet::new(),
+            deferred_verdict: None,
+            recently_evicted: EvictionHistory::default(),
+            applied_ops: DedupeWindow::default(),
+            cold_entries: HashMap::new(),
+            mirrored: HashMap::new(),
+            cold_order: VecDeque::new(),
+            sticky_sessions: HashMap::new(),
+            sticky_expiry: BTreeSet::new(),
+            sticky_refs: HashMap::new(),
+            quarantined: HashSet::new(),
+            checked_out: HashMap::new(),
+            affinity_groups: HashMap::new(),
+            chain_children: HashMap::new(),
+            outstanding: HashMap::new(),
+            misses,
+            adaptive_hits: HashMap::new(),
+            next_adaptation,
+            #[cfg(feature = "trace-record")]
+            recorder,
+            #[cfg(feature = "trace-record")]
+            trace_op: None,
+            sequence_watchers: HashMap::new(),
+            watcher_prune_at: WATCHER_PRUNE_MIN,
+            evicted_views: None,
+            next_seq: 0,
+            reorder_buffer: BTreeMap::new(),
+            turn: None,
+            op_context: None,
+            op_events,
+            op_latencies: HashMap::new(),
+            eviction_events,
+            evicting_for: None,
+            pool_events,
+            pending_capacity: None,
+            capacity_log: VecDeque::with_capacity(CAPACITY_LOG_LEN),
+            availability,
+            holds,
+            continuation: None,
+            parked_streams: Vec::new(),
+            completed_ops: CompletedOps::default(),
+            op_fences: Vec::new(),
+            ops_since_integrity_check: 0,
+            offloading: HashMap::new(),
+            offload_order: VecDeque::new(),
+            offloading_blocks,
+            self_check,
+            pool_ref,
+            pending_priorities: HashMap::new(),
+            shutting_down: false,
+        }
+    }
+
+    fn handle_sequenced(&mut self, request: Sequenced<SequencedRequest>) {
+        let Some(seq) = request.seq else {
+            self.dispatch(request);
+            return;
+        };
+
+        if seq != self.next_seq {
+            log::trace!(
+                seq,
+                next_seq = self.next_seq,
+                "holding back out of order request"
+            );
+        }
+
+        self.reorder_buffer.insert(seq, request);
+        self.drain_reorder_buffer();
+    }
+
+    // Dispatch the requests which are next in sequence, stopping at a chunked request
+    fn drain_reorder_buffer(&mut self) {
+        while self.continuation.is_none() {
+            let Some(request) = self.reorder_buffer.remove(&self.next_seq) else {
+                break;
+            };
+            self.dispatch(request);
+            self.next_seq += 1;
+        }
+    }
+
+    fn dispatch(&mut self, request: Sequenced<SequencedRequest>) {
+        self.op_context = request.context.map(|context| ActiveOp {
+            context,
+            dispatched: Instant::now(),
+        });
+
+        #[cfg(feature = "trace-record")]
+        {
+            self.trace_op = None;
+        }
+
+        let op_id = match &request.request {
+            SequencedRequest::Control(request) => request.op_id(),
+            SequencedRequest::Match(_) => None,
+        };
+        let parked = self.parked_streams.len();
+        match request.request {
+            SequencedRequest::Match(request) => self.handle_match_request(request),
+            SequencedRequest::Control(request) => self.handle_control_request(request),
+        }
+
+        // a chunked request takes the context along; other requests do not report one
+        self.op_context = None;
+        #[cfg(feature = "trace-record")]
+        {
+            self.trace_op = None;
+        }
+
+        // a chunked request completes its op when the last chunk has been handled, also when it
+        // was parked in its first turn
+        if let Some(op_id) = op_id {
+            let continuation = self
+                .continuation
+                .as_mut()
+                .or_else(|| self.parked_streams.get_mut(parked));
+            match continuation {
+                Some(continuation) => continuation.op_id = Some(op_id),
+                None => self.complete_op(op_id),
+            }
+        }
+
+        self.check_integrity_periodically();
+    }
+
+    // Rebuild the eviction order from the lookup map if their sizes diverged
+    fn check_integrity_periodically(&mut self) {
+        let Some(every) = self.config.integrity_check_interval else {
+            return;
+        };
+        self.ops_since_integrity_check += 1;
+        if self.ops_since_integrity_check < every {
+            return;
+        }
+        self.ops_since_integrity_check = 0;
+
+        if self.priority_set.len() == self.lookup_map.len() {
+            return;
+        }
+        log::error!(
+            priority_set = self.priority_set.len(),
+            lookup_map = self.lookup_map.len(),
+            "priority set diverged from lookup map; rebuilding it"
+        );
+        self.counters.integrity_repairs += 1;
+        self.rebuild_priority_set();
+    }
+
+    fn begin_offload(&mut self, sequence_hash: SequenceHash) -> Option<BlockDescriptor> {
+        let block = self.take_with_sequence_hash(sequence_hash)?;
+        self.shields.remove(&sequence_hash);
+        self.available_blocks.fetch_sub(1, Ordering::SeqCst);
+        self.notify_sequence(sequence_hash, SequenceState::Absent);
+
+        let descriptor = BlockDescriptor::from(&*block);
+        let began = Instant::now();
+        self.offloading.insert(sequence_hash, (block, began));
+        if self.config.offload_timeout.is_some() {
+            self.offload_order.push_back((began, sequence_hash));
+        }
+        self.offloading_blocks
+            .store(self.offloading.len() as u64, Ordering::SeqCst);
+        Some(descriptor)
+    }
+
+    fn complete_offload(&mut self, sequence_hash: SequenceHash) -> bool {
+        match self.offloading.remove(&sequence_hash) {
+            Some((block, _)) => {
+                self.resolve_offload(block);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The contents of an offloaded block live elsewhere, so its slot returns to the pool blank
+    fn resolve_offload(&mut self, mut block: PoolValue<KvBlock>) {
+        self.offloading_blocks
+            .store(self.offloading.len() as u64, Ordering::SeqCst);
+        self.reset_block(&mut block);
+        self.available_blocks.fetch_add(1, Ordering::SeqCst);
+        self.insert(block);
+    }
+
+    // Abandon the offloads past the janitor timeout, at most WATCHDOG_STEP per sweep
+    fn sweep_offloads(&mut self) {
+        let Some(timeout) = self.config.offload_timeout else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut budget = WATCHDOG_STEP;
+        while budget > 0 {
+            let Some(&(began, sequence_hash)) = self.offload_order.front() else {
+                break;
+            };
+            if began + timeout > now {
+                break;
+            }
+            self.offload_order.pop_front();
+
+            // completed offloads leave their entry behind
+            let abandoned = self
+                .offloading
+                .get(&sequence_hash)
+                .is_some_and(|(_, current)| *current == began);
+            if !abandoned {
+                continue;
+            }
+
+            budget -= 1;
+            log::warn!(
+                sequence_hash,
+                "offload did not complete in time; reclaiming block"
+            );
+            let (block, _) = self.offloading.remove(&sequence_hash).unwrap();
+            self.counters.offload_abandoned += 1;
+            self.resolve_offload(block);
+        }
+    }
+
+    // Count a hit on a cached block towards its adaptive priority
+    fn record_hit(&mut self, hash: SequenceHash) {
+        if self.config.adaptive_priority.is_some() {
+            *self.adaptive_hits.entry(hash).or_default() += 1;
+        }
+    }
+
+    // Raise the priority of hot cached blocks and lower that of cold ones, once per interval
+    fn adapt_priorities(&mut self) {
+        let (Some(adaptive), Some(due)) = (self.config.adaptive_priority, self.next_adaptation)
+        else {
+            return;
+        };
+        let now = Instant::now();
+        if now < due {
+            return;
+        }
+        self.next_adaptation = Some(now + adaptive.interval);
+
+        let hits = std::mem::take(&mut self.adaptive_hits);
+        let (mut promotions, mut demotions) = (0, 0);
+        let scores: Vec<(SequenceHash, BlockPriority)> = self
+            .lookup_map
+            .iter()
+            .filter(|(_, block)| (adaptive.min..=adaptive.max).contains(&block.priority))
+            .filter_map(|(sequence_hash, block)| {
+                let hits = hits.get(sequence_hash).copied().unwrap_or(0);
+                let priority = if hits >= adaptive.promote_threshold {
+                    block.priority.bump(1).min(adaptive.max)
+                } else if hits <= adaptive.demote_threshold {
+                    block.priority.lower(1).max(adaptive.min)
+                } else {
+                    block.priority
+                };
+                match priority.cmp(&block.priority) {
+                    std::cmp::Ordering::Greater => promotions += 1,
+                    std::cmp::Ordering::Less => demotions += 1,
+                    std::cmp::Ordering::Equal => return None,
+                }
+                Some((*sequence_hash, priority))
+            })
+            .collect();
+        if scores.is_empty() {
+            return;
+        }
+
+        log::debug!(
+            promotions,
+            demotions,
+            "adapting priorities to hit frequency"
+        );
+        self.counters.adaptive_promotions += promotions;
+        self.counters.adaptive_demotions += demotions;
+        self.handle_rebalance(scores);
+    }
+
+    // Drop the uninitialized blocks past their TTL, except preallocated slots
+    fn expire_uninitialized(&mut self) {
+        let Some(ttl) = self.config.uninitialized_ttl else {
+            return;
+        };
+
+        let now = Instant::now();
+        let before = self.uninitialized_set.len();
+        let mut freed_bytes = 0;
+        self.uninitialized_set.retain(|block| {
+            let expired =
+                !block.preallocated && block.blank_since.is_some_and(|since| since + ttl <= now);
+            if expired {
+                freed_bytes += block.token_block.storage_bytes();
+            }
+            !expired
+        });
+
+        let expired = (before - self.uninitialized_set.len()) as u64;
+        if expired == 0 {
+            return;
+        }
+        self.blank_bytes -= freed_bytes;
+        self.available_blocks.fetch_sub(expired, Ordering::SeqCst);
+        self.total_blocks.fetch_sub(expired, Ordering::SeqCst);
+        self.record_capacity_change(
+            -(expired as i64),
+            CapacityChangeReason::UninitializedExpired,
+        );
+        self.counters.uninitialized_expired += expired;
+        log::debug!(expired, "dropped idle uninitialized blocks");
+    }
+
+    fn rebuild_priority_set(&mut self) {
+        self.priority_set = self.priority_set.rebuilt(
+            self.lookup_map
+                .iter()
+                .map(|(sequence_hash, block)| (PriorityKey::from(&**block), *sequence_hash)),
+        );
+    }
+
+    fn has_continuation(&self) -> bool {
+        self.continuation.is_some()
+    }
+
+    // Handle the next chunk of the chunked request in progress, if any
+    fn advance_continuation(&mut self) {
+        let Some(mut continuation) = self.continuation.take() else {
+            return;
+        };
+
+        // under a watchdog, the chunk is handled in steps until the hard threshold passes
+        let mut remaining = self.config.handler_chunk_size.unwrap_or(usize::MAX);
+        let deadline = self.turn_deadline();
+        let done = loop {
+            let step = match deadline {
+                Some(_) => remaining.min(WATCHDOG_STEP),
+                None => remaining,
+            };
+            remaining -= step;
+
+            if self.advance_chunk(&mut continuation.kind, step) {
+                break true;
+            }
+            if continuation.kind.take_backpressure() {
+                self.parked_streams.push(continuation);
+                return;
+            }
+            if remaining == 0 {
+                break false;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                log::debug!(
+                    request = continuation.kind.name(),
+                    "splitting request at the watchdog's hard threshold"
+                );
+                self.counters.split_turns += 1;
+                break false;
+            }
+        };
+
+        if !done {
+            self.continuation = Some(continuation);
+            return;
+        }
+
+        match continuation.kind {
+            ContinuationKind::Match(state) => {
+                let hits = state.matched.len();
+                if state.tx.send(Ok(state.matched)).is_err() {
+                    log::trace!("Failed to send matched blocks to requester");
+                }
+                if let Some(op) = continuation.context {
+                    self.report_op(op, OpKind::Match, hits, state.requested - hits);
+                }
+            }
+            // dropping the sender ends the stream
+            ContinuationKind::MatchStream(_) => {}
+            ContinuationKind::Update(state) => {
+                if state.tx.send(state.error.map_or(Ok(()), Err)).is_err() {
+                    log::trace!("Failed to send update multiple ack; receiver dropped");
+                }
+            }
+            ContinuationKind::Rebalance(state) => {
+                self.trim_reserved_bands();
+                if state.tx.send(state.report).is_err() {
+                    log::trace!("Failed to send rebalance report; receiver dropped");
+                }
+            }
+            ContinuationKind::Reconcile(state) => {
+                if state.tx.send(state.report).is_err() {
+                    log::trace!("Failed to send reconcile report; receiver dropped");
+                }
+            }
+            ContinuationKind::Compact(state) => {
+                self.counters.compactions += 1;
+                log::debug!(
+                    capacity_before = state.capacity_before,
+                    capacity = self.lookup_map.capacity(),
+                    "compacted lookup map"
+                );
+            }
+        }
+
+        if let Some(op_id) = continuation.op_id {
+            self.complete_op(op_id);
+        }
+    }
+
+    // Returns true once the chunked request has finished
+    fn advance_chunk(&mut self, kind: &mut ContinuationKind, chunk_size: usize) -> bool {
+        match kind {
+            ContinuationKind::Match(state) => self.match_chunk(state, chunk_size),
+            ContinuationKind::MatchStream(state) => {
+                self.match_stream_chunk(state, chunk_size.min(MATCH_STREAM_BATCH_SIZE))
+            }
+            ContinuationKind::Update(state) => self.update_chunk(state, chunk_size),
+            ContinuationKind::Rebalance(state) => self.rebalance_chunk(state, chunk_size),
+            ContinuationKind::Reconcile(state) => self.reconcile_chunk(state, chunk_size),
+            ContinuationKind::Compact(state) => {
+                let budget = self
+                    .config
+                    .compaction
+                    .map_or(chunk_size, |policy| policy.budget);
+                self.compact_chunk(state, chunk_size.min(budget))
+            }
+        }
+    }
+
+    fn is_compacting(&self) -> bool {
+        matches!(
+            self.continuation,
+            Some(Continuation {
+                kind: ContinuationKind::Compact(_),
+                ..
+            })
+        )
+    }
+
+    // Start compacting the lookup map once it is mostly empty slots; the compaction runs as a
+    // continuation from the next turn on
+    fn schedule_compaction(&mut self) {
+        let Some(policy) = self.config.compaction else {
+            return;
+        };
+        let len = self.lookup_map.len();
+        let capacity = self.lookup_map.capacity();
+        if self.has_continuation()
+            || capacity < COMPACTION_MIN_CAPACITY
+            || capacity < len.max(1) * policy.max_slack
+        {
+            return;
+        }
+
+        let compacted =
+            HashMap::with_capacity_and_hasher(len + len / 4, self.lookup_map.hasher().clone());
+        let entries = std::mem::replace(&mut self.lookup_map, compacted).into_iter();
+        self.continuation = Some(Continuation {
+            op_id: None,
+            context: None,
+            kind: ContinuationKind::Compact(CompactContinuation {
+                entries,
+                capacity_before: capacity,
+            }),
+        });
+    }
+
+    // Returns true once every entry has moved to the compacted map
+    fn compact_chunk(&mut self, state: &mut CompactContinuation, chunk_size: usize) -> bool {
+        for (sequence_hash, block) in state.entries.by_ref().take(chunk_size) {
+            self.lookup_map.insert(sequence_hash, block);
+            self.counters.compacted_entries += 1;
+        }
+        state.entries.len() == 0
+    }
+
+    // Chunked requests are split by the handler chunk size or by the turn watchdog
+    fn chunks_requests(&self) -> bool {
+        self.config.handler_chunk_size.is_some() || self.config.turn_watchdog.is_some()
+    }
+
+    fn begin_turn(&mut self) {
+        if self.config.turn_watchdog.is_some() {
+            self.turn = Some((Instant::now(), self.counters.touched()));
+        }
+    }
+
+    fn turn_deadline(&self) -> Option<Instant> {
+        let watchdog = self.config.turn_watchdog?;
+        let (started, _) = self.turn?;
+        Some(started + watchdog.hard)
+    }
+
+    // Report the turn started by begin_turn if it ran past the soft threshold
+    fn end_turn(&mut self, request: &'static str) {
+        if self.config.self_check.is_some() {
+            self.self_check.stamp_turn();
+        }
+        self.schedule_compaction();
+        self.enforce_aux_budget();
+        self.publish_capacity_change();
+        self.publish_residency();
+        self.publish_token_stats();
+        let available = self.available_blocks.load(Ordering::SeqCst);
+        self.available_tx.send_if_modified(|published| {
+            let changed = *published != available;
+            *published = available;
+            changed
+        });
+
+        let (Some(watchdog), Some((started, touched_before))) =
+            (self.config.turn_watchdog, self.turn.take())
+        else {
+            return;
+        };
+
+        let elapsed = started.elapsed();
+        if elapsed < watchdog.soft {
+            return;
+        }
+
+        self.counters.slow_turns += 1;
+        let touched = self.counters.touched().saturating_sub(touched_before);
+        log::warn!(
+            request,
+            touched,
+            ?elapsed,
+            over_hard = elapsed >= watchdog.hard,
+            "slow progress engine turn"
+        );
+    }
+
+    // Account for a change of total_blocks already applied; a change for the same reason as the
+    // pending one of the turn is merged into it
+    fn record_capacity_change(&mut self, delta: i64, reason: CapacityChangeReason) {
+        if delta == 0 {
+            return;
+        }
+
+        let new_total = self.total_blocks.load(Ordering::SeqCst);
+        if let Some(pending) = self.pending_capacity.as_mut() {
+            if pending.reason == reason {
+                pending.delta += delta;
+                pending.new_total = new_total;
+                return;
+            }
+        }
+
+        self.publish_capacity_change();
+        self.pending_capacity = Some(CapacityChange {
+            at: Instant::now(),
+            delta,
+            new_total,
+            reason,
+        });
+    }
+
+    fn publish_capacity_change(&mut self) {
+        let Some(change) = self.pending_capacity.take() else {
+            return;
+        };
+
+        if self.capacity_log.len() == CAPACITY_LOG_LEN {
+            self.capacity_log.pop_front();
+        }
+        self.capacity_log.push_back(change);
+
+        // sending fails only when there are no subscribers
+        let _ = self.pool_events.send(PoolEvent::CapacityChanged {
+            delta: change.delta,
+            new_total: change.new_total,
+            reason: change.reason,
+        });
+    }
+
+    // Apply the residency changes of the turn to the index read by probe_fast and to the shared
+    // content index, in one update each
+    //
+    // The spare snapshot is brought up to date with the changes of the previous turn and of this
+    // one, outside the watch lock, and then swapped in; the replaced snapshot becomes the spare.
+    // Arc::make_mut copies the spare only if a probe still holds it.
+    fn publish_residency(&mut self) {
+        if self.residency_changes.is_empty() {
+            return;
+        }
+
+        let changes = &self.residency_changes;
+        if self.config.fast_probe {
+            let mut next = std::mem::take(&mut self.resident_spare);
+            let resident = Arc::make_mut(&mut next);
+            for &(sequence_hash, state) in self.spare_changes.iter().chain(changes) {
+                if state == SequenceState::Cached {
+                    resident.insert(sequence_hash);
+                } else {
+                    resident.remove(&sequence_hash);
+                }
+            }
+            self.resident_spare = self.resident_tx.send_replace(next);
+            self.spare_changes.clear();
+            self.spare_changes.extend_from_slice(changes);
+        }
+        if let Some((index, pool)) = &self.config.shared_content_index {
+            index.apply(
+                *pool,
+                changes
+                    .iter()
+                    .map(|&(sequence_hash, state)| (sequence_hash, state != SequenceState::Absent)),
+            );
+        }
+        self.residency_changes.clear();
+    }
+
+    fn continuation_name(&self) -> &'static str {
+        self.continuation
+            .as_ref()
+            .map_or("continuation", |continuation| continuation.kind.name())
+    }
+
+    // Continue a chunked request from the engine loop and release the requests held back by it
+    fn resume_continuation(&mut self) {
+        self.advance_continuation();
+        if !self.has_continuation() {
+            self.drain_reorder_buffer();
+        }
+    }
+
+    // Resolves with the index of a parked stream whose channel has room again or whose caller
+    // dropped the stream; never resolves without parked streams
+    fn parked_stream_ready(&self) -> impl Future<Output = usize> + 'static {
+        let senders: Vec<_> = self
+            .parked_streams
+            .iter()
+            .filter_map(|continuation| match &continuation.kind {
+                ContinuationKind::MatchStream(state) => Some(state.tx.clone()),
+                _ => None,
+            })
+            .collect();
+        async move {
+            if senders.is_empty() {
+                return std::future::pending().await;
+            }
+            let ready = senders
+                .into_iter()
+                .map(|tx| Box::pin(async move { tx.reserve_owned().await.map(drop) }));
+            futures::future::select_all(ready).await.1
+        }
+    }
+
+    // Continue the parked stream at `index` as the chunked request in progress
+    fn resume_parked_stream(&mut self, index: usize) {
+        self.continuation = Some(self.parked_streams.swap_remove(index));
+        self.resume_continuation();
+    }
+
+    fn start_continuation(&mut self, kind: ContinuationKind) {
+        self.continuation = Some(Continuation {
+            op_id: None,
+            context: self.op_context.take(),
+            kind,
+        });
+        self.advance_continuation();
+    }
+
+    // Returns true once the match has finished
+    fn match_chunk(&mut self, state: &mut MatchContinuation, chunk_size: usize) -> bool {
+        let mut done = false;
+        let mut matched = 0;
+        let headroom = self.in_use_headroom().unwrap_or(0);
+
+        for _ in 0..chunk_size {
+            if matched == headroom {
+                done = true;
+                break;
+            }
+            let Some(hash) = state.hashes.next() else {
+                done = true;
+                break;
+            };
+
+            let Some(mut block) = self.take_matched_since(hash, state.start_tick) else {
+                done = true;
+                break;
+            };
+            block.single_use = state.single_use;
+            self.token_stats.tokens_saved += block.token_block.tokens().len() as u64;
+            state
+                .matched
+                .push(self.create_pool_item(block, self.return_handle.clone()));
+            matched += 1;
+        }
+
+        self.available_blocks.fetch_sub(matched, Ordering::SeqCst);
+        self.counters.matches += matched;
+
+        done || state.hashes.as_slice().is_empty()
+    }
+
+    // Take a block for a chunked match; blocks returned since the match started are misses
+    fn take_matched_since(
+        &mut self,
+        sequence_hash: SequenceHash,
+        start_tick: u64,
+    ) -> Option<PoolValue<KvBlock>> {
+        let present = self
+            .lookup_map
+            .get(&sequence_hash)
+            .is_some_and(|block| block.return_tick <= start_tick);
+        if !present {
+            self.record_miss(sequence_hash);
+            return None;
+        }
+
+        let block = self
+            .take_with_sequence_hash(sequence_hash)
+            .expect("block present in lookup map");
+        self.shields.remove(&sequence_hash);
+        self.check_out(sequence_hash);
+        Some(block)
+    }
+
+    // Returns true once the streamed match has finished or the stream was dropped
+    fn match_stream_chunk(
+        &mut self,
+        state: &mut MatchStreamContinuation,
+        chunk_size: usize,
+    ) -> bool {
+        // the stream is parked while its last batch is unconsumed
+        let permit = match state.tx.try_reserve() {
+            Ok(permit) => permit,
+            Err(TrySendError::Full(())) => {
+                state.backpressured = true;
+                return false;
+            }
+            Err(TrySendError::Closed(())) => return true,
+        };
+
+        let mut batch = Vec::with_capacity(chunk_size.min(state.hashes.len()));
+        let mut done = false;
+        let headroom = self.in_use_headroom().unwrap_or(0);
+
+        while batch.len() < chunk_size {
+            if batch.len() as u64 == headroom {
+                done = true;
+                break;
+            }
+            let Some(hash) = state.hashes.next() else {
+                done = true;
+                break;
+            };
+            let Some(block) = self.take_matched_since(hash, state.start_tick) else {
+                done = true;
+                break;
+            };
+            batch.push(self.create_pool_item(block, self.return_handle.clone()));
+        }
+
+        self.available_blocks
+            .fetch_sub(batch.len() as u64, Ordering::SeqCst);
+        self.counters.matches += batch.len() as u64;
+        self.token_stats.tokens_saved += valid_tokens(&batch);
+
+        if !batch.is_empty() {
+            permit.send(batch);
+        }
+
+        done || state.hashes.as_slice().is_empty()
+    }
+
+    fn handle_reconcile(&mut self, verdict: BlockVerdict, tx: oneshot::Sender<ReconcileReport>) {
+        let in_flight = self
+            .total_blocks
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.available_blocks.load(Ordering::SeqCst));
+
+        // blocks checked out from now on are not covered by this verdict
+        let reconcile_epoch = self.return_handle.reconcile_epoch + 1;
+        self.return_handle = Arc::new(self.return_handle.with_reconcile_epoch(reconcile_epoch));
+        self.deferred_verdict = Some(verdict.clone());
+
+        let hashes: Vec<SequenceHash> = self.lookup_map.keys().copied().collect();
+        self.start_continuation(ContinuationKind::Reconcile(ReconcileContinuation {
+            verdict,
+            blanks_left: self.uninitialized_set.len(),
+            hashes: hashes.into_iter(),
+            report: ReconcileReport {
+                in_flight,
+                ..Default::default()
+            },
+            tx,
+        }));
+    }
+
+    // Returns true once every block has been visited
+    fn reconcile_chunk(&mut self, state: &mut ReconcileContinuation, chunk_size: usize) -> bool {
+        let mut budget = chunk_size;
+
+        // uninitialized blocks are rotated through the back of the set
+        while budget > 0 && state.blanks_left > 0 {
+            budget -= 1;
+            state.blanks_left -= 1;
+
+            let Some(block) = self.pop_uninitialized() else {
+                state.blanks_left = 0;
+                break;
+            };
+
+            match (state.verdict)(&block) {
+                BlockFate::Keep => {
+                    state.report.kept += 1;
+                    self.push_uninitialized(block);
+                }
+                BlockFate::Blank => {
+                    state.report.blanked += 1;
+                    self.push_uninitialized(block);
+                }
+                BlockFate::Remove => {
+                    state.report.removed += 1;
+                    self.remove_idle_slot();
+                }
+            }
+        }
+
+        while budget > 0 {
+            let Some(hash) = state.hashes.next() else {
+                break;
+            };
+            budget -= 1;
+
+            let Some(block) = self.lookup_map.get(&hash) else {
+                continue;
+            };
+
+            match (state.verdict)(block) {
+                BlockFate::Keep => state.report.kept += 1,
+                BlockFate::Blank => {
+                    state.report.blanked += 1;
+                    if let Some(mut block) = self.discard_resident(hash) {
+                        self.reset_block(&mut block);
+                        self.push_uninitialized(block);
+                    }
+                }
+                BlockFate::Remove => {
+                    state.report.removed += 1;
+                    if self.discard_resident(hash).is_some() {
+                        self.remove_idle_slot();
+                    }
+                }
+            }
+        }
+
+        state.blanks_left == 0 && state.hashes.as_slice().is_empty()
+    }
+
+    // Remove a reusable block from the pool without counting it as an eviction
+    fn discard_resident(&mut self, sequence_hash: SequenceHash) -> Option<PoolValue<KvBlock>> {
+        let block = self.take_with_sequence_hash(sequence_hash)?;
+        self.shields.remove(&sequence_hash);
+        self.notify_sequence(sequence_hash, SequenceState::Absent);
+        Some(block)
+    }
+
+    // Account for an idle block which was dropped from the pool by a reconcile
+    fn remove_idle_slot(&mut self) {
+        self.total_blocks.fetch_sub(1, Ordering::SeqCst);
+        self.available_blocks.fetch_sub(1, Ordering::SeqCst);
+        self.record_capacity_change(-1, CapacityChangeReason::Reconcile);
+    }
+
+    // Returns true once all updates have been applied
+    fn update_chunk(&mut self, state: &mut UpdateContinuation, chunk_size: usize) -> bool {
+        let updates: Vec<UpdateBlock> = state.updates.by_ref().take(chunk_size).collect();
+        if let Err(err) = self.update_block(updates) {
+            state.error.get_or_insert(err);
+        }
+        state.updates.as_slice().is_empty()
+    }
+
+    fn complete_op(&mut self, op_id: u64) {
+        let mut observed = false;
+
+        let mut index = 0;
+        while index < self.op_fences.len() {
+            let fence = &mut self.op_fences[index];
+            observed |= fence.remaining.remove(&op_id);
+
+            if fence.remaining.is_empty() {
+                let fence = self.op_fences.swap_remove(index);
+                if fence.tx.send(()).is_err() {
+                    log::trace!("Failed to send fence ops ack; receiver dropped");
+                }
+            } else {
+                index += 1;
+            }
+        }
+
+        if !observed {
+            let window = self
+                .config
+                .completed_ops_window
+                .unwrap_or(COMPLETED_OPS_WINDOW);
+            self.completed_ops.insert(op_id, window);
+        }
+    }
+
+    fn handle_fence_ops(&mut self, ops: Vec<u64>, tx: oneshot::Sender<()>) {
+        let remaining: HashSet<u64> = ops
+            .into_iter()
+            .filter(|op_id| !self.completed_ops.observe(*op_id))
+            .collect();
+
+        if !remaining.is_empty() {
+            self.op_fences.push(OpFence { remaining, tx });
+            return;
+        }
+
+        if tx.send(()).is_err() {
+            log::trace!("Failed to send fence ops ack; receiver dropped");
+        }
+    }
+
+    fn watch_sequence(&mut self, sequence_hash: SequenceHash) -> watch::Receiver<SequenceState> {
+        // watchers of hashes which never change state again are pruned here, amortized over the
+        // registrations which doubled the map since the last prune
+        if self.sequence_watchers.len() >= self.watcher_prune_at {
+            self.sequence_watchers
+                .retain(|_, tx| tx.receiver_count() > 0);
+            self.watcher_prune_at = (2 * self.sequence_watchers.len()).max(WATCHER_PRUNE_MIN);
+        }
+
+        let state = if self.lookup_map.contains_key(&sequence_hash) {
+            SequenceState::Cached
+        } else {
+            SequenceState::Absent
+        };
+
+        self.sequence_watchers
+            .entry(sequence_hash)
+            .or_insert_with(|| watch::channel(state).0)
+            .subscribe()
+    }
+
+    // Publish a state transition to the watchers of a sequence hash, if any
+    // A cached block leaves the pool for a match or a maintenance checkout
+    fn check_out(&mut self, sequence_hash: SequenceHash) {
+        *self.checked_out.entry(sequence_hash).or_default() += 1;
+        self.notify_sequence(sequence_hash, SequenceState::InUse);
+    }
+
+    fn check_in(&mut self, sequence_hash: SequenceHash) {
+        if let Some(count) = self.checked_out.get_mut(&sequence_hash) {
+            *count -= 1;
+            if *count == 0 {
+                self.checked_out.remove(&sequence_hash);
+            }
+        }
+    }
+
+    fn notify_sequence(&mut self, sequence_hash: SequenceHash, state: SequenceState) {
+        if self.config.fast_probe || self.config.shared_content_index.is_some() {
+            self.residency_changes.push((sequence_hash, state));
+        }
+
+        if self.sequence_watchers.is_empty() {
+            return;
+        }
+
+        let Some(tx) = self.sequence_watchers.get(&sequence_hash) else {
+            return;
+        };
+
+        if tx.receiver_count() == 0 {
+            self.sequence_watchers.remove(&sequence_hash);
+            return;
+        }
+
+        tx.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+    // Whether an inserted block belongs in the uninitialized set; see
+    // AvailableBlocksConfig::with_uninitialized_rule
+    fn is_uninitialized(&self, block: &KvBlock) -> bool {
+        match self.config.uninitialized_rule {
+            Some(is_uninitialized) => is_uninitialized(block),
+            None => block.lookup_key() == 0,
+        }
+    }
+
+    // Insert an item with a given key and sequence_hash
+    fn insert(&mut self, mut block: PoolValue<KvBlock>) {
+        let sequence_hash = block.lookup_key();
+        let deadline = block.deadline.take();
+        block.set_pool(self.pool_ref.clone());
+        block.sticky = self.sticky_refs.contains_key(&sequence_hash);
+        log::debug!(sequence_hash, "inserting block into available blocks");
+
+        // If we already have an entry for this sequence hash, we need to move it to the uninitialized set
+        // the lookup map has only one entry per sequence hash
+        if self.lookup_map.contains_key(&sequence_hash) || self.is_uninitialized(&block) {
+            log::debug!(sequence_hash, "inserted block to uninitialized set");
+            self.push_uninitialized(block);
+            return;
+        }
+
+        if self.is_recently_evicted(sequence_hash) {
+            log::debug!(
+                sequence_hash,
+                "suppressed re-admission of recently evicted block"
+            );
+            self.push_uninitialized(block);
+            return;
+        }
+
+        self.join_affinity_group(&mut block);
+
+        // Insert into timestamp set
+        let key = PriorityKey::from(&*block);
         let check_multiple_entries = self.priority_set.insert(key, sequence_hash);
         assert!(
             check_multiple_entries.is_none(),
             "fatal error: multiple entries for the same sequence hash in timestamp set"
         );
 
-        // Add to the lookup map
-        let check_multiple_entries = self.lookup_map.insert(sequence_hash, block);
-        assert!(
-            check_multiple_entries.is_none(),
-            "fatal error: multiple entries for the same sequence hash in lookup map"
+        // Add to the lookup map
+        self.link_chain(sequence_hash, block.token_block.parent_sequence_hash());
+        let check_multiple_entries = self.lookup_map.insert(sequence_hash, block);
+        assert!(
+            check_multiple_entries.is_none(),
+            "fatal error: multiple entries for the same sequence hash in lookup map"
+        );
+
+        // a session deadline becomes a retention window for the cached block
+        if let Some(deadline) = deadline {
+            if deadline > Instant::now() {
+                self.shields
+                    .entry(sequence_hash)
+                    .or_insert(Shield::new(deadline, None))
+                    .shield_until(deadline);
+            }
+        }
+
+        // the block is hot again
+        self.cold_entries.remove(&sequence_hash);
+        self.mirrored.remove(&sequence_hash);
+
+        self.notify_sequence(sequence_hash, SequenceState::Cached);
+        self.enforce_band(key.priority);
+    }
+
+    fn band_keys<'a>(
+        &'a self,
+        band: &'a ReservedBand,
+    ) -> impl Iterator<Item = &'a PriorityKey> + 'a {
+        let start = PriorityKey {
+            priority: *band.priorities.start(),
+            sticky: false,
+            affinity_tick: 0,
+            return_tick: 0,
+            sequence_hash: 0,
+        };
+        self.priority_set
+            .range(start..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.priority <= *band.priorities.end())
+    }
+
+    fn band_reservation(&self, band: &ReservedBand) -> usize {
+        let total = self.total_blocks.load(Ordering::SeqCst);
+        (band.fraction * total as f64).floor() as usize
+    }
+
+    // Priority ranges of the bands at or under their reservation, which takes must not evict from
+    fn protected_bands(&self) -> Vec<RangeInclusive<u32>> {
+        self.config
+            .reserved_bands
+            .iter()
+            .enumerate()
+            .filter(|(index, band)| {
+                self.priority_set.band_len(*index) <= self.band_reservation(band)
+            })
+            .map(|(_, band)| band.priorities.clone())
+            .collect()
+    }
+
+    // Evict within the band containing `priority` until it is back under its cap
+    fn enforce_band(&mut self, priority: BlockPriority) {
+        let Some(index) = self
+            .config
+            .reserved_bands
+            .iter()
+            .position(|band| band.priorities.contains(&priority))
+        else {
+            return;
+        };
+        let band = self.config.reserved_bands[index].clone();
+
+        let reservation = self.band_reservation(&band);
+        while self.priority_set.band_len(index) > reservation {
+            let Some(key) = self.band_keys(&band).next().copied() else {
+                break;
+            };
+            let Some(sequence_hash) = self.priority_set.remove(&key) else {
+                break;
+            };
+            log::debug!(sequence_hash, "evicting block from full reserved band");
+            let mut block = self.evict(sequence_hash);
+            self.reset_block(&mut block);
+            self.push_uninitialized(block);
+        }
+    }
+
+    fn take_with_sequence_hash(
+        &mut self,
+        sequence_hash: SequenceHash,
+    ) -> Option<PoolValue<KvBlock>> {
+        match self.lookup_map.remove(&sequence_hash) {
+            Some(block) => {
+                // Remove from timestamp set
+                self.priority_set.remove(&PriorityKey::from(&*block));
+                self.leave_affinity_group(&block);
+                self.unlink_chain(sequence_hash, block.token_block.parent_sequence_hash());
+                Some(block)
+            }
+            None => None,
+        }
+    }
+
+    // Record a block entering the lookup map under its parent in the chain index
+    fn link_chain(&mut self, sequence_hash: SequenceHash, parent: Option<SequenceHash>) {
+        if let Some(parent) = parent {
+            self.chain_children
+                .entry(parent)
+                .or_default()
+                .push(sequence_hash);
+        }
+    }
+
+    // Drop a block leaving the lookup map from the chain index
+    fn unlink_chain(&mut self, sequence_hash: SequenceHash, parent: Option<SequenceHash>) {
+        let Some(parent) = parent else {
+            return;
+        };
+        if let Some(children) = self.chain_children.get_mut(&parent) {
+            children.retain(|&child| child != sequence_hash);
+            if children.is_empty() {
+                self.chain_children.remove(&parent);
+            }
+        }
+    }
+
+    fn match_hashes(
+        &mut self,
+        hashes: Vec<SequenceHash>,
+        single_use: bool,
+    ) -> Vec<PoolItem<KvBlock>> {
+        let headroom = self.in_use_headroom().unwrap_or(0);
+        let mut matched_blocks = Vec::with_capacity(hashes.len());
+
+        #[cfg(feature = "trace-record")]
+        let traced = self.trace_op().then(|| hashes.clone());
+
+        for hash in hashes {
+            // the in-use cap ends the match without a miss
+            if matched_blocks.len() as u64 == headroom {
+                break;
+            }
+            if let Some(mut block) = self.take_with_sequence_hash(hash) {
+                block.single_use = single_use;
+                self.remove_shield(hash);
+                self.check_out(hash);
+                self.record_hit(hash);
+                matched_blocks.push(self.create_pool_item(block, self.return_handle.clone()));
+            } else {
+                self.record_miss(hash);
+                break;
+            }
+        }
+
+        self.available_blocks
+            .fetch_sub(matched_blocks.len() as u64, Ordering::SeqCst);
+        self.counters.matches += matched_blocks.len() as u64;
+        self.token_stats.tokens_saved += valid_tokens(&matched_blocks);
+
+        #[cfg(feature = "trace-record")]
+        if let Some(hashes) = traced {
+            let requested = hashes.len();
+            self.record_trace(TraceOp::Match, hashes, requested, matched_blocks.len());
+        }
+
+        matched_blocks
+    }
+
+    // Same as match_hashes for a single hash
+    fn match_hash(&mut self, hash: SequenceHash) -> Option<UniqueBlock> {
+        self.match_hashes(vec![hash], false).into_iter().next()
+    }
+
+    // The hash ending the cached prefix of `hashes`, if its block is checked out for maintenance
+    fn under_maintenance(&self, hashes: &[SequenceHash]) -> Option<SequenceHash> {
+        hashes
+            .iter()
+            .find(|hash| !self.lookup_map.contains_key(*hash))
+            .filter(|hash| self.maintenance.contains(*hash))
+            .copied()
+    }
+
+    fn handle_checkout_for_maintenance(&mut self, hash: SequenceHash) -> Option<UniqueBlock> {
+        let mut block = self.take_with_sequence_hash(hash)?;
+        block.maintenance = Some(MaintenanceCheckout {
+            sequence_hash: hash,
+            bump_recency: false,
+        });
+        self.maintenance.insert(hash);
+        self.check_out(hash);
+        self.available_blocks.fetch_sub(1, Ordering::SeqCst);
+        self.counters.maintenance_checkouts += 1;
+        Some(self.create_pool_item(block, self.return_handle.clone()))
+    }
+
+    fn record_miss(&mut self, sequence_hash: SequenceHash) {
+        if let Some(misses) = self.misses.as_mut() {
+            misses.record(sequence_hash);
+        }
+    }
+
+    fn handle_match_single(&mut self, match_single: MatchSingle) {
+        let (hash, rx) = match_single.dissolve();
+
+        let optional_single = self.in_use_headroom().map(|_| self.match_hash(hash));
+        let hits = matches!(optional_single, Ok(Some(_))) as usize;
+
+        // Send the result back through the channel
+        if rx.send(optional_single).is_err() {
+            log::trace!("Failed to send matched block to requester");
+        }
+        self.finish_op(OpKind::Match, hits, 1 - hits);
+    }
+
+    fn handle_match_if_version(&mut self, match_if_version: MatchIfVersion) {
+        let (hash, version, tx) = match_if_version.dissolve();
+
+        let current = self
+            .lookup_map
+            .get(&hash)
+            .is_none_or(|block| block.version == version);
+        let matched = if current {
+            self.match_hashes(vec![hash], false).into_iter().next()
+        } else {
+            log::debug!(
+                sequence_hash = hash,
+                expected_version = version,
+                "block version changed; not matching"
+            );
+            self.record_miss(hash);
+            None
+        };
+
+        if tx.send(Ok(matched)).is_err() {
+            log::trace!("Failed to send matched block to requester");
+        }
+    }
+
+    fn handle_match_multiple(&mut self, match_multiple: MatchMultiple) {
+        let (mut hashes, single_use, wait_for_maintenance, rx) = match_multiple.dissolve();
+        let requested = hashes.len();
+
+        // the client retries once the block is re-published; the retry reports the operation
+        if wait_for_maintenance {
+            if let Some(hash) = self.under_maintenance(&hashes) {
+                if rx.send(Err(KvPoolError::UnderMaintenance(hash))).is_err() {
+                    log::trace!("Failed to send match error to requester");
+                }
+                return;
+            }
+        }
+
+        match self.in_use_headroom() {
+            Ok(headroom) => hashes.truncate(usize::try_from(headroom).unwrap_or(usize::MAX)),
+            Err(err) => {
+                if rx.send(Err(err)).is_err() {
+                    log::trace!("Failed to send match error to requester");
+                }
+                self.finish_op(OpKind::Match, 0, requested);
+                return;
+            }
+        }
+
+        if self.chunks_requests() {
+            self.start_continuation(ContinuationKind::Match(MatchContinuation {
+                requested,
+                hashes: hashes.into_iter(),
+                single_use,
+                start_tick: self.return_tick,
+                matched: Vec::new(),
+                tx: rx,
+            }));
+            return;
+        }
+
+        let matched_blocks = self.match_hashes(hashes, single_use);
+        let hits = matched_blocks.len();
+
+        // Send the matched blocks back through the channel
+        if rx.send(Ok(matched_blocks)).is_err() {
+            log::trace!("Failed to send matched blocks to requester");
+        }
+        self.finish_op(OpKind::Match, hits, requested - hits);
+    }
+
+    fn handle_acquire_and_demote(&mut self, acquire_and_demote: AcquireAndDemote) {
+        let (hashes, demote, priority, tx) = acquire_and_demote.dissolve();
+
+        let matched_blocks = self.match_hashes(hashes, false);
+        for hash in demote {
+            let Some(current) = self.lookup_map.get(&hash).map(|block| block.priority) else {
+                continue;
+            };
+            if current <= priority {
+                continue;
+            }
+
+            let mut block = self
+                .take_with_sequence_hash(hash)
+                .expect("block present in lookup map");
+            block.priority = priority;
+            self.insert(block);
+        }
+
+        if tx.send(Ok(matched_blocks)).is_err() {
+            log::trace!("Failed to send matched blocks to requester");
+        }
+    }
+
+    fn handle_allocate(&mut self, allocate: Allocate) {
+        let (mut hashes, extra, request_tokens, max_blocks, tx) = allocate.dissolve();
+        let requested = hashes.len();
+
+        if let Err(err) = self.in_use_headroom() {
+            if tx.send(Err(err)).is_err() {
+                log::trace!("Failed to send allocation error to requester");
+            }
+            self.finish_op(OpKind::Allocate, 0, requested);
+            return;
+        }
+        let resume_from = truncate_prefix(&mut hashes, max_blocks);
+
+        let matched = self.match_hashes(hashes, false);
+        let hits = matched.len();
+        if let Some(request_tokens) = request_tokens {
+            self.record_request_tokens(valid_tokens(&matched), request_tokens);
+        }
+
+        // the hashes past a truncated match may still be cached, so nothing is taken for them
+        let resume_from = resume_from.filter(|_| Some(hits) == max_blocks);
+        let takes = match resume_from {
+            Some(_) => 0,
+            None => requested - hits + extra as usize,
+        };
+        let taken = self.take_items(takes as u32, self.return_handle.clone());
+
+        // the hashes past the cut were not looked up, so they are not counted as misses
+        let misses = match resume_from {
+            Some(_) => 0,
+            None => requested - hits,
+        };
+        let allocation = Allocation {
+            matched,
+            taken,
+            truncated: resume_from.is_some(),
+            resume_from,
+        };
+        if tx.send(Ok(allocation)).is_err() {
+            log::trace!("Failed to send allocation to requester");
+        }
+        self.finish_op(OpKind::Allocate, hits, misses);
+    }
+
+    fn handle_allocate_batch(&mut self, allocate: AllocateBatch) {
+        let (requests, mode, tx) = allocate.dissolve();
+        let plan = self.plan_batch(&requests, mode);
+
+        // match for every request before the first take, so takes can not evict planned matches
+        let matched: Vec<Option<(Vec<UniqueBlock>, usize)>> = requests
+            .into_iter()
+            .zip(plan)
+            .map(|(request, prefix)| {
+                prefix.map(|prefix| {
+                    let takes = request.hashes.len() - prefix;
+                    let mut hashes = request.hashes;
+                    hashes.truncate(prefix);
+                    (self.match_hashes(hashes, false), takes)
+                })
+            })
+            .collect();
+        // the plan leaves the takes within every limit of take_with_hits, so none of them fails
+        let allocations = matched
+            .into_iter()
+            .map(|planned| {
+                planned.map(|(matched, takes)| {
+                    let taken = self
+                        .take_with_hits(takes as u32, matched.len())
+                        .unwrap_or_default();
+                    Allocation {
+                        matched,
+                        taken,
+                        truncated: false,
+                        resume_from: None,
+                    }
+                })
+            })
+            .collect();
+
+        if tx.send(Ok(allocations)).is_err() {
+            log::trace!("Failed to send batch allocation to requester");
+        }
+    }
+
+    // Length of the prefix to match for each request the batch serves, without changing the pool
+    //
+    // Capacity is tracked conservatively: a matched block counts against it whenever a take
+    // could have evicted it, even if an earlier take of the batch would have reached its band's
+    // reservation first. Matched and taken blocks both count against the in-use cap, and the
+    // takes of a request without hits leave the hit reserve.
+    fn plan_batch(&self, requests: &[AllocateSpec], mode: BatchMode) -> Vec<Option<usize>> {
+        let bands = &self.config.reserved_bands;
+        let reservations: Vec<usize> = bands
+            .iter()
+            .map(|band| self.band_reservation(band))
+            .collect();
+        let mut band_blocks: Vec<usize> = (0..bands.len())
+            .map(|index| self.priority_set.band_len(index))
+            .collect();
+        let mut capacity = self.unreserved_blocks() as usize;
+        let mut in_use_room =
+            usize::try_from(self.in_use_headroom().unwrap_or(0)).unwrap_or(usize::MAX);
+        let reserve = self.hit_reserve() as usize;
+        let mut claimed = HashSet::new();
+
+        let mut plan = Vec::with_capacity(requests.len());
+        for request in requests {
+            let prefix = request
+                .hashes
+                .iter()
+                .take_while(|hash| self.lookup_map.contains_key(hash) && !claimed.contains(*hash))
+                .count();
+
+            // matched blocks are no longer there for the takes to evict
+            let mut remaining = capacity;
+            let mut remaining_bands = band_blocks.clone();
+            for hash in &request.hashes[..prefix] {
+                let priority = self.lookup_map[hash].priority;
+                match bands
+                    .iter()
+                    .position(|band| band.priorities.contains(&priority))
+                {
+                    Some(band) => {
+                        if remaining_bands[band] > reservations[band] {
+                            remaining -= 1;
+                        }
+                        remaining_bands[band] -= 1;
+                    }
+                    None => remaining -= 1,
+                }
+            }
+
+            let takes = request.hashes.len() - prefix;
+            let limit = match prefix {
+                0 => remaining.saturating_sub(reserve),
+                _ => remaining,
+            };
+            if takes > limit || request.hashes.len() > in_use_room {
+                match mode {
+                    BatchMode::AllOrNothing => return vec![None; requests.len()],
+                    BatchMode::BestEffortRanked => break,
+                }
+            }
+
+            capacity = remaining - takes;
+            in_use_room -= request.hashes.len();
+            band_blocks = remaining_bands;
+            claimed.extend(request.hashes[..prefix].iter().copied());
+            plan.push(Some(prefix));
+        }
+
+        plan.resize(requests.len(), None);
+        plan
+    }
+
+    // Whether the operation being handled is recorded; an operation with a context is sampled
+    // by its request, so the operations of a request are recorded together
+    #[cfg(feature = "trace-record")]
+    fn trace_sampled(&mut self) -> bool {
+        let request_id = self.op_context.map(|active| active.context.request_id);
+        self.recorder
+            .as_mut()
+            .is_some_and(|recorder| match request_id {
+                Some(request_id) => recorder.sample_request(request_id),
+                None => recorder.sample(),
+            })
+    }
+
+    // Whether the request being dispatched is recorded, sampled once for all of its matches
+    // and takes
+    #[cfg(feature = "trace-record")]
+    fn trace_op(&mut self) -> bool {
+        if let Some(traced) = self.trace_op {
+            return traced;
+        }
+        let traced = self.trace_sampled();
+        self.trace_op = Some(traced);
+        traced
+    }
+
+    #[cfg(feature = "trace-record")]
+    fn record_trace(
+        &mut self,
+        op: TraceOp,
+        hashes: Vec<SequenceHash>,
+        requested: usize,
+        served: usize,
+    ) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(op, hashes, requested, served);
+        }
+    }
+
+    // Account the tokens of an allocated request in the totals and the hit window
+    fn record_request_tokens(&mut self, saved: u64, requested: u64) {
+        self.token_stats.tokens_recomputed += requested.saturating_sub(saved);
+
+        let Some(window) = self.config.token_hit_window else {
+            return;
+        };
+        let now = Instant::now();
+        self.roll_token_window(now, window);
+
+        let saved = saved.min(requested);
+        match self.token_buckets.back_mut() {
+            Some(bucket) if now < bucket.start + window.bucket => {
+                bucket.saved += saved;
+                bucket.requested += requested;
+            }
+            _ => self.token_buckets.push_back(TokenBucket {
+                start: now,
+                saved,
+                requested,
+            }),
+        }
+    }
+
+    // Drop the buckets which have left the hit window
+    fn roll_token_window(&mut self, now: Instant, window: TokenHitWindow) {
+        let span = window.bucket * window.buckets as u32;
+        while self
+            .token_buckets
+            .front()
+            .is_some_and(|bucket| bucket.start + span <= now)
+        {
+            self.token_buckets.pop_front();
+        }
+    }
+
+    fn token_stats(&mut self) -> TokenStats {
+        if let Some(window) = self.config.token_hit_window {
+            self.roll_token_window(Instant::now(), window);
+        }
+        TokenStats {
+            window_tokens_saved: self.token_buckets.iter().map(|bucket| bucket.saved).sum(),
+            window_tokens_requested: self
+                .token_buckets
+                .iter()
+                .map(|bucket| bucket.requested)
+                .sum(),
+            ..self.token_stats
+        }
+    }
+
+    fn publish_token_stats(&mut self) {
+        let stats = self.token_stats();
+        self.token_stats_tx.send_if_modified(|published| {
+            let changed = *published != stats;
+            *published = stats;
+            changed
+        });
+    }
+
+    fn finish_op(&mut self, op: OpKind, hits: usize, misses: usize) {
+        if let Some(active) = self.op_context.take() {
+            self.report_op(active, op, hits, misses);
+        }
+    }
+
+    fn report_op(&mut self, active: ActiveOp, op: OpKind, hits: usize, misses: usize) {
+        let event = OpCompleted {
+            request_id: active.context.request_id,
+            op,
+            queued: active
+                .dispatched
+                .saturating_duration_since(active.context.enqueued),
+            handled: active.dispatched.elapsed(),
+            hits,
+            misses,
+        };
+
+        let samples = self.op_latencies.entry(op).or_default();
+        if samples.len() == OP_LATENCY_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(event.queued + event.handled);
+
+        // nobody may be subscribed
+        let _ = self.op_events.send(event);
+    }
+
+    fn op_latency_percentiles(&self) -> Vec<OpLatency> {
+        let mut latencies: Vec<OpLatency> = self
+            .op_latencies
+            .iter()
+            .map(|(op, samples)| {
+                let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+                sorted.sort_unstable();
+                let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+                OpLatency {
+                    op: *op,
+                    samples: sorted.len(),
+                    p50: percentile(50),
+                    p99: percentile(99),
+                }
+            })
+            .collect();
+        latencies.sort_by_key(|latency| latency.op);
+        latencies
+    }
+
+    fn handle_match_stream(&mut self, match_stream: MatchStream) {
+        let (hashes, tx) = match_stream.dissolve();
+        self.start_continuation(ContinuationKind::MatchStream(MatchStreamContinuation {
+            hashes: hashes.into_iter(),
+            start_tick: self.return_tick,
+            tx,
+            backpressured: false,
+        }));
+    }
+
+    fn handle_match_detailed(&mut self, match_detailed: MatchDetailed) {
+        let (hashes, tx) = match_detailed.dissolve();
+
+        // ranks are computed before any of the matched blocks leave the priority set
+        let details: Vec<(SequenceHash, BlockPriority, u64, Option<usize>, u64)> = hashes
+            .iter()
+            .map_while(|hash| {
+                let block = self.lookup_map.get(hash)?;
+                let age = self.return_tick.saturating_sub(block.return_tick);
+                let rank = self.eviction_rank(&PriorityKey::from(&**block));
+                Some((*hash, block.priority, age, rank, block.version))
+            })
+            .collect();
+        if let Some(missed) = hashes.get(details.len()) {
+            self.record_miss(*missed);
+        }
+
+        let blocks = self.match_hashes(details.iter().map(|detail| detail.0).collect(), false);
+        let matched_blocks = blocks
+            .into_iter()
+            .zip(details)
+            .map(|(block, (_, priority, age, rank, version))| MatchedBlock {
+                block,
+                priority,
+                age,
+                rank,
+                version,
+            })
+            .collect::<Vec<_>>();
+
+        if tx.send(Ok(matched_blocks)).is_err() {
+            log::trace!("Failed to send matched blocks to requester");
+        }
+    }
+
+    // Position of a key in eviction order, counted up to the horizon
+    fn eviction_rank(&self, key: &PriorityKey) -> Option<usize> {
+        let rank = self
+            .priority_set
+            .range(..*key)
+            .take(EVICTION_RANK_HORIZON)
+            .count();
+        (rank < EVICTION_RANK_HORIZON).then_some(rank)
+    }
+
+    fn record_eviction(&mut self, sequence_hash: SequenceHash) {
+        if self.config.eviction_hysteresis.is_none() {
+            return;
+        }
+
+        self.recently_evicted
+            .record(sequence_hash, self.return_tick);
+    }
+
+    fn handle_insert_once(&mut self, op_id: u128, blocks: Vec<KvBlock>) -> InsertOutcome {
+        if self.applied_ops.contains(op_id) {
+            self.counters.duplicate_inserts += 1;
+            return InsertOutcome::Duplicate;
+        }
+
+        let window = self
+            .config
+            .insert_dedupe_window
+            .unwrap_or(INSERT_DEDUPE_WINDOW);
+        self.applied_ops.insert(op_id, window);
+
+        for block in blocks {
+            self.handle_insert(block);
+        }
+        InsertOutcome::Applied
+    }
+
+    fn is_recently_evicted(&mut self, sequence_hash: SequenceHash) -> bool {
+        let Some(window) = self.config.eviction_hysteresis else {
+            return false;
+        };
+
+        self.recently_evicted.expire(window, self.return_tick);
+        self.recently_evicted.contains(sequence_hash)
+    }
+
+    fn aux_bytes(&self) -> usize {
+        self.recently_evicted.aux_bytes()
+            + self.misses.as_ref().map_or(0, |misses| misses.aux_bytes())
+    }
+
+    // Shed auxiliary history, oldest kind first, until it fits within with_aux_budget
+    fn enforce_aux_budget(&mut self) {
+        let Some(budget) = self.config.aux_budget else {
+            return;
+        };
+        let mut over = self.aux_bytes().saturating_sub(budget);
+        if over == 0 {
+            return;
+        }
+
+        over = self
+            .recently_evicted
+            .shed_over(over, &mut self.counters.aux_shed_evictions);
+        if let Some(misses) = self.misses.as_mut() {
+            misses.shed_over(over, &mut self.counters.aux_shed_misses);
+        }
+    }
+
+    // Key a block entering the eviction order by the newest return tick of its affinity group,
+    // pulling the group's cached members up to the block's tick if it is newer
+    fn join_affinity_group(&mut self, block: &mut KvBlock) {
+        block.affinity_tick = 0;
+        if !self.config.affinity_grouping {
+            return;
+        }
+        block.affinity_tick = block.return_tick;
+        let Some(group_id) = block.affinity else {
+            return;
+        };
+
+        let group = self.affinity_groups.entry(group_id).or_default();
+        if group.tick >= block.return_tick {
+            block.affinity_tick = group.tick;
+            group.members.insert(block.lookup_key());
+            return;
+        }
+        group.tick = block.return_tick;
+        let members: Vec<SequenceHash> = group.members.iter().copied().collect();
+
+        let mut stale = Vec::new();
+        for hash in members {
+            let Some(member) = self.lookup_map.get_mut(&hash) else {
+                stale.push(hash);
+                continue;
+            };
+            if member.affinity != Some(group_id) {
+                stale.push(hash);
+                continue;
+            }
+            if self
+                .priority_set
+                .remove(&PriorityKey::from(&**member))
+                .is_some()
+            {
+                member.affinity_tick = block.return_tick;
+                self.priority_set.insert(PriorityKey::from(&**member), hash);
+            }
+        }
+
+        let group = self.affinity_groups.entry(group_id).or_default();
+        for hash in stale {
+            group.members.remove(&hash);
+        }
+        group.members.insert(block.lookup_key());
+    }
+
+    fn leave_affinity_group(&mut self, block: &KvBlock) {
+        let Some(group_id) = block.affinity else {
+            return;
+        };
+        if let Some(group) = self.affinity_groups.get_mut(&group_id) {
+            group.members.remove(&block.lookup_key());
+            if group.members.is_empty() {
+                self.affinity_groups.remove(&group_id);
+            }
+        }
+    }
+
+    // Reset a block leaving the cache; blanks keep their token storage only under a storage cap,
+    // which releases it past the cap
+    fn reset_block(&self, block: &mut KvBlock) {
+        if self.config.blank_storage_cap.is_some() {
+            block.reset_keeping_storage();
+        } else {
+            block.reset();
+        }
+    }
+
+    fn push_uninitialized(&mut self, mut block: PoolValue<KvBlock>) {
+        if let Some(cap) = self.config.blank_storage_cap {
+            if self.uninitialized_set.len() >= cap.cap {
+                self.blank_released_bytes += block.token_block.storage_bytes() as u64;
+                block.token_block.release_storage();
+            }
+        }
+
+        if self.config.uninitialized_ttl.is_some() {
+            block.blank_since = Some(Instant::now());
+        }
+        self.blank_bytes += block.token_block.storage_bytes();
+        self.uninitialized_set.push_back(block);
+    }
+
+    fn pop_uninitialized(&mut self) -> Option<PoolValue<KvBlock>> {
+        let block = self.uninitialized_set.pop_front()?;
+        self.blank_bytes -= block.token_block.storage_bytes();
+        Some(block)
+    }
+
+    fn take(&mut self) -> Option<PoolValue<KvBlock>> {
+        self.take_with_priority().map(|(_, block)| block)
+    }
+
+    // Take a block along with the priority it was taken at; `None` for an uninitialized block
+    fn take_with_priority(&mut self) -> Option<(Option<BlockPriority>, PoolValue<KvBlock>)> {
+        // First try uninitialized blocks - these are often part of sequences
+        // that have been arranged in the correct order
+        if let Some(mut block) = self.pop_uninitialized() {
+            if let Some(cap) = self.config.blank_storage_cap {
+                block.token_block.reserve_storage(cap.block_size);
+            }
+            return Some((None, block));
+        }
+
+        // if we have blocks in the priority set, pop the first (it's sorted by priority)
+        self.expire_sticky();
+        if self.shields.is_empty() && self.config.reserved_bands.is_empty() {
+            let (key, sequence_hash) = self.priority_set.pop_first()?;
+            return Some((Some(key.priority), self.evict(sequence_hash)));
+        }
+
+        let key = self.pick_victim(None)?;
+        let sequence_hash = self.priority_set.remove(&key)?;
+        Some((Some(key.priority), self.evict(sequence_hash)))
+    }
+
+    fn take_at_priority(&mut self, priority: BlockPriority) -> Option<PoolValue<KvBlock>> {
+        let key = self.pick_victim(Some(priority))?;
+        let sequence_hash = self.priority_set.remove(&key)?;
+        Some(self.evict(sequence_hash))
+    }
+
+    // The first key in eviction order, optionally restricted to one priority, which is not
+    // shielded; if every candidate is shielded, the first candidate is sacrificed. Keys in a
+    // protected reserved band are never candidates.
+    fn pick_victim(&mut self, priority: Option<BlockPriority>) -> Option<PriorityKey> {
+        self.expire_shields();
+        self.expire_sticky();
+        let protected = self.protected_bands();
+
+        // the oldest block at a priority is the first key at or after (priority, 0)
+        let start = PriorityKey {
+            priority: priority.unwrap_or_default(),
+            sticky: false,
+            affinity_tick: 0,
+            return_tick: 0,
+            sequence_hash: 0,
+        };
+        let mut candidates = self
+            .priority_set
+            .range(start..)
+            .take_while(|(key, _)| priority.is_none_or(|priority| key.priority == priority))
+            .filter_map(|(key, _)| {
+                self.victim_candidate(key, &protected)
+                    .map(|shielded| (*key, shielded))
+            });
+
+        let (first, shielded) = candidates.next()?;
+        if !shielded {
+            return Some(first);
+        }
+
+        match candidates.find(|(_, shielded)| !shielded) {
+            Some((key, _)) => Some(key),
+            None => {
+                log::debug!(
+                    sequence_hash = first.sequence_hash,
+                    "evicting shielded block"
+                );
+                self.counters.shield_breaches += 1;
+                Some(first)
+            }
+        }
+    }
+
+    // Whether a take may pick `key` given the `protected` bands, and if so whether it is
+    // shielded: a shielded key is only picked once every candidate is shielded
+    fn victim_candidate(
+        &self,
+        key: &PriorityKey,
+        protected: &[RangeInclusive<u32>],
+    ) -> Option<bool> {
+        if protected.iter().any(|band| band.contains(&key.priority)) {
+            return None;
+        }
+        Some(self.shields.contains_key(&key.sequence_hash))
+    }
+
+    fn handle_stick(
+        &mut self,
+        mut hashes: Vec<SequenceHash>,
+        session_id: u64,
+        ttl: Duration,
+    ) -> usize {
+        self.release_sticky(session_id);
+
+        if let Some(cap) = self.config.sticky_cap {
+            hashes.truncate(cap);
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+        for &hash in &hashes {
+            *self.sticky_refs.entry(hash).or_default() += 1;
+            self.restick(hash);
+        }
+
+        let stuck = hashes.len();
+        let expiry = Instant::now() + ttl;
+        self.sticky_expiry.insert((expiry, session_id));
+        self.sticky_sessions
+            .insert(session_id, StickySession { hashes, expiry });
+        stuck
+    }
+
+    // End a sticky session; returns the number of hashes it held
+    fn release_sticky(&mut self, session_id: u64) -> usize {
+        let Some(session) = self.sticky_sessions.remove(&session_id) else {
+            return 0;
+        };
+        self.sticky_expiry.remove(&(session.expiry, session_id));
+
+        for &hash in &session.hashes {
+            if let Some(refs) = self.sticky_refs.get_mut(&hash) {
+                *refs -= 1;
+                if *refs == 0 {
+                    self.sticky_refs.remove(&hash);
+                }
+            }
+            self.restick(hash);
+        }
+        session.hashes.len()
+    }
+
+    fn expire_sticky(&mut self) {
+        let now = Instant::now();
+        while let Some(&(expiry, session_id)) = self.sticky_expiry.first() {
+            if expiry > now {
+                break;
+            }
+            self.release_sticky(session_id);
+        }
+    }
+
+    // Re-key a cached block whose stickiness changed
+    fn restick(&mut self, sequence_hash: SequenceHash) {
+        let sticky = self.sticky_refs.contains_key(&sequence_hash);
+        let Some(block) = self.lookup_map.get_mut(&sequence_hash) else {
+            return;
+        };
+        if block.sticky == sticky {
+            return;
+        }
+        self.priority_set.remove(&PriorityKey::from(&**block));
+        block.sticky = sticky;
+        self.priority_set
+            .insert(PriorityKey::from(&**block), sequence_hash);
+    }
+
+    fn expire_shields(&mut self) {
+        if self.shields.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        self.shields.retain(|_, shield| shield.expiry > now);
+    }
+
+    // Drop the shield of a matched block; waiters on a soft hold learn it was claimed
+    fn remove_shield(&mut self, hash: SequenceHash) {
+        if let Some(Shield {
+            holder: Some(_), ..
+        }) = self.shields.remove(&hash)
+        {
+            self.holds.notify_waiters();
+        }
+    }
+
+    // Whether the block is soft held, unexpired, by a holder other than `holder`
+    fn held_by_other(&self, hash: &SequenceHash, holder: Option<u64>, now: Instant) -> bool {
+        self.shields.get(hash).is_some_and(|shield| {
+            shield.expiry > now && shield.holder.is_some() && shield.holder != holder
+        })
+    }
+
+    fn handle_expect(&mut self, hashes: Vec<SequenceHash>, ttl: Duration) -> usize {
+        let expiry = Instant::now() + ttl;
+        let mut shielded = 0;
+
+        for hash in hashes {
+            if self.lookup_map.contains_key(&hash) {
+                // a peek hold on the block keeps its holder
+                let shield = self
+                    .shields
+                    .entry(hash)
+                    .or_insert(Shield::new(expiry, None));
+                shield.shield_until(expiry);
+                shielded += 1;
+            }
+        }
+
+        shielded
+    }
+
+    fn handle_peek_hold(&mut self, holder: u64, hashes: Vec<SequenceHash>, ttl: Duration) -> usize {
+        let now = Instant::now();
+        let expiry = now + ttl;
+        let mut held = 0;
+
+        // the held prefix ends at the first block not cached or held by another holder
+        for hash in hashes {
+            if !self.lookup_map.contains_key(&hash) || self.held_by_other(&hash, Some(holder), now)
+            {
+                break;
+            }
+            // a hold placed over a shield lasts at least as long, and leaves it behind on release
+            let shield = self
+                .shields
+                .entry(hash)
+                .or_insert(Shield::new(expiry, Some(holder)));
+            if shield.holder.is_none() {
+                shield.unheld_expiry = Some(shield.expiry);
+            }
+            shield.holder = Some(holder);
+            shield.expiry = shield.expiry.max(expiry);
+            held += 1;
+        }
+
+        held
+    }
+
+    fn handle_release_holds(&mut self, holder: u64) -> usize {
+        let now = Instant::now();
+        let mut released = 0;
+        self.shields.retain(|_, shield| {
+            if shield.holder != Some(holder) {
+                return true;
+            }
+            released += 1;
+            shield.holder = None;
+            match shield.unheld_expiry.take() {
+                Some(expiry) if expiry > now => {
+                    shield.expiry = expiry;
+                    true
+                }
+                _ => false,
+            }
+        });
+        if released > 0 {
+            self.holds.notify_waiters();
+        }
+        released
+    }
+
+    // Remove a block already popped from the priority set from the lookup map
+    // a fatal error will occur if the block is not found in the lookup map
+    fn evict(&mut self, sequence_hash: SequenceHash) -> PoolValue<KvBlock> {
+        let block = match self.lookup_map.remove(&sequence_hash) {
+            Some(block) => block,
+            None => {
+                panic!("block from priority set not found in lookup map");
+            }
+        };
+        self.unlink_chain(sequence_hash, block.token_block.parent_sequence_hash());
+
+        // the block still held reusable state, so this is an eviction
+        let age = self.return_tick.saturating_sub(block.return_tick);
+        self.eviction_ages[AgeBucket::index(age)] += 1;
+        self.class_eviction_ages
+            .entry(block.class())
+            .or_insert([0; EVICTION_AGE_BUCKETS])[AgeBucket::index(age)] += 1;
+        self.counters.evictions += 1;
+        self.shields.remove(&sequence_hash);
+        self.leave_affinity_group(&block);
+        if let Some(views) = self.evicted_views.as_mut() {
+            views.push(KvBlockView::from(&*block));
+        }
+
+        self.record_eviction(sequence_hash);
+        self.offload(sequence_hash, &block);
+        self.notify_sequence(sequence_hash, SequenceState::Absent);
+
+        // sending fails only when there are no subscribers
+        let _ = self.eviction_events.send(EvictionEvent {
+            sequence_hash,
+            caller: self.evicting_for,
+            request_id: self.op_context.map(|active| active.context.request_id),
+        });
+        block
+    }
+
+    fn offload(&mut self, sequence_hash: SequenceHash, block: &KvBlock) {
+        let Some(hook) = self.config.eviction_hook.clone() else {
+            return;
+        };
+
+        match (hook.hook)(block) {
+            EvictOutcome::Forget => {
+                self.cold_entries.remove(&sequence_hash);
+            }
+            EvictOutcome::Cold(location) => {
+                let expiry = Instant::now() + hook.ttl;
+                let entry = ColdEntry {
+                    location,
+                    expiry,
+                    parent_sequence_hash: block.token_block.parent_sequence_hash(),
+                    tokens: ColdTokens::new(
+                        block.token_block.tokens(),
+                        self.config.cold_compression,
+                    ),
+                };
+                // an offload of this pool supersedes a mirrored location
+                self.mirrored.remove(&sequence_hash);
+                self.cold_entries.insert(sequence_hash, entry);
+                self.cold_order.push_back((expiry, sequence_hash));
+                self.expire_cold(hook.cap);
+
+                // a hash offloaded again leaves its older entry behind in the order; drop the
+                // stale entries once they outnumber the live ones
+                if self.cold_order.len() > 2 * self.cold_entries.len() {
+                    self.compact_cold_order();
+                }
+            }
+        }
+    }
+
+    // Drop cold entries past their expiry, then the oldest entries beyond the cap
+    fn expire_cold(&mut self, cap: usize) {
+        let now = Instant::now();
+        while let Some(&(expiry, hash)) = self.cold_order.front() {
+            if expiry > now && self.cold_entries.len() <= cap {
+                break;
+            }
+            self.cold_order.pop_front();
+
+            // the hash may have been offloaded again since
+            if self
+                .cold_entries
+                .get(&hash)
+                .is_some_and(|entry| entry.expiry == expiry)
+            {
+                self.cold_entries.remove(&hash);
+            }
+        }
+    }
+
+    // Keep a single entry in the expiry order for each live cold entry
+    fn compact_cold_order(&mut self) {
+        let cold_entries = &self.cold_entries;
+        let mut queued = HashSet::with_capacity(cold_entries.len());
+        self.cold_order.retain(|&(expiry, hash)| {
+            cold_entries
+                .get(&hash)
+                .is_some_and(|entry| entry.expiry == expiry)
+                && queued.insert(hash)
+        });
+    }
+
+    // Rebuild an offloaded block in a block taken from the pool
+    fn promote(&mut self, sequence_hash: SequenceHash) -> Option<PoolValue<KvBlock>> {
+        if let Some(cap) = self.config.eviction_hook.as_ref().map(|hook| hook.cap) {
+            self.expire_cold(cap);
+        }
+        // a block which is hot again is matched instead
+        if self.lookup_map.contains_key(&sequence_hash) {
+            return None;
+        }
+        let entry = self.cold_entries.get(&sequence_hash)?;
+        let tokens = entry.tokens.to_tokens();
+        let parent_sequence_hash = entry.parent_sequence_hash;
+
+        let mut block = self.take()?;
+        self.cold_entries.remove(&sequence_hash);
+        block.update_token_block(TokenBlock::with_precomputed_hash(
+            tokens,
+            sequence_hash,
+            parent_sequence_hash,
+        ));
+        Some(block)
+    }
+
+    fn mirror_cold(&mut self, added: Vec<ColdHit>, removed: Vec<SequenceHash>, replace: bool) {
+        if replace {
+            self.mirrored.clear();
+        }
+        for hash in removed {
+            self.mirrored.remove(&hash);
+        }
+
+        let cap = self.config.mirror_cap.unwrap_or(MIRROR_COLD_CAP);
+        for hit in added {
+            // blocks cached or offloaded by this pool take precedence
+            if self.lookup_map.contains_key(&hit.sequence_hash)
+                || self.cold_entries.contains_key(&hit.sequence_hash)
+            {
+                continue;
+            }
+            if self.mirrored.len() >= cap && !self.mirrored.contains_key(&hit.sequence_hash) {
+                self.counters.mirror_overflow += 1;
+                continue;
+            }
+            self.mirrored.insert(hit.sequence_hash, hit.location);
+        }
+    }
+
+    fn handle_promote_cold(&mut self, promote: PromoteCold) {
+        let (hash, tx) = promote.dissolve();
+
+        if let Err(err) = self.in_use_headroom() {
+            if tx.send(Err(err)).is_err() {
+                log::trace!("Failed to send promote error to requester");
+            }
+            return;
+        }
+        let promoted = (self.take_limit() > 0)
+            .then(|| self.promote(hash))
+            .flatten()
+            .map(|block| self.create_pool_item(block, self.return_handle.clone()));
+
+        if promoted.is_some() {
+            self.available_blocks
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            self.counters.takes += 1;
+        }
+
+        if tx.send(Ok(promoted)).is_err() {
+            log::trace!("Failed to send promoted block to requester");
+        }
+    }
+
+    fn handle_match_with_options(&mut self, match_with_options: MatchWithOptions) {
+        let (mut hashes, options, waited, final_attempt, tx) = match_with_options.dissolve();
+        let now = Instant::now();
+        truncate_prefix(&mut hashes, options.max_blocks);
+
+        // the first block of the cached prefix soft held by another holder
+        let conflict = hashes
+            .iter()
+            .take_while(|hash| self.lookup_map.contains_key(*hash))
+            .position(|hash| self.held_by_other(hash, options.holder, now));
+
+        match (conflict, options.on_soft_hold) {
+            (None, _) => {
+                if waited {
+                    self.counters.soft_hold_waits += 1;
+                }
+            }
+            (Some(_), OnSoftHold::Steal) => self.counters.soft_hold_steals += 1,
+            (Some(held), OnSoftHold::Wait(_)) if !final_attempt => {
+                let expiry = self.shields[&hashes[held]].expiry;
+                if tx.send(Ok(HeldMatch::Held(expiry))).is_err() {
+                    log::trace!("Failed to send held match to requester");
+                }
+                return;
+            }
+            (Some(held), _) => {
+                self.counters.soft_hold_misses += 1;
+                hashes.truncate(held);
+            }
+        }
+
+        let blocks = self.match_hashes(hashes, false);
+        if tx.send(Ok(HeldMatch::Matched(blocks))).is_err() {
+            log::trace!("Failed to send matched blocks to requester");
+        }
+    }
+
+    fn handle_match_tiered(&mut self, match_tiered: MatchTiered) {
+        let (hashes, tx) = match_tiered.dissolve();
+
+        let blocks = self.match_hashes(hashes.clone(), false);
+
+        if let Some(cap) = self.config.eviction_hook.as_ref().map(|hook| hook.cap) {
+            self.expire_cold(cap);
+        }
+        let cold = hashes[blocks.len()..]
+            .iter()
+            .map_while(|hash| {
+                let location = match self.cold_entries.get(hash) {
+                    Some(entry) => entry.location,
+                    None => *self.mirrored.get(hash)?,
+                };
+                Some(ColdHit {
+                    sequence_hash: *hash,
+                    location,
+                })
+            })
+            .collect();
+
+        if tx.send(Ok(TieredMatch { blocks, cold })).is_err() {
+            log::trace!("Failed to send tiered match to requester");
+        }
+    }
+
+    fn take_items(&mut self, count: u32, return_handle: Arc<ReturnHandleImpl>) -> Vec<UniqueBlock> {
+        let limit = self.take_limit();
+        self.take_items_up_to(count, limit, return_handle)
+    }
+
+    // Takes at most `limit` blocks; plain takes go through take_items
+    fn take_items_up_to(
+        &mut self,
+        count: u32,
+        limit: u64,
+        return_handle: Arc<ReturnHandleImpl>,
+    ) -> Vec<UniqueBlock> {
+        #[cfg(feature = "trace-record")]
+        let requested = count as usize;
+
+        let count = (count as u64).min(limit) as u32;
+        let mut taken_blocks = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            if let Some(block) = self.take() {
+                taken_blocks.push(self.create_pool_item(block, return_handle.clone()));
+            } else {
+                break;
+            }
+        }
+
+        self.available_blocks.fetch_sub(
+            taken_blocks.len() as u64,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        self.counters.takes += taken_blocks.len() as u64;
+
+        #[cfg(feature = "trace-record")]
+        if self.trace_op() {
+            self.record_trace(TraceOp::Take, Vec::new(), requested, taken_blocks.len());
+        }
+
+        taken_blocks
+    }
+
+    fn handle_take(&mut self, take: Take) {
+        let (count, emergency, tx) = take.dissolve();
+
+        let (served, result) = self.serve_take(count, emergency);
+        let hits = result.as_ref().map(|blocks| blocks.len());
+
+        // Send the result back through the channel
+        if tx.send(result).is_err() {
+            log::trace!("Failed to send matched blocks to requester");
+        }
+        if let Ok(hits) = hits {
+            self.finish_op(OpKind::Take, hits, served as usize - hits);
+        }
+    }
+
+    // The take of handle_take, along with the count it was served up to
+    fn serve_take(
+        &mut self,
+        count: u32,
+        emergency: bool,
+    ) -> (u32, std::result::Result<Vec<UniqueBlock>, KvPoolError>) {
+        let headroom = match self.in_use_headroom() {
+            Ok(headroom) => headroom,
+            Err(err) => return (0, Err(err)),
+        };
+        let limit = if emergency {
+            headroom
+        } else {
+            self.take_limit()
+        };
+        let count = (count as u64).min(limit) as u32;
+
+        if self.config.pinned_exhaustion == PinnedExhaustion::Fail {
+            let available = self.takeable_blocks();
+            let pinned =
+                (self.uninitialized_set.len() + self.priority_set.len()) as u64 - available;
+            if available < count as u64 && pinned > 0 {
+                let err = KvPoolError::OnlyPinnedRemaining {
+                    requested: count,
+                    available,
+                    pinned,
+                };
+                return (count, Err(err));
+            }
+        }
+
+        let taken_blocks = self.take_items_up_to(count, limit, self.return_handle.clone());
+        (count, Ok(taken_blocks))
+    }
+
+    fn handle_take_reporting_evictions(&mut self, take: TakeReportingEvictions) {
+        let (count, tx) = take.dissolve();
+
+        self.evicted_views = Some(Vec::new());
+        let (served, result) = self.serve_take(count, false);
+        let evicted = self.evicted_views.take().unwrap_or_default();
+        let hits = result.as_ref().map(|taken| taken.len());
+
+        let outcome = result.map(|taken| {
+            let shortfall =
+                (taken.len() < count as usize).then(|| self.shortfall(self.takeable_blocks()));
+            TakeOutcome {
+                taken,
+                evicted,
+                shortfall,
+            }
+        });
+        if tx.send(outcome).is_err() {
+            log::trace!("Failed to send taken blocks to requester");
+        }
+        if let Ok(hits) = hits {
+            self.finish_op(OpKind::Take, hits, served as usize - hits);
+        }
+    }
+
+    fn handle_take_exact(&mut self, take: TakeExact) {
+        let (count, tx) = take.dissolve();
+
+        let available = self.unreserved_blocks();
+        let headroom = self.in_use_headroom().unwrap_or(0);
+        let result = if headroom < count as u64 {
+            Err(KvPoolError::InUseLimitReached {
+                max: self.config.max_in_use.unwrap_or(u64::MAX),
+            })
+        } else if available < count as u64 {
+            Err(KvPoolError::InsufficientBlocks {
+                requested: count,
+                available,
+                shortfall: self.shortfall(available),
+            })
+        } else {
+            Ok(self.take_items(count, self.return_handle.clone()))
+        };
+        // a failed attempt may be retried, so only the take which succeeds is reported
+        let hits = result.as_ref().map(|blocks| blocks.len());
+
+        if tx.send(result).is_err() {
+            log::trace!("Failed to send taken blocks to requester");
+        }
+        if let Ok(hits) = hits {
+            self.finish_op(OpKind::Take, hits, count as usize - hits);
+        }
+    }
+
+    // The blocks handle_take would hand out, selected as take_with_priority and pick_victim do
+    // but without removing them
+    fn handle_preview_take(
+        &mut self,
+        count: u32,
+    ) -> std::result::Result<Vec<KvBlockView>, KvPoolError> {
+        let headroom = self.in_use_headroom()?;
+        let count = (count as u64).min(self.unreserved_blocks()).min(headroom) as usize;
+
+        // the expiries a take runs first; they change what it picks
+        self.expire_sticky();
+        self.expire_shields();
+
+        let mut preview: Vec<KvBlockView> = self
+            .uninitialized_set
+            .iter()
+            .take(count)
+            .map(|block| KvBlockView::from(&**block))
+            .collect();
+        let view = |sequence_hash: &SequenceHash| {
+            let block = self
+                .lookup_map
+                .get(sequence_hash)
+                .expect("block from priority set not found in lookup map");
+            KvBlockView::from(&**block)
+        };
+
+        let remaining = count - preview.len();
+        if self.shields.is_empty() && self.config.reserved_bands.is_empty() {
+            preview.extend(self.priority_set.values().take(remaining).map(view));
+            return Ok(preview);
+        }
+
+        // A take picks the first candidate of pick_victim each time, and evicting it re-keys no
+        // other block, affinity group members included, so one walk of the eviction order sees
+        // the picks in order. Each pick shrinks its band, which may become protected: a band
+        // only ever shrinks, so a key skipped as protected stays skipped. Shielded candidates are
+        // set aside; a take sacrifices them in order once no other candidate is left.
+        let bands = &self.config.reserved_bands;
+        let mut band_sizes: Vec<usize> = (0..bands.len())
+            .map(|index| self.priority_set.band_len(index))
+            .collect();
+        let mut protected = self.protected_bands();
+        let mut shielded = Vec::new();
+        let mut picked = Vec::with_capacity(remaining);
+        let shrink_band = |key: &PriorityKey,
+                           band_sizes: &mut [usize],
+                           protected: &mut Vec<RangeInclusive<u32>>| {
+            if let Some(index) = bands
+                .iter()
+                .position(|band| band.priorities.contains(&key.priority))
+            {
+                band_sizes[index] -= 1;
+                if band_sizes[index] == self.band_reservation(&bands[index]) {
+                    protected.push(bands[index].priorities.clone());
+                }
+            }
+        };
+
+        for (key, _) in self.priority_set.range(..) {
+            if picked.len() == remaining {
+                break;
+            }
+            match self.victim_candidate(key, &protected) {
+                None => {}
+                Some(true) => shielded.push(*key),
+                Some(false) => {
+                    shrink_band(key, &mut band_sizes, &mut protected);
+                    picked.push(key.sequence_hash);
+                }
+            }
+        }
+        for key in shielded {
+            if picked.len() == remaining {
+                break;
+            }
+            if self.victim_candidate(&key, &protected).is_some() {
+                shrink_band(&key, &mut band_sizes, &mut protected);
+                picked.push(key.sequence_hash);
+            }
+        }
+
+        preview.extend(picked.iter().map(view));
+        Ok(preview)
+    }
+
+    // Breakdown of the blocks left in the pool, given the idle blocks a take could get
+    fn shortfall(&self, takeable: u64) -> ShortfallReason {
+        let blank = self.uninitialized_set.len() as u64;
+        let idle = blank + self.priority_set.len() as u64;
+        ShortfallReason {
+            blank: blank.min(takeable),
+            evictable: takeable.saturating_sub(blank),
+            protected: idle.saturating_sub(takeable),
+            transitional: (self.offloading.len() + self.quarantined.len() + self.maintenance.len())
+                as u64,
+        }
+    }
+
+    // Blocks which can still be checked out under AvailableBlocksConfig::with_max_in_use
+    fn in_use_headroom(&self) -> std::result::Result<u64, KvPoolError> {
+        let Some(max) = self.config.max_in_use else {
+            return Ok(u64::MAX);
+        };
+        let in_use =
+            self.total_blocks.load(Ordering::SeqCst) - self.available_blocks.load(Ordering::SeqCst);
+        match max.checked_sub(in_use) {
+            Some(headroom) if headroom > 0 => Ok(headroom),
+            _ => Err(KvPoolError::InUseLimitReached { max }),
+        }
+    }
+
+    // Blocks a plain take can hand out: within the in-use cap and above the reserved headroom
+    fn take_limit(&self) -> u64 {
+        self.in_use_headroom()
+            .unwrap_or(0)
+            .min(self.unreserved_blocks())
+    }
+
+    // Idle blocks a take can get: a reserved band only gives up its blocks above the reservation,
+    // and a band holding fewer blocks than its reservation protects only the blocks it holds
+    fn takeable_blocks(&self) -> u64 {
+        let protected: usize = self
+            .config
+            .reserved_bands
+            .iter()
+            .enumerate()
+            .map(|(index, band)| {
+                self.priority_set
+                    .band_len(index)
+                    .min(self.band_reservation(band))
+            })
+            .sum();
+        (self.uninitialized_set.len() + self.priority_set.len() - protected) as u64
+    }
+
+    // Blocks plain takes can hand out, leaving the headroom for emergency takes
+    fn unreserved_blocks(&self) -> u64 {
+        self.takeable_blocks()
+            .saturating_sub(self.config.reserved_headroom)
+    }
+
+    // Blocks held back for takes backing cache hits
+    fn hit_reserve(&self) -> u64 {
+        self.config.hit_reserve.map_or(0, |fraction| {
+            (self.total_blocks.load(Ordering::SeqCst) as f64 * fraction).ceil() as u64
+        })
+    }
+
+    fn handle_take_with_options(&mut self, take: TakeWithOptions) {
+        let (count, options, tx) = take.dissolve();
+
+        let result = self.take_with_hits(count, options.hits);
+
+        if tx.send(result).is_err() {
+            log::trace!("Failed to send taken blocks to requester");
+        }
+    }
+
+    // Takes for a request which matched `hits` blocks; takes without hits leave the hit reserve
+    fn take_with_hits(
+        &mut self,
+        count: u32,
+        hits: usize,
+    ) -> std::result::Result<Vec<UniqueBlock>, KvPoolError> {
+        self.in_use_headroom()?;
+        let reserve = self.hit_reserve();
+        let unreserved = self.takeable_blocks().saturating_sub(reserve);
+
+        if hits > 0 {
+            let taken_blocks = self.take_items(count, self.return_handle.clone());
+            self.counters.reserve_takes += (taken_blocks.len() as u64).saturating_sub(unreserved);
+            Ok(taken_blocks)
+        } else if unreserved == 0 && count > 0 {
+            self.counters.reserve_rejections += 1;
+            Err(KvPoolError::ReserveForHits { reserve })
+        } else {
+            let count = (count as u64).min(unreserved) as u32;
+            Ok(self.take_items(count, self.return_handle.clone()))
+        }
+    }
+
+    fn handle_take_for(&mut self, take: TakeFor) {
+        let (caller, count, tx) = take.dissolve();
+
+        if let Err(err) = self.in_use_headroom() {
+            if tx.send(Err(err)).is_err() {
+                log::trace!("Failed to send take error to requester");
+            }
+            return;
+        }
+        let outstanding = self.outstanding.get(&caller).copied().unwrap_or(0);
+        let count = match self.config.per_caller_quota {
+            Some(max) if outstanding >= max => {
+                if tx.send(Err(KvPoolError::QuotaExceeded(caller))).is_err() {
+                    log::trace!("Failed to send quota error to requester");
+                }
+                return;
+            }
+            Some(max) => count.min(max - outstanding),
+            None => count,
+        };
+
+        let return_handle = Arc::new(self.return_handle.with_caller(caller));
+        self.evicting_for = Some(caller);
+        let taken_blocks = self.take_items(count, return_handle);
+        self.evicting_for = None;
+        if !taken_blocks.is_empty() {
+            *self.outstanding.entry(caller).or_default() += taken_blocks.len() as u32;
+        }
+
+        if tx.send(Ok(taken_blocks)).is_err() {
+            log::trace!("Failed to send taken blocks to requester");
+        }
+    }
+
+    fn release_quota(&mut self, caller: CallerId) {
+        if let Some(outstanding) = self.outstanding.get_mut(&caller) {
+            *outstanding = outstanding.saturating_sub(1);
+            if *outstanding == 0 {
+                self.outstanding.remove(&caller);
+            }
+        }
+    }
+
+    fn handle_take_grouped(&mut self, take: TakeGrouped) {
+        let (count, tx) = take.dissolve();
+
+        if let Err(err) = self.in_use_headroom() {
+            if tx.send(Err(err)).is_err() {
+                log::trace!("Failed to send take error to requester");
+            }
+            return;
+        }
+        let mut grouped_blocks: BTreeMap<Option<BlockPriority>, Vec<UniqueBlock>> = BTreeMap::new();
+        let mut taken = 0;
+        let count = (count as u64).min(self.take_limit());
+
+        for _ in 0..count {
+            let Some((priority, block)) = self.take_with_priority() else {
+                break;
+            };
+            let item = self.create_pool_item(block, self.return_handle.clone());
+            grouped_blocks.entry(priority).or_default().push(item);
+            taken += 1;
+        }
+
+        self.available_blocks
+            .fetch_sub(taken, std::sync::atomic::Ordering::SeqCst);
+        self.counters.takes += taken;
+
+        if tx.send(Ok(grouped_blocks)).is_err() {
+            log::trace!("Failed to send grouped blocks to requester");
+        }
+    }
+
+    fn handle_take_at_priority(&mut self, take: TakeAtPriority) {
+        let (priority, tx) = take.dissolve();
+
+        if let Err(err) = self.in_use_headroom() {
+            if tx.send(Err(err)).is_err() {
+                log::trace!("Failed to send take error to requester");
+            }
+            return;
+        }
+        let taken_block = (self.take_limit() > 0)
+            .then(|| self.take_at_priority(priority))
+            .flatten()
+            .map(|block| self.create_pool_item(block, self.return_handle.clone()));
+
+        if taken_block.is_some() {
+            self.available_blocks
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            self.counters.takes += 1;
+        }
+
+        if tx.send(Ok(taken_block)).is_err() {
+            log::trace!("Failed to send taken block to requester");
+        }
+    }
+
+    fn handle_match_request(&mut self, match_request: MatchRequest) {
+        if self.shutting_down && self.config.shutdown_mode == ShutdownMode::RejectNew {
+            self.reject_match_request(match_request);
+            return;
+        }
+
+        match match_request {
+            MatchRequest::MatchSingle(match_single) => self.handle_match_single(match_single),
+            MatchRequest::MatchIfVersion(match_if_version) => {
+                self.handle_match_if_version(match_if_version)
+            }
+            MatchRequest::MatchMultiple(match_multiple) => {
+                self.handle_match_multiple(match_multiple)
+            }
+            MatchRequest::MatchDetailed(match_detailed) => {
+                self.handle_match_detailed(match_detailed)
+            }
+            MatchRequest::MatchStream(match_stream) => self.handle_match_stream(match_stream),
+            MatchRequest::MatchTiered(match_tiered) => self.handle_match_tiered(match_tiered),
+            MatchRequest::MatchWithOptions(match_with_options) => {
+                self.handle_match_with_options(match_with_options)
+            }
+            MatchRequest::Take(take) => self.handle_take(take),
+            MatchRequest::TakeExact(take) => self.handle_take_exact(take),
+            MatchRequest::TakeReportingEvictions(take) => {
+                self.handle_take_reporting_evictions(take)
+            }
+            MatchRequest::Allocate(allocate) => self.handle_allocate(allocate),
+            MatchRequest::AllocateBatch(allocate) => self.handle_allocate_batch(allocate),
+            MatchRequest::AcquireAndDemote(acquire_and_demote) => {
+                self.handle_acquire_and_demote(acquire_and_demote)
+            }
+            MatchRequest::TakeWithOptions(take) => self.handle_take_with_options(take),
+            MatchRequest::TakeFor(take) => self.handle_take_for(take),
+            MatchRequest::TakeGrouped(take) => self.handle_take_grouped(take),
+            MatchRequest::TakeAtPriority(take) => self.handle_take_at_priority(take),
+            MatchRequest::PromoteCold(promote) => self.handle_promote_cold(promote),
+        }
+    }
+
+    // Fail a match request queued before a shutdown under ShutdownMode::RejectNew
+    fn reject_match_request(&mut self, match_request: MatchRequest) {
+        fn reject<T>(tx: oneshot::Sender<std::result::Result<T, KvPoolError>>) {
+            if tx.send(Err(KvPoolError::ShuttingDown)).is_err() {
+                log::trace!("Failed to send shutting down error; receiver dropped");
+            }
+        }
+
+        match match_request {
+            MatchRequest::MatchSingle(request) => reject(request.tx),
+            MatchRequest::MatchMultiple(request) => reject(request.tx),
+            MatchRequest::Take(request) => reject(request.tx),
+            MatchRequest::TakeExact(request) => reject(request.tx),
+            MatchRequest::TakeFor(request) => reject(request.tx),
+            MatchRequest::TakeWithOptions(request) => reject(request.tx),
+            MatchRequest::TakeReportingEvictions(request) => reject(request.tx),
+            MatchRequest::MatchIfVersion(request) => reject(request.tx),
+            MatchRequest::MatchDetailed(request) => reject(request.tx),
+            MatchRequest::MatchTiered(request) => reject(request.tx),
+            MatchRequest::MatchWithOptions(request) => reject(request.tx),
+            MatchRequest::Allocate(request) => reject(request.tx),
+            MatchRequest::AllocateBatch(request) => reject(request.tx),
+            MatchRequest::AcquireAndDemote(request) => reject(request.tx),
+            MatchRequest::TakeGrouped(request) => reject(request.tx),
+            MatchRequest::TakeAtPriority(request) => reject(request.tx),
+            MatchRequest::PromoteCold(request) => reject(request.tx),
+            // a stream can not carry an error; it ends without blocks
+            MatchRequest::MatchStream(_) => {
+                log::trace!("dropping match stream request; pool shutting down")
+            }
+        }
+    }
+
+    // Whether a shutdown can complete within the engine: no request is in progress or held back
+    // and every block is back
+    fn is_drained(&self) -> bool {
+        self.shutting_down
+            && self.continuation.is_none()
+            && self.parked_streams.is_empty()
+            && self.reorder_buffer.is_empty()
+            && self.return_handle.overflow.depth.load(Ordering::SeqCst) == 0
+            && self.available_blocks.load(Ordering::SeqCst)
+                + self.offloading_blocks.load(Ordering::SeqCst)
+                >= self.total_blocks.load(Ordering::SeqCst)
+    }
+
+    fn handle_control_request(&mut self, control_request: ControlRequest) {
+        match control_request {
+            ControlRequest::Insert(insert) => {
+                let (block, _, tx) = insert.dissolve();
+                self.handle_insert(block);
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send insert ack; receiver dropped");
+                }
+            }
+            ControlRequest::InsertMultiple(insert_multiple) => {
+                let (blocks, _, tx) = insert_multiple.dissolve();
+                for block in blocks {
+                    self.handle_insert(block);
+                }
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send insert multiple ack; receiver dropped");
+                }
+            }
+            ControlRequest::InsertOnce(insert_once) => {
+                let (op_id, blocks, tx) = insert_once.dissolve();
+                let outcome = self.handle_insert_once(op_id, blocks);
+                if tx.send(outcome).is_err() {
+                    log::trace!("Failed to send insert once ack; receiver dropped");
+                }
+            }
+            ControlRequest::UpdateSingle(update_single) => {
+                let (update, tx) = update_single.dissolve();
+                let result = self.handle_update_single(update);
+                if tx.send(result).is_err() {
+                    log::trace!("Failed to send update single ack; receiver dropped");
+                }
+            }
+            ControlRequest::UpdatePriorityIf(update) => {
+                let (hash, expected, new, tx) = update.dissolve();
+                let swapped = self.handle_update_priority_if(hash, expected, new);
+                if tx.send(swapped).is_err() {
+                    log::trace!("Failed to send update priority if ack; receiver dropped");
+                }
+            }
+            ControlRequest::UpdateMultiple(update_multiple) => {
+                let (updates, _, tx) = update_multiple.dissolve();
+                if self.chunks_requests() {
+                    self.start_continuation(ContinuationKind::Update(UpdateContinuation {
+                        updates: updates.into_iter(),
+                        error: None,
+                        tx,
+                    }));
+                    return;
+                }
+                let result = self.handle_update_multiple(updates);
+                if tx.send(result).is_err() {
+                    log::trace!("Failed to send update multiple ack; receiver dropped");
+                }
+            }
+            ControlRequest::Rebalance(rebalance) => {
+                let (scores, _, tx) = rebalance.dissolve();
+                if self.chunks_requests() {
+                    self.start_continuation(ContinuationKind::Rebalance(RebalanceContinuation {
+                        scores: sort_scores(scores).into_iter(),
+                        report: RebalanceReport::default(),
+                        tx,
+                    }));
+                    return;
+                }
+                let report = self.handle_rebalance(scores);
+                if tx.send(report).is_err() {
+                    log::trace!("Failed to send rebalance report; receiver dropped");
+                }
+            }
+            ControlRequest::Touch(touch) => {
+                let (hashes, tx) = touch.dissolve();
+                let touched = self.handle_touch(hashes);
+                if tx.send(touched).is_err() {
+                    log::trace!("Failed to send touch count; receiver dropped");
+                }
+            }
+            ControlRequest::Reset(reset) => {
+                let (sequence_hashes, _, tx) = reset.dissolve();
+                self.handle_reset(sequence_hashes);
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send reset ack; receiver dropped");
+                }
+            }
+            ControlRequest::ResetAndReprioritize(reset) => {
+                let (entries, tx) = reset.dissolve();
+                let processed = self.handle_reset_and_reprioritize(entries);
+                if tx.send(processed).is_err() {
+                    log::trace!("Failed to send reset ack; receiver dropped");
+                }
+            }
+            ControlRequest::ResetAll(reset_all) => {
+                let tx = reset_all.dissolve();
+                self.handle_reset_all();
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send reset all ack; receiver dropped");
+                }
+            }
+            #[cfg(feature = "trace-record")]
+            ControlRequest::ExportTrace(export) => {
+                let tx = export.dissolve();
+                let trace = self
+                    .recorder
+                    .as_mut()
+                    .map_or_else(RecordedTrace::default, |recorder| recorder.export());
+                if tx.send(trace).is_err() {
+                    log::trace!("Failed to send trace; receiver dropped");
+                }
+            }
+            ControlRequest::MirrorCold(mirror) => {
+                let (added, removed, replace, tx) = mirror.dissolve();
+                self.mirror_cold(added, removed, replace);
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send mirror cold ack; receiver dropped");
+                }
+            }
+            ControlRequest::DumpState(dump) => {
+                let tx = dump.dissolve();
+                let dump = self.dump_state();
+                if tx.send(dump).is_err() {
+                    log::trace!("Failed to send state dump; receiver dropped");
+                }
+            }
+            ControlRequest::VerifyIntegrity(verify) => {
+                let (repair, tx) = verify.dissolve();
+                let report = self.verify_integrity();
+                *self.self_check.last_audit.lock().unwrap() = Some(report);
+                // the drift a self check flagged was a race with a turn
+                if report.is_consistent() {
+                    *self.self_check.anomaly.lock().unwrap() = None;
+                }
+                if repair && !report.is_consistent() {
+                    self.repair_integrity(report);
+                }
+                if tx.send(report).is_err() {
+                    log::trace!("Failed to send integrity report; receiver dropped");
+                }
+            }
+            ControlRequest::ExportManifest(export) => {
+                let (reset, tx) = export.dissolve();
+                self.expire_sticky();
+                let manifest = self.export_manifest();
+                if reset {
+                    self.handle_reset_all();
+                }
+                if tx.send(manifest).is_err() {
+                    log::trace!("Failed to send manifest; receiver dropped");
+                }
+            }
+            ControlRequest::Shrink(shrink) => {
+                let (target_total, tx) = shrink.dissolve();
+                let report = self.handle_shrink(target_total);
+                if tx.send(report).is_err() {
+                    log::trace!("Failed to send shrink report; receiver dropped");
+                }
+            }
+            ControlRequest::EvictChains(evict_chains) => {
+                let (target_blocks, tx) = evict_chains.dissolve();
+                let report = self.handle_evict_chains(target_blocks);
+                if tx.send(report).is_err() {
+                    log::trace!("Failed to send evict chains report; receiver dropped");
+                }
+            }
+            ControlRequest::FenceOps(fence_ops) => {
+                let (ops, tx) = fence_ops.dissolve();
+                self.handle_fence_ops(ops, tx);
+            }
+            ControlRequest::BlankSetStats(blank_set_stats) => {
+                let tx = blank_set_stats.dissolve();
+                let stats = BlankSetStats {
+                    blocks: self.uninitialized_set.len(),
+                    storage_bytes: self.blank_bytes,
+                    released_bytes: self.blank_released_bytes,
+                };
+                if tx.send(stats).is_err() {
+                    log::trace!("Failed to send blank set stats; receiver dropped");
+                }
+            }
+            ControlRequest::ListUninitialized(list_uninitialized) => {
+                let (limit, tx) = list_uninitialized.dissolve();
+                let views = self
+                    .uninitialized_set
+                    .iter()
+                    .take(limit)
+                    .map(|block| KvBlockView::from(&**block))
+                    .collect();
+                if tx.send(views).is_err() {
+                    log::trace!("Failed to send uninitialized blocks; receiver dropped");
+                }
+            }
+            ControlRequest::PreviewTake(preview) => {
+                let (count, tx) = preview.dissolve();
+                let views = self.handle_preview_take(count);
+                if tx.send(views).is_err() {
+                    log::trace!("Failed to send take preview; receiver dropped");
+                }
+            }
+            ControlRequest::Reconcile(reconcile) => {
+                let (verdict, tx) = reconcile.dissolve();
+                self.handle_reconcile(verdict, tx);
+            }
+            ControlRequest::Stats(stats) => {
+                let tx = stats.dissolve();
+                self.expire_shields();
+                let tokens = self.token_stats();
+                let stats = PoolStats {
+                    name: self.config.name.clone(),
+                    total_blocks: self.total_blocks.load(Ordering::SeqCst),
+                    available_blocks: self.available_blocks.load(Ordering::SeqCst),
+                    uninitialized_blocks: self.uninitialized_set.len() as u64,
+                    reusable_blocks: self.lookup_map.len() as u64,
+                    active_shields: self.shields.len() as u64,
+                    reserved_band_blocks: (0..self.config.reserved_bands.len())
+                        .map(|index| self.priority_set.band_len(index) as u64)
+                        .collect(),
+                    class_blocks: self.priority_set.class_lens(),
+                    op_latencies: self.op_latency_percentiles(),
+                    return_queue_depth: self.return_handle.overflow.depth.load(Ordering::SeqCst),
+                    lookup_capacity: self.lookup_map.capacity() as u64,
+                    aux_bytes: self.aux_bytes() as u64,
+                    capacity_log: self.capacity_log.iter().copied().collect(),
+                    tokens,
+                };
+                if tx.send(stats).is_err() {
+                    log::trace!("Failed to send stats; receiver dropped");
+                }
+            }
+            ControlRequest::Expect(expect) => {
+                let (hashes, ttl, tx) = expect.dissolve();
+                let shielded = self.handle_expect(hashes, ttl);
+                if tx.send(shielded).is_err() {
+                    log::trace!("Failed to send expect ack; receiver dropped");
+                }
+            }
+            ControlRequest::PeekHold(peek_hold) => {
+                let (holder, hashes, ttl, tx) = peek_hold.dissolve();
+                let held = self.handle_peek_hold(holder, hashes, ttl);
+                if tx.send(held).is_err() {
+                    log::trace!("Failed to send peek hold ack; receiver dropped");
+                }
+            }
+            ControlRequest::CheckoutForMaintenance(checkout) => {
+                let (hash, tx) = checkout.dissolve();
+                let block = self.handle_checkout_for_maintenance(hash);
+                if tx.send(block).is_err() {
+                    log::trace!("Failed to send maintenance checkout; receiver dropped");
+                }
+            }
+            ControlRequest::ReleaseHolds(release) => {
+                let (holder, tx) = release.dissolve();
+                let released = self.handle_release_holds(holder);
+                if tx.send(released).is_err() {
+                    log::trace!("Failed to send release ack; receiver dropped");
+                }
+            }
+            ControlRequest::Stick(stick) => {
+                let (hashes, session_id, ttl, tx) = stick.dissolve();
+                let stuck = self.handle_stick(hashes, session_id, ttl);
+                if tx.send(stuck).is_err() {
+                    log::trace!("Failed to send stick ack; receiver dropped");
+                }
+            }
+            ControlRequest::ReportCorrupt(report) => {
+                let (hash, tx) = report.dissolve();
+                self.handle_report_corrupt(hash);
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send report corrupt ack; receiver dropped");
+                }
+            }
+            ControlRequest::Shutdown(shutdown) => {
+                let tx = shutdown.dissolve();
+                log::info!("shutting down; draining the progress engine");
+                self.shutting_down = true;
+                self.lifecycle_tx.send_replace(PoolLifecycle::ShuttingDown);
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send shutdown ack; receiver dropped");
+                }
+            }
+            ControlRequest::SetBlockPriority(set) => {
+                let (hash, priority, tx) = set.dissolve();
+                let result = self.handle_set_block_priority(hash, priority);
+                if tx.send(result).is_err() {
+                    log::trace!("Failed to send set priority ack; receiver dropped");
+                }
+            }
+            ControlRequest::Unstick(unstick) => {
+                let (session_id, tx) = unstick.dissolve();
+                let released = self.release_sticky(session_id);
+                if tx.send(released).is_err() {
+                    log::trace!("Failed to send unstick ack; receiver dropped");
+                }
+            }
+            ControlRequest::DrainCounters(drain_counters) => {
+                let tx = drain_counters.dissolve();
+                let counters = std::mem::take(&mut self.counters);
+                if tx.send(counters).is_err() {
+                    log::trace!("Failed to send counters; receiver dropped");
+                }
+            }
+            ControlRequest::MarkReady(mark_ready) => {
+                let tx = mark_ready.dissolve();
+                if !self.shutting_down {
+                    self.lifecycle_tx.send_replace(PoolLifecycle::Ready);
+                }
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send mark ready ack; receiver dropped");
+                }
+            }
+            ControlRequest::EvictionAgeHistogram(histogram) => {
+                let (class, tx) = histogram.dissolve();
+                let ages = match class {
+                    None => Some(&self.eviction_ages),
+                    Some(class) => self.class_eviction_ages.get(&class),
+                };
+                let histogram = ages
+                    .unwrap_or(&[0; EVICTION_AGE_BUCKETS])
+                    .iter()
+                    .enumerate()
+                    .map(|(index, count)| (AgeBucket::from_index(index), *count))
+                    .collect();
+                if tx.send(histogram).is_err() {
+                    log::trace!("Failed to send eviction age histogram; receiver dropped");
+                }
+            }
+            ControlRequest::TopMisses(top_misses) => {
+                let (k, tx) = top_misses.dissolve();
+                let misses = self
+                    .misses
+                    .as_mut()
+                    .map_or_else(Vec::new, |misses| misses.top(k));
+                if tx.send(misses).is_err() {
+                    log::trace!("Failed to send top misses; receiver dropped");
+                }
+            }
+            ControlRequest::WatchSequence(watch_sequence) => {
+                let (hash, tx) = watch_sequence.dissolve();
+                let watcher = self.watch_sequence(hash);
+                if tx.send(watcher).is_err() {
+                    log::trace!("Failed to send sequence watcher; receiver dropped");
+                }
+            }
+            ControlRequest::IsFullyCached(is_fully_cached) => {
+                let (hashes, tx) = is_fully_cached.dissolve();
+                let fully_cached = self.is_fully_cached(&hashes);
+                if tx.send(fully_cached).is_err() {
+                    log::trace!("Failed to send is fully cached response; receiver dropped");
+                }
+            }
+            ControlRequest::BeginOffload(begin_offload) => {
+                let (hash, tx) = begin_offload.dissolve();
+                let descriptor = self.begin_offload(hash);
+                if tx.send(descriptor).is_err() {
+                    log::trace!("Failed to send begin offload response; receiver dropped");
+                }
+            }
+            ControlRequest::CompleteOffload(complete_offload) => {
+                let (hash, tx) = complete_offload.dissolve();
+                let completed = self.complete_offload(hash);
+                if tx.send(completed).is_err() {
+                    log::trace!("Failed to send complete offload response; receiver dropped");
+                }
+            }
+            ControlRequest::CachedPrefixLen(cached_prefix_len) => {
+                let (hashes, tx) = cached_prefix_len.dissolve();
+                let len = hashes
+                    .iter()
+                    .take_while(|hash| self.lookup_map.contains_key(hash))
+                    .count();
+                if tx.send(len).is_err() {
+                    log::trace!("Failed to send cached prefix length; receiver dropped");
+                }
+            }
+        }
+    }
+
+    fn is_fully_cached(&self, hashes: &[SequenceHash]) -> bool {
+        hashes.iter().all(|hash| self.lookup_map.contains_key(hash))
+    }
+
+    fn handle_insert(&mut self, block: KvBlock) {
+        // waiting takes retry after this turn
+        self.availability.notify_waiters();
+
+        self.available_blocks
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.total_blocks
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.record_capacity_change(1, CapacityChangeReason::Insert);
+        self.return_tick += 1;
+        self.counters.inserts += 1;
+
+        // update the return tick
+        let mut block = block;
+        block.return_tick = self.return_tick;
+
+        self.insert(PoolValue::Direct(block));
+    }
+
+    fn handle_return_message(&mut self, message: ReturnMessage) {
+        match message {
+            ReturnMessage::Block(block) => self.handle_return(block),
+            ReturnMessage::Batch(blocks) => {
+                self.counters.return_batches += 1;
+                for block in blocks {
+                    self.handle_return(block);
+                }
+            }
+            ReturnMessage::Fence(tx) => {
+                // blocks spilled before the fence are absorbed first
+                self.drain_overflow();
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send return fence ack; receiver dropped");
+                }
+            }
+        }
+    }
+
+    fn drain_overflow(&mut self) {
+        for block in self.return_handle.overflow.drain() {
+            self.counters.spilled_returns += 1;
+            self.handle_return(block);
+        }
+    }
+
+    fn handle_return(&mut self, returned: ReturnedBlock) {
+        let ReturnedBlock {
+            generation,
+            reconcile_epoch,
+            caller,
+            mut block,
+        } = returned;
+
+        let checked_out_as = match block.maintenance {
+            Some(checkout) => {
+                self.maintenance.remove(&checkout.sequence_hash);
+                self.holds.notify_waiters();
+
+                // a block patched to another sequence hash is published under neither
+                if block.lookup_key() != checkout.sequence_hash {
+                    log::debug!("resetting block whose sequence hash changed under maintenance");
+                    self.reset_block(&mut block);
+                    self.notify_sequence(checkout.sequence_hash, SequenceState::Absent);
+                }
+                checkout.sequence_hash
+            }
+            None => {
+                self.counters.returns += 1;
+                block.lookup_key()
+            }
+        };
+        self.return_handle
+            .overflow
+            .depth
+            .fetch_sub(1, Ordering::SeqCst);
+        self.check_integrity_periodically();
+
+        // waiting takes retry after this turn
+        self.availability.notify_waiters();
+        self.check_in(checked_out_as);
+
+        if let Some(caller) = caller {
+            self.release_quota(caller);
+        }
+
+        #[cfg(feature = "trace-record")]
+        if self.trace_sampled() {
+            let hash = block.token_block.sequence_hash();
+            self.record_trace(TraceOp::Return, vec![hash], 1, 1);
+        }
+
+        if generation != self.return_handle.generation {
+            self.handle_stale_return(block);
+            return;
+        }
+
+        // checked out before the latest reconcile; apply its verdict now
+        if reconcile_epoch != self.return_handle.reconcile_epoch {
+            if let Some(verdict) = &self.deferred_verdict {
+                match verdict(&block) {
+                    BlockFate::Keep => {}
+                    BlockFate::Blank => self.reset_block(&mut block),
+                    BlockFate::Remove => {
+                        log::debug!("dropping returned block removed by reconcile");
+                        self.total_blocks.fetch_sub(1, Ordering::SeqCst);
+                        self.record_capacity_change(-1, CapacityChangeReason::Reconcile);
+                        return;
+                    }
+                }
+            }
+        }
+
+        self.return_block(block);
+    }
+
+    fn handle_stale_return(&mut self, mut block: PoolValue<KvBlock>) {
+        if self.retired_in_flight > 0 {
+            // the slot was already removed from the totals by a shrink
+            self.retired_in_flight -= 1;
+            log::debug!("dropping stale return of a retired slot");
+            return;
+        }
+
+        match self.config.stale_return_policy {
+            StaleReturnPolicy::ReadmitAsBlank => {
+                self.reset_block(&mut block);
+                self.return_block(block);
+            }
+            StaleReturnPolicy::Drop => {
+                log::debug!("dropping stale return");
+                self.total_blocks.fetch_sub(1, Ordering::SeqCst);
+                self.record_capacity_change(-1, CapacityChangeReason::StaleReturn);
+            }
+        }
+    }
+
+    fn return_block(&mut self, block: PoolValue<KvBlock>) {
+        self.available_blocks
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        // single use and quarantined blocks give up their sequence hash once served
+        let mut block = block;
+        let pending_priority = self.pending_priorities.remove(&block.lookup_key());
+        if block.single_use || self.quarantined.remove(&block.lookup_key()) {
+            let sequence_hash = block.lookup_key();
+            self.reset_block(&mut block);
+            self.notify_sequence(sequence_hash, SequenceState::Absent);
+        } else if let Some(priority) = pending_priority {
+            block.priority = self
+                .check_priority_chain(&block, priority)
+                .unwrap_or(block.priority);
+        }
+
+        // update the return tick; blocks back from maintenance keep theirs unless asked to bump
+        match block.maintenance.take() {
+            Some(checkout) if !checkout.bump_recency => {}
+            _ => {
+                self.return_tick += 1;
+                block.return_tick = self.return_tick;
+            }
+        }
+
+        self.insert(block);
+    }
+    // Remove an idle block from the pool, uninitialized blocks first
+    fn pop_idle(&mut self) -> Option<PoolValue<KvBlock>> {
+        if let Some(block) = self.pop_uninitialized() {
+            return Some(block);
+        }
+
+        let (_key, sequence_hash) = self.priority_set.pop_first()?;
+        let block = match self.lookup_map.remove(&sequence_hash) {
+            Some(block) => block,
+            None => panic!("block from priority set not found in lookup map"),
+        };
+        self.unlink_chain(sequence_hash, block.token_block.parent_sequence_hash());
+
+        self.notify_sequence(sequence_hash, SequenceState::Absent);
+        Some(block)
+    }
+
+    fn handle_shrink(&mut self, target_total: u64) -> ShrinkReport {
+        let mut removed_idle = 0;
+        let mut total = self.total_blocks.load(Ordering::SeqCst);
+
+        while total > target_total {
+            if self.pop_idle().is_none() {
+                break;
+            }
+            removed_idle += 1;
+            total -= 1;
+        }
+        self.available_blocks
+            .fetch_sub(removed_idle, Ordering::SeqCst);
+
+        // the remaining excess is checked out; retire those slots and start a new generation
+        // so the returns of the previous generation can be told apart
+        let retired_in_flight = total.saturating_sub(target_total);
+        if retired_in_flight > 0 {
+            self.retired_in_flight += retired_in_flight;
+            let generation = self.return_handle.generation + 1;
+            self.return_handle = Arc::new(self.return_handle.with_generation(generation));
+            log::debug!(generation, retired_in_flight, "retired checked out slots");
+        }
+
+        self.total_blocks
+            .fetch_sub(removed_idle + retired_in_flight, Ordering::SeqCst);
+        self.record_capacity_change(
+            -((removed_idle + retired_in_flight) as i64),
+            CapacityChangeReason::Shrink,
+        );
+
+        ShrinkReport {
+            removed_idle,
+            retired_in_flight,
+        }
+    }
+
+    fn handle_update_single(
+        &mut self,
+        update: UpdateBlock,
+    ) -> std::result::Result<(), KvPoolError> {
+        self.update_block(vec![update])
+    }
+
+    fn handle_update_multiple(
+        &mut self,
+        updates: Vec<UpdateBlock>,
+    ) -> std::result::Result<(), KvPoolError> {
+        self.update_block(updates)
+    }
+
+    fn handle_update_priority_if(
+        &mut self,
+        hash: SequenceHash,
+        expected: BlockPriority,
+        new: BlockPriority,
+    ) -> std::result::Result<bool, KvPoolError> {
+        if self.lookup_map.get(&hash).map(|block| block.priority) != Some(expected) {
+            return Ok(false);
+        }
+        self.update_block(vec![UpdateBlock::new(hash, new)])?;
+        Ok(true)
+    }
+
+    // Applies every valid update; returns the first rejected update, if any
+    fn update_block(&mut self, updates: Vec<UpdateBlock>) -> std::result::Result<(), KvPoolError> {
+        let mut result = Ok(());
+
+        for update in updates {
+            if let Some(mut block) = self.take_with_sequence_hash(update.hash) {
+                if let Some(priority) = update.priority {
+                    match self.check_priority_chain(&block, priority) {
+                        Ok(priority) => block.priority = priority,
+                        Err(err) => {
+                            if result.is_ok() {
+                                result = Err(err);
+                            }
+                        }
+                    }
+                }
+
+                // if let Some(deadline) = update.deadline {
+                //     block.set_deadline(deadline);
+                // }
+
+                self.insert(block);
+            }
+        }
+
+        result
+    }
+
+    fn handle_rebalance(&mut self, scores: Vec<(SequenceHash, BlockPriority)>) -> RebalanceReport {
+        let mut report = RebalanceReport::default();
+        self.apply_scores(sort_scores(scores).into_iter(), &mut report);
+        self.trim_reserved_bands();
+        report
+    }
+
+    // Returns true once every score has been applied
+    fn rebalance_chunk(&mut self, state: &mut RebalanceContinuation, chunk_size: usize) -> bool {
+        self.apply_scores(state.scores.by_ref().take(chunk_size), &mut state.report);
+        state.scores.as_slice().is_empty()
+    }
+
+    // Apply sorted scores, counting them into `report`; a chunked rebalance only rebuilds the
+    // eviction order for a pool at most four times its chunk, so a turn stays within its budget
+    fn apply_scores(
+        &mut self,
+        scores: impl Iterator<Item = (SequenceHash, BlockPriority)>,
+        report: &mut RebalanceReport,
+    ) {
+        let changed: Vec<(SequenceHash, BlockPriority)> = scores
+            .filter_map(
+                |(sequence_hash, priority)| match self.lookup_map.get(&sequence_hash) {
+                    Some(block) => {
+                        report.applied += 1;
+                        (block.priority != priority).then_some((sequence_hash, priority))
+                    }
+                    None => {
+                        report.missing += 1;
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        // past a quarter of the pool, one bulk rebuild beats re-keying entry by entry
+        let rebuild = changed.len() * 4 >= self.priority_set.len();
+        for (sequence_hash, priority) in changed {
+            let block = self
+                .lookup_map
+                .get_mut(&sequence_hash)
+                .expect("block present in lookup map");
+            if !rebuild {
+                self.priority_set.remove(&PriorityKey::from(&**block));
+            }
+            block.priority = priority;
+            if !rebuild {
+                self.priority_set
+                    .insert(PriorityKey::from(&**block), sequence_hash);
+            }
+        }
+        if rebuild {
+            self.rebuild_priority_set();
+        }
+    }
+
+    fn trim_reserved_bands(&mut self) {
+        let bands: Vec<BlockPriority> = self
+            .config
+            .reserved_bands
+            .iter()
+            .map(|band| *band.priorities.start())
+            .collect();
+        for priority in bands {
+            self.enforce_band(priority);
+        }
+    }
+
+    // Re-key each cached block under a fresh return tick, keeping its lookup entry and state
+    fn handle_touch(&mut self, hashes: Vec<SequenceHash>) -> usize {
+        let mut touched = 0;
+        for hash in hashes {
+            let Some(mut block) = self.take_with_sequence_hash(hash) else {
+                continue;
+            };
+            self.return_tick += 1;
+            block.return_tick = self.return_tick;
+            self.join_affinity_group(&mut block);
+            self.priority_set.insert(PriorityKey::from(&*block), hash);
+            self.link_chain(hash, block.token_block.parent_sequence_hash());
+            self.lookup_map.insert(hash, block);
+            touched += 1;
+        }
+        touched
+    }
+
+    // The priority to apply to `block` under the priority chain policy
+    fn check_priority_chain(
+        &self,
+        block: &KvBlock,
+        priority: BlockPriority,
+    ) -> std::result::Result<BlockPriority, KvPoolError> {
+        let Some(policy) = self.config.priority_chain_policy else {
+            return Ok(priority);
+        };
+        let Some(parent_priority) = block
+            .token_block
+            .parent_sequence_hash()
+            .and_then(|parent| self.lookup_map.get(&parent))
+            .map(|parent| parent.priority)
+        else {
+            return Ok(priority);
+        };
+
+        if priority <= parent_priority {
+            return Ok(priority);
+        }
+
+        match policy {
+            PriorityChainPolicy::Clamp => Ok(parent_priority),
+            PriorityChainPolicy::Reject => Err(KvPoolError::InvalidPriorityChain {
+                sequence_hash: block.token_block.sequence_hash(),
+                priority,
+                parent_priority,
+            }),
+        }
+    }
+
+    // Re-key a cached block, or remember the priority until the block checked out is returned
+    fn handle_set_block_priority(
+        &mut self,
+        hash: SequenceHash,
+        priority: BlockPriority,
+    ) -> std::result::Result<(), KvPoolError> {
+        if hash == 0 {
+            return Err(KvPoolError::ZeroHash);
+        }
+        if self.lookup_map.contains_key(&hash) {
+            return self.update_block(vec![UpdateBlock {
+                hash,
+                priority: Some(priority),
+            }]);
+        }
+        // a block which left the pool other than by a checkout never returns under its hash
+        if self.checked_out.contains_key(&hash) {
+            self.pending_priorities.insert(hash, priority);
+        }
+        Ok(())
+    }
+
+    fn handle_report_corrupt(&mut self, hash: SequenceHash) {
+        // blank blocks carry no data to be corrupt; a hash neither cached nor checked out has
+        // no block the quarantine could ever apply to
+        if hash == 0
+            || !(self.lookup_map.contains_key(&hash) || self.checked_out.contains_key(&hash))
+        {
+            log::debug!(
+                sequence_hash = hash,
+                "ignoring corrupt report for an unknown block"
+            );
+            return;
+        }
+        log::warn!(
+            sequence_hash = hash,
+            "quarantining block after failed checksum verification"
+        );
+        self.counters.corrupt_blocks += 1;
+
+        match self.take_with_sequence_hash(hash) {
+            Some(mut block) => {
+                self.notify_sequence(hash, SequenceState::Absent);
+                self.reset_block(&mut block);
+                self.insert(block);
+            }
+            None => {
+                self.quarantined.insert(hash);
+            }
+        }
+    }
+
+    fn handle_evict_chains(&mut self, target_blocks: u64) -> EvictionReport {
+        let mut report = EvictionReport::default();
+        if target_blocks == 0 {
+            return report;
+        }
+        self.expire_shields();
+        let protected = self.protected_bands();
+
+        // walking the eviction order, a chain is complete once its hottest member is reached, so
+        // chains complete coldest first and the walk stops once they cover the target
+        let mut roots = HashMap::new();
+        let mut partial: HashMap<SequenceHash, ChainScan> = HashMap::new();
+        let mut chains = Vec::new();
+        let mut found = 0;
+        for (key, &hash) in self.priority_set.range(..) {
+            if found >= target_blocks {
+                break;
+            }
+            let root = self.chain_root(hash, &mut roots);
+            let scan = partial.entry(root).or_insert_with(|| ChainScan {
+                members: self.chain_members(root),
+                seen: 0,
+                pinned: false,
+            });
+            scan.seen += 1;
+            scan.pinned |= self.shields.contains_key(&hash)
+                || protected.iter().any(|band| band.contains(&key.priority));
+            if scan.seen < scan.members.len() {
+                continue;
+            }
+            let scan = partial.remove(&root).expect("chain is being scanned");
+            if !scan.pinned {
+                found += scan.members.len() as u64;
+                chains.push(scan.members);
+            }
+        }
+
+        for members in chains {
+            report.chains += 1;
+            report.blocks += members.len() as u64;
+            for hash in members.into_iter().rev() {
+                self.priority_set
+                    .remove(&PriorityKey::from(&*self.lookup_map[&hash]));
+                let mut block = self.evict(hash);
+                self.reset_block(&mut block);
+                self.push_uninitialized(block);
+            }
+        }
+
+        log::debug!(
+            chains = report.chains,
+            blocks = report.blocks,
+            "evicted whole chains"
+        );
+        report
+    }
+
+    // The furthest cached ancestor of a cached block; `roots` memoizes the roots found so far
+    fn chain_root(
+        &self,
+        sequence_hash: SequenceHash,
+        roots: &mut HashMap<SequenceHash, SequenceHash>,
+    ) -> SequenceHash {
+        let mut path = Vec::new();
+        let mut current = sequence_hash;
+        let root = loop {
+            if let Some(&root) = roots.get(&current) {
+                break root;
+            }
+            path.push(current);
+            match self.lookup_map[&current].token_block.parent_sequence_hash() {
+                Some(parent) if self.lookup_map.contains_key(&parent) => current = parent,
+                _ => break current,
+            }
+        };
+        for hash in path {
+            roots.insert(hash, root);
+        }
+        root
+    }
+
+    // A cached block and its cached descendants, breadth first so every member comes after its
+    // parent
+    fn chain_members(&self, root: SequenceHash) -> Vec<SequenceHash> {
+        let mut members = vec![root];
+        let mut next = 0;
+        while next < members.len() {
+            if let Some(children) = self.chain_children.get(&members[next]) {
+                members.extend_from_slice(children);
+            }
+            next += 1;
+        }
+        members
+    }
+
+    fn handle_reset(&mut self, sequence_hashes: Vec<SequenceHash>) {
+        for hash in sequence_hashes {
+            self.pending_priorities.remove(&hash);
+            if let Some(mut block) = self.take_with_sequence_hash(hash) {
+                self.shields.remove(&hash);
+                self.notify_sequence(hash, SequenceState::Absent);
+                self.counters.resets += 1;
+                self.reset_block(&mut block);
+                self.insert(block);
+            }
+        }
+    }
+
+    fn handle_reset_and_reprioritize(
+        &mut self,
+        entries: Vec<(SequenceHash, BlockPriority)>,
+    ) -> usize {
+        let mut processed = 0;
+        for (hash, priority) in entries {
+            if let Some(mut block) = self.take_with_sequence_hash(hash) {
+                self.shields.remove(&hash);
+                self.notify_sequence(hash, SequenceState::Absent);
+                self.counters.resets += 1;
+                self.reset_block(&mut block);
+                block.priority = priority;
+                self.insert(block);
+                processed += 1;
+            }
+        }
+        processed
+    }
+
+    fn export_manifest(&self) -> PoolManifest {
+        let blocks = self
+            .priority_set
+            .values()
+            .map(|sequence_hash| {
+                let block = self
+                    .lookup_map
+                    .get(sequence_hash)
+                    .expect("block from priority set not found in lookup map");
+                BlockDescriptor::from(&**block)
+            })
+            .collect();
+        PoolManifest {
+            compat: self.config.compat_manifest(),
+            blocks,
+        }
+    }
+
+    fn dump_state(&self) -> StateDump {
+        let mut lookup_keys: Vec<SequenceHash> = self.lookup_map.keys().copied().collect();
+        lookup_keys.sort_unstable();
+
+        StateDump {
+            name: self.config.name.clone(),
+            total_blocks: self.total_blocks.load(Ordering::SeqCst),
+            available_blocks: self.available_blocks.load(Ordering::SeqCst),
+            return_tick: self.return_tick,
+            uninitialized_blocks: self.uninitialized_set.len() as u64,
+            lookup_keys,
+            eviction_order: self
+                .export_manifest()
+                .blocks
+                .into_iter()
+                .map(DumpedBlock::from)
+                .collect(),
+        }
+    }
+
+    fn verify_integrity(&self) -> IntegrityReport {
+        let orphaned_priorities = self
+            .priority_set
+            .range(..)
+            .filter(|(key, sequence_hash)| {
+                self.lookup_map
+                    .get(*sequence_hash)
+                    .is_none_or(|block| PriorityKey::from(&**block) != **key)
+            })
+            .count();
+        let misfiled_blocks = self
+            .lookup_map
+            .iter()
+            .filter(|(sequence_hash, block)| block.lookup_key() != **sequence_hash)
+            .count();
+
+        IntegrityReport {
+            lookup_entries: self.lookup_map.len() as u64,
+            priority_entries: self.priority_set.len() as u64,
+            uninitialized_blocks: self.uninitialized_set.len() as u64,
+            available_blocks: self.available_blocks.load(Ordering::SeqCst),
+            orphaned_priorities: orphaned_priorities as u64,
+            misfiled_blocks: misfiled_blocks as u64,
+        }
+    }
+
+    // Repair the state an audit found inconsistent, under AvailableBlocksConfig::with_self_check_repair
+    fn repair_integrity(&mut self, report: IntegrityReport) {
+        log::error!(
+            ?report,
+            "integrity audit failed; repairing the engine state"
+        );
+        self.counters.integrity_repairs += 1;
+        if report.orphaned_priorities > 0 || report.priority_entries != report.lookup_entries {
+            self.rebuild_priority_set();
+        }
+        let held = (self.lookup_map.len() + self.uninitialized_set.len()) as u64;
+        self.available_blocks.store(held, Ordering::SeqCst);
+    }
+
+    fn handle_reset_all(&mut self) {
+        self.pending_priorities.clear();
+        self.shields.clear();
+
+        // for all blocks in the priority set, reset them
+        while let Some((_key, sequence_hash)) = self.priority_set.pop_first() {
+            if let Some(mut block) = self.lookup_map.remove(&sequence_hash) {
+                self.unlink_chain(sequence_hash, block.token_block.parent_sequence_hash());
+                self.notify_sequence(sequence_hash, SequenceState::Absent);
+                self.counters.resets += 1;
+                self.reset_block(&mut block);
+                self.insert(block);
+            } else {
+                panic!("block from priority set not found in lookup map");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PoolExt<KvBlock> for AvailableBlocksState {}
+
+#[derive(Dissolve)]
+pub struct MatchSingle {
+    hash: SequenceHash,
+    tx: oneshot::Sender<std::result::Result<Option<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct MatchIfVersion {
+    hash: SequenceHash,
+    version: u64,
+    tx: oneshot::Sender<std::result::Result<Option<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct MatchMultiple {
+    hashes: Vec<SequenceHash>,
+    single_use: bool,
+    wait_for_maintenance: bool,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct MatchTiered {
+    hashes: Vec<SequenceHash>,
+    tx: oneshot::Sender<std::result::Result<TieredMatch, KvPoolError>>,
+}
+
+// Reply to a MatchWithOptions: the matched prefix, or the expiry of the soft hold a waiting match
+// ran into
+enum HeldMatch {
+    Matched(Vec<UniqueBlock>),
+    Held(Instant),
+}
+
+#[derive(Dissolve)]
+pub struct MatchWithOptions {
+    hashes: Vec<SequenceHash>,
+    options: MatchOptions,
+    waited: bool,
+    final_attempt: bool,
+    tx: oneshot::Sender<std::result::Result<HeldMatch, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct MatchStream {
+    hashes: Vec<SequenceHash>,
+    tx: mpsc::Sender<Vec<UniqueBlock>>,
+}
+
+#[derive(Dissolve)]
+pub struct MatchDetailed {
+    hashes: Vec<SequenceHash>,
+    tx: oneshot::Sender<std::result::Result<Vec<MatchedBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct Take {
+    count: u32,
+    emergency: bool,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct TakeReportingEvictions {
+    count: u32,
+    tx: oneshot::Sender<std::result::Result<TakeOutcome, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct TakeExact {
+    count: u32,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct AcquireAndDemote {
+    hashes: Vec<SequenceHash>,
+    demote: Vec<SequenceHash>,
+    priority: BlockPriority,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct Allocate {
+    hashes: Vec<SequenceHash>,
+    extra: u32,
+    request_tokens: Option<u64>,
+    max_blocks: Option<usize>,
+    tx: oneshot::Sender<std::result::Result<Allocation, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct AllocateBatch {
+    requests: Vec<AllocateSpec>,
+    mode: BatchMode,
+    tx: oneshot::Sender<std::result::Result<Vec<Option<Allocation>>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct TakeFor {
+    caller: CallerId,
+    count: u32,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct TakeWithOptions {
+    count: u32,
+    options: TakeOptions,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct TakeGrouped {
+    count: u32,
+    tx: oneshot::Sender<
+        std::result::Result<BTreeMap<Option<BlockPriority>, Vec<UniqueBlock>>, KvPoolError>,
+    >,
+}
+
+#[derive(Dissolve)]
+pub struct TakeAtPriority {
+    priority: BlockPriority,
+    tx: oneshot::Sender<std::result::Result<Option<UniqueBlock>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct PromoteCold {
+    hash: SequenceHash,
+    tx: oneshot::Sender<std::result::Result<Option<UniqueBlock>, KvPoolError>>,
+}
+
+pub enum MatchRequest {
+    MatchSingle(MatchSingle),
+    MatchIfVersion(MatchIfVersion),
+    MatchMultiple(MatchMultiple),
+    MatchDetailed(MatchDetailed),
+    MatchStream(MatchStream),
+    MatchTiered(MatchTiered),
+    MatchWithOptions(MatchWithOptions),
+    Take(Take),
+    TakeExact(TakeExact),
+    TakeReportingEvictions(TakeReportingEvictions),
+    Allocate(Allocate),
+    AllocateBatch(AllocateBatch),
+    AcquireAndDemote(AcquireAndDemote),
+    TakeWithOptions(TakeWithOptions),
+    TakeFor(TakeFor),
+    TakeGrouped(TakeGrouped),
+    TakeAtPriority(TakeAtPriority),
+    PromoteCold(PromoteCold),
+}
+
+pub struct UpdateBlock {
+    hash: SequenceHash,
+    priority: Option<BlockPriority>,
+}
+
+impl UpdateBlock {
+    /// Set the priority of the block cached under `hash`; a `u32` is converted by the
+    /// [BlockPriority] shim
+    pub fn new(hash: SequenceHash, priority: impl Into<BlockPriority>) -> Self {
+        Self {
+            hash,
+            priority: Some(priority.into()),
+        }
+    }
+}
+
+#[derive(Dissolve)]
+pub struct InsertControl {
+    block: KvBlock,
+    op_id: Option<u64>,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct InsertMultipleControl {
+    blocks: Vec<KvBlock>,
+    op_id: Option<u64>,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct InsertOnceControl {
+    op_id: u128,
+    blocks: Vec<KvBlock>,
+    tx: oneshot::Sender<InsertOutcome>,
+}
+
+#[derive(Dissolve)]
+pub struct UpdateSingleControl {
+    update: UpdateBlock,
+    tx: oneshot::Sender<std::result::Result<(), KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct UpdatePriorityIfControl {
+    hash: SequenceHash,
+    expected: BlockPriority,
+    new: BlockPriority,
+    tx: oneshot::Sender<std::result::Result<bool, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct UpdateMultipleControl {
+    updates: Vec<UpdateBlock>,
+    op_id: Option<u64>,
+    tx: oneshot::Sender<std::result::Result<(), KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct RebalanceControl {
+    scores: Vec<(SequenceHash, BlockPriority)>,
+    op_id: Option<u64>,
+    tx: oneshot::Sender<RebalanceReport>,
+}
+
+#[derive(Dissolve)]
+pub struct TouchControl {
+    hashes: Vec<SequenceHash>,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct ResetControl {
+    sequence_hashes: Vec<SequenceHash>,
+    op_id: Option<u64>,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct ResetAndReprioritizeControl {
+    entries: Vec<(SequenceHash, BlockPriority)>,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct ResetAllControl {
+    tx: oneshot::Sender<()>,
+}
+
+#[cfg(feature = "trace-record")]
+#[derive(Dissolve)]
+pub struct ExportTraceControl {
+    tx: oneshot::Sender<RecordedTrace>,
+}
+
+#[derive(Dissolve)]
+pub struct MirrorColdControl {
+    added: Vec<ColdHit>,
+    removed: Vec<SequenceHash>,
+    replace: bool,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct DumpStateControl {
+    tx: oneshot::Sender<StateDump>,
+}
+
+#[derive(Dissolve)]
+pub struct VerifyIntegrityControl {
+    repair: bool,
+    tx: oneshot::Sender<IntegrityReport>,
+}
+
+#[derive(Dissolve)]
+pub struct ExportManifestControl {
+    reset: bool,
+    tx: oneshot::Sender<PoolManifest>,
+}
+
+#[derive(Dissolve)]
+pub struct ShrinkControl {
+    target_total: u64,
+    tx: oneshot::Sender<ShrinkReport>,
+}
+
+#[derive(Dissolve)]
+pub struct EvictChainsControl {
+    target_blocks: u64,
+    tx: oneshot::Sender<EvictionReport>,
+}
+
+#[derive(Dissolve)]
+pub struct FenceOpsControl {
+    ops: Vec<u64>,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct BlankSetStatsControl {
+    tx: oneshot::Sender<BlankSetStats>,
+}
+
+#[derive(Dissolve)]
+pub struct ListUninitializedControl {
+    limit: usize,
+    tx: oneshot::Sender<Vec<KvBlockView>>,
+}
+
+#[derive(Dissolve)]
+pub struct PreviewTakeControl {
+    count: u32,
+    tx: oneshot::Sender<std::result::Result<Vec<KvBlockView>, KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct ReconcileControl {
+    verdict: BlockVerdict,
+    tx: oneshot::Sender<ReconcileReport>,
+}
+
+#[derive(Dissolve)]
+pub struct StatsControl {
+    tx: oneshot::Sender<PoolStats>,
+}
+
+#[derive(Dissolve)]
+pub struct ExpectControl {
+    hashes: Vec<SequenceHash>,
+    ttl: Duration,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct PeekHoldControl {
+    holder: u64,
+    hashes: Vec<SequenceHash>,
+    ttl: Duration,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct CheckoutForMaintenanceControl {
+    hash: SequenceHash,
+    tx: oneshot::Sender<Option<UniqueBlock>>,
+}
+
+#[derive(Dissolve)]
+pub struct ReleaseHoldsControl {
+    holder: u64,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct StickControl {
+    hashes: Vec<SequenceHash>,
+    session_id: u64,
+    ttl: Duration,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct ReportCorruptControl {
+    hash: SequenceHash,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct ShutdownControl {
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct SetBlockPriorityControl {
+    hash: SequenceHash,
+    priority: BlockPriority,
+    tx: oneshot::Sender<std::result::Result<(), KvPoolError>>,
+}
+
+#[derive(Dissolve)]
+pub struct UnstickControl {
+    session_id: u64,
+    tx: oneshot::Sender<usize>,
+}
+
+#[derive(Dissolve)]
+pub struct DrainCountersControl {
+    tx: oneshot::Sender<CounterSnapshot>,
+}
+
+#[derive(Dissolve)]
+pub struct MarkReadyControl {
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Dissolve)]
+pub struct EvictionAgeHistogramControl {
+    class: Option<u8>,
+    tx: oneshot::Sender<Vec<(AgeBucket, u64)>>,
+}
+
+#[derive(Dissolve)]
+pub struct WatchSequenceControl {
+    hash: SequenceHash,
+    tx: oneshot::Sender<watch::Receiver<SequenceState>>,
+}
+
+#[derive(Dissolve)]
+pub struct TopMissesControl {
+    k: usize,
+    tx: oneshot::Sender<Vec<(SequenceHash, u32)>>,
+}
+
+#[derive(Dissolve)]
+pub struct IsFullyCachedControl {
+    hashes: Vec<SequenceHash>,
+    tx: oneshot::Sender<bool>,
+}
+
+#[derive(Dissolve)]
+pub struct BeginOffloadControl {
+    hash: SequenceHash,
+    tx: oneshot::Sender<Option<BlockDescriptor>>,
+}
+
+#[derive(Dissolve)]
+pub struct CompleteOffloadControl {
+    hash: SequenceHash,
+    tx: oneshot::Sender<bool>,
+}
+
+#[derive(Dissolve)]
+pub struct CachedPrefixLenControl {
+    hashes: Vec<SequenceHash>,
+    tx: oneshot::Sender<usize>,
+}
+
+pub enum ControlRequest {
+    Insert(InsertControl),
+    InsertMultiple(InsertMultipleControl),
+    InsertOnce(InsertOnceControl),
+    UpdateSingle(UpdateSingleControl),
+    UpdatePriorityIf(UpdatePriorityIfControl),
+    UpdateMultiple(UpdateMultipleControl),
+    Rebalance(RebalanceControl),
+    Touch(TouchControl),
+    Reset(ResetControl),
+    ResetAndReprioritize(ResetAndReprioritizeControl),
+    ResetAll(ResetAllControl),
+    #[cfg(feature = "trace-record")]
+    ExportTrace(ExportTraceControl),
+    MirrorCold(MirrorColdControl),
+    DumpState(DumpStateControl),
+    VerifyIntegrity(VerifyIntegrityControl),
+    ExportManifest(ExportManifestControl),
+    Shrink(ShrinkControl),
+    EvictChains(EvictChainsControl),
+    FenceOps(FenceOpsControl),
+    BlankSetStats(BlankSetStatsControl),
+    ListUninitialized(ListUninitializedControl),
+    PreviewTake(PreviewTakeControl),
+    Reconcile(ReconcileControl),
+    Stats(StatsControl),
+    Expect(ExpectControl),
+    PeekHold(PeekHoldControl),
+    ReleaseHolds(ReleaseHoldsControl),
+    CheckoutForMaintenance(CheckoutForMaintenanceControl),
+    Stick(StickControl),
+    ReportCorrupt(ReportCorruptControl),
+    SetBlockPriority(SetBlockPriorityControl),
+    Shutdown(ShutdownControl),
+    Unstick(UnstickControl),
+    DrainCounters(DrainCountersControl),
+    MarkReady(MarkReadyControl),
+    IsFullyCached(IsFullyCachedControl),
+    CachedPrefixLen(CachedPrefixLenControl),
+    BeginOffload(BeginOffloadControl),
+    CompleteOffload(CompleteOffloadControl),
+    WatchSequence(WatchSequenceControl),
+    EvictionAgeHistogram(EvictionAgeHistogramControl),
+    TopMisses(TopMissesControl),
+}
+
+impl ControlRequest {
+    // Op id the request was tagged with for fence_ops
+    fn op_id(&self) -> Option<u64> {
+        match self {
+            ControlRequest::Insert(insert) => insert.op_id,
+            ControlRequest::InsertMultiple(insert) => insert.op_id,
+            ControlRequest::UpdateMultiple(update) => update.op_id,
+            ControlRequest::Rebalance(rebalance) => rebalance.op_id,
+            ControlRequest::Reset(reset) => reset.op_id,
+            _ => None,
+        }
+    }
+}
+
+/// A request stamped with its position in the issuing handle's order, if strict sequencing is
+/// enabled, and with the caller's op id if it was submitted tagged
+struct Sequenced<T> {
+    seq: Option<u64>,
+    context: Option<OpContext>,
+    request: T,
+}
+
+impl<T> Sequenced<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Sequenced<U> {
+        Sequenced {
+            seq: self.seq,
+            context: self.context,
+            request: f(self.request),
+        }
+    }
+}
+
+enum SequencedRequest {
+    Match(MatchRequest),
+    Control(ControlRequest),
+}
+
+struct OpFence {
+    remaining: HashSet<u64>,
+    tx: oneshot::Sender<()>,
+}
+
+struct Continuation {
+    op_id: Option<u64>,
+    context: Option<ActiveOp>,
+    kind: ContinuationKind,
+}
+
+enum ContinuationKind {
+    Match(MatchContinuation),
+    MatchStream(MatchStreamContinuation),
+    Update(UpdateContinuation),
+    Rebalance(RebalanceContinuation),
+    Reconcile(ReconcileContinuation),
+    Compact(CompactContinuation),
+}
+
+impl ContinuationKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ContinuationKind::Match(_) => "match",
+            ContinuationKind::MatchStream(_) => "match_stream",
+            ContinuationKind::Update(_) => "update",
+            ContinuationKind::Rebalance(_) => "rebalance",
+            ContinuationKind::Reconcile(_) => "reconcile",
+            ContinuationKind::Compact(_) => "compaction",
+        }
+    }
+
+    // Whether the last chunk stopped on a full stream channel; clears the flag
+    fn take_backpressure(&mut self) -> bool {
+        match self {
+            ContinuationKind::MatchStream(state) => std::mem::take(&mut state.backpressured),
+            _ => false,
+        }
+    }
+}
+
+struct MatchContinuation {
+    requested: usize,
+    hashes: std::vec::IntoIter<SequenceHash>,
+    single_use: bool,
+    start_tick: u64,
+    matched: Vec<UniqueBlock>,
+    tx: oneshot::Sender<std::result::Result<Vec<UniqueBlock>, KvPoolError>>,
+}
+
+// A chain of handle_evict_chains whose members the eviction order has not all reached yet
+struct ChainScan {
+    members: Vec<SequenceHash>,
+    seen: usize,
+    pinned: bool,
+}
+
+struct MatchStreamContinuation {
+    hashes: std::vec::IntoIter<SequenceHash>,
+    start_tick: u64,
+    tx: mpsc::Sender<Vec<UniqueBlock>>,
+    // set when a chunk found the channel full
+    backpressured: bool,
+}
+
+struct UpdateContinuation {
+    updates: std::vec::IntoIter<UpdateBlock>,
+    error: Option<KvPoolError>,
+    tx: oneshot::Sender<std::result::Result<(), KvPoolError>>,
+}
+
+struct RebalanceContinuation {
+    scores: std::vec::IntoIter<(SequenceHash, BlockPriority)>,
+    report: RebalanceReport,
+    tx: oneshot::Sender<RebalanceReport>,
+}
+
+// Entries of the lookup map not yet moved to the compacted map
+struct CompactContinuation {
+    entries: std::collections::hash_map::IntoIter<SequenceHash, PoolValue<KvBlock>>,
+    capacity_before: usize,
+}
+
+struct ReconcileContinuation {
+    verdict: BlockVerdict,
+    blanks_left: usize,
+    hashes: std::vec::IntoIter<SequenceHash>,
+    report: ReconcileReport,
+    tx: oneshot::Sender<ReconcileReport>,
+}
+
+async fn progress_engine(
+    state: AvailableBlocksState,
+    match_rx: mpsc::UnboundedReceiver<Sequenced<MatchRequest>>,
+    return_rx: ReturnReceiver,
+    overflow: Arc<ReturnOverflow>,
+    ctrl_rx: mpsc::UnboundedReceiver<Sequenced<ControlRequest>>,
+    fence_rx: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+) {
+    let mut state = state;
+    let mut match_rx = match_rx;
+    let mut return_rx = return_rx;
+    let mut ctrl_rx = ctrl_rx;
+    let mut fence_rx = fence_rx;
+    let adaptation = state
+        .config
+        .adaptive_priority
+        .map(|adaptive| adaptive.interval);
+    let mut janitor = [
+        state.config.offload_timeout,
+        state.config.uninitialized_ttl,
+        adaptation,
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+    .map(|period| tokio::time::interval(period.max(Duration::from_millis(1))));
+
+    loop {
+        if state.is_drained() && match_rx.is_empty() && ctrl_rx.is_empty() && return_rx.is_empty() {
+            // fences issued before the engine stopped still resolve
+            while let Ok(tx) = fence_rx.try_recv() {
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send fence ack; receiver dropped");
+                }
+            }
+            log::info!("pool drained; stopping the progress engine");
+            return;
+        }
+
+        tokio::select! {
+            biased;
+
+            // new requests wait while a chunked request is in progress
+            Some(match_req) = match_rx.recv(), if !match_rx.is_closed() && !state.has_continuation() => {
+                state.begin_turn();
+                state.handle_sequenced(match_req.map(SequencedRequest::Match));
+                state.end_turn("match");
+            }
+
+            // returns re-enter the lookup map, so they also wait for a compaction
+            Some(message) = return_rx.recv(), if !return_rx.is_closed() && !state.is_compacting() => {
+                state.begin_turn();
+                state.handle_return_message(message);
+                state.end_turn("return");
+            }
+
+            _ = overflow.notify.notified(), if !state.is_compacting() => {
+                state.begin_turn();
+                state.drain_overflow();
+                state.end_turn("overflow");
+            }
+
+            Some(req) = ctrl_rx.recv(), if !ctrl_rx.is_closed() && !state.has_continuation() => {
+                state.begin_turn();
+                state.handle_sequenced(req.map(SequencedRequest::Control));
+                state.end_turn("control");
+            }
+
+            _ = janitor_tick(&mut janitor), if !state.is_compacting() => {
+                state.begin_turn();
+                state.sweep_offloads();
+                state.expire_uninitialized();
+                state.adapt_priorities();
+                state.end_turn("janitor");
+            }
+
+            // a fence must not overtake the returns held back by a compaction
+            Some(tx) = fence_rx.recv(), if !state.is_compacting() => {
+                if tx.send(()).is_err() {
+                    log::trace!("Failed to send fence ack; receiver dropped");
+                }
+            }
+
+            index = state.parked_stream_ready(), if !state.has_continuation() => {
+                state.begin_turn();
+                state.resume_parked_stream(index);
+                state.end_turn("match_stream");
+            }
+
+            _ = std::future::ready(()), if state.has_continuation() => {
+                let request = state.continuation_name();
+                state.begin_turn();
+                state.resume_continuation();
+                state.end_turn(request);
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+// Fail a request which can only come from a caller bug; debug builds panic instead, so the bug
+// surfaces where it was made
+fn invalid_request(description: String) -> Result<()> {
+    if cfg!(debug_assertions) {
+        panic!("invalid request: {description}");
+    }
+    raise!(KvPoolError::InvalidRequest(description));
+}
+
+// Reject a match listing a hash twice, see the match ordering of the module
+fn validate_match(hashes: &[SequenceHash]) -> Result<()> {
+    if hashes.len() < 2 {
+        return Ok(());
+    }
+    let mut seen = HashSet::with_capacity(hashes.len());
+    if let Some(hash) = hashes.iter().find(|hash| !seen.insert(**hash)) {
+        raise!(KvPoolError::InvalidRequest(format!(
+            "hash {hash} appears more than once in the match"
+        )));
+    }
+    Ok(())
+}
+
+// Reject a take of zero blocks, a caller bug
+fn validate_take(count: u32) -> Result<()> {
+    if count == 0 {
+        return invalid_request("take of zero blocks".to_string());
+    }
+    Ok(())
+}
+
+// Cut `hashes` to at most `max_blocks`; returns the first hash cut off
+fn truncate_prefix(
+    hashes: &mut Vec<SequenceHash>,
+    max_blocks: Option<usize>,
+) -> Option<SequenceHash> {
+    let max_blocks = max_blocks?;
+    let resume_from = hashes.get(max_blocks).copied();
+    hashes.truncate(max_blocks);
+    resume_from
+}
+
+// Sort rebalance scores by hash, keeping the last score of each
+fn sort_scores(
+    mut scores: Vec<(SequenceHash, BlockPriority)>,
+) -> Vec<(SequenceHash, BlockPriority)> {
+    scores.reverse();
+    scores.sort_by_key(|(sequence_hash, _)| *sequence_hash);
+    scores.dedup_by_key(|(sequence_hash, _)| *sequence_hash);
+    scores
+}
+
+// Tokens held by matched blocks; a partial block counts its valid tokens
+fn valid_tokens(blocks: &[PoolItem<KvBlock>]) -> u64 {
+    blocks
+        .iter()
+        .map(|block| block.token_block.tokens().len() as u64)
+        .sum()
+}
+
+// Completes on the next tick of the janitor; never completes without one
+async fn janitor_tick(janitor: &mut Option<tokio::time::Interval>) {
+    match janitor {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::tokens::Token;
+
+    use super::*;
+
+    #[test]
+    fn test_priority_key_ord() {
+        let mut map = BTreeMap::new();
+        let hash1 = SequenceHash::from(1u64);
+        let hash2 = SequenceHash::from(2u64);
+        let hash3 = SequenceHash::from(3u64);
+
+        map.insert(
+            PriorityKey {
+                priority: BlockPriority::new(0, 0),
+                sticky: false,
+                affinity_tick: 0,
+                return_tick: 1,
+                sequence_hash: hash1,
+            },
+            "value1",
+        );
+        map.insert(
+            PriorityKey {
+                priority: BlockPriority::new(0, 1),
+                sticky: false,
+                affinity_tick: 0,
+                return_tick: 0,
+                sequence_hash: hash2,
+            },
+            "value2",
+        );
+        map.insert(
+            PriorityKey {
+                priority: BlockPriority::new(0, 0),
+                sticky: false,
+                affinity_tick: 0,
+                return_tick: 2,
+                sequence_hash: hash3,
+            },
+            "value3",
+        );
+
+        let keys: Vec<_> = map.keys().collect();
+
+        // Priority is the primary sort key (0 before 1)
+        assert_eq!(keys[0].priority.score(), 0);
+        assert_eq!(keys[1].priority.score(), 0);
+        assert_eq!(keys[2].priority.score(), 1);
+
+        // For same priority, return_tick is the secondary sort key
+        assert_eq!(keys[0].return_tick, 1);
+        assert_eq!(keys[1].return_tick, 2);
+
+        // Test popping from the map to verify ordering
+        let (first_key, first_value) = map.pop_first().unwrap();
+        assert_eq!(first_key.priority.score(), 0);
+        assert_eq!(first_key.return_tick, 1);
+        assert_eq!(first_key.sequence_hash, hash1);
+        assert_eq!(first_value, "value1");
+
+        let (second_key, second_value) = map.pop_first().unwrap();
+        assert_eq!(second_key.priority.score(), 0);
+        assert_eq!(second_key.return_tick, 2);
+        assert_eq!(second_key.sequence_hash, hash3);
+        assert_eq!(second_value, "value3");
+
+        let (third_key, third_value) = map.pop_first().unwrap();
+        assert_eq!(third_key.priority.score(), 1);
+        assert_eq!(third_key.return_tick, 0);
+        assert_eq!(third_key.sequence_hash, hash2);
+        assert_eq!(third_value, "value2");
+
+        // Map should now be empty
+        assert!(map.is_empty());
+    }
+
+    // Helper function to create a sequence of tokens
+    pub fn create_token_sequence(values: &[u32]) -> Tokens {
+        let tokens: Vec<Token> = values.iter().map(|&v| Token::from(v)).collect();
+        Tokens::from(tokens)
+    }
+
+    // Helper to create blocks from a sequence with given size
+    pub fn create_blocks(sequence: Tokens, block_size: usize) -> Vec<KvBlock> {
+        let (blocks, _) = sequence.into_sequence(block_size).into_parts();
+        blocks
+            .into_iter()
+            .map(|token_block| KvBlock {
+                token_block,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // Sequence hashes of the blocks, in order
+    pub fn block_hashes(blocks: &[KvBlock]) -> Vec<SequenceHash> {
+        blocks
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_basic_sequence_matching() {
+        let pool = AvailableBlocks::new().await;
+
+        // Create a sequence of 4 tokens split into blocks of 2
+        let sequence = create_token_sequence(&[1, 2, 3, 4]);
+        let blocks = create_blocks(sequence, 2);
+        assert_eq!(blocks.len(), 2);
+
+        // Match the blocks in sequence
+        let hashes: Vec<_> = blocks
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect();
+
+        // Insert blocks into pool
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        pool.fence().await.unwrap();
+
+        assert_eq!(pool.total_blocks(), 2);
+        assert_eq!(pool.available_blocks(), 2);
+
+        // Match the blocks in sequence
+        let matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(matched.len(), 2);
+
+        assert_eq!(pool.total_blocks(), 2);
+        assert_eq!(pool.available_blocks(), 0);
+
+        // Validate the blocks are in the correct order and match the sequence hashes
+        assert_eq!(matched[0].token_block.sequence_hash(), hashes[0]);
+        assert_eq!(matched[1].token_block.sequence_hash(), hashes[1]);
+
+        // Return blocks in reverse order (tail to root)
+        for block in matched.into_iter().rev() {
+            drop(block); // This will trigger return_to_pool
+        }
+
+        pool.fence().await.unwrap();
+
+        assert_eq!(pool.total_blocks(), 2);
+        assert_eq!(pool.available_blocks(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_equal_priority_taking() {
+        let pool = AvailableBlocks::new().await;
+
+        // Create two sequences with different priorities
+        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
+        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+
+        let mut blocks1 = create_blocks(seq1, 2);
+        let mut blocks2 = create_blocks(seq2, 2);
+
+        for block in blocks1.iter_mut() {
+            block.set_priority(1);
+        }
+        for block in blocks2.iter_mut() {
+            block.set_priority(1);
+        }
+
+        // If priorities were equal, first in, first out would apply
+
+        // Insert Sequence 2
+        for block in blocks2.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Insert Sequence 1
+        for block in blocks1.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        pool.fence().await.unwrap();
+
+        let blocks = pool.take_blocks(4).await.unwrap();
+        assert_eq!(blocks.len(), 4);
+
+        // Validate the blocks are in the correct order
+        assert_eq!(blocks[0].token_block.tokens()[0], 7);
+        assert_eq!(blocks[1].token_block.tokens()[0], 5);
+        assert_eq!(blocks[2].token_block.tokens()[0], 3);
+        assert_eq!(blocks[3].token_block.tokens()[0], 1);
+    }
+
+    #[tokio::test]
+    async fn test_priority_taking() {
+        let pool = AvailableBlocks::new().await;
+
+        // Create two sequences with different priorities
+        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
+        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+
+        let mut blocks1 = create_blocks(seq1, 2);
+        let mut blocks2 = create_blocks(seq2, 2);
+
+        for block in blocks1.iter_mut() {
+            block.set_priority(1);
+        }
+        for block in blocks2.iter_mut() {
+            block.set_priority(2);
+        }
+
+        // If priorities were equal, first in, first out would apply
+        // but here we have a higher priority block first (which are taken last)
+        // returned first, but lower priority blocks inserted after
+        // we expect the lower priority blocks to be taken first
+
+        // Insert Sequence 2
+        for block in blocks2.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Insert Sequence 1
+        for block in blocks1.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        pool.fence().await.unwrap();
+
+        let blocks = pool.take_blocks(4).await.unwrap();
+        assert_eq!(blocks.len(), 4);
+
+        // Validate the blocks are in the correct order
+        assert_eq!(blocks[0].token_block.tokens()[0], 3);
+        assert_eq!(blocks[1].token_block.tokens()[0], 1);
+        assert_eq!(blocks[2].token_block.tokens()[0], 7);
+        assert_eq!(blocks[3].token_block.tokens()[0], 5);
+    }
+
+    #[tokio::test]
+    async fn test_priority_taking_after_update() {
+        let pool = AvailableBlocks::new().await;
+
+        // Create two sequences with different priorities
+        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
+        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+
+        let mut blocks1 = create_blocks(seq1, 2);
+        let mut blocks2 = create_blocks(seq2, 2);
+
+        for block in blocks1.iter_mut() {
+            block.set_priority(1);
+        }
+        for block in blocks2.iter_mut() {
+            block.set_priority(1);
+        }
+
+        // record hash of blocks 2
+        // insert blocks 2, then blocks 1
+        // update priority of blocks 2 to 2 using the update api
+        // pull 4 blocks and test order
+
+        let block_hashes = blocks2
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect::<Vec<_>>();
+
+        // Insert Sequence 2
+        for block in blocks2.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Insert Sequence 1
+        for block in blocks1.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        pool.fence().await.unwrap();
+
+        // Update priority of blocks 2 to 2
+        pool.update_multiple(
+            block_hashes
+                .into_iter()
+                .map(|h| UpdateBlock::new(h, 2))
+                .collect(),
+        )
+        .await
+        .unwrap();
+
+        pool.fence().await.unwrap();
+
+        let blocks = pool.take_blocks(4).await.unwrap();
+        assert_eq!(blocks.len(), 4);
+
+        // Validate the blocks are in the correct order
+        assert_eq!(blocks[0].token_block.tokens()[0], 3);
+        assert_eq!(blocks[1].token_block.tokens()[0], 1);
+        assert_eq!(blocks[2].token_block.tokens()[0], 7);
+        assert_eq!(blocks[3].token_block.tokens()[0], 5);
+    }
+
+    #[tokio::test]
+    async fn test_reset_all() {
+        let pool = AvailableBlocks::new().await;
+
+        // Create two sequences with different priorities
+        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
+        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+
+        let mut blocks1 = create_blocks(seq1, 2);
+        let mut blocks2 = create_blocks(seq2, 2);
+
+        for block in blocks1.iter_mut() {
+            block.set_priority(1);
+        }
+
+        for block in blocks2.iter_mut() {
+            block.set_priority(1);
+        }
+
+        // record hash of blocks 2
+        let block_hashes = blocks2
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect::<Vec<_>>();
+
+        // Insert Sequence 2
+        for block in blocks2.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Insert Sequence 1
+        for block in blocks1.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Reset All
+        pool.reset_all().await.unwrap();
+        pool.fence().await.unwrap();
+
+        // Try to match from block 2 hashes, expect no matches
+        let matched = pool.match_blocks(block_hashes).await.unwrap();
+        assert_eq!(matched.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset_block2() {
+        let pool = AvailableBlocks::new().await;
+
+        // Create two sequences with different priorities
+        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
+        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+
+        let mut blocks1 = create_blocks(seq1, 2);
+        let mut blocks2 = create_blocks(seq2, 2);
+
+        for block in blocks1.iter_mut() {
+            block.set_priority(1);
+        }
+
+        for block in blocks2.iter_mut() {
+            block.set_priority(1);
+        }
+
+        // record hash of blocks 2
+        let block2_hashes = blocks2
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect::<Vec<_>>();
+
+        let block1_hashes = blocks1
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect::<Vec<_>>();
+
+        // Insert Sequence 2
+        for block in blocks2.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Insert Sequence 1
+        for block in blocks1.into_iter().rev() {
+            pool.insert(block).await.unwrap();
+        }
+
+        // Reset Block 2
+        pool.reset(block2_hashes.clone()).await.unwrap();
+        pool.fence().await.unwrap();
+
+        // Try to match from block 2 hashes, expect no matches
+        let matched = pool.match_blocks(block2_hashes).await.unwrap();
+        assert_eq!(matched.len(), 0);
+
+        let matched = pool.match_blocks(block1_hashes).await.unwrap();
+        assert_eq!(matched.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bounded_return_channel_never_loses_blocks() {
+        let config = AvailableBlocksConfig::default().with_bounded_returns(1);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let values: Vec<u32> = (0..512).collect();
+        let blocks = create_blocks(create_token_sequence(&values), 2);
+        let block_count = blocks.len() as u64;
+
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), block_count);
+
+        for _ in 0..8 {
+            let taken = pool.take_blocks(block_count as u32).await.unwrap();
+            assert_eq!(taken.len() as u64, block_count);
+            assert_eq!(pool.available_blocks(), 0);
+
+            // all drops happen synchronously, far exceeding the channel capacity
+            drop(taken);
+
+            pool.fence().await.unwrap();
+            assert_eq!(pool.total_blocks(), block_count);
+            assert_eq!(pool.available_blocks(), block_count);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_fully_cached() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes: Vec<_> = blocks
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect();
+
+        // only insert the first two blocks of the sequence
+        for block in blocks.into_iter().take(2) {
+            pool.insert(block).await.unwrap();
+        }
+
+        assert!(pool.is_fully_cached(hashes[..2].to_vec()).await.unwrap());
+        assert!(!pool.is_fully_cached(hashes.clone()).await.unwrap());
+        assert!(pool.is_fully_cached(vec![]).await.unwrap());
+
+        // the query must not remove any blocks
+        assert_eq!(pool.available_blocks(), 2);
+        let matched = pool.match_blocks(hashes).await.unwrap();
+        assert_eq!(matched.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_gate_rejects_until_ready() {
+        let config = AvailableBlocksConfig::default().with_readiness_gate(NotReadyPolicy::Reject);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        assert_eq!(pool.health().lifecycle, PoolLifecycle::Initializing);
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes: Vec<_> = blocks
+            .iter()
+            .map(|b| b.token_block.sequence_hash())
+            .collect();
+
+        // inserts are allowed while initializing
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        let err = pool.take_blocks(1).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::NotReady)
+        ));
+        let err = pool.match_blocks(hashes.clone()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::NotReady)
+        ));
+
+        pool.mark_ready().await.unwrap();
+        assert_eq!(pool.health().lifecycle, PoolLifecycle::Ready);
+
+        let matched = pool.match_blocks(hashes).await.unwrap();
+        assert_eq!(matched.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_gate_queues_until_ready() {
+        let config = AvailableBlocksConfig::default().with_readiness_gate(NotReadyPolicy::Queue);
+        let pool = Arc::new(AvailableBlocks::new_with_config(config).await);
+
+        let pool_clone = pool.clone();
+        let take = tokio::spawn(async move { pool_clone.take_blocks(2).await.unwrap().len() });
+
+        for block in create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2) {
+            pool.insert(block).await.unwrap();
+        }
+
+        // the take is parked until the pool is marked ready
+        tokio::task::yield_now().await;
+        assert!(!take.is_finished());
+
+        pool.mark_ready().await.unwrap();
+        assert_eq!(take.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_age_bucket_bounds() {
+        for age in [0, 1, 2, 3, 4, 7, 8, 1000, u64::MAX] {
+            let bucket = AgeBucket::from_index(AgeBucket::index(age));
+            assert!(bucket.min <= age);
+            assert!(bucket.max.is_none_or(|max| age < max));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eviction_age_histogram() {
+        let pool = AvailableBlocks::new().await;
+
+        // inserted at ticks 1..=4, so at tick 4 the ages are 3, 2, 1 and 0
+        for block in create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2) {
+            pool.insert(block).await.unwrap();
+        }
+
+        // blocks taken from the uninitialized set are not evictions
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let taken = pool.take_blocks(5).await.unwrap();
+        assert_eq!(taken.len(), 5);
+
+        let histogram = pool.eviction_age_histogram().await.unwrap();
+        assert_eq!(histogram.len(), EVICTION_AGE_BUCKETS);
+
+        // the blank block was inserted at tick 5, so the ages of the evicted blocks are 4, 3, 2 and 1
+        let counts: Vec<u64> = histogram.iter().map(|(_, count)| *count).collect();
+        assert_eq!(&counts[..4], &[0, 1, 2, 1]);
+        assert_eq!(counts.iter().sum::<u64>(), 4);
+        assert_eq!(
+            histogram[3].0,
+            AgeBucket {
+                min: 4,
+                max: Some(8)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eviction_age_histogram_of_class() {
+        let pool = AvailableBlocks::new().await;
+
+        // inserted at ticks 1..=3, so at tick 3 the ages are 2, 1 and 0
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        blocks[0].set_priority(BlockPriority::new(1, 0));
+        blocks[1].set_priority(BlockPriority::new(0, u32::MAX));
+        blocks[2].set_priority(BlockPriority::new(1, 1));
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        let taken = pool.take_blocks(3).await.unwrap();
+        assert_eq!(taken.len(), 3);
+
+        let counts = |histogram: Vec<(AgeBucket, u64)>| -> Vec<u64> {
+            histogram[..3].iter().map(|(_, count)| *count).collect()
+        };
+        let class_0 = pool.eviction_age_histogram_of_class(0).await.unwrap();
+        assert_eq!(counts(class_0), vec![0, 1, 0]);
+        let class_1 = pool.eviction_age_histogram_of_class(1).await.unwrap();
+        assert_eq!(counts(class_1), vec![1, 0, 1]);
+
+        let class_2 = pool.eviction_age_histogram_of_class(2).await.unwrap();
+        assert_eq!(class_2.len(), EVICTION_AGE_BUCKETS);
+        assert!(class_2.iter().all(|(_, count)| *count == 0));
+    }
+
+    #[tokio::test]
+    async fn test_reject_zero_hash() {
+        let config = AvailableBlocksConfig::default().with_reject_zero_hash(true);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let err = pool.insert(KvBlock::default()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::ZeroHash)
+        ));
+
+        // a batch containing a zero hash block is rejected as a whole
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        blocks.push(KvBlock::default());
+        let err = pool.insert_many(blocks).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::ZeroHash)
+        ));
+        assert_eq!(pool.total_blocks(), 0);
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        pool.insert_many(blocks).await.unwrap();
+        assert_eq!(pool.total_blocks(), 2);
+
+        // without the flag, zero hash blocks are accepted as uninitialized
+        let pool = AvailableBlocks::new().await;
+        pool.insert(KvBlock::default()).await.unwrap();
+        assert_eq!(pool.total_blocks(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shrink_removes_idle_blocks_first() {
+        let pool = AvailableBlocks::new().await;
+
+        for block in create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2) {
+            pool.insert(block).await.unwrap();
+        }
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 2);
+
+        let report = pool.shrink(1).await.unwrap();
+        assert_eq!(report.removed_idle, 1);
+        assert_eq!(report.retired_in_flight, 0);
+        assert_eq!(pool.total_blocks(), 1);
+        assert_eq!(pool.available_blocks(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shrink_retires_checked_out_blocks() {
+        let pool = AvailableBlocks::new().await;
+
+        for block in create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2) {
+            pool.insert(block).await.unwrap();
+        }
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 4);
+
+        let taken = pool.take_blocks(4).await.unwrap();
+        assert_eq!(taken.len(), 4);
+
+        let report = pool.shrink(2).await.unwrap();
+        assert_eq!(report.removed_idle, 0);
+        assert_eq!(report.retired_in_flight, 2);
+        assert_eq!(pool.total_blocks(), 2);
+
+        // the first two stale returns fill the retired slots, the rest are re-admitted
+        drop(taken);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 2);
+        assert_eq!(pool.available_blocks(), 2);
+
+        // checkouts of the new generation return as usual
+        let taken = pool.take_blocks(2).await.unwrap();
+        assert_eq!(taken.len(), 2);
+        drop(taken);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 2);
+        assert_eq!(pool.available_blocks(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_take_one_at_priority() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        for (block, priority) in blocks.iter_mut().zip([0, 1, 1, 2]) {
+            block.set_priority(priority);
+        }
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+        pool.insert(KvBlock::default()).await.unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 5);
+
+        // the oldest block of the requested tier is taken first
+        let block = pool.take_one_at_priority(1).await.unwrap().unwrap();
+        assert_eq!(block.token_block.tokens()[0], 3);
+        let block = pool.take_one_at_priority(1).await.unwrap().unwrap();
+        assert_eq!(block.token_block.tokens()[0], 5);
+        assert!(pool.take_one_at_priority(1).await.unwrap().is_none());
+        assert!(pool.take_one_at_priority(3).await.unwrap().is_none());
+        assert_eq!(pool.available_blocks(), 3);
+
+        // the other tiers and the uninitialized block are untouched
+        let blocks = pool.take_blocks(3).await.unwrap();
+        let priorities: Vec<u32> = blocks.iter().map(|block| block.score()).collect();
+        assert_eq!(priorities, vec![0, 0, 2]);
+        assert_eq!(blocks[1].token_block.tokens()[0], 1);
+    }
+
+    #[tokio::test]
+    async fn test_watch_sequence() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+
+        let mut watcher = pool.watch_sequence(hash).await.unwrap();
+        assert_eq!(*watcher.borrow_and_update(), SequenceState::Absent);
+
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+        watcher.changed().await.unwrap();
+        assert_eq!(*watcher.borrow_and_update(), SequenceState::Cached);
+
+        // a watcher registered later starts from the current state
+        let late_watcher = pool.watch_sequence(hash).await.unwrap();
+        assert_eq!(*late_watcher.borrow(), SequenceState::Cached);
+
+        let matched = pool.match_blocks(vec![hash]).await.unwrap();
+        assert_eq!(matched.len(), 1);
+        watcher.changed().await.unwrap();
+        assert_eq!(*watcher.borrow_and_update(), SequenceState::InUse);
+
+        drop(matched);
+        watcher.changed().await.unwrap();
+        assert_eq!(*watcher.borrow_and_update(), SequenceState::Cached);
+
+        pool.reset(vec![hash]).await.unwrap();
+        watcher.changed().await.unwrap();
+        assert_eq!(*watcher.borrow_and_update(), SequenceState::Absent);
+        assert_eq!(*late_watcher.borrow(), SequenceState::Absent);
+    }
+
+    #[test]
+    fn test_dropped_watchers_are_pruned() {
+        let mut state = detached_state(AvailableBlocksConfig::default());
+        let kept = state.watch_sequence(0);
+        for hash in 1..WATCHER_PRUNE_MIN as u64 {
+            drop(state.watch_sequence(hash));
+        }
+        assert_eq!(state.sequence_watchers.len(), WATCHER_PRUNE_MIN);
+
+        // the next registration prunes every watcher without a receiver
+        let _next = state.watch_sequence(1000);
+        assert_eq!(state.sequence_watchers.len(), 2);
+        assert!(state.sequence_watchers.contains_key(&0));
+        drop(kept);
+    }
+
+    async fn update_then_match(pool: &AvailableBlocks, hash: SequenceHash) -> BlockPriority {
+        let update = UpdateBlock::new(hash, 5);
+
+        // both requests are issued before the engine gets to run
+        let (updated, matched) =
+            tokio::join!(pool.update_single(update), pool.match_blocks(vec![hash]));
+        updated.unwrap();
+
+        let matched = matched.unwrap();
+        assert_eq!(matched.len(), 1);
+        matched[0].priority
+    }
+
+    #[tokio::test]
+    async fn test_strict_sequencing() {
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+
+        // without sequencing the match overtakes the update
+        let pool = AvailableBlocks::new().await;
+        for block in create_blocks(create_token_sequence(&[1, 2]), 2) {
+            pool.insert(block).await.unwrap();
+        }
+        assert_eq!(
+            update_then_match(&pool, hash).await,
+            BlockPriority::new(0, 0)
+        );
+
+        let config = AvailableBlocksConfig::default().with_strict_sequencing(true);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+        assert_eq!(
+            update_then_match(&pool, hash).await,
+            BlockPriority::new(0, 5)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fence_ops() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let second = blocks.pop().unwrap();
+        let first = blocks.pop().unwrap();
+
+        pool.insert_tagged(first, Some(1)).await.unwrap();
+        pool.fence_ops(vec![1]).await.unwrap();
+        assert_eq!(pool.total_blocks(), 1);
+
+        // a fence on an op which has not been submitted yet keeps waiting for it
+        let fence = pool.fence_ops(vec![2]);
+        tokio::pin!(fence);
+        let waited = tokio::time::timeout(std::time::Duration::from_millis(10), &mut fence).await;
+        assert!(waited.is_err());
+
+        pool.insert_tagged(second, Some(2)).await.unwrap();
+        fence.await.unwrap();
+        assert_eq!(pool.total_blocks(), 2);
+
+        pool.fence_ops(vec![]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fence_ops_window() {
+        let config = AvailableBlocksConfig::default().with_completed_ops_window(2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        for op_id in 1..=3 {
+            pool.insert_tagged(KvBlock::default(), Some(op_id))
+                .await
+                .unwrap();
+        }
+        pool.reset_tagged(vec![0], Some(4)).await.unwrap();
+
+        // the ids still in the window resolve at once, the oldest one was forgotten
+        pool.fence_ops(vec![3, 4]).await.unwrap();
+        let waited = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            pool.fence_ops(vec![1]),
+        )
+        .await;
+        assert!(waited.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blank_storage_cap() {
+        let config = AvailableBlocksConfig::default().with_blank_storage_cap(1, 2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let block_bytes = blocks[0].token_block.storage_bytes();
+        assert!(block_bytes > 0);
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        let stats = pool.blank_set_stats().await.unwrap();
+        assert_eq!(stats.blocks, 0);
+        assert_eq!(stats.storage_bytes, 0);
+
+        // the reset blocks keep their storage, except those beyond the cap
+        pool.reset_all().await.unwrap();
+        let stats = pool.blank_set_stats().await.unwrap();
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.storage_bytes, block_bytes);
+        assert_eq!(stats.released_bytes, 3 * block_bytes as u64);
+
+        // blanks are handed out with storage for a full block
+        let taken = pool.take_blocks(4).await.unwrap();
+        assert_eq!(taken.len(), 4);
+        for block in &taken {
+            assert!(block.token_block.storage_bytes() >= 2 * std::mem::size_of::<Token>());
+        }
+
+        let stats = pool.blank_set_stats().await.unwrap();
+        assert_eq!(stats.blocks, 0);
+        assert_eq!(stats.storage_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset_frees_storage_without_cap() {
+        let pool = AvailableBlocks::new().await;
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2))
+            .await
+            .unwrap();
+
+        pool.reset_all().await.unwrap();
+        let stats = pool.blank_set_stats().await.unwrap();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.storage_bytes, 0);
+        assert_eq!(stats.released_bytes, 0);
+    }
+
+    // Repeatedly take a block and refill it with alternating content, returning the number of evictions
+    async fn evictions_under_oscillation(config: AvailableBlocksConfig) -> u64 {
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let contents: Vec<TokenBlock> = blocks.iter().map(|b| b.token_block.clone()).collect();
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        for i in 0..8 {
+            let mut taken = pool.take_blocks(1).await.unwrap();
+            taken[0].update_token_block(contents[i % 2].clone());
+            drop(taken);
+            pool.fence().await.unwrap();
+        }
+
+        let histogram = pool.eviction_age_histogram().await.unwrap();
+        histogram.iter().map(|(_, count)| count).sum()
+    }
+
+    #[tokio::test]
+    async fn test_eviction_hysteresis() {
+        // without hysteresis every take evicts a block which is re-admitted right after
+        let flapping = evictions_under_oscillation(AvailableBlocksConfig::default()).await;
+        assert_eq!(flapping, 8);
+
+        let config = AvailableBlocksConfig::default().with_eviction_hysteresis(100);
+        let smoothed = evictions_under_oscillation(config).await;
+        assert_eq!(smoothed, 1);
+
+        // once the window has passed, the hash is admitted again
+        let config = AvailableBlocksConfig::default().with_eviction_hysteresis(0);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let content = blocks[0].token_block.clone();
+        let hash = content.sequence_hash();
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        let mut taken = pool.take_blocks(1).await.unwrap();
+        taken[0].update_token_block(content);
+        drop(taken);
+        pool.fence().await.unwrap();
+        assert!(pool.is_fully_cached(vec![hash]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_match_is_consistent() {
+        let config = AvailableBlocksConfig::default().with_handler_chunk_size(1);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        for block in blocks {
+            pool.insert(block).await.unwrap();
+        }
+
+        let held = pool.match_blocks(vec![hashes[2]]).await.unwrap();
+        assert_eq!(held.len(), 1);
+
+        // the last block is returned while the match is in progress
+        let (matched, _) = tokio::join!(pool.match_blocks(hashes.clone()), async { drop(held) });
+        let matched = matched.unwrap();
+        assert_eq!(matched.len(), 2);
+
+        let matched = pool.match_blocks(vec![hashes[2]]).await.unwrap();
+        assert_eq!(matched.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fence_during_chunked_match() {
+        let config = AvailableBlocksConfig::default().with_handler_chunk_size(16);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let values: Vec<u32> = (0..4096).collect();
+        let blocks = create_blocks(create_token_sequence(&values), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let completed = Mutex::new(Vec::new());
+        let (matched, _) = tokio::join!(
+            async {
+                let matched = pool.match_blocks(hashes.clone()).await.unwrap();
+                completed.lock().unwrap().push("match");
+                matched
+            },
+            async {
+                pool.fence().await.unwrap();
+                completed.lock().unwrap().push("fence");
+            }
+        );
+
+        assert_eq!(matched.len(), hashes.len());
+        assert_eq!(*completed.lock().unwrap(), vec!["fence", "match"]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_counters() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        drop(pool.match_blocks(hashes[..2].to_vec()).await.unwrap());
+        pool.fence().await.unwrap();
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(
+            counters,
+            CounterSnapshot {
+                matches: 2,
+                inserts: 3,
+                returns: 2,
+                ..Default::default()
+            }
+        );
+
+        // the second drain only reflects what happened since the first
+        pool.reset(vec![hashes[0]]).await.unwrap();
+        drop(pool.take_blocks(2).await.unwrap());
+        pool.fence().await.unwrap();
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(
+            counters,
+            CounterSnapshot {
+                takes: 2,
+                evictions: 1,
+                returns: 2,
+                resets: 1,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            pool.drain_counters().await.unwrap(),
+            CounterSnapshot::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expect_shields_blocks() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let ttl = Duration::from_secs(60);
+        let shielded = pool.expect(vec![hashes[0], hashes[1]], ttl).await.unwrap();
+        assert_eq!(shielded, 2);
+        assert_eq!(pool.stats().await.unwrap().active_shields, 2);
+
+        // the unshielded blocks are evicted first, even though they are younger
+        let taken = pool.take_blocks(2).await.unwrap();
+        let taken: Vec<SequenceHash> = taken
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        assert_eq!(taken, vec![hashes[2], hashes[3]]);
+        assert_eq!(pool.drain_counters().await.unwrap().shield_breaches, 0);
+
+        // once only shielded blocks are left, takes still succeed
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert_eq!(taken[0].token_block.sequence_hash(), hashes[0]);
+        assert_eq!(pool.drain_counters().await.unwrap().shield_breaches, 1);
+
+        // matching a block lifts its shield
+        let matched = pool.match_blocks(vec![hashes[1]]).await.unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(pool.stats().await.unwrap().active_shields, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset_clears_shields() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let ttl = Duration::from_secs(60);
+        assert_eq!(pool.expect(hashes.clone(), ttl).await.unwrap(), 3);
+
+        pool.reset(vec![hashes[0]]).await.unwrap();
+        assert_eq!(pool.stats().await.unwrap().active_shields, 2);
+        pool.reset_and_reprioritize(vec![(hashes[1], BlockPriority::new(0, 1))])
+            .await
+            .unwrap();
+        assert_eq!(pool.stats().await.unwrap().active_shields, 1);
+        pool.reset_all().await.unwrap();
+        assert_eq!(pool.stats().await.unwrap().active_shields, 0);
+    }
+
+    #[tokio::test]
+    async fn test_expect_ttl_expires() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
+
+        // hashes which are not resident are not shielded
+        let shielded = pool.expect(vec![hash, 42], Duration::ZERO).await.unwrap();
+        assert_eq!(shielded, 1);
+        assert_eq!(pool.stats().await.unwrap().active_shields, 0);
+    }
+
+    #[tokio::test]
+    async fn test_hash_extender_separates_adapters() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+
+        let adapter1 = AdapterHashExtender::new(1);
+        let adapter2 = AdapterHashExtender::new(2);
+        pool.with_hash_extender(&adapter1)
+            .insert_many(blocks)
+            .await
+            .unwrap();
+
+        // the same tokens under another adapter, or without one, do not match
+        let other = pool.with_hash_extender(&adapter2);
+        assert!(!other.is_fully_cached(hashes.clone()).await.unwrap());
+        assert!(other.match_blocks(hashes.clone()).await.unwrap().is_empty());
+        assert!(pool.match_blocks(hashes.clone()).await.unwrap().is_empty());
+
+        // both adapters can hold the same tokens side by side
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        other.insert_many(blocks).await.unwrap();
+        assert_eq!(pool.stats().await.unwrap().reusable_blocks, 4);
+
+        let matched = pool
+            .with_hash_extender(&adapter1)
+            .match_blocks(hashes.clone())
+            .await
+            .unwrap();
+        assert_eq!(matched.len(), 2);
+        assert_eq!(matched[0].lookup_key(), adapter1.extend(hashes[0]));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let config = AvailableBlocksConfig::default().with_handler_chunk_size(3);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let values: Vec<u32> = (0..16).collect();
+        let blocks = create_blocks(create_token_sequence(&values), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let in_flight = pool.match_blocks(vec![hashes[5]]).await.unwrap();
+        assert_eq!(in_flight.len(), 1);
+
+        // blank the first half, remove the third quarter, keep the rest
+        let report = pool
+            .reconcile(|block| match block.token_block.tokens().first() {
+                Some(token) if *token < 8 => BlockFate::Blank,
+                Some(token) if *token < 12 => BlockFate::Remove,
+                _ => BlockFate::Keep,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReconcileReport {
+                kept: 2,
+                blanked: 4,
+                removed: 1,
+                in_flight: 1,
+            }
+        );
+
+        assert_eq!(pool.total_blocks(), 7);
+        assert_eq!(pool.available_blocks(), 6);
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.uninitialized_blocks, 4);
+        assert_eq!(stats.reusable_blocks, 2);
+        assert!(!pool.is_fully_cached(vec![hashes[0]]).await.unwrap());
+        assert!(pool
+            .is_fully_cached(vec![hashes[6], hashes[7]])
+            .await
+            .unwrap());
+
+        // the in flight block is removed once it is returned
+        drop(in_flight);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 6);
+        assert_eq!(pool.available_blocks(), 6);
+
+        // blocks checked out after the reconcile return as usual
+        drop(pool.match_blocks(vec![hashes[6]]).await.unwrap());
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 6);
+        assert!(pool.is_fully_cached(vec![hashes[6]]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_take_grouped() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        for (block, priority) in blocks.iter_mut().zip([0, 1, 1, 2]) {
+            block.set_priority(priority);
+        }
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        pool.fence().await.unwrap();
+
+        let grouped = pool.take_grouped(4).await.unwrap();
+        let sizes: Vec<(Option<u32>, usize)> = grouped
+            .iter()
+            .map(|(priority, blocks)| (priority.map(|priority| priority.score()), blocks.len()))
+            .collect();
+        assert_eq!(sizes, vec![(None, 1), (Some(0), 1), (Some(1), 2)]);
+        let group = |score| Some(BlockPriority::new(0, score));
+        assert_eq!(grouped[&group(0)][0].token_block.tokens()[0], 1);
+        assert!(grouped[&group(1)]
+            .iter()
+            .all(|block| block.priority == BlockPriority::new(0, 1)));
+        assert_eq!(pool.available_blocks(), 1);
+
+        // the remaining block lands in its own group
+        let grouped = pool.take_grouped(4).await.unwrap();
+        assert_eq!(grouped.len(), 1);
+        assert_eq!(grouped[&group(2)].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_take_grouped_keeps_blanks_apart_from_top_class() {
+        let pool = AvailableBlocks::new().await;
+
+        // the highest priority there is still groups apart from the uninitialized blocks
+        let top = BlockPriority::new(u8::MAX, u32::MAX);
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        blocks[0].set_priority(top);
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let grouped = pool.take_grouped(2).await.unwrap();
+        assert_eq!(grouped.len(), 2);
+        assert!(grouped[&None][0].token_block.tokens().is_empty());
+        assert_eq!(grouped[&Some(top)][0].token_block.tokens(), &[1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_session_stamps_blocks() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let hashes = block_hashes(&blocks);
+        for block in blocks[2..].iter_mut() {
+            block.set_priority(1);
+        }
+        pool.insert_many(blocks).await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let session = pool.session(SessionOptions {
+            deadline: Some(deadline),
+            ..Default::default()
+        });
+
+        let matched = session
+            .match_blocks(vec![hashes[0], hashes[1]])
+            .await
+            .unwrap();
+        assert_eq!(matched.len(), 2);
+        assert!(matched
+            .iter()
+            .all(|block| block.priority() == BlockPriority::default()
+                && block.deadline() == Some(deadline)));
+
+        // once returned, the lowest priority blocks are protected until the deadline
+        session.finish(matched).await.unwrap();
+        assert_eq!(pool.stats().await.unwrap().active_shields, 2);
+
+        let taken = pool.take_blocks(2).await.unwrap();
+        let taken: Vec<SequenceHash> = taken
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        assert_eq!(taken, vec![hashes[2], hashes[3]]);
+        assert_eq!(pool.drain_counters().await.unwrap().shield_breaches, 0);
+
+        // the stamps are consumed by the pool
+        let matched = pool.match_blocks(vec![hashes[0]]).await.unwrap();
+        assert_eq!(matched[0].deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_commit() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+
+        // a deadline which has already passed does not shield the blocks
+        let session = pool.session(SessionOptions {
+            deadline: Some(Instant::now()),
+            priority: BlockPriority::new(0, 2),
+            ..Default::default()
+        });
+        session.insert_many(blocks).await.unwrap();
+        assert_eq!(pool.stats().await.unwrap().active_shields, 0);
+
+        let matched = pool.match_blocks(hashes).await.unwrap();
+        assert_eq!(matched.len(), 2);
+        assert!(matched
+            .iter()
+            .all(|block| block.priority() == BlockPriority::new(0, 2)));
+    }
+
+    #[tokio::test]
+    async fn test_single_use_match() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let matched = pool
+            .match_blocks_single_use(vec![hashes[0], hashes[1]])
+            .await
+            .unwrap();
+        assert_eq!(matched.len(), 2);
+        drop(matched);
+        pool.fence().await.unwrap();
+
+        // the slots are back but the prefix is gone
+        assert_eq!(pool.available_blocks(), 3);
+        assert!(pool.match_blocks(vec![hashes[0]]).await.unwrap().is_empty());
+        assert_eq!(pool.stats().await.unwrap().uninitialized_blocks, 2);
+
+        // a regular match of a block once matched single use caches it as usual
+        let matched = pool.match_blocks(vec![hashes[2]]).await.unwrap();
+        drop(matched);
+        pool.fence().await.unwrap();
+        assert!(pool.is_fully_cached(vec![hashes[2]]).await.unwrap());
+
+        let blanks = pool.take_blocks(2).await.unwrap();
+        assert!(blanks
+            .iter()
+            .all(|block| block.token_block.tokens().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_match_blocks_detailed() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let hashes = block_hashes(&blocks);
+        for (block, priority) in blocks.iter_mut().zip([1, 0, 0, 1]) {
+            block.set_priority(priority);
+        }
+        pool.insert_many(blocks).await.unwrap();
+
+        // eviction order is hashes 1, 2, 0, 3
+        let matched = pool
+            .match_blocks_detailed(vec![hashes[0], hashes[1], hashes[2]])
+            .await
+            .unwrap();
+        let details: Vec<(u32, u64, Option<usize>)> = matched
+            .iter()
+            .map(|matched| (matched.priority.score(), matched.age, matched.rank))
+            .collect();
+        assert_eq!(
+            details,
+            vec![(1, 3, Some(2)), (0, 2, Some(0)), (0, 1, Some(1))]
+        );
+        assert!(matched[1].was_next_victim());
+        assert_eq!(pool.available_blocks(), 1);
+
+        // a miss ends the match
+        drop(matched);
+        pool.fence().await.unwrap();
+        let matched = pool
+            .match_blocks_detailed(vec![hashes[3], 42, hashes[0]])
+            .await
+            .unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].block.token_block.sequence_hash(), hashes[3]);
+    }
+
+    #[tokio::test]
+    async fn test_match_blocks_detailed_rank_horizon() {
+        let pool = AvailableBlocks::new().await;
+
+        let count = EVICTION_RANK_HORIZON as u32 + 1;
+        let values: Vec<u32> = (0..count * 2).collect();
+        let blocks = create_blocks(create_token_sequence(&values), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let last = *hashes.last().unwrap();
+        let matched = pool.match_blocks_detailed(vec![last]).await.unwrap();
+        assert_eq!(matched[0].rank, None);
+
+        let second_last = hashes[hashes.len() - 2];
+        let matched = pool.match_blocks_detailed(vec![second_last]).await.unwrap();
+        assert_eq!(matched[0].rank, Some(EVICTION_RANK_HORIZON - 1));
+    }
+
+    #[tokio::test]
+    async fn test_pool_name() {
+        let config = AvailableBlocksConfig::default().with_name("llama-gpu0");
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2))
+            .await
+            .unwrap();
+
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.name.as_deref(), Some("llama-gpu0"));
+        assert_eq!(stats.reusable_blocks, 2);
+
+        let pool = AvailableBlocks::new().await;
+        assert_eq!(pool.stats().await.unwrap().name, None);
+    }
+
+    #[tokio::test]
+    async fn test_insert_stream() {
+        let pool = AvailableBlocks::new().await;
+
+        // blocks are built lazily as the pool consumes the stream
+        let blocks = futures::stream::iter(0..10_000u32).map(|value| {
+            create_blocks(create_token_sequence(&[value]), 1)
+                .pop()
+                .unwrap()
+        });
+        let inserted = pool.insert_stream(blocks).await.unwrap();
+        assert_eq!(inserted, 10_000);
+
+        assert_eq!(pool.total_blocks(), 10_000);
+        assert_eq!(pool.available_blocks(), 10_000);
+        assert_eq!(pool.stats().await.unwrap().reusable_blocks, 10_000);
+
+        let inserted = pool.insert_stream(futures::stream::empty()).await.unwrap();
+        assert_eq!(inserted, 0);
+    }
+
+    fn long_sequence(block_count: u32) -> Vec<KvBlock> {
+        let values: Vec<u32> = (0..block_count * 2).collect();
+        create_blocks(create_token_sequence(&values), 2)
+    }
+
+    #[tokio::test]
+    async fn test_match_blocks_stream() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = long_sequence(2_000);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let stream = pool.match_blocks_stream(hashes.clone()).await.unwrap();
+        let matched: Vec<PoolItem<KvBlock>> = stream.collect().await;
+        assert_eq!(matched.len(), 2_000);
+        assert!(matched
+            .iter()
+            .zip(&hashes)
+            .all(|(block, hash)| block.token_block.sequence_hash() == *hash));
+        assert_eq!(pool.available_blocks(), 0);
+
+        drop(matched);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 2_000);
+
+        // the stream ends at the first miss
+        let mut missing = hashes.clone();
+        missing[1_500] = 42;
+        let stream = pool.match_blocks_stream(missing).await.unwrap();
+        assert_eq!(stream.count().await, 1_500);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 2_000);
+    }
+
+    #[tokio::test]
+    async fn test_match_blocks_stream_early_drop() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = long_sequence(2_000);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let mut stream = Box::pin(pool.match_blocks_stream(hashes.clone()).await.unwrap());
+        let first = stream.next().await.unwrap();
+        assert_eq!(first.token_block.sequence_hash(), hashes[0]);
+
+        // every block but the one held is returned, sent or not
+        drop(stream);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 1_999);
+        assert!(pool.is_fully_cached(hashes[1..].to_vec()).await.unwrap());
+
+        drop(first);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 2_000);
+    }
+
+    #[tokio::test]
+    async fn test_match_blocks_stream_backpressure() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = long_sequence(2_000);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // an unconsumed batch parks the stream, not the engine
+        let stream = pool.match_blocks_stream(hashes.clone()).await.unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(
+            pool.available_blocks(),
+            2_000 - MATCH_STREAM_BATCH_SIZE as u64
+        );
+        let last = pool.match_one(hashes[1_999]).await.unwrap();
+        assert!(last.is_some());
+
+        // the stream resumes as it is consumed and ends at the block matched meanwhile
+        assert_eq!(stream.count().await, 1_999);
+        drop(last);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 2_000);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_and_reset_all() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        blocks[0].set_priority(1);
+        let expected: Vec<BlockDescriptor> = [&blocks[1], &blocks[2], &blocks[0]]
+            .into_iter()
+            .map(BlockDescriptor::from)
+            .collect();
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        // exporting alone leaves the pool untouched
+        let manifest = pool.export_manifest().await.unwrap();
+        assert_eq!(manifest.blocks, expected);
+        assert!(pool.is_fully_cached(hashes.clone()).await.unwrap());
+
+        let manifest = pool.snapshot_and_reset_all().await.unwrap();
+        assert_eq!(manifest.blocks, expected);
+
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.reusable_blocks, 0);
+        assert_eq!(stats.uninitialized_blocks, 4);
+        assert_eq!(pool.total_blocks(), 4);
+        assert!(!pool.is_fully_cached(vec![hashes[0]]).await.unwrap());
+        assert_eq!(
+            pool.export_manifest().await.unwrap(),
+            PoolManifest {
+                compat: pool.manifest(),
+                blocks: Vec::new(),
+            }
+        );
+    }
+
+    fn cold_for_priority_zero(block: &KvBlock) -> EvictOutcome {
+        match block.score() {
+            0 => EvictOutcome::Cold(ColdLocation(block.token_block.tokens()[0] as u64)),
+            _ => EvictOutcome::Forget,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eviction_hook_cold_hits() {
+        let config = AvailableBlocksConfig::default().with_eviction_hook(
+            cold_for_priority_zero,
+            Duration::from_secs(60),
+            16,
+        );
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let hashes = block_hashes(&blocks);
+        blocks[2].set_priority(1);
+        pool.insert_many(blocks).await.unwrap();
+
+        // evicts hashes 0, 1 and 3 to cold storage and forgets hash 2
+        let taken = pool.take_blocks(4).await.unwrap();
+        let taken_hashes: Vec<SequenceHash> = taken
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        assert_eq!(
+            taken_hashes,
+            vec![hashes[0], hashes[1], hashes[3], hashes[2]]
+        );
+
+        let tiered = pool.match_blocks_tiered(hashes.clone()).await.unwrap();
+        assert!(tiered.blocks.is_empty());
+        assert_eq!(
+            tiered.cold,
+            vec![
+                ColdHit {
+                    sequence_hash: hashes[0],
+                    location: ColdLocation(1),
+                },
+                ColdHit {
+                    sequence_hash: hashes[1],
+                    location: ColdLocation(3),
+                },
+            ]
+        );
+
+        // a plain match is a miss as before
+        assert!(pool.match_blocks(hashes.clone()).await.unwrap().is_empty());
+
+        // once the block is hot again the prefix is matched in the pool
+        let mut block = create_blocks(create_token_sequence(&[1, 2]), 2)
+            .pop()
+            .unwrap();
+        block.set_priority(1);
+        pool.insert(block).await.unwrap();
+
+        let tiered = pool.match_blocks_tiered(hashes.clone()).await.unwrap();
+        assert_eq!(tiered.blocks.len(), 1);
+        assert_eq!(tiered.cold.len(), 1);
+        assert_eq!(tiered.cold[0].sequence_hash, hashes[1]);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_hook_cold_cap_and_ttl() {
+        let config = AvailableBlocksConfig::default().with_eviction_hook(
+            cold_for_priority_zero,
+            Duration::from_secs(60),
+            1,
+        );
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        let _taken = pool.take_blocks(2).await.unwrap();
+
+        // only the latest eviction is retained
+        let tiered = pool.match_blocks_tiered(hashes.clone()).await.unwrap();
+        assert!(tiered.cold.is_empty());
+        let tiered = pool.match_blocks_tiered(vec![hashes[1]]).await.unwrap();
+        assert_eq!(tiered.cold.len(), 1);
+
+        // entries expire after the ttl
+        let config = AvailableBlocksConfig::default().with_eviction_hook(
+            cold_for_priority_zero,
+            Duration::ZERO,
+            16,
+        );
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2]), 2))
+            .await
+            .unwrap();
+        let _taken = pool.take_blocks(1).await.unwrap();
+        let tiered = pool.match_blocks_tiered(vec![hashes[0]]).await.unwrap();
+        assert!(tiered.cold.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_per_caller_quota() {
+        let config = AvailableBlocksConfig::default().with_per_caller_quota(3);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..8 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+
+        let greedy = CallerId(1);
+        let polite = CallerId(2);
+
+        // the take is cut down to the quota, after which takes fail
+        let held = pool.take_blocks_for(greedy, 10).await.unwrap();
+        assert_eq!(held.len(), 3);
+        let err = pool.take_blocks_for(greedy, 1).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::QuotaExceeded(caller)) if *caller == greedy
+        ));
+
+        // other callers have their own quota
+        let other = pool.take_blocks_for(polite, 2).await.unwrap();
+        assert_eq!(other.len(), 2);
+        assert_eq!(pool.available_blocks(), 3);
+
+        // returning blocks frees up quota
+        let mut held = held;
+        held.truncate(1);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.take_blocks_for(greedy, 5).await.unwrap().len(), 2);
+    }
+
+    async fn update_child_above_parent(policy: PriorityChainPolicy) -> (Result<()>, BlockPriority) {
+        let config = AvailableBlocksConfig::default().with_priority_chain_policy(policy);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the root has no parent, so any priority is valid
+        pool.update_single(UpdateBlock::new(hashes[0], 3))
+            .await
+            .unwrap();
+
+        let result = pool.update_single(UpdateBlock::new(hashes[1], 5)).await;
+
+        let matched = pool.match_blocks(vec![hashes[1]]).await.unwrap();
+        (result, matched[0].priority)
+    }
+
+    #[tokio::test]
+    async fn test_priority_chain_reject() {
+        let (result, priority) = update_child_above_parent(PriorityChainPolicy::Reject).await;
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::InvalidPriorityChain {
+                priority,
+                parent_priority,
+                ..
+            }) if *priority == BlockPriority::new(0, 5)
+                && *parent_priority == BlockPriority::new(0, 3)
+        ));
+        assert_eq!(priority, BlockPriority::new(0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_priority_chain_clamp() {
+        let (result, priority) = update_child_above_parent(PriorityChainPolicy::Clamp).await;
+        result.unwrap();
+        assert_eq!(priority, BlockPriority::new(0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_reserved_band() {
+        const SYSTEM: BlockPriority = BlockPriority::new(0, 10);
+
+        let config = AvailableBlocksConfig::default().with_reserved_band(SYSTEM..=SYSTEM, 0.3);
+        let available_blocks = AvailableBlocks::new_with_config(config).await;
+
+        for _ in 0..7 {
+            available_blocks.insert(KvBlock::default()).await.unwrap();
+        }
+
+        // the band's cap grows with the pool; the fourth system block pushes out the first
+        let mut system = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        for block in system.iter_mut() {
+            block.set_priority(SYSTEM);
+        }
+        let system_hashes = block_hashes(&system);
+        available_blocks.insert_many(system).await.unwrap();
+
+        let stats = available_blocks.stats().await.unwrap();
+        assert_eq!(stats.total_blocks, 11);
+        assert_eq!(stats.reserved_band_blocks, vec![3]);
+        assert!(!available_blocks
+            .is_fully_cached(system_hashes[..1].to_vec())
+            .await
+            .unwrap());
+        assert!(available_blocks
+            .is_fully_cached(system_hashes[1..].to_vec())
+            .await
+            .unwrap());
+
+        // flood the pool with other traffic and take every block that can be evicted
+        let other = create_blocks(create_token_sequence(&[100, 101, 102, 103, 104]), 1);
+        available_blocks.insert_many(other).await.unwrap();
+
+        let taken = available_blocks.take_blocks(100).await.unwrap();
+        assert_eq!(taken.len(), 16 - 3);
+        assert!(available_blocks
+            .is_fully_cached(system_hashes[1..].to_vec())
+            .await
+            .unwrap());
+
+        let stats = available_blocks.stats().await.unwrap();
+        assert_eq!(stats.reserved_band_blocks, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_turn_watchdog_splits_bulk_match() {
+        // every turn is over both thresholds, so a chunked request yields after each step
+        let config =
+            AvailableBlocksConfig::default().with_turn_watchdog(Duration::ZERO, Duration::ZERO);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = long_sequence(1024);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        pool.drain_counters().await.unwrap();
+
+        let matched = pool.match_blocks(hashes).await.unwrap();
+        assert_eq!(matched.len(), 1024);
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert!(counters.split_turns >= (1024 / WATCHDOG_STEP - 1) as u64);
+        assert!(counters.slow_turns >= counters.split_turns);
+    }
+
+    #[tokio::test]
+    async fn test_top_misses() {
+        let config =
+            AvailableBlocksConfig::default().with_miss_tracking(32, Duration::from_secs(3600));
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        // three popular misses among 240 distinct noise misses
+        let popular = [(1001, 60), (1002, 40), (1003, 20)];
+        let mut noise = 10_000;
+        for round in 0..60 {
+            for (hash, count) in popular {
+                if round < count {
+                    pool.match_blocks(vec![hash]).await.unwrap();
+                }
+            }
+            for _ in 0..4 {
+                pool.match_blocks(vec![noise]).await.unwrap();
+                noise += 1;
+            }
+        }
+
+        // counts are overestimated by at most the total misses over the capacity
+        let max_error = (60 + 40 + 20 + 240) / 32;
+        let top = pool.top_misses(3).await.unwrap();
+        assert_eq!(top.len(), 3);
+        for ((hash, count), (expected_hash, expected_count)) in top.into_iter().zip(popular) {
+            assert_eq!(hash, expected_hash);
+            assert!(count >= expected_count && count <= expected_count + max_error);
+        }
+
+        assert_eq!(pool.top_misses(100).await.unwrap().len(), 32);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_top_misses_decay() {
+        let config =
+            AvailableBlocksConfig::default().with_miss_tracking(8, Duration::from_millis(100));
+        let pool = AvailableBlocks::new_with_config(config).await;
+        assert!(pool.top_misses(1).await.unwrap().is_empty());
+
+        for _ in 0..8 {
+            pool.match_blocks(vec![7]).await.unwrap();
+        }
+        pool.match_blocks(vec![8]).await.unwrap();
+        assert_eq!(pool.top_misses(1).await.unwrap(), vec![(7, 8)]);
+
+        // the single miss fades out, the repeated one is halved at least once
+        tokio::time::advance(Duration::from_millis(150)).await;
+        let top = pool.top_misses(2).await.unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, 7);
+        assert!(top[0].1 <= 4);
+    }
+
+    #[tokio::test]
+    async fn test_match_versions() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the version is stable across matches while the contents are untouched
+        let matched = pool.match_blocks_detailed(hashes.clone()).await.unwrap();
+        let versions: Vec<u64> = matched.iter().map(|matched| matched.version).collect();
+        assert!(matched
+            .iter()
+            .all(|matched| matched.block.version() == matched.version));
+        drop(matched);
+        pool.fence().await.unwrap();
+
+        let matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(
+            matched
+                .iter()
+                .map(|block| block.version())
+                .collect::<Vec<_>>(),
+            versions
+        );
+        drop(matched);
+        pool.fence().await.unwrap();
+
+        let matched = pool
+            .match_if_version(hashes[0], versions[0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(matched.version(), versions[0]);
+    }
+
+    #[tokio::test]
+    async fn test_match_if_version_mismatch() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
+
+        let mut block = pool.match_blocks(vec![hash]).await.unwrap().remove(0);
+        let version = block.version();
+
+        // a writer rewrites the KV memory while the block is checked out
+        block.mark_modified();
+        drop(block);
+        pool.fence().await.unwrap();
+
+        assert!(pool
+            .match_if_version(hash, version)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(pool.is_fully_cached(vec![hash]).await.unwrap());
+
+        let block = pool
+            .match_if_version(hash, version + 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.version(), version + 1);
+    }
+
+    #[test]
+    fn test_dedicated_thread_engine() {
+        // the eviction hook runs on the engine task, so it sees the engine's thread
+        let engine_threads = Arc::new(Mutex::new(Vec::new()));
+        let config = AvailableBlocksConfig::default().with_eviction_hook(
+            {
+                let engine_threads = engine_threads.clone();
+                move |_: &KvBlock| {
+                    let name = std::thread::current().name().map(str::to_string);
+                    engine_threads.lock().unwrap().push(name);
+                    EvictOutcome::Forget
+                }
+            },
+            Duration::from_secs(60),
+            16,
+        );
+        let pool = Arc::new(
+            AvailableBlocks::new_dedicated_thread_with(
+                config,
+                EngineThreadOptions::default().with_name("kv-pool-engine"),
+            )
+            .unwrap(),
+        );
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        let held = runtime.block_on(async {
+            let tasks: Vec<_> = (0..4u32)
+                .map(|task| {
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        let values: Vec<u32> = (task * 100..task * 100 + 8).collect();
+                        let blocks = create_blocks(create_token_sequence(&values), 2);
+                        let hashes = block_hashes(&blocks);
+                        pool.insert_many(blocks).await.unwrap();
+                        assert_eq!(pool.match_blocks(hashes).await.unwrap().len(), 4);
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            pool.fence().await.unwrap();
+
+            assert_eq!(pool.total_blocks(), 16);
+            pool.take_blocks(16).await.unwrap()
+        });
+
+        assert_eq!(held.len(), 16);
+        assert!(engine_threads
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|name| name.as_deref() == Some("kv-pool-engine")));
+        assert!(pool.is_active());
+
+        // dropping the pool joins the engine thread; blocks returned afterwards are dropped
+        drop(Arc::into_inner(pool).unwrap());
+        drop(held);
+        drop(runtime);
+    }
+
+    #[test]
+    fn test_engine_on_runtime_handle() {
+        let engine_runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let pool = AvailableBlocks::new_on(engine_runtime.handle().clone());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            pool.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2))
+                .await
+                .unwrap();
+            assert_eq!(pool.stats().await.unwrap().reusable_blocks, 2);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_list_uninitialized() {
+        let pool = AvailableBlocks::new().await;
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        // the second copy of each block is demoted to the uninitialized set
+        let sequence = create_token_sequence(&[1, 2, 3, 4]);
+        pool.insert_many(create_blocks(sequence.clone(), 2))
+            .await
+            .unwrap();
+        let duplicates = create_blocks(sequence, 2);
+        let duplicate_hashes = block_hashes(&duplicates);
+        pool.insert_many(duplicates).await.unwrap();
+
+        let views = pool.list_uninitialized(10).await.unwrap();
+        assert_eq!(views.len(), 3);
+        assert!(views[0].is_empty_slot());
+        for (view, hash) in views[1..].iter().zip(duplicate_hashes) {
+            assert!(!view.is_empty_slot());
+            assert_eq!(view.sequence_hash, hash);
+            assert_eq!(view.token_count, 2);
+        }
+
+        assert_eq!(pool.list_uninitialized(1).await.unwrap(), views[..1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_op_completed_events() {
+        let config = AvailableBlocksConfig::default().with_readiness_gate(NotReadyPolicy::Queue);
+        let pool = Arc::new(AvailableBlocks::new_with_config(config).await);
+        let mut events = pool.subscribe_op_events();
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks.into_iter().take(2).collect())
+            .await
+            .unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        // the match is called before the pool is ready and waits for 5ms
+        let matcher = tokio::spawn({
+            let pool = pool.clone();
+            let hashes = hashes.clone();
+            async move {
+                let session = pool.session(SessionOptions {
+                    request_id: Some(7),
+                    ..Default::default()
+                });
+                session.match_blocks(hashes).await.unwrap().len()
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        pool.mark_ready().await.unwrap();
+        assert_eq!(matcher.await.unwrap(), 2);
+        pool.fence().await.unwrap();
+
+        let event = events.recv().await.unwrap();
+        assert_eq!(
+            event,
+            OpCompleted {
+                request_id: 7,
+                op: OpKind::Match,
+                queued: Duration::from_millis(5),
+                handled: Duration::ZERO,
+                hits: 2,
+                misses: 1,
+            }
+        );
+
+        let session = pool.session(SessionOptions {
+            request_id: Some(8),
+            ..Default::default()
+        });
+        let allocation = session.allocate(hashes).await.unwrap();
+        assert_eq!(allocation.matched.len(), 2);
+        assert_eq!(allocation.taken.len(), 1);
+        let taken = session.take_blocks(4).await.unwrap();
+        assert!(taken.is_empty());
+
+        let allocate = events.recv().await.unwrap();
+        assert_eq!((allocate.request_id, allocate.op), (8, OpKind::Allocate));
+        assert_eq!((allocate.hits, allocate.misses), (2, 1));
+        assert_eq!(allocate.queued, Duration::ZERO);
+
+        let take = events.recv().await.unwrap();
+        assert_eq!(take.op, OpKind::Take);
+        assert_eq!((take.hits, take.misses), (0, 4));
+
+        // operations without a request id are not reported
+        pool.match_blocks(vec![1]).await.unwrap();
+        assert!(events.try_recv().is_err());
+
+        let stats = pool.stats().await.unwrap();
+        let ops: Vec<_> = stats
+            .op_latencies
+            .iter()
+            .map(|latency| latency.op)
+            .collect();
+        assert_eq!(ops, vec![OpKind::Match, OpKind::Take, OpKind::Allocate]);
+        assert_eq!(stats.op_latencies[0].p50, Duration::from_millis(5));
+        assert_eq!(stats.op_latencies[0].p99, Duration::from_millis(5));
+        assert_eq!(stats.op_latencies[2].samples, 1);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_and_demote() {
+        let pool = AvailableBlocks::new().await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        for block in blocks.iter_mut() {
+            block.set_priority(5);
+        }
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let mut other = create_blocks(create_token_sequence(&[100, 101]), 2);
+        other[0].set_priority(3);
+        pool.insert_many(other).await.unwrap();
+
+        let matched = pool
+            .acquire_and_demote(hashes[..2].to_vec(), hashes[2..].to_vec(), 1)
+            .await
+            .unwrap();
+        assert_eq!(matched.len(), 2);
+
+        // the demoted tail is now evicted before the unrelated block at priority 3
+        let taken = pool.take_blocks(2).await.unwrap();
+        let taken: Vec<_> = taken
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        assert_eq!(taken, hashes[2..]);
+
+        // blocks are never promoted
+        drop(matched);
+        pool.fence().await.unwrap();
+        pool.acquire_and_demote(vec![], hashes[..1].to_vec(), 9)
+            .await
+            .unwrap();
+        let detailed = pool
+            .match_blocks_detailed(hashes[..2].to_vec())
+            .await
+            .unwrap();
+        assert!(detailed
+            .iter()
+            .all(|matched| matched.priority == BlockPriority::new(0, 5)));
+    }
+
+    fn manifest_order(manifest: PoolManifest) -> Vec<SequenceHash> {
+        manifest
+            .blocks
+            .iter()
+            .map(|descriptor| descriptor.sequence_hash)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_matches_rebuild() {
+        let score = |index: u32| BlockPriority::new(0, (index * 7919) % 13);
+
+        // unchunked, and in chunks smaller and larger than a quarter of the pool
+        for chunk_size in [None, Some(4), Some(32)] {
+            for (count, rescored) in [(64u32, 8u32), (64, 64)] {
+                let config = match chunk_size {
+                    Some(chunk_size) => {
+                        AvailableBlocksConfig::default().with_handler_chunk_size(chunk_size)
+                    }
+                    None => AvailableBlocksConfig::default(),
+                };
+                let pool = AvailableBlocks::new_with_config(config).await;
+                let blocks = long_sequence(count);
+                let hashes = block_hashes(&blocks);
+                pool.insert_many(blocks).await.unwrap();
+
+                // the first `rescored` blocks get a score, the missing hashes are skipped
+                let mut scores: Vec<_> = (0..rescored)
+                    .map(|index| (hashes[index as usize], score(index)))
+                    .collect();
+                scores.push((u64::MAX, BlockPriority::new(0, 1)));
+                scores.push((hashes[0], BlockPriority::new(0, 99)));
+                let report = pool.rebalance(scores).await.unwrap();
+                assert_eq!(
+                    report,
+                    RebalanceReport {
+                        applied: rescored as usize,
+                        missing: 1
+                    }
+                );
+
+                // the same blocks inserted from scratch with their final priorities
+                let expected = AvailableBlocks::new().await;
+                let mut blocks = long_sequence(count);
+                for (index, block) in blocks.iter_mut().enumerate().take(rescored as usize) {
+                    block.priority = score(index as u32);
+                }
+                blocks[0].priority = BlockPriority::new(0, 99);
+                expected.insert_many(blocks).await.unwrap();
+
+                assert_eq!(
+                    manifest_order(pool.export_manifest().await.unwrap()),
+                    manifest_order(expected.export_manifest().await.unwrap())
+                );
+            }
+        }
+    }
+
+    fn assert_insufficient(result: Result<Vec<PoolItem<KvBlock>>>, requested: u32, available: u64) {
+        let err = result.err().expect("take should fail");
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::InsufficientBlocks { requested: r, available: a, .. })
+                if *r == requested && *a == available
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_under_supply_policies() {
+        let pool = Arc::new(AvailableBlocks::new().await);
+        pool.insert_many(create_blocks(
+            create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            2,
+        ))
+        .await
+        .unwrap();
+
+        // the pool starts short: only one of its four blocks is idle
+        let held = pool.take_blocks(3).await.unwrap();
+
+        let partial = pool
+            .take_blocks_with_policy(2, UnderSupply::ReturnPartial)
+            .await
+            .unwrap();
+        assert_eq!(partial.len(), 1);
+        drop(partial);
+        pool.fence().await.unwrap();
+
+        assert_insufficient(
+            pool.take_blocks_with_policy(2, UnderSupply::Fail).await,
+            2,
+            1,
+        );
+        assert_insufficient(
+            pool.take_blocks_with_policy(2, UnderSupply::WaitUpTo(Duration::from_millis(20)))
+                .await,
+            2,
+            1,
+        );
+        assert_eq!(pool.available_blocks(), 1);
+
+        // a concurrent return replenishes the pool while the take waits
+        let returner = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let taken = pool
+            .take_blocks_with_policy(4, UnderSupply::WaitUpTo(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(taken.len(), 4);
+        returner.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_default_under_supply() {
+        let config = AvailableBlocksConfig::default().with_under_supply(UnderSupply::Fail);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        assert_insufficient(pool.take_blocks(2).await, 2, 1);
+        assert_eq!(pool.take_blocks(1).await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_return_queue_limits() {
+        // the engine only makes progress while its runtime is driven, so between `block_on`
+        // calls it is paused and returns pile up
+        let engine_runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let config = AvailableBlocksConfig::default().with_return_queue_limits(2, 4);
+        let pool = AvailableBlocks::new_with_config_on(config, engine_runtime.handle().clone());
+
+        let taken = engine_runtime.block_on(async {
+            pool.insert_many(long_sequence(8)).await.unwrap();
+            pool.drain_counters().await.unwrap();
+            pool.take_blocks(8).await.unwrap()
+        });
+        assert_eq!(pool.return_queue_depth(), 0);
+
+        // four returns fit under the hard limit, the other four are spilled
+        drop(taken);
+        assert_eq!(pool.health().return_queue_depth, 8);
+        assert_eq!(pool.available_blocks(), 0);
+
+        engine_runtime.block_on(async {
+            pool.fence().await.unwrap();
+            assert_eq!(pool.return_queue_depth(), 0);
+            assert_eq!(pool.available_blocks(), 8);
+
+            let stats = pool.stats().await.unwrap();
+            assert_eq!(stats.return_queue_depth, 0);
+            assert_eq!(stats.reusable_blocks, 8);
+
+            let counters = pool.drain_counters().await.unwrap();
+            assert_eq!(counters.returns, 8);
+            assert_eq!(counters.spilled_returns, 4);
+
+            // once recovered, returns go through the channel again
+            drop(pool.take_blocks(2).await.unwrap());
+            pool.fence().await.unwrap();
+            let counters = pool.drain_counters().await.unwrap();
+            assert_eq!(counters.returns, 2);
+            assert_eq!(counters.spilled_returns, 0);
+        });
+    }
+
+    #[test]
+    fn test_return_batch_scope_spills_past_hard_limit() {
+        let engine_runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let config = AvailableBlocksConfig::default().with_return_queue_limits(2, 4);
+        let pool = AvailableBlocks::new_with_config_on(config, engine_runtime.handle().clone());
+
+        let taken = engine_runtime.block_on(async {
+            pool.insert_many(long_sequence(8)).await.unwrap();
+            pool.drain_counters().await.unwrap();
+            pool.take_blocks(8).await.unwrap()
+        });
+
+        // the batch would take the queue past its hard limit, so it goes to the spill buffer
+        let mut scope = pool.return_batch_scope();
+        scope.extend(taken);
+        drop(scope);
+        assert_eq!(pool.return_queue_depth(), 8);
+
+        engine_runtime.block_on(async {
+            pool.fence().await.unwrap();
+            assert_eq!(pool.return_queue_depth(), 0);
+            assert_eq!(pool.available_blocks(), 8);
+
+            let counters = pool.drain_counters().await.unwrap();
+            assert_eq!(counters.returns, 8);
+            assert_eq!(counters.spilled_returns, 8);
+            assert_eq!(counters.return_batches, 0);
+
+            // a batch under the hard limit travels through the channel
+            let mut scope = pool.return_batch_scope();
+            scope.extend(pool.take_blocks(3).await.unwrap());
+            scope.flush().await.unwrap();
+            let counters = pool.drain_counters().await.unwrap();
+            assert_eq!(counters.returns, 3);
+            assert_eq!(counters.spilled_returns, 0);
+            assert_eq!(counters.return_batches, 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_return_with_meta() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let mut matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        let last = matched.pop().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let items = matched
+            .into_iter()
+            .map(|block| {
+                let meta = BlockMeta {
+                    priority: Some(BlockPriority::new(0, 5)),
+                    deadline: Some(deadline),
+                };
+                (block, meta)
+            })
+            .chain([(last, BlockMeta::default())])
+            .collect();
+        pool.return_with_meta(items).await.unwrap();
+
+        assert_eq!(pool.stats().await.unwrap().active_shields, 2);
+        let matched = pool.match_blocks(hashes).await.unwrap();
+        let priorities: Vec<u32> = matched.iter().map(|block| block.score()).collect();
+        assert_eq!(priorities, vec![5, 5, 0]);
+        assert!(matched.iter().all(|block| block.deadline().is_none()));
+    }
+
+    // Engine state without a progress engine, to manipulate its internals directly
+    fn detached_state(config: AvailableBlocksConfig) -> AvailableBlocksState {
+        let (return_tx, _) = mpsc::unbounded_channel();
+        let return_handle = Arc::new(ReturnHandleImpl {
+            return_tx: ReturnSender::Unbounded(return_tx),
+            overflow: Arc::new(ReturnOverflow::new(None)),
+            generation: 0,
+            reconcile_epoch: 0,
+            caller: None,
+        });
+        let (lifecycle_tx, _) = watch::channel(PoolLifecycle::Ready);
+        let (token_stats_tx, _) = watch::channel(TokenStats::default());
+        let (available_tx, _) = watch::channel(0);
+        let (resident_tx, _) = watch::channel(Arc::default());
+        let (op_events, _) = broadcast::channel(1);
+        let (eviction_events, _) = broadcast::channel(1);
+        let (pool_events, _) = broadcast::channel(1);
+        AvailableBlocksState::new(
+            config,
+            return_handle,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicU64::new(0)),
+            lifecycle_tx,
+            token_stats_tx,
+            available_tx,
+            resident_tx,
+            op_events,
+            eviction_events,
+            pool_events,
+            Arc::new(Notify::new()),
+            Arc::new(Notify::new()),
+            Arc::new(SelfCheckState::new()),
+            PoolRef(mpsc::unbounded_channel().0.downgrade()),
+        )
+    }
+
+    fn dispatch_insert(state: &mut AvailableBlocksState, block: KvBlock) {
+        let (tx, _) = oneshot::channel();
+        state.dispatch(Sequenced {
+            seq: None,
+            context: None,
+            request: SequencedRequest::Control(ControlRequest::Insert(InsertControl {
+                block,
+                op_id: None,
+                tx,
+            })),
+        });
+    }
+
+    #[test]
+    fn test_periodic_integrity_check_repairs_divergence() {
+        let config = AvailableBlocksConfig::default().with_periodic_integrity_check(3);
+        let mut state = detached_state(config);
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let last = blocks.pop().unwrap();
+        for block in blocks {
+            dispatch_insert(&mut state, block);
+        }
+        assert_eq!(state.counters.integrity_repairs, 0);
+
+        // an eviction order entry without a block, which would panic once popped
+        let orphan = PriorityKey {
+            priority: BlockPriority::default(),
+            sticky: false,
+            affinity_tick: 0,
+            return_tick: 0,
+            sequence_hash: 42,
+        };
+        state.priority_set.insert(orphan, 42);
+
+        // the next checkpoint rebuilds the eviction order
+        dispatch_insert(&mut state, last);
+        assert_eq!(state.counters.integrity_repairs, 1);
+        assert!(!state.priority_set.values().any(|hash| *hash == 42));
+
+        state.handle_reset_all();
+        assert_eq!(state.uninitialized_set.len(), 3);
+    }
+
+    #[test]
+    fn test_cold_order_drops_stale_entries() {
+        let config = AvailableBlocksConfig::default().with_eviction_hook(
+            cold_for_priority_zero,
+            Duration::from_secs(60),
+            16,
+        );
+        let mut state = detached_state(config);
+
+        // the same hash evicted to cold storage over and over
+        for _ in 0..64 {
+            let block = create_blocks(create_token_sequence(&[1, 2]), 2)
+                .pop()
+                .unwrap();
+            dispatch_insert(&mut state, block);
+            assert!(state.take().is_some());
+        }
+        assert_eq!(state.cold_entries.len(), 1);
+        assert!(state.cold_order.len() <= 2);
+    }
+
+    #[test]
+    fn test_verify_integrity_reports_orphans() {
+        let mut state = detached_state(AvailableBlocksConfig::default());
+        for block in create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2) {
+            dispatch_insert(&mut state, block);
+        }
+        let report = state.verify_integrity();
+        assert_eq!(report.lookup_entries, 2);
+        assert_eq!(report.priority_entries, 2);
+        assert_eq!(report.orphaned_priorities, 0);
+
+        let orphan = PriorityKey {
+            priority: BlockPriority::default(),
+            sticky: false,
+            affinity_tick: 0,
+            return_tick: 0,
+            sequence_hash: 42,
+        };
+        state.priority_set.insert(orphan, 42);
+        let report = state.verify_integrity();
+        assert_eq!(report.orphaned_priorities, 1);
+        assert!(!report.is_consistent());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_offload_janitor_reclaims_stranded_blocks() {
+        let config = AvailableBlocksConfig::default().with_offload_janitor(Duration::from_secs(1));
+        let pool = Arc::new(AvailableBlocks::new_with_config(config).await);
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        pool.drain_counters().await.unwrap();
+
+        // one worker completes its offload, the other dies mid-transfer
+        let descriptor = pool.begin_offload(hashes[0]).await.unwrap().unwrap();
+        assert_eq!(descriptor.sequence_hash, hashes[0]);
+        assert!(pool.begin_offload(hashes[1]).await.unwrap().is_some());
+        assert_eq!(pool.health().offloading_blocks, 2);
+        assert_eq!(pool.available_blocks(), 0);
+        assert!(pool.match_blocks(vec![hashes[0]]).await.unwrap().is_empty());
+
+        let worker = tokio::spawn({
+            let pool = pool.clone();
+            let hash = hashes[1];
+            async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                pool.complete_offload(hash).await.unwrap()
+            }
+        });
+        assert!(pool.complete_offload(hashes[0]).await.unwrap());
+        worker.abort();
+        assert_eq!(pool.health().offloading_blocks, 1);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        pool.fence().await.unwrap();
+
+        let health = pool.health();
+        assert_eq!(health.offloading_blocks, 0);
+        assert_eq!(health.total_blocks, 2);
+        assert_eq!(health.available_blocks, 2);
+
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.uninitialized_blocks, 2);
+        assert_eq!(stats.reusable_blocks, 0);
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.offload_abandoned, 1);
+
+        // a late completion finds nothing to complete
+        assert!(!pool.complete_offload(hashes[1]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_take_reports_evictions() {
+        let pool = AvailableBlocks::new().await;
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        blocks[0].set_priority(1);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the empty slot is taken first, then the cached blocks in eviction order
+        let outcome = pool.take_blocks_reporting_evictions(3).await.unwrap();
+        assert_eq!(outcome.taken.len(), 3);
+        let evicted: Vec<SequenceHash> = outcome
+            .evicted
+            .iter()
+            .map(|view| view.sequence_hash)
+            .collect();
+        assert_eq!(evicted, vec![hashes[1], hashes[2]]);
+        assert!(outcome.evicted.iter().all(|view| view.token_count == 2));
+
+        // plain takes are not reported
+        let plain = pool.take_blocks(1).await.unwrap();
+        assert_eq!(plain.len(), 1);
+        let outcome = pool.take_blocks_reporting_evictions(1).await.unwrap();
+        assert!(outcome.taken.is_empty());
+        assert!(outcome.evicted.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sticky_sessions() {
+        let config = AvailableBlocksConfig::default().with_sticky_cap(2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = long_sequence(4);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // sessions 1 and 2 hold the two oldest blocks, so the newer ones go first
+        let ttl = Duration::from_secs(60);
+        assert_eq!(pool.stick(vec![hashes[0]], 1, ttl).await.unwrap(), 1);
+        assert_eq!(
+            pool.stick(vec![hashes[1]], 2, Duration::from_secs(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            manifest_order(pool.export_manifest().await.unwrap()),
+            vec![hashes[2], hashes[3], hashes[0], hashes[1]]
+        );
+
+        // once session 2 expires, its block is back to its age order
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(
+            manifest_order(pool.export_manifest().await.unwrap()),
+            vec![hashes[1], hashes[2], hashes[3], hashes[0]]
+        );
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert_eq!(taken[0].token_block.sequence_hash(), hashes[1]);
+
+        // a higher priority still outranks stickiness
+        pool.rebalance(vec![(hashes[2], BlockPriority::new(0, 1))])
+            .await
+            .unwrap();
+        assert_eq!(
+            manifest_order(pool.export_manifest().await.unwrap()),
+            vec![hashes[3], hashes[0], hashes[2]]
+        );
+
+        // sessions are capped and can end early
+        assert_eq!(pool.stick(hashes.clone(), 3, ttl).await.unwrap(), 2);
+        assert_eq!(pool.unstick(1).await.unwrap(), 1);
+        assert_eq!(pool.unstick(3).await.unwrap(), 2);
+        assert_eq!(pool.unstick(3).await.unwrap(), 0);
+        assert_eq!(
+            manifest_order(pool.export_manifest().await.unwrap()),
+            vec![hashes[0], hashes[3], hashes[2]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_match_one() {
+        let pool = AvailableBlocks::new().await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let block = pool.match_one(hashes[1]).await.unwrap().unwrap();
+        assert_eq!(block.token_block.sequence_hash(), hashes[1]);
+        assert_eq!(pool.available_blocks(), 1);
+
+        // a block already checked out is a miss, as is an unknown hash
+        assert!(pool.match_one(hashes[1]).await.unwrap().is_none());
+        assert!(pool.match_one(42).await.unwrap().is_none());
+
+        drop(block);
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 2);
+        assert_eq!(pool.drain_counters().await.unwrap().matches, 1);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_batch() {
+        let pool = AvailableBlocks::new().await;
+
+        // two cached blocks and two empty slots
+        let cached = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let mut prefix: Vec<SequenceHash> = cached
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        pool.insert_many(cached).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        pool.drain_counters().await.unwrap();
+
+        let new_hashes = |tokens: &[u32]| -> Vec<SequenceHash> {
+            create_blocks(create_token_sequence(tokens), 2)
+                .iter()
+                .map(|block| block.token_block.sequence_hash())
+                .collect()
+        };
+        prefix.extend(new_hashes(&[1, 2, 3, 4, 5, 6]).into_iter().skip(2));
+
+        // the first request matches both cached blocks and takes one empty slot, leaving one
+        let fits = AllocateSpec {
+            hashes: prefix.clone(),
+        };
+        let too_big = AllocateSpec {
+            hashes: new_hashes(&[7, 8, 9, 10]),
+        };
+        let batch = vec![fits, too_big];
+
+        let manifest = manifest_order(pool.export_manifest().await.unwrap());
+        let allocations = pool
+            .allocate_batch(batch.clone(), BatchMode::AllOrNothing)
+            .await
+            .unwrap();
+        assert!(allocations.iter().all(Option::is_none));
+
+        // the rolled back batch left no trace
+        assert_eq!(
+            manifest_order(pool.export_manifest().await.unwrap()),
+            manifest
+        );
+        assert_eq!(pool.available_blocks(), 4);
+        assert_eq!(
+            pool.drain_counters().await.unwrap(),
+            CounterSnapshot::default()
+        );
+
+        let allocations = pool
+            .allocate_batch(batch, BatchMode::BestEffortRanked)
+            .await
+            .unwrap();
+        let served = allocations[0].as_ref().unwrap();
+        assert_eq!(served.matched.len(), 2);
+        assert_eq!(served.taken.len(), 1);
+        assert!(allocations[1].is_none());
+        assert_eq!(pool.available_blocks(), 1);
+        drop(allocations);
+        pool.fence().await.unwrap();
+
+        // a second request for the same prefix can not match the blocks the first one holds
+        let small = AllocateSpec {
+            hashes: vec![prefix[0]],
+        };
+        let allocations = pool
+            .allocate_batch(vec![small.clone(), small], BatchMode::AllOrNothing)
+            .await
+            .unwrap();
+        let first = allocations[0].as_ref().unwrap();
+        let second = allocations[1].as_ref().unwrap();
+        assert_eq!(first.matched.len(), 1);
+        assert_eq!(first.matched[0].token_block.sequence_hash(), prefix[0]);
+        assert!(second.matched.is_empty());
+        assert_eq!(second.taken.len(), 1);
+        assert_eq!(pool.available_blocks(), 2);
+    }
+
+    #[test]
+    fn test_pack_tokens_round_trip() {
+        let tokens = [5, 70000, 3, u32::MAX, 0, 0, 4_000_000_000, 1];
+        let packed = pack_tokens(&tokens);
+        assert_eq!(unpack_tokens(&packed), tokens);
+
+        // nearby token ids pack into a byte each
+        let run: Vec<u32> = (1000..1064).collect();
+        assert!(pack_tokens(&run).len() < run.len() + 2);
+        assert_eq!(unpack_tokens(&pack_tokens(&run)), run);
+        assert!(unpack_tokens(&pack_tokens(&[])).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mirrored_entries_do_not_expire_offloads() {
+        let config = AvailableBlocksConfig::default()
+            .with_eviction_hook(cold_for_priority_zero, Duration::from_secs(60), 1)
+            .with_mirror_cap(2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        // a mirror past the hook cap, and past its own cap
+        let mirrored: Vec<ColdHit> = (101..=103)
+            .map(|sequence_hash| ColdHit {
+                sequence_hash,
+                location: ColdLocation(sequence_hash),
+            })
+            .collect();
+        pool.mirror_cold(mirrored, Vec::new(), false).await.unwrap();
+        assert_eq!(pool.drain_counters().await.unwrap().mirror_overflow, 1);
+
+        // an offload still fits under the hook cap
+        let blocks = create_blocks(create_token_sequence(&[7, 8]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
+        let _taken = pool.take_blocks(1).await.unwrap();
+
+        let tiered = pool.match_blocks_tiered(vec![hash]).await.unwrap();
+        assert_eq!(
+            tiered.cold,
+            vec![ColdHit {
+                sequence_hash: hash,
+                location: ColdLocation(7)
+            }]
+        );
+        for sequence_hash in [101, 102] {
+            let tiered = pool.match_blocks_tiered(vec![sequence_hash]).await.unwrap();
+            assert_eq!(tiered.cold.len(), 1);
+        }
+        assert!(pool
+            .match_blocks_tiered(vec![103])
+            .await
+            .unwrap()
+            .cold
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_promote_compressed_cold_entries() {
+        let config = AvailableBlocksConfig::default()
+            .with_eviction_hook(cold_for_priority_zero, Duration::from_secs(60), 16)
+            .with_cold_compression(true);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(
+            create_token_sequence(&[5, 70000, 3, u32::MAX, 0, 0, 4_000_000_000, 1]),
+            4,
+        );
+        let expected: Vec<(SequenceHash, Vec<u32>)> = blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.token_block.sequence_hash(),
+                    block.token_block.tokens().to_vec(),
+                )
+            })
+            .collect();
+        pool.insert_many(blocks).await.unwrap();
+
+        // offload both blocks and hold on to them so they are not cached again
+        let _taken = pool.take_blocks(2).await.unwrap();
+        for _ in 0..2 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+
+        let mut promoted = Vec::new();
+        for (hash, tokens) in &expected {
+            let block = pool.promote_cold(*hash).await.unwrap().unwrap();
+            assert_eq!(block.token_block.sequence_hash(), *hash);
+            assert_eq!(block.token_block.tokens().to_vec(), *tokens);
+            promoted.push(block);
+        }
+        assert!(pool.promote_cold(expected[0].0).await.unwrap().is_none());
+        assert_eq!(pool.available_blocks(), 0);
+
+        // promoted blocks are cached under their hash once returned
+        drop(promoted);
+        pool.fence().await.unwrap();
+        let hashes = expected.iter().map(|(hash, _)| *hash).collect();
+        assert_eq!(pool.match_blocks(hashes).await.unwrap().len(), 2);
+    }
+
+    // checksum of a block in these tests: its first token
+    fn first_token_checksum(block: &KvBlock) -> bool {
+        block.checksum() == Some(block.token_block.tokens()[0] as u64)
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_blocks_are_quarantined() {
+        let config = AvailableBlocksConfig::default().with_checksum_verifier(first_token_checksum);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the engine stamps a checksum as it writes each block; the second block gets corrupted
+        let mut matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(matched.len(), 3);
+        matched[0].set_checksum(Some(1));
+        matched[1].set_checksum(Some(99));
+        drop(matched);
+        pool.fence().await.unwrap();
+
+        // the match stops at the corrupt block, which is quarantined on return
+        let matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].checksum(), Some(1));
+        drop(matched);
+        pool.fence().await.unwrap();
+
+        assert_eq!(pool.match_blocks(hashes.clone()).await.unwrap().len(), 1);
+        assert!(pool.match_blocks(vec![hashes[1]]).await.unwrap().is_empty());
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 3);
+
+        // a block reported while cached is reset right away
+        pool.report_corrupt(hashes[2]).await.unwrap();
+        assert!(pool.match_blocks(vec![hashes[2]]).await.unwrap().is_empty());
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.corrupt_blocks, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reset_and_reprioritize() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let processed = pool
+            .reset_and_reprioritize(vec![
+                (hashes[0], BlockPriority::new(0, 7)),
+                (42, BlockPriority::new(0, 3)),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(processed, 1);
+
+        // the content is cleared, the other block is untouched
+        assert!(pool.match_blocks(vec![hashes[0]]).await.unwrap().is_empty());
+        assert!(pool.is_fully_cached(vec![hashes[1]]).await.unwrap());
+        assert_eq!(pool.available_blocks(), 2);
+
+        // the blank slot is taken first and carries its new priority
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert!(taken[0].token_block.tokens().is_empty());
+        assert_eq!(taken[0].priority(), BlockPriority::new(0, 7));
+    }
+
+    #[tokio::test]
+    async fn test_hit_reserve() {
+        let config = AvailableBlocksConfig::default().with_hit_reserve(0.2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..10 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+        let cold = TakeOptions::default();
+        let hit = TakeOptions { hits: 3 };
+
+        // cold takes stop short of the two reserved blocks
+        let mut held = pool.take_blocks_with_options(5, cold).await.unwrap();
+        held.extend(pool.take_blocks_with_options(5, cold).await.unwrap());
+        assert_eq!(held.len(), 8);
+
+        let err = pool.take_blocks_with_options(1, cold).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::ReserveForHits { reserve: 2 })
+        ));
+
+        // a take backing cache hits may use the reserve
+        let taken = pool.take_blocks_with_options(2, hit).await.unwrap();
+        assert_eq!(taken.len(), 2);
+        assert_eq!(pool.available_blocks(), 0);
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.reserve_rejections, 1);
+        assert_eq!(counters.reserve_takes, 2);
+
+        // above the reserve both are served alike
+        drop(held);
+        pool.fence().await.unwrap();
+        assert_eq!(
+            pool.take_blocks_with_options(3, cold).await.unwrap().len(),
+            3
+        );
+    }
+
+    // Evict every block of a pool holding four single blocks in two affinity groups, inserted
+    // alternating between the groups, after a hit on the first block
+    async fn affinity_eviction_order(grouping: bool) -> Vec<SequenceHash> {
+        let config = AvailableBlocksConfig::default().with_affinity_grouping(grouping);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let mut hashes = Vec::new();
+        for (i, tokens) in [[1, 2], [3, 4], [5, 6], [7, 8]].iter().enumerate() {
+            let mut block = create_blocks(create_token_sequence(tokens), 2)
+                .pop()
+                .unwrap();
+            block.set_affinity(Some(i as u64 % 2));
+            hashes.push(block.token_block.sequence_hash());
+            pool.insert(block).await.unwrap();
+        }
+
+        drop(pool.match_blocks(vec![hashes[0]]).await.unwrap());
+        pool.fence().await.unwrap();
+
+        pool.take_blocks(4)
+            .await
+            .unwrap()
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_affinity_grouping() {
+        let hashes: Vec<SequenceHash> = [[1, 2], [3, 4], [5, 6], [7, 8]]
+            .iter()
+            .map(|tokens| {
+                create_blocks(create_token_sequence(tokens), 2)[0]
+                    .token_block
+                    .sequence_hash()
+            })
+            .collect();
+
+        // without grouping the hit only keeps its own block
+        assert_eq!(
+            affinity_eviction_order(false).await,
+            vec![hashes[1], hashes[2], hashes[3], hashes[0]]
+        );
+
+        // with grouping the hit keeps its group resident and each group is evicted as a unit
+        assert_eq!(
+            affinity_eviction_order(true).await,
+            vec![hashes[1], hashes[3], hashes[2], hashes[0]]
         );
     }
 
-    fn take_with_sequence_hash(
-        &mut self,
-        sequence_hash: SequenceHash,
-    ) -> Option<PoolValue<KvBlock>> {
-        match self.lookup_map.remove(&sequence_hash) {
-            Some(block) => {
-                // Remove from timestamp set
-                self.priority_set.remove(&PriorityKey::from(&*block));
-                Some(block)
-            }
-            None => None,
+    #[cfg(feature = "trace-record")]
+    #[tokio::test]
+    async fn test_recorded_trace_replays_in_simulator() {
+        use crate::kv::simulate::{simulate, EvictionPolicy};
+        use crate::kv::trace::TraceOp;
+
+        let capacity = 4;
+        let config = AvailableBlocksConfig::default().with_trace_recorder(1024, 1);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..capacity {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
-    }
 
-    fn match_hashes(
-        &mut self,
-        hashes: Vec<SequenceHash>,
-        return_handle: Arc<ReturnHandleImpl>,
-    ) -> Vec<PoolItem<KvBlock>> {
-        let mut matched_blocks = Vec::with_capacity(hashes.len());
+        let requests: [&[u32]; 5] = [
+            &[1, 2, 3, 4],
+            &[1, 2, 3, 4, 5, 6],
+            &[7, 8, 9, 10],
+            &[1, 2, 3, 4, 5, 6],
+            &[7, 8, 11, 12],
+        ];
+
+        // drive the pool like the simulator models a request: match the prefix, take the rest,
+        // then return every block from tail to root
+        let mut requested = 0;
+        let mut hits = 0;
+        for tokens in requests {
+            let blocks = create_blocks(create_token_sequence(tokens), 2);
+            let hashes = block_hashes(&blocks);
+
+            let mut held = pool.match_blocks(hashes.clone()).await.unwrap();
+            let matched = held.len();
+            let mut taken = pool
+                .take_blocks((hashes.len() - matched) as u32)
+                .await
+                .unwrap();
+            for (item, block) in taken.iter_mut().zip(&blocks[matched..]) {
+                item.update_token_block(block.token_block.clone());
+            }
+            held.extend(taken);
 
-        for hash in hashes {
-            if let Some(block) = self.take_with_sequence_hash(hash) {
-                matched_blocks.push(self.create_pool_item(block, return_handle.clone()));
-            } else {
-                break;
+            requested += hashes.len();
+            hits += matched;
+            for block in held.into_iter().rev() {
+                drop(block);
             }
+            pool.fence().await.unwrap();
         }
 
-        self.available_blocks
-            .fetch_sub(matched_blocks.len() as u64, Ordering::SeqCst);
-
-        matched_blocks
+        let recorded = pool.export_trace().await.unwrap();
+        assert_eq!(recorded.dropped, 0);
+        let ops = |op| {
+            recorded
+                .events
+                .iter()
+                .filter(|event| event.op == op)
+                .count()
+        };
+        assert_eq!(ops(TraceOp::Match), requests.len());
+        assert_eq!(ops(TraceOp::Take), requests.len());
+        assert_eq!(ops(TraceOp::Return), requested);
+
+        let report = simulate(&recorded.to_trace(), EvictionPolicy::PriorityFifo, capacity);
+        assert_eq!(report.blocks_requested, requested as u64);
+        assert_eq!(report.blocks_hit, hits as u64);
+        assert!(hits > 0);
+
+        // the buffer was drained
+        assert!(pool.export_trace().await.unwrap().events.is_empty());
     }
 
-    fn handle_match_single(&mut self, match_single: MatchSingle) {
-        let (hash, return_handle, rx) = match_single.dissolve();
+    #[cfg(feature = "trace-record")]
+    #[tokio::test]
+    async fn test_trace_samples_requests() {
+        use crate::kv::trace::TraceOp;
+
+        let config = AvailableBlocksConfig::default().with_trace_recorder(1024, 2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        for _ in 0..4 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+        pool.fence().await.unwrap();
+
+        // the allocation and the match of request 4 are recorded, none of request 3
+        for request_id in [3, 4] {
+            let session = pool.session(SessionOptions {
+                request_id: Some(request_id),
+                ..Default::default()
+            });
+            let mut extended = hashes.clone();
+            extended.push(1000 + request_id);
+            let allocation = session.allocate(extended).await.unwrap();
+            assert_eq!(allocation.matched.len(), 2);
+            drop(allocation);
+            pool.fence_returns().await.unwrap();
+            let matched = session.match_blocks(vec![hashes[0]]).await.unwrap();
+            assert_eq!(matched.len(), 1);
+        }
+        pool.fence().await.unwrap();
 
-        let matched_blocks = self.match_hashes(vec![hash], return_handle);
-        let optional_single = matched_blocks.into_iter().next();
+        let recorded = pool.export_trace().await.unwrap();
+        let served: Vec<(TraceOp, usize, usize)> = recorded
+            .events
+            .iter()
+            .filter(|event| event.op != TraceOp::Return)
+            .map(|event| (event.op, event.requested, event.served))
+            .collect();
+        assert_eq!(
+            served,
+            vec![
+                (TraceOp::Match, 3, 2),
+                (TraceOp::Take, 1, 1),
+                (TraceOp::Match, 1, 1),
+            ]
+        );
+    }
 
-        // Send the result back through the channel
-        if rx.send(optional_single).is_err() {
-            log::trace!("Failed to send matched block to requester");
+    #[tokio::test]
+    async fn test_dump_state() {
+        let config = AvailableBlocksConfig::default().with_name("dump");
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        blocks[0].set_priority(2);
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        let _held = pool.match_blocks(vec![hashes[0]]).await.unwrap();
+
+        let mut out = Vec::new();
+        pool.dump_state(&mut out).await.unwrap();
+        let dump: StateDump = serde_json::from_slice(&out).unwrap();
+
+        let mut lookup_keys = vec![hashes[1], hashes[2]];
+        lookup_keys.sort_unstable();
+        assert_eq!(
+            dump,
+            StateDump {
+                name: Some("dump".to_string()),
+                total_blocks: 4,
+                available_blocks: 3,
+                return_tick: 4,
+                uninitialized_blocks: 1,
+                lookup_keys,
+                eviction_order: vec![
+                    DumpedBlock {
+                        sequence_hash: hashes[1],
+                        parent_sequence_hash: Some(hashes[0]),
+                        priority: BlockPriority::default(),
+                        token_count: 2,
+                    },
+                    DumpedBlock {
+                        sequence_hash: hashes[2],
+                        parent_sequence_hash: Some(hashes[1]),
+                        priority: BlockPriority::default(),
+                        token_count: 2,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_block_priority_shim() {
+        // single value priorities keep their order through the shim
+        let legacy = [
+            0,
+            1,
+            7,
+            BlockPriority::LEGACY_SCORE_MASK,
+            BlockPriority::LEGACY_SCORE_MASK + 1,
+            0x0200_0005,
+            u32::MAX - 1,
+            u32::MAX,
+        ];
+        for pair in legacy.windows(2) {
+            assert!(BlockPriority::from(pair[0]) < BlockPriority::from(pair[1]));
         }
+        assert_eq!(BlockPriority::from(0x0200_0005), BlockPriority::new(2, 5));
+        assert_eq!(
+            BlockPriority::from(u32::MAX),
+            BlockPriority::new(u8::MAX, BlockPriority::LEGACY_SCORE_MASK)
+        );
+
+        // the score spans all of u32 and a bump saturates within its class
+        let top = BlockPriority::new(0, u32::MAX - 1).bump(10);
+        assert_eq!(top, BlockPriority::new(0, u32::MAX));
+        assert!(top < BlockPriority::new(1, 0));
+        assert_eq!(BlockPriority::new(1, 3).lower(10), BlockPriority::new(1, 0));
+        assert_eq!(
+            BlockPriority::class_range(1),
+            BlockPriority::new(1, 0)..=BlockPriority::new(1, u32::MAX)
+        );
+        assert!(!BlockPriority::class_range(1).contains(&BlockPriority::new(2, 0)));
+        assert_eq!(BlockPriority::new(3, 9).to_string(), "3:9");
     }
 
-    fn handle_match_multiple(&mut self, match_multiple: MatchMultiple) {
-        let (hashes, return_handle, rx) = match_multiple.dissolve();
+    #[tokio::test]
+    async fn test_block_priority_classes() {
+        let config = AvailableBlocksConfig::default().with_reserved_class(2, 0.25);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let hashes = block_hashes(&blocks);
+        blocks[0].set_priority(BlockPriority::new(1, 0));
+        blocks[1].set_priority(BlockPriority::new(0, 3).bump(u32::MAX));
+        blocks[2].set_priority(BlockPriority::new(2, 0));
+        blocks[3].set_priority(0);
+        assert_eq!(blocks[1].class(), 0);
+        assert_eq!(blocks[1].score(), u32::MAX);
+        pool.insert_many(blocks).await.unwrap();
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.class_blocks, vec![(0, 2), (1, 1), (2, 1)]);
+
+        // a raised score in class 0 is still evicted before class 1; class 2 is reserved
+        let held = pool.take_blocks(4).await.unwrap();
+        let taken: Vec<SequenceHash> = held
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        assert_eq!(taken, vec![hashes[3], hashes[1], hashes[0]]);
+
+        // updates speak classes too
+        pool.update_single(UpdateBlock::new(hashes[2], BlockPriority::new(0, 1)))
+            .await
+            .unwrap();
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert_eq!(taken[0].token_block.sequence_hash(), hashes[2]);
+    }
 
-        let matched_blocks = self.match_hashes(hashes, return_handle);
+    async fn pinned_pool(policy: PinnedExhaustion) -> AvailableBlocks {
+        // a band covering every cached block with room for all of them pins the whole cache
+        let config = AvailableBlocksConfig::default()
+            .with_reserved_band(0..=0, 1.0)
+            .with_pinned_exhaustion(policy);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2))
+            .await
+            .unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        pool
+    }
 
-        // Send the matched blocks back through the channel
-        if rx.send(matched_blocks).is_err() {
-            log::trace!("Failed to send matched blocks to requester");
-        }
+    #[tokio::test]
+    async fn test_pinned_exhaustion() {
+        // by default the take silently returns the blank block only
+        let pool = pinned_pool(PinnedExhaustion::Partial).await;
+        let taken = pool.take_blocks(2).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        assert!(taken[0].token_block.tokens().is_empty());
+
+        let pool = pinned_pool(PinnedExhaustion::Fail).await;
+        let err = pool.take_blocks(2).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::OnlyPinnedRemaining {
+                requested: 2,
+                available: 1,
+                pinned: 3,
+            })
+        ));
+        assert_eq!(pool.available_blocks(), 4);
+
+        // a take which can be satisfied is served as usual
+        let _blank = pool.take_blocks(1).await.unwrap();
+        let err = pool.take_blocks(1).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::OnlyPinnedRemaining { available: 0, .. })
+        ));
     }
 
-    fn take(&mut self) -> Option<PoolValue<KvBlock>> {
-        // First try uninitialized blocks - these are often part of sequences
-        // that have been arranged in the correct order
-        if let Some(block) = self.uninitialized_set.pop_front() {
-            return Some(block);
+    #[tokio::test(start_paused = true)]
+    async fn test_uninitialized_ttl() {
+        let config =
+            AvailableBlocksConfig::default().with_uninitialized_ttl(Duration::from_secs(10));
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let mut preallocated = KvBlock::default();
+        preallocated.set_preallocated(true);
+        pool.insert(KvBlock::default()).await.unwrap();
+        pool.insert(preallocated).await.unwrap();
+        for _ in 0..2 {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2]), 2))
+            .await
+            .unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 5);
 
-        // if we have blocks in the priority set, pop the first (it's sorted by priority)
-        // a fatal error will occur if the block is not found in the lookup map
-        if let Some((_key, sequence_hash)) = self.priority_set.pop_first() {
-            let block = match self.lookup_map.remove(&sequence_hash) {
-                Some(block) => block,
-                None => {
-                    panic!("block from priority set not found in lookup map");
-                }
-            };
+        // a load spike takes every blank; two come back early, the others late
+        let mut early = pool.take_blocks(4).await.unwrap();
+        let late = early.split_off(2);
+        drop(early);
+        pool.fence().await.unwrap();
 
-            return Some(block);
-        }
+        // the early blank outlives its TTL, the preallocated slot is exempt
+        tokio::time::advance(Duration::from_secs(15)).await;
+        drop(late);
+        pool.fence().await.unwrap();
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.uninitialized_expired, 1);
+        assert_eq!(pool.total_blocks(), 4);
+        assert_eq!(pool.available_blocks(), 4);
 
-        None
-    }
+        // once the spike subsides the late blanks age out too
+        tokio::time::advance(Duration::from_secs(20)).await;
+        pool.fence().await.unwrap();
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.uninitialized_expired, 2);
+        assert_eq!(pool.total_blocks(), 2);
 
-    fn handle_take(&mut self, take: Take) {
-        let (count, return_handle, tx) = take.dissolve();
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.uninitialized_blocks, 1);
+        assert_eq!(stats.reusable_blocks, 1);
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert!(taken[0].is_preallocated());
+    }
 
-        let mut taken_blocks = Vec::with_capacity(count as usize);
+    // A block holding fewer tokens than the block size, as cached for the tail of a prompt
+    fn tail_block(tokens: &[u32], parent: SequenceHash) -> KvBlock {
+        let block_hash = crate::kv_router::indexer::compute_hash(bytemuck::cast_slice(tokens));
+        let sequence_hash =
+            crate::kv_router::indexer::compute_hash(bytemuck::cast_slice(&[parent, block_hash]));
+        KvBlock::new(TokenBlock::with_precomputed_hash(
+            Tokens::from(tokens.to_vec()),
+            sequence_hash,
+            Some(parent),
+        ))
+    }
 
-        for _ in 0..count {
-            if let Some(block) = self.take() {
-                taken_blocks.push(self.create_pool_item(block, return_handle.clone()));
-            } else {
-                break;
-            }
+    #[tokio::test(start_paused = true)]
+    async fn test_token_accounting() {
+        let config =
+            AvailableBlocksConfig::default().with_token_hit_window(Duration::from_secs(10), 3);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let token_stats = pool.watch_token_stats();
+
+        // two full blocks of four tokens and a tail block of two
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 4);
+        blocks.push(tail_block(&[9, 10], blocks[1].token_block.sequence_hash()));
+        let mut hashes: Vec<SequenceHash> = blocks
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        pool.insert_many(blocks).await.unwrap();
+        for _ in 0..4 {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
 
-        self.available_blocks.fetch_sub(
-            taken_blocks.len() as u64,
-            std::sync::atomic::Ordering::SeqCst,
+        // a 13 token request matches 10 tokens and recomputes the rest
+        hashes.push(0xdead);
+        let allocation = pool.allocate_request(hashes.clone(), 13).await.unwrap();
+        assert_eq!(allocation.matched.len(), 3);
+        drop(allocation);
+        pool.fence().await.unwrap();
+
+        let stats = pool.stats().await.unwrap().tokens;
+        assert_eq!(
+            stats,
+            TokenStats {
+                tokens_saved: 10,
+                tokens_recomputed: 3,
+                window_tokens_saved: 10,
+                window_tokens_requested: 13,
+            }
         );
+        assert_eq!(*token_stats.borrow(), stats);
 
-        // Send the result back through the channel
-        if tx.send(taken_blocks).is_err() {
-            log::trace!("Failed to send matched blocks to requester");
-        }
+        // plain matches count saved tokens, but only allocated requests enter the window
+        drop(pool.match_blocks(hashes[..1].to_vec()).await.unwrap());
+        tokio::time::advance(Duration::from_secs(25)).await;
+        drop(pool.allocate_request(vec![0xbeef], 5).await.unwrap());
+        pool.fence().await.unwrap();
+
+        let stats = pool.stats().await.unwrap().tokens;
+        assert_eq!(stats.tokens_saved, 14);
+        assert_eq!(stats.tokens_recomputed, 8);
+        assert_eq!(stats.window_tokens_saved, 10);
+        assert_eq!(stats.window_tokens_requested, 18);
+        assert_eq!(stats.window_hit_ratio(), Some(10.0 / 18.0));
+        assert_eq!(*token_stats.borrow(), stats);
+
+        // the first request leaves the window
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let stats = pool.stats().await.unwrap().tokens;
+        assert_eq!(stats.window_tokens_saved, 0);
+        assert_eq!(stats.window_tokens_requested, 5);
+        assert_eq!(stats.window_hit_ratio(), Some(0.0));
     }
 
-    fn handle_match_request(&mut self, match_request: MatchRequest) {
-        match match_request {
-            MatchRequest::MatchSingle(match_single) => self.handle_match_single(match_single),
-            MatchRequest::MatchMultiple(match_multiple) => {
-                self.handle_match_multiple(match_multiple)
+    #[tokio::test(start_paused = true)]
+    async fn test_debounced_availability() {
+        let pool = AvailableBlocks::new().await;
+        for _ in 0..8 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+        let taken = pool.take_blocks(8).await.unwrap();
+        pool.fence().await.unwrap();
+
+        let mut raw = pool.subscribe_availability();
+        let mut updates =
+            Box::pin(pool.subscribe_availability_debounced(Duration::from_millis(100)));
+
+        // a burst of returns, each absorbed in its own turn
+        let mut raw_changes = 0;
+        for block in taken {
+            drop(block);
+            pool.fence().await.unwrap();
+            if raw.has_changed().unwrap() {
+                raw.borrow_and_update();
+                raw_changes += 1;
             }
-            MatchRequest::Take(take) => self.handle_take(take),
         }
+        assert_eq!(raw_changes, 8);
+
+        // coalesced into a single emission of the settled value
+        assert_eq!(updates.next().await, Some(8));
+        let quiet = tokio::time::timeout(Duration::from_secs(1), updates.next()).await;
+        assert!(quiet.is_err());
     }
 
-    fn handle_control_request(&mut self, control_request: ControlRequest) {
-        match control_request {
-            ControlRequest::Insert(insert) => {
-                let (block, tx) = insert.dissolve();
-                self.handle_insert(block);
-                if tx.send(()).is_err() {
-                    log::trace!("Failed to send insert ack; receiver dropped");
-                }
-            }
-            ControlRequest::UpdateSingle(update_single) => {
-                let (update, tx) = update_single.dissolve();
-                self.handle_update_single(update);
-                if tx.send(()).is_err() {
-                    log::trace!("Failed to send update single ack; receiver dropped");
-                }
-            }
-            ControlRequest::UpdateMultiple(update_multiple) => {
-                let (updates, tx) = update_multiple.dissolve();
-                self.handle_update_multiple(updates);
-                if tx.send(()).is_err() {
-                    log::trace!("Failed to send update multiple ack; receiver dropped");
-                }
-            }
-            ControlRequest::Reset(reset) => {
-                let (sequence_hashes, tx) = reset.dissolve();
-                self.handle_reset(sequence_hashes);
-                if tx.send(()).is_err() {
-                    log::trace!("Failed to send reset ack; receiver dropped");
-                }
+    // Match a chain of which the `cached` blocks are in the pool through one of the match APIs,
+    // optionally returning the last block of the cached prefix while the match is in progress
+    async fn check_ordered_match(api: usize, chunk_size: usize, cached: Vec<bool>, perturb: bool) {
+        let config = AvailableBlocksConfig::default().with_handler_chunk_size(chunk_size);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let values: Vec<u32> = (0..cached.len() as u32 * 2).collect();
+        let blocks = create_blocks(create_token_sequence(&values), 2);
+        let hashes = block_hashes(&blocks);
+        for (block, cached) in blocks.into_iter().zip(&cached) {
+            if *cached {
+                pool.insert(block).await.unwrap();
             }
-            ControlRequest::ResetAll(reset_all) => {
-                let tx = reset_all.dissolve();
-                self.handle_reset_all();
-                if tx.send(()).is_err() {
-                    log::trace!("Failed to send reset all ack; receiver dropped");
+        }
+        for _ in 0..cached.len() {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+        let prefix = cached
+            .iter()
+            .position(|cached| !cached)
+            .unwrap_or(cached.len());
+
+        let held = match prefix {
+            0 => Vec::new(),
+            _ if perturb => pool.match_blocks(vec![hashes[prefix - 1]]).await.unwrap(),
+            _ => Vec::new(),
+        };
+        let perturbed = !held.is_empty();
+
+        let (matched, _) = tokio::join!(
+            async {
+                match api {
+                    0 => pool
+                        .match_blocks(hashes.clone())
+                        .await
+                        .unwrap()
+                        .iter()
+                        .map(|block| block.lookup_key())
+                        .collect::<Vec<_>>(),
+                    1 => {
+                        pool.match_blocks_stream(hashes.clone())
+                            .await
+                            .unwrap()
+                            .map(|block| block.lookup_key())
+                            .collect()
+                            .await
+                    }
+                    _ => pool
+                        .allocate(hashes.clone())
+                        .await
+                        .unwrap()
+                        .matched
+                        .iter()
+                        .map(|block| block.lookup_key())
+                        .collect(),
                 }
-            }
+            },
+            async { drop(held) }
+        );
+
+        assert_eq!(matched[..], hashes[..matched.len()]);
+        if perturbed {
+            assert!(matched.len() == prefix || matched.len() == prefix - 1);
+        } else {
+            assert_eq!(matched.len(), prefix);
         }
     }
-    fn handle_insert(&mut self, block: KvBlock) {
-        self.available_blocks
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        self.total_blocks
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        self.return_tick += 1;
-
-        // update the return tick
-        let mut block = block;
-        block.return_tick = self.return_tick;
 
-        self.insert(PoolValue::Direct(block));
+    proptest::proptest! {
+        #![proptest_config(proptest::test_runner::Config::with_cases(64))]
+
+        #[test]
+        fn test_match_results_are_ordered_prefixes(
+            api in 0..3usize,
+            chunk_size in 1..6usize,
+            cached in proptest::collection::vec(proptest::bool::weighted(0.8), 1..12),
+            perturb in proptest::bool::ANY,
+        ) {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(check_ordered_match(api, chunk_size, cached, perturb));
+        }
     }
-    fn handle_return(&mut self, block: PoolValue<KvBlock>) {
-        self.available_blocks
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        self.return_tick += 1;
 
-        // update the return tick
-        let mut block = block;
-        block.return_tick = self.return_tick;
+    #[tokio::test]
+    async fn test_reserved_headroom() {
+        let config = AvailableBlocksConfig::default().with_reserved_headroom(3);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..10 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
 
-        self.insert(block);
-    }
-    fn handle_update_single(&mut self, update: UpdateBlock) {
-        self.update_block(vec![update]);
-    }
+        // a batch takes down to the headroom and no further
+        let batch = pool.take_blocks(10).await.unwrap();
+        assert_eq!(batch.len(), 7);
+        assert!(pool.take_blocks(1).await.unwrap().is_empty());
+        let err = pool
+            .take_blocks_with_policy(1, UnderSupply::Fail)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::InsufficientBlocks {
+                requested: 1,
+                available: 0,
+                ..
+            })
+        ));
+        assert_eq!(pool.available_blocks(), 3);
 
-    fn handle_update_multiple(&mut self, updates: Vec<UpdateBlock>) {
-        self.update_block(updates);
+        // a latency critical request still gets its slots
+        let urgent = pool.take_blocks_emergency(2).await.unwrap();
+        assert_eq!(urgent.len(), 2);
+        assert_eq!(pool.available_blocks(), 1);
     }
 
-    fn update_block(&mut self, updates: Vec<UpdateBlock>) {
-        for update in updates {
-            if let Some(mut block) = self.take_with_sequence_hash(update.hash) {
-                if let Some(priority) = update.priority {
-                    block.priority = priority;
-                }
+    #[tokio::test(start_paused = true)]
+    async fn test_soft_hold_policies() {
+        let pool = Arc::new(AvailableBlocks::new().await);
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let match_with = |holder, on_soft_hold| {
+            let pool = pool.clone();
+            let hashes = hashes.clone();
+            async move {
+                let options = MatchOptions {
+                    holder: Some(holder),
+                    on_soft_hold,
+                    max_blocks: None,
+                };
+                let matched = pool.match_blocks_with_options(hashes, options).await;
+                let matched = matched.unwrap().len();
+                pool.fence().await.unwrap();
+                matched
+            }
+        };
+
+        // holder 1 peeks at the request and holds its second block
+        assert_eq!(
+            pool.peek_hold(1, hashes[1..2].to_vec(), Duration::from_secs(60))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(match_with(2, OnSoftHold::Miss).await, 1);
+
+        // a waiting match is served once the holder releases its hold
+        let waiter = tokio::spawn(match_with(2, OnSoftHold::Wait(Duration::from_secs(10))));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(pool.release_holds(1).await.unwrap(), 1);
+        assert_eq!(waiter.await.unwrap(), 3);
+
+        // or once the hold expires, whichever comes first
+        pool.peek_hold(1, hashes[1..2].to_vec(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            match_with(2, OnSoftHold::Wait(Duration::from_secs(10))).await,
+            3
+        );
 
-                // if let Some(deadline) = update.deadline {
-                //     block.set_deadline(deadline);
-                // }
+        // the wait is bounded
+        pool.peek_hold(1, hashes[1..2].to_vec(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        let started = Instant::now();
+        assert_eq!(
+            match_with(2, OnSoftHold::Wait(Duration::from_secs(1))).await,
+            1
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+
+        // the holder claims its own hold under any policy
+        assert_eq!(match_with(1, OnSoftHold::Miss).await, 3);
+        assert_eq!(match_with(2, OnSoftHold::Miss).await, 3);
+
+        pool.peek_hold(1, hashes.clone(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(match_with(2, OnSoftHold::Steal).await, 3);
+        assert_eq!(match_with(2, OnSoftHold::Miss).await, 3);
+
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.soft_hold_misses, 2);
+        assert_eq!(counters.soft_hold_waits, 2);
+        assert_eq!(counters.soft_hold_steals, 1);
+    }
 
-                self.insert(block);
-            }
-        }
+    #[tokio::test]
+    async fn test_uninitialized_rule() {
+        // by default a zero hash block is uninitialized
+        let pool = AvailableBlocks::new().await;
+        pool.insert(KvBlock::default()).await.unwrap();
+        assert!(pool.match_blocks(vec![0]).await.unwrap().is_empty());
+
+        // with a rule demoting no block, the zero hash block is cached
+        let config = AvailableBlocksConfig::default().with_uninitialized_rule(|_| false);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert(KvBlock::default()).await.unwrap();
+        let matched = pool.match_blocks(vec![0]).await.unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].token_block.sequence_hash(), 0);
     }
 
-    fn handle_reset(&mut self, sequence_hashes: Vec<SequenceHash>) {
-        for hash in sequence_hashes {
-            if let Some(mut block) = self.take_with_sequence_hash(hash) {
-                block.reset();
-                self.insert(block);
-            }
+    #[tokio::test]
+    async fn test_insert_many_once() {
+        let config = AvailableBlocksConfig::default().with_insert_dedupe_window(2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        // a batch delivered three times is applied once
+        let batch = || create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let mut outcomes = Vec::new();
+        for _ in 0..3 {
+            outcomes.push(pool.insert_many_once(1, batch()).await.unwrap());
         }
-    }
+        assert_eq!(
+            outcomes,
+            vec![
+                InsertOutcome::Applied,
+                InsertOutcome::Duplicate,
+                InsertOutcome::Duplicate
+            ]
+        );
+        assert_eq!(pool.total_blocks(), 2);
 
-    fn handle_reset_all(&mut self) {
-        // for all blocks in the priority set, reset them
-        while let Some((_key, sequence_hash)) = self.priority_set.pop_first() {
-            if let Some(mut block) = self.lookup_map.remove(&sequence_hash) {
-                block.reset();
-                self.insert(block);
-            } else {
-                panic!("block from priority set not found in lookup map");
-            }
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.inserts, 2);
+        assert_eq!(counters.duplicate_inserts, 2);
+
+        // new operations keep being applied as old ids leave the window
+        for op_id in 2..=3 {
+            let outcome = pool
+                .insert_many_once(op_id, vec![KvBlock::default()])
+                .await
+                .unwrap();
+            assert_eq!(outcome, InsertOutcome::Applied);
         }
-    }
-}
+        assert_eq!(
+            pool.insert_many_once(3, vec![KvBlock::default()])
+                .await
+                .unwrap(),
+            InsertOutcome::Duplicate
+        );
+        assert_eq!(pool.total_blocks(), 4);
 
-#[async_trait]
-impl PoolExt<KvBlock> for AvailableBlocksState {}
+        // the first operation fell out of the window and is applied again
+        assert_eq!(
+            pool.insert_many_once(1, batch()).await.unwrap(),
+            InsertOutcome::Applied
+        );
+        assert_eq!(pool.drain_counters().await.unwrap().duplicate_inserts, 1);
+    }
 
-#[derive(Dissolve)]
-pub struct MatchSingle {
-    hash: SequenceHash,
-    return_handle: Arc<ReturnHandleImpl>,
-    tx: oneshot::Sender<Option<UniqueBlock>>,
-}
+    #[tokio::test]
+    async fn test_update_priority_if() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
 
-#[derive(Dissolve)]
-pub struct MatchMultiple {
-    hashes: Vec<SequenceHash>,
-    return_handle: Arc<ReturnHandleImpl>,
-    tx: oneshot::Sender<Vec<UniqueBlock>>,
-}
+        assert!(pool.update_priority_if(hash, 0, 3).await.unwrap());
 
-#[derive(Dissolve)]
-pub struct Take {
-    count: u32,
-    return_handle: Arc<ReturnHandleImpl>,
-    tx: oneshot::Sender<Vec<UniqueBlock>>,
-}
+        // the priority moved on, so a swap expecting the old one is a no-op
+        assert!(!pool.update_priority_if(hash, 0, 7).await.unwrap());
 
-pub enum MatchRequest {
-    MatchSingle(MatchSingle),
-    MatchMultiple(MatchMultiple),
-    Take(Take),
-}
+        // a hash not in the pool is never swapped
+        assert!(!pool.update_priority_if(hash + 1, 0, 7).await.unwrap());
 
-pub struct UpdateBlock {
-    hash: SequenceHash,
-    priority: Option<u32>,
-}
+        let matched = pool.match_blocks(vec![hash]).await.unwrap();
+        assert_eq!(matched[0].priority(), BlockPriority::new(0, 3));
+    }
 
-#[derive(Dissolve)]
-pub struct InsertControl {
-    block: KvBlock,
-    tx: oneshot::Sender<()>,
-}
+    #[test]
+    fn test_compaction_keeps_entries_and_order() {
+        let config = AvailableBlocksConfig::default().with_compaction(4, 16);
+        let mut state = detached_state(config);
+        let tokens: Vec<u32> = (0..4096).collect();
+        for block in create_blocks(create_token_sequence(&tokens), 2) {
+            dispatch_insert(&mut state, block);
+        }
 
-#[derive(Dissolve)]
-pub struct UpdateSingleControl {
-    update: UpdateBlock,
-    tx: oneshot::Sender<()>,
-}
+        // churn: most blocks leave the map, which keeps its peak capacity
+        let hashes: Vec<_> = state.priority_set.values().copied().collect();
+        let _checked_out = state.match_hashes(hashes[..1900].to_vec(), false);
+        let capacity_before = state.lookup_map.capacity();
+        let order: Vec<_> = state.priority_set.values().copied().collect();
+        let mut entries: Vec<_> = state.lookup_map.keys().copied().collect();
+        entries.sort_unstable();
+
+        state.end_turn("test");
+        assert!(state.is_compacting());
+
+        // each turn moves at most the budget
+        state.resume_continuation();
+        assert_eq!(state.counters.compacted_entries, 16);
+        while state.has_continuation() {
+            state.resume_continuation();
+        }
 
-#[derive(Dissolve)]
-pub struct UpdateMultipleControl {
-    updates: Vec<UpdateBlock>,
-    tx: oneshot::Sender<()>,
-}
+        assert_eq!(state.counters.compactions, 1);
+        assert_eq!(state.counters.compacted_entries, 148);
+        assert!(state.lookup_map.capacity() < capacity_before);
+        let mut compacted: Vec<_> = state.lookup_map.keys().copied().collect();
+        compacted.sort_unstable();
+        assert_eq!(compacted, entries);
+        assert_eq!(
+            state.priority_set.values().copied().collect::<Vec<_>>(),
+            order
+        );
 
-#[derive(Dissolve)]
-pub struct ResetControl {
-    sequence_hashes: Vec<SequenceHash>,
-    tx: oneshot::Sender<()>,
-}
+        // a compacted map is not compacted again
+        state.end_turn("test");
+        assert!(!state.has_continuation());
+    }
 
-#[derive(Dissolve)]
-pub struct ResetAllControl {
-    tx: oneshot::Sender<()>,
-}
+    #[tokio::test]
+    async fn test_fence_during_compaction() {
+        let config = AvailableBlocksConfig::default().with_compaction(4, 16);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let tokens: Vec<u32> = (0..4096).collect();
+        let blocks = create_blocks(create_token_sequence(&tokens), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the match leaves the map mostly empty slots, which schedules a compaction
+        let mut held = pool.match_blocks(hashes[..1900].to_vec()).await.unwrap();
+        assert_eq!(pool.available_blocks(), 148);
+
+        drop(held.pop());
+        pool.fence().await.unwrap();
+        assert_eq!(pool.available_blocks(), 149);
+        assert_eq!(pool.drain_counters().await.unwrap().compactions, 1);
+    }
 
-pub enum ControlRequest {
-    Insert(InsertControl),
-    UpdateSingle(UpdateSingleControl),
-    UpdateMultiple(UpdateMultipleControl),
-    Reset(ResetControl),
-    ResetAll(ResetAllControl),
-}
+    #[tokio::test]
+    async fn test_fence_returns() {
+        // a bounded channel of one spills most returns to the overflow buffer
+        for config in [
+            AvailableBlocksConfig::default(),
+            AvailableBlocksConfig::default().with_bounded_returns(1),
+        ] {
+            let pool = AvailableBlocks::new_with_config(config).await;
+            let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+            let hashes = block_hashes(&blocks);
+            pool.insert_many(blocks).await.unwrap();
+
+            let matched = pool.match_blocks(hashes).await.unwrap();
+            assert_eq!(pool.available_blocks(), 0);
+            drop(matched);
+
+            pool.fence_returns().await.unwrap();
+            assert_eq!(pool.available_blocks(), 4);
+        }
+    }
 
-pub async fn progress_engine(
-    match_rx: mpsc::UnboundedReceiver<MatchRequest>,
-    return_rx: mpsc::UnboundedReceiver<PoolValue<KvBlock>>,
-    ctrl_rx: mpsc::UnboundedReceiver<ControlRequest>,
-    fence_rx: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
-    total_blocks: Arc<AtomicU64>,
-    available_blocks: Arc<AtomicU64>,
-) {
-    let mut match_rx = match_rx;
-    let mut return_rx = return_rx;
-    let mut ctrl_rx = ctrl_rx;
-    let mut fence_rx = fence_rx;
+    #[tokio::test]
+    async fn test_max_in_use() {
+        let config = AvailableBlocksConfig::default().with_max_in_use(3);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        for _ in 0..4 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
 
-    let mut state = AvailableBlocksState::new(total_blocks, available_blocks);
+        // a take is served up to the cap
+        let mut checked_out = pool.take_blocks(2).await.unwrap();
+        checked_out.extend(pool.match_blocks(hashes.clone()).await.unwrap());
+        assert_eq!(checked_out.len(), 3);
+
+        let in_use_limit = |err: anyhow::Error| {
+            matches!(
+                err.downcast_ref::<KvPoolError>(),
+                Some(KvPoolError::InUseLimitReached { max: 3 })
+            )
+        };
+        assert!(in_use_limit(
+            pool.match_blocks(hashes[1..].to_vec()).await.unwrap_err()
+        ));
+        assert!(in_use_limit(pool.match_one(hashes[1]).await.unwrap_err()));
+        assert!(in_use_limit(pool.take_blocks(1).await.unwrap_err()));
 
-    loop {
-        tokio::select! {
-            biased;
+        // a returned block frees a slot
+        drop(checked_out.pop());
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.match_blocks(hashes).await.unwrap().len(), 1);
+    }
 
-            Some(match_req) = match_rx.recv(), if !match_rx.is_closed() => {
-                state.handle_match_request(match_req);
-            }
+    #[tokio::test]
+    async fn test_checkout_for_maintenance() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        drop(pool.match_blocks(hashes.clone()).await.unwrap());
+        pool.fence_returns().await.unwrap();
+        pool.drain_counters().await.unwrap();
+
+        // the block is re-published with its changes and its original tick
+        let mut guard = pool
+            .checkout_for_maintenance(hashes[0])
+            .await
+            .unwrap()
+            .unwrap();
+        let tick = guard.return_tick;
+        guard.set_checksum(Some(7));
+        assert!(pool.match_blocks(hashes.clone()).await.unwrap().is_empty());
+        drop(guard);
+        pool.fence_returns().await.unwrap();
+
+        let block = pool.match_one(hashes[0]).await.unwrap().unwrap();
+        assert_eq!(block.return_tick, tick);
+        assert_eq!(block.checksum(), Some(7));
+        drop(block);
+
+        // unless the guard asks for a recency bump
+        let mut guard = pool
+            .checkout_for_maintenance(hashes[1])
+            .await
+            .unwrap()
+            .unwrap();
+        let tick = guard.return_tick;
+        guard.bump_recency();
+        drop(guard);
+        pool.fence_returns().await.unwrap();
+        let block = pool.match_one(hashes[1]).await.unwrap().unwrap();
+        assert!(block.return_tick > tick);
+        drop(block);
+        pool.fence_returns().await.unwrap();
+
+        // maintenance is not counted as matches or returns
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.maintenance_checkouts, 2);
+        assert_eq!(counters.matches, 2);
+        assert_eq!(counters.returns, 2);
+
+        assert!(pool.checkout_for_maintenance(7).await.unwrap().is_none());
+    }
 
-            Some(block) = return_rx.recv(), if !return_rx.is_closed() => {
-                state.handle_return(block);
-            }
+    #[tokio::test]
+    async fn test_maintenance_rejects_changed_hash() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        let other = create_blocks(create_token_sequence(&[5, 6]), 2);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the patched block is neither re-published under its old hash nor under the new one
+        let mut guard = pool
+            .checkout_for_maintenance(hashes[1])
+            .await
+            .unwrap()
+            .unwrap();
+        guard.update_token_block(other[0].token_block.clone());
+        drop(guard);
+        pool.fence_returns().await.unwrap();
+
+        assert_eq!(pool.cached_prefix_len(hashes.clone()).await.unwrap(), 1);
+        let patched = vec![other[0].token_block.sequence_hash()];
+        assert_eq!(pool.cached_prefix_len(patched).await.unwrap(), 0);
+        assert_eq!(pool.available_blocks(), 2);
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.uninitialized_blocks, 1);
+        assert_eq!(stats.reusable_blocks, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_maintenance_conflict_wait() {
+        let config = AvailableBlocksConfig::default()
+            .with_maintenance_conflict(OnMaintenance::Wait(Duration::from_secs(10)));
+        let pool = Arc::new(AvailableBlocks::new_with_config(config).await);
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // a match waits for the block to be re-published
+        let guard = pool
+            .checkout_for_maintenance(hashes[1])
+            .await
+            .unwrap()
+            .unwrap();
+        let waiter = {
+            let pool = pool.clone();
+            let hashes = hashes.clone();
+            tokio::spawn(async move { pool.match_blocks(hashes).await.unwrap().len() })
+        };
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!waiter.is_finished());
+        drop(guard);
+        assert_eq!(waiter.await.unwrap(), 2);
+        pool.fence_returns().await.unwrap();
+
+        // the wait is bounded
+        let _guard = pool
+            .checkout_for_maintenance(hashes[1])
+            .await
+            .unwrap()
+            .unwrap();
+        let started = Instant::now();
+        assert_eq!(pool.match_blocks(hashes.clone()).await.unwrap().len(), 1);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_evictions_attributed_to_caller() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let mut hashes: Vec<_> = blocks
+            .iter()
+            .map(|block| block.token_block.sequence_hash())
+            .collect();
+        pool.insert_many(blocks).await.unwrap();
+        let mut evictions = pool.subscribe_evictions();
+
+        // a tagged take evicts the first block on behalf of its caller
+        let taken = pool.take_blocks_for(CallerId(5), 1).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        let tagged = evictions.recv().await.unwrap();
+        assert_eq!(tagged.caller, Some(CallerId(5)));
+        assert_eq!(tagged.request_id, None);
+
+        // an untagged take is not attributed
+        let session = pool.session(SessionOptions {
+            request_id: Some(9),
+            ..Default::default()
+        });
+        let taken = session.take_blocks(1).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        let untagged = evictions.recv().await.unwrap();
+        assert_eq!(untagged.caller, None);
+        assert_eq!(untagged.request_id, Some(9));
+
+        let mut evicted = vec![tagged.sequence_hash, untagged.sequence_hash];
+        evicted.sort();
+        hashes.sort();
+        assert_eq!(evicted, hashes);
+    }
 
-            Some(req) = ctrl_rx.recv(), if !ctrl_rx.is_closed() => {
-                state.handle_control_request(req);
+    #[test]
+    fn test_deterministic_lookup_order() {
+        let order = |deterministic| {
+            let config = AvailableBlocksConfig::default().with_deterministic_mode(deterministic);
+            let mut state = detached_state(config);
+            let tokens: Vec<u32> = (0..64).collect();
+            for block in create_blocks(create_token_sequence(&tokens), 2) {
+                dispatch_insert(&mut state, block);
             }
+            state.lookup_map.keys().copied().collect::<Vec<_>>()
+        };
+        assert_eq!(order(true), order(true));
+    }
 
-            Some(tx) = fence_rx.recv() => {
-                if tx.send(()).is_err() {
-                    log::trace!("Failed to send fence ack; receiver dropped");
+    #[tokio::test]
+    async fn test_deterministic_mode_replays_identically() {
+        async fn run() -> (Vec<u8>, Vec<EvictionEvent>, Vec<u64>) {
+            let config = AvailableBlocksConfig::default().with_deterministic_mode(true);
+            let pool = AvailableBlocks::new_with_config(config).await;
+            let mut evictions = pool.subscribe_evictions();
+
+            // distinct versions tell the slots apart once they are reset
+            let tokens: Vec<u32> = (0..64).collect();
+            let mut blocks = create_blocks(create_token_sequence(&tokens), 2);
+            for (i, block) in blocks.iter_mut().enumerate() {
+                for _ in 0..i {
+                    block.mark_modified();
                 }
             }
+            let hashes = block_hashes(&blocks);
+            pool.insert_many(blocks).await.unwrap();
+            drop(pool.match_blocks(hashes[..8].to_vec()).await.unwrap());
+            pool.fence_returns().await.unwrap();
+
+            // the reconcile walks the lookup map, so the blanked slots are queued in its order
+            pool.reconcile(|block| match block.token_block.sequence_hash() % 3 {
+                0 => BlockFate::Blank,
+                _ => BlockFate::Keep,
+            })
+            .await
+            .unwrap();
+            let taken = pool.take_blocks(20).await.unwrap();
+            let versions = taken.iter().map(|block| block.version()).collect();
+
+            let mut dump = Vec::new();
+            pool.dump_state(&mut dump).await.unwrap();
+            let mut events = Vec::new();
+            while let Ok(event) = evictions.try_recv() {
+                events.push(event);
+            }
+            (dump, events, versions)
         }
-    }
-}
-
-#[cfg(test)]
-pub(crate) mod tests {
-    use crate::tokens::Token;
 
-    use super::*;
+        let (dump, events, versions) = run().await;
+        assert!(!events.is_empty());
+        assert_eq!(run().await, (dump, events, versions));
+    }
 
     #[test]
-    fn test_priority_key_ord() {
-        let mut map = BTreeMap::new();
-        let hash1 = SequenceHash::from(1u64);
-        let hash2 = SequenceHash::from(2u64);
-        let hash3 = SequenceHash::from(3u64);
+    fn test_uniform_queue_late_keys() {
+        let key = |tick: u64| PriorityKey {
+            priority: BlockPriority::default(),
+            sticky: false,
+            affinity_tick: 0,
+            return_tick: tick,
+            sequence_hash: tick,
+        };
+        let hashes = |iter: PrioritySetIter<'_>| iter.map(|(_, hash)| *hash).collect::<Vec<_>>();
+
+        let mut queue = UniformQueue::default();
+        for tick in [2, 4, 6] {
+            assert_eq!(queue.insert(key(tick), tick), None);
+        }
 
-        map.insert(
-            PriorityKey {
-                priority: 0,
-                return_tick: 1,
-                sequence_hash: hash1,
-            },
-            "value1",
-        );
-        map.insert(
-            PriorityKey {
-                priority: 1,
-                return_tick: 0,
-                sequence_hash: hash2,
-            },
-            "value2",
-        );
-        map.insert(
-            PriorityKey {
-                priority: 0,
-                return_tick: 2,
-                sequence_hash: hash3,
-            },
-            "value3",
-        );
+        // keys below the back of the queue do not shift it
+        assert_eq!(queue.insert(key(3), 3), None);
+        assert_eq!(queue.insert(key(1), 1), None);
+        assert_eq!(queue.entries.len(), 3);
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.insert(key(3), 30), Some(3));
 
-        let keys: Vec<_> = map.keys().collect();
+        assert_eq!(hashes(queue.range(..)), vec![1, 2, 30, 4, 6]);
+        assert_eq!(hashes(queue.range(key(3)..key(6))), vec![30, 4]);
 
-        // Priority is the primary sort key (0 before 1)
-        assert_eq!(keys[0].priority, 0);
-        assert_eq!(keys[1].priority, 0);
-        assert_eq!(keys[2].priority, 1);
+        assert_eq!(queue.remove(&key(4)), Some(4));
+        assert_eq!(queue.remove(&key(3)), Some(30));
+        assert_eq!(queue.remove(&key(3)), None);
+        assert_eq!(queue.len(), 3);
 
-        // For same priority, return_tick is the secondary sort key
-        assert_eq!(keys[0].return_tick, 1);
-        assert_eq!(keys[1].return_tick, 2);
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop_first())
+            .map(|(_, hash)| hash)
+            .collect();
+        assert_eq!(popped, vec![1, 2, 6]);
+        assert_eq!(queue.len(), 0);
+    }
 
-        // Test popping from the map to verify ordering
-        let (first_key, first_value) = map.pop_first().unwrap();
-        assert_eq!(first_key.priority, 0);
-        assert_eq!(first_key.return_tick, 1);
-        assert_eq!(first_key.sequence_hash, hash1);
-        assert_eq!(first_value, "value1");
+    #[test]
+    fn test_uniform_priority_upgrade() {
+        let config = AvailableBlocksConfig::default().with_uniform_priority();
+        let mut state = detached_state(config);
+        let tokens: Vec<u32> = (0..8).collect();
+        let blocks = create_blocks(create_token_sequence(&tokens), 2);
+        let hashes = block_hashes(&blocks);
+        for block in blocks {
+            dispatch_insert(&mut state, block);
+        }
+        assert!(state.priority_set.is_uniform());
+        assert_eq!(
+            state.priority_set.values().copied().collect::<Vec<_>>(),
+            hashes
+        );
 
-        let (second_key, second_value) = map.pop_first().unwrap();
-        assert_eq!(second_key.priority, 0);
-        assert_eq!(second_key.return_tick, 2);
-        assert_eq!(second_key.sequence_hash, hash3);
-        assert_eq!(second_value, "value3");
+        // a block leaving the middle of the queue keeps the others in order
+        let block = state.take_with_sequence_hash(hashes[1]).unwrap();
+        assert_eq!(state.priority_set.len(), 3);
+        let order: Vec<_> = state.priority_set.values().copied().collect();
+        assert_eq!(order, vec![hashes[0], hashes[2], hashes[3]]);
 
-        let (third_key, third_value) = map.pop_first().unwrap();
-        assert_eq!(third_key.priority, 1);
-        assert_eq!(third_key.return_tick, 0);
-        assert_eq!(third_key.sequence_hash, hash2);
-        assert_eq!(third_value, "value2");
+        // the first block stored at another priority moves the pool to the ordered map
+        let mut block = block;
+        block.set_priority(5);
+        state.insert(block);
+        assert!(!state.priority_set.is_uniform());
+        assert_eq!(
+            state.priority_set.values().copied().collect::<Vec<_>>(),
+            vec![hashes[0], hashes[2], hashes[3], hashes[1]]
+        );
 
-        // Map should now be empty
-        assert!(map.is_empty());
+        let evicted: Vec<_> = std::iter::from_fn(|| state.pop_idle())
+            .map(|block| block.lookup_key())
+            .collect();
+        assert_eq!(evicted, vec![hashes[0], hashes[2], hashes[3], hashes[1]]);
     }
 
-    // Helper function to create a sequence of tokens
-    pub fn create_token_sequence(values: &[u32]) -> Tokens {
-        let tokens: Vec<Token> = values.iter().map(|&v| Token::from(v)).collect();
-        Tokens::from(tokens)
-    }
+    #[tokio::test]
+    async fn test_take_shortfall_reasons() {
+        let shortfall = |err: anyhow::Error| match err.downcast_ref::<KvPoolError>() {
+            Some(KvPoolError::InsufficientBlocks { shortfall, .. }) => *shortfall,
+            other => panic!("unexpected take error {other:?}"),
+        };
 
-    // Helper to create blocks from a sequence with given size
-    pub fn create_blocks(sequence: Tokens, block_size: usize) -> Vec<KvBlock> {
-        let (blocks, _) = sequence.into_sequence(block_size).into_parts();
-        blocks
-            .into_iter()
-            .map(|token_block| KvBlock {
-                token_block,
+        // blank and evictable blocks which are too few
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        let err = pool
+            .take_blocks_with_policy(3, UnderSupply::Fail)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            shortfall(err),
+            ShortfallReason {
+                blank: 1,
+                evictable: 1,
+                ..Default::default()
+            }
+        );
+
+        // the cached block is out for maintenance
+        let guard = pool.checkout_for_maintenance(hash).await.unwrap().unwrap();
+        let outcome = pool.take_blocks_reporting_evictions(2).await.unwrap();
+        assert_eq!(outcome.taken.len(), 1);
+        assert_eq!(
+            outcome.shortfall,
+            Some(ShortfallReason {
+                transitional: 1,
                 ..Default::default()
             })
-            .collect()
+        );
+        drop((guard, outcome));
+        pool.fence_returns().await.unwrap();
+
+        // a take which gets every block reports no shortfall
+        let outcome = pool.take_blocks_reporting_evictions(2).await.unwrap();
+        assert_eq!(outcome.shortfall, None);
+        drop(outcome);
+        pool.fence_returns().await.unwrap();
+
+        // blocks held back as emergency headroom
+        let config = AvailableBlocksConfig::default().with_reserved_headroom(1);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..2 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
+        let err = pool
+            .take_blocks_with_policy(2, UnderSupply::Fail)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            shortfall(err),
+            ShortfallReason {
+                blank: 1,
+                protected: 1,
+                ..Default::default()
+            }
+        );
     }
 
     #[tokio::test]
-    async fn test_basic_sequence_matching() {
-        let pool = AvailableBlocks::new().await;
+    async fn test_preview_take() {
+        let pool = AvailableBlocks::new_with_config(
+            AvailableBlocksConfig::default().with_reserved_band(10..=10, 0.5),
+        )
+        .await;
+        let tokens: Vec<u32> = (0..12).collect();
+        let blocks = create_blocks(create_token_sequence(&tokens), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        // two blocks in the reserved band, one shielded block and one of the newest blocks
+        for hash in &hashes[..2] {
+            assert!(pool.update_priority_if(*hash, 0, 10).await.unwrap());
+        }
+        pool.expect(hashes[2..3].to_vec(), Duration::from_secs(60))
+            .await
+            .unwrap();
 
-        // Create a sequence of 4 tokens split into blocks of 2
-        let sequence = create_token_sequence(&[1, 2, 3, 4]);
-        let blocks = create_blocks(sequence, 2);
-        assert_eq!(blocks.len(), 2);
+        let preview = pool.preview_take(5).await.unwrap();
+        assert_eq!(preview.len(), 5);
+        assert!(preview[0].is_empty_slot());
 
-        // Match the blocks in sequence
-        let hashes: Vec<_> = blocks
+        // previewing takes nothing
+        assert_eq!(pool.preview_take(5).await.unwrap(), preview);
+        assert_eq!(pool.available_blocks(), 7);
+
+        let taken = pool.take_blocks(5).await.unwrap();
+        let views: Vec<_> = taken
             .iter()
-            .map(|b| b.token_block.sequence_hash())
+            .map(|block| KvBlockView::from(&**block))
+            .collect();
+        assert_eq!(views, preview);
+    }
+
+    #[tokio::test]
+    async fn test_preview_take_sacrifices_shields_in_order() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // the unshielded block goes first, then the shielded ones in eviction order
+        pool.expect(
+            vec![hashes[0], hashes[1], hashes[3]],
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        let preview = pool.preview_take(3).await.unwrap();
+        let previewed: Vec<_> = preview.iter().map(|view| view.sequence_hash).collect();
+        assert_eq!(previewed, vec![hashes[2], hashes[0], hashes[1]]);
+
+        let taken = pool.take_blocks(3).await.unwrap();
+        let views: Vec<_> = taken
+            .iter()
+            .map(|block| KvBlockView::from(&**block))
             .collect();
+        assert_eq!(views, preview);
+    }
 
-        // Insert blocks into pool
-        for block in blocks {
-            pool.insert(block).await.unwrap();
-        }
+    #[tokio::test]
+    async fn test_block_metadata_round_trip() {
+        #[derive(Debug, PartialEq)]
+        struct Revision(&'static str);
 
+        let pool = AvailableBlocks::new().await;
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        blocks[0].set_metadata(Some(Arc::new(Revision("r1"))));
+        pool.insert_many(blocks).await.unwrap();
         pool.fence().await.unwrap();
 
-        assert_eq!(pool.total_blocks(), 2);
-        assert_eq!(pool.available_blocks(), 2);
+        let mut matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(matched[0].metadata_as::<Revision>(), Some(&Revision("r1")));
+        assert_eq!(matched[0].metadata_as::<u64>(), None);
+        assert!(matched[1].metadata().is_none());
 
-        // Match the blocks in sequence
-        let matched = pool.match_blocks(hashes.clone()).await.unwrap();
-        assert_eq!(matched.len(), 2);
+        // the data set on a matched block comes back with it on the next match
+        matched[1].set_metadata(Some(Arc::new(7u64)));
+        drop(matched);
+        pool.fence_returns().await.unwrap();
 
-        assert_eq!(pool.total_blocks(), 2);
-        assert_eq!(pool.available_blocks(), 0);
+        let matched = pool.match_blocks(hashes).await.unwrap();
+        assert_eq!(matched[0].metadata_as::<Revision>(), Some(&Revision("r1")));
+        assert_eq!(matched[1].metadata_as::<u64>(), Some(&7));
+        drop(matched);
+        pool.fence_returns().await.unwrap();
 
-        // Validate the blocks are in the correct order and match the sequence hashes
-        assert_eq!(matched[0].token_block.sequence_hash(), hashes[0]);
-        assert_eq!(matched[1].token_block.sequence_hash(), hashes[1]);
+        // a reset drops it with the contents
+        pool.reset_all().await.unwrap();
+        let taken = pool.take_blocks(2).await.unwrap();
+        assert!(taken.iter().all(|block| block.metadata().is_none()));
+    }
 
-        // Return blocks in reverse order (tail to root)
-        for block in matched.into_iter().rev() {
-            drop(block); // This will trigger return_to_pool
-        }
+    #[tokio::test(start_paused = true)]
+    async fn test_self_check_flags_counter_drift() {
+        let config = AvailableBlocksConfig::default().with_self_check(1, Duration::from_secs(1));
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2))
+            .await
+            .unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(pool.health().anomaly, None);
+        assert_eq!(pool.self_check.warnings.load(Ordering::Relaxed), 0);
 
+        pool.inject_available_drift(5);
+        pool.match_blocks(vec![1]).await.unwrap();
         pool.fence().await.unwrap();
 
-        assert_eq!(pool.total_blocks(), 2);
-        assert_eq!(pool.available_blocks(), 2);
+        let health = pool.health();
+        assert_eq!(
+            health.anomaly,
+            Some(PoolAnomaly::CounterDrift {
+                total: 2,
+                available: 7,
+                offloading: 0
+            })
+        );
+        let audit = health.last_audit.unwrap();
+        assert!(!audit.is_consistent());
+        assert_eq!(audit.available_blocks, 7);
+        assert_eq!(pool.self_check.warnings.load(Ordering::Relaxed), 1);
+
+        // repairs are opt-in
+        assert_eq!(pool.available_blocks(), 7);
+        assert_eq!(pool.drain_counters().await.unwrap().integrity_repairs, 0);
+
+        // the warning is rate limited
+        pool.match_blocks(vec![1]).await.unwrap();
+        assert_eq!(pool.self_check.warnings.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(SELF_CHECK_WARNING_INTERVAL).await;
+        pool.match_blocks(vec![1]).await.unwrap();
+        assert_eq!(pool.self_check.warnings.load(Ordering::Relaxed), 2);
     }
 
     #[tokio::test]
-    async fn test_equal_priority_taking() {
-        let pool = AvailableBlocks::new().await;
-
-        // Create two sequences with different priorities
-        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
-        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+    async fn test_self_check_repairs_drift() {
+        let config = AvailableBlocksConfig::default()
+            .with_self_check(1, Duration::from_secs(1))
+            .with_self_check_repair(true);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2))
+            .await
+            .unwrap();
+        pool.fence().await.unwrap();
 
-        let mut blocks1 = create_blocks(seq1, 2);
-        let mut blocks2 = create_blocks(seq2, 2);
+        pool.inject_available_drift(5);
+        pool.match_blocks(vec![1]).await.unwrap();
+        pool.fence().await.unwrap();
 
-        for block in blocks1.iter_mut() {
-            block.priority = 1;
-        }
-        for block in blocks2.iter_mut() {
-            block.priority = 1;
-        }
+        assert!(pool.health().anomaly.is_some());
+        assert_eq!(pool.available_blocks(), 2);
+        assert_eq!(pool.drain_counters().await.unwrap().integrity_repairs, 1);
+        assert!(pool.verify_integrity().await.unwrap().is_consistent());
 
-        // If priorities were equal, first in, first out would apply
+        // the repaired counters clear the anomaly
+        assert_eq!(pool.health().anomaly, None);
+    }
 
-        // Insert Sequence 2
-        for block in blocks2.into_iter().rev() {
-            pool.insert(block).await.unwrap();
-        }
+    #[tokio::test]
+    async fn test_return_batch_scope() {
+        let pool = AvailableBlocks::new().await;
+        pool.insert_many(create_blocks(
+            create_token_sequence(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]),
+            2,
+        ))
+        .await
+        .unwrap();
+        pool.fence().await.unwrap();
 
-        // Insert Sequence 1
-        for block in blocks1.into_iter().rev() {
-            pool.insert(block).await.unwrap();
-        }
+        let mut scope = pool.return_batch_scope();
+        scope.extend(pool.take_blocks(2).await.unwrap());
+        scope.extend(pool.take_blocks(2).await.unwrap());
+        assert_eq!(scope.len(), 4);
+
+        // held blocks are not returned
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.available_blocks(), 1);
+
+        scope.flush().await.unwrap();
+        assert!(scope.is_empty());
+        assert_eq!(pool.available_blocks(), 5);
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.returns, 4);
+        assert_eq!(counters.return_batches, 1);
+
+        // dropping the scope returns the rest without waiting
+        scope.extend(pool.take_blocks(3).await.unwrap());
+        drop(scope);
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.available_blocks(), 5);
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.returns, 3);
+        assert_eq!(counters.return_batches, 1);
+        assert_eq!(pool.return_queue_depth(), 0);
+    }
 
+    #[tokio::test]
+    async fn test_block_ops() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes: Vec<_> = blocks.iter().map(|block| block.lookup_key()).collect();
+        assert!(blocks[0].pool_ops().is_none());
+        pool.insert_many(blocks).await.unwrap();
         pool.fence().await.unwrap();
 
-        let blocks = pool.take_blocks(4).await.unwrap();
-        assert_eq!(blocks.len(), 4);
+        // a checked out block takes the priority on return
+        let matched = pool.match_blocks(hashes[..1].to_vec()).await.unwrap();
+        let ops = matched[0].pool_ops().unwrap();
+        assert_eq!(ops.hash(), hashes[0]);
+        ops.set_priority(5).await.unwrap();
+        drop(matched);
+        pool.fence_returns().await.unwrap();
+        let matched = pool.match_blocks(hashes[..1].to_vec()).await.unwrap();
+        assert_eq!(matched[0].priority(), BlockPriority::new(0, 5));
+        drop(matched);
+        pool.fence_returns().await.unwrap();
+
+        // a cached block is re-keyed right away
+        ops.set_priority(7).await.unwrap();
+        let matched = pool.match_blocks(hashes[..1].to_vec()).await.unwrap();
+        assert_eq!(matched[0].priority(), BlockPriority::new(0, 7));
+        drop(matched);
+
+        let matched = pool.match_blocks(hashes[1..].to_vec()).await.unwrap();
+        matched[1]
+            .pool_ops()
+            .unwrap()
+            .report_corrupt()
+            .await
+            .unwrap();
+        drop(matched);
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.cached_prefix_len(hashes.clone()).await.unwrap(), 2);
+        assert_eq!(pool.drain_counters().await.unwrap().corrupt_blocks, 1);
+
+        // the reference does not keep the pool alive
+        drop(pool);
+        assert!(ops.report_corrupt().await.is_err());
+        assert!(ops.set_priority(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_modes() {
+        for mode in [ShutdownMode::RejectNew, ShutdownMode::DrainThenReject] {
+            let config = AvailableBlocksConfig::default().with_shutdown_mode(mode);
+            let pool = AvailableBlocks::new_with_config(config).await;
+            let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+            let hashes = block_hashes(&blocks);
+            pool.insert_many(blocks).await.unwrap();
+
+            // a checked out block keeps the engine draining
+            let held = pool.match_blocks(hashes[..1].to_vec()).await.unwrap();
+            pool.begin_shutdown().await.unwrap();
+            assert_eq!(pool.lifecycle(), PoolLifecycle::ShuttingDown);
+            assert!(pool.is_active());
+
+            let matched = pool.match_blocks(hashes[1..].to_vec()).await;
+            match mode {
+                ShutdownMode::RejectNew => assert!(matches!(
+                    matched.unwrap_err().downcast_ref::<KvPoolError>(),
+                    Some(KvPoolError::ShuttingDown)
+                )),
+                ShutdownMode::DrainThenReject => assert_eq!(matched.unwrap().len(), 1),
+            }
+
+            // every reply able to carry an error gets one
+            if mode == ShutdownMode::RejectNew {
+                let rejected = [
+                    pool.allocate(hashes[1..].to_vec()).await.err(),
+                    pool.match_blocks_detailed(hashes[1..].to_vec()).await.err(),
+                    pool.match_blocks_tiered(hashes[1..].to_vec()).await.err(),
+                    pool.take_grouped(1).await.err(),
+                ];
+                for err in rejected {
+                    assert!(matches!(
+                        err.unwrap().downcast_ref::<KvPoolError>(),
+                        Some(KvPoolError::ShuttingDown)
+                    ));
+                }
+            }
+
+            // returns and control requests are still handled while draining
+            pool.fence_returns().await.unwrap();
+            assert_eq!(pool.available_blocks(), 1);
 
-        // Validate the blocks are in the correct order
-        assert_eq!(blocks[0].token_block.tokens()[0], 7);
-        assert_eq!(blocks[1].token_block.tokens()[0], 5);
-        assert_eq!(blocks[2].token_block.tokens()[0], 3);
-        assert_eq!(blocks[3].token_block.tokens()[0], 1);
+            drop(held);
+            pool.shutdown().await.unwrap();
+            assert!(pool.match_blocks(hashes).await.is_err());
+        }
     }
 
     #[tokio::test]
-    async fn test_priority_taking() {
+    async fn test_match_prefix_in_chunks() {
         let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&(0..200).collect::<Vec<u32>>()), 2);
+        let hashes = block_hashes(&blocks);
+        assert_eq!(hashes.len(), 100);
+        pool.insert_many(blocks).await.unwrap();
+
+        let options = MatchOptions::default().max_blocks(32);
+        let mut matched = Vec::new();
+        let mut truncations = Vec::new();
+        loop {
+            let prefix = pool
+                .match_prefix(hashes[matched.len()..].to_vec(), options)
+                .await
+                .unwrap();
+            truncations.push(prefix.truncated);
+            matched.extend(prefix.blocks);
+            match prefix.resume_from {
+                Some(resume_from) => assert_eq!(resume_from, hashes[matched.len()]),
+                None => break,
+            }
+        }
+        assert_eq!(truncations, [true, true, true, false]);
+        let matched: Vec<_> = matched.iter().map(|block| block.sequence_hash()).collect();
+        assert_eq!(matched, hashes);
+    }
 
-        // Create two sequences with different priorities
-        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
-        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+    #[tokio::test]
+    async fn test_allocate_with_max_blocks() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&(0..24).collect::<Vec<u32>>()), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks.into_iter().take(10).collect())
+            .await
+            .unwrap();
+        pool.insert_many(vec![KvBlock::default(), KvBlock::default()])
+            .await
+            .unwrap();
+
+        // nothing is taken while the match is cut short
+        let options = MatchOptions::default().max_blocks(4);
+        let mut held = Vec::new();
+        let mut start = 0;
+        let taken = loop {
+            let allocation = pool
+                .allocate_with_options(hashes[start..].to_vec(), options)
+                .await
+                .unwrap();
+            start += allocation.matched.len();
+            held.extend(allocation.matched);
+            if !allocation.truncated {
+                assert_eq!(allocation.resume_from, None);
+                break allocation.taken;
+            }
+            assert!(allocation.taken.is_empty());
+            assert_eq!(allocation.resume_from, Some(hashes[start]));
+        };
 
-        let mut blocks1 = create_blocks(seq1, 2);
-        let mut blocks2 = create_blocks(seq2, 2);
+        // the last call misses after two blocks and takes for the rest
+        assert_eq!(held.len(), 10);
+        assert_eq!(taken.len(), 2);
+    }
 
-        for block in blocks1.iter_mut() {
-            block.priority = 1;
+    #[tokio::test(start_paused = true)]
+    async fn test_adaptive_priority_promotes_hot_blocks() {
+        let config =
+            AvailableBlocksConfig::default().with_adaptive_priority(3, 1, Duration::from_secs(1));
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hot = blocks[0].sequence_hash();
+        let cold = blocks[1].sequence_hash();
+        for block in blocks.iter_mut() {
+            block.set_priority(1);
         }
-        for block in blocks2.iter_mut() {
-            block.priority = 2;
+        pool.insert_many(blocks).await.unwrap();
+
+        let match_priority = |hash| {
+            let pool = &pool;
+            async move {
+                let matched = pool.match_one(hash).await.unwrap().unwrap();
+                let priority = matched.priority();
+                drop(matched);
+                pool.fence_returns().await.unwrap();
+                priority
+            }
+        };
+        for _ in 0..3 {
+            assert_eq!(match_priority(hot).await, BlockPriority::new(0, 1));
         }
+        assert_eq!(match_priority(cold).await, BlockPriority::new(0, 1));
 
-        // If priorities were equal, first in, first out would apply
-        // but here we have a higher priority block first (which are taken last)
-        // returned first, but lower priority blocks inserted after
-        // we expect the lower priority blocks to be taken first
+        tokio::time::advance(Duration::from_secs(1)).await;
+        pool.fence().await.unwrap();
 
-        // Insert Sequence 2
-        for block in blocks2.into_iter().rev() {
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.adaptive_promotions, 1);
+        assert_eq!(counters.adaptive_demotions, 1);
+        assert_eq!(match_priority(hot).await, BlockPriority::new(0, 2));
+        assert_eq!(match_priority(cold).await, BlockPriority::new(0, 0));
+
+        // the cold block is now evicted first
+        let mut evictions = pool.subscribe_evictions();
+        pool.insert(KvBlock::default()).await.unwrap();
+        drop(pool.take_blocks(2).await.unwrap());
+        assert_eq!(evictions.recv().await.unwrap().sequence_hash, cold);
+    }
+
+    #[tokio::test]
+    async fn test_aux_budget_sheds_oldest_history_first() {
+        let budget = 2 * EvictionHistory::ENTRY_BYTES + 2 * MissCounter::ENTRY_BYTES;
+        let config = AvailableBlocksConfig::default()
+            .with_eviction_hysteresis(1000)
+            .with_miss_tracking(64, Duration::from_secs(3600))
+            .with_aux_budget(budget);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        // two evictions and two misses fill the budget
+        for block in create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2) {
             pool.insert(block).await.unwrap();
         }
+        drop(pool.take_blocks(2).await.unwrap());
+        for hash in [1001, 1002] {
+            assert!(pool.match_blocks(vec![hash]).await.unwrap().is_empty());
+        }
+        pool.fence().await.unwrap();
+        assert_eq!(pool.stats().await.unwrap().aux_bytes, budget as u64);
 
-        // Insert Sequence 1
-        for block in blocks1.into_iter().rev() {
-            pool.insert(block).await.unwrap();
+        // the dedupe window is not shed, so a redelivery is still a duplicate
+        for op_id in 1..=3 {
+            pool.insert_many_once(op_id, vec![KvBlock::default()])
+                .await
+                .unwrap();
         }
+        assert_eq!(
+            pool.insert_many_once(3, vec![KvBlock::default()])
+                .await
+                .unwrap(),
+            InsertOutcome::Duplicate
+        );
+        pool.fence().await.unwrap();
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.aux_shed_evictions, 0);
+        assert_eq!(counters.aux_shed_misses, 0);
+        assert_eq!(pool.top_misses(8).await.unwrap().len(), 2);
 
+        // recent evictions go first, then the least missed hashes
+        pool.match_blocks(vec![1003]).await.unwrap();
         pool.fence().await.unwrap();
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(
+            counters.aux_shed_evictions,
+            MissCounter::ENTRY_BYTES.div_ceil(EvictionHistory::ENTRY_BYTES) as u64
+        );
+        assert_eq!(counters.aux_shed_misses, 0);
 
-        let blocks = pool.take_blocks(4).await.unwrap();
-        assert_eq!(blocks.len(), 4);
+        for hash in [1001, 1004, 1005, 1006] {
+            pool.match_blocks(vec![hash]).await.unwrap();
+        }
+        pool.fence().await.unwrap();
+        let counters = pool.drain_counters().await.unwrap();
+        assert!(counters.aux_shed_misses > 0);
+        let top = pool.top_misses(8).await.unwrap();
+        assert_eq!(top[0], (1001, 2));
+        assert!(pool.stats().await.unwrap().aux_bytes <= budget as u64);
+
+        // the blocks themselves are untouched
+        assert_eq!(pool.total_blocks(), 5);
+        assert_eq!(pool.available_blocks(), 5);
+        assert!(pool.verify_integrity().await.unwrap().is_consistent());
+    }
 
-        // Validate the blocks are in the correct order
-        assert_eq!(blocks[0].token_block.tokens()[0], 3);
-        assert_eq!(blocks[1].token_block.tokens()[0], 1);
-        assert_eq!(blocks[2].token_block.tokens()[0], 7);
-        assert_eq!(blocks[3].token_block.tokens()[0], 5);
+    #[tokio::test]
+    async fn test_aux_budget_of_zero_keeps_pool_working() {
+        let config = AvailableBlocksConfig::default()
+            .with_eviction_hysteresis(100)
+            .with_miss_tracking(8, Duration::from_secs(3600))
+            .with_aux_budget(0);
+        let pool = AvailableBlocks::new_with_config(config).await;
+
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many_once(1, blocks).await.unwrap();
+        assert_eq!(pool.match_blocks(hashes.clone()).await.unwrap().len(), 2);
+        pool.match_blocks(vec![1001]).await.unwrap();
+        pool.fence().await.unwrap();
+
+        let stats = pool.stats().await.unwrap();
+        assert_eq!(stats.aux_bytes, 0);
+        assert_eq!(stats.reusable_blocks, 2);
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.aux_shed_misses, 1);
+        assert_eq!(
+            pool.insert_many_once(1, vec![KvBlock::default()])
+                .await
+                .unwrap(),
+            InsertOutcome::Duplicate
+        );
+        assert!(pool.top_misses(8).await.unwrap().is_empty());
+        assert!(pool.verify_integrity().await.unwrap().is_consistent());
     }
 
     #[tokio::test]
-    async fn test_priority_taking_after_update() {
+    async fn test_return_visibility_to_take() {
         let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
 
-        // Create two sequences with different priorities
-        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
-        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+        // the drop is not absorbed until the engine runs again
+        let matched = pool.match_blocks(vec![hash]).await.unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(pool.available_blocks(), 0);
+        drop(matched);
+        assert_eq!(pool.available_blocks(), 0);
 
-        let mut blocks1 = create_blocks(seq1, 2);
-        let mut blocks2 = create_blocks(seq2, 2);
+        // once fenced, a take sees the returned block
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.available_blocks(), 1);
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        drop(taken);
+
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.available_blocks(), pool.total_blocks());
+        let counters = pool.drain_counters().await.unwrap();
+        assert_eq!(counters.returns, counters.matches + counters.takes);
+    }
 
-        for block in blocks1.iter_mut() {
-            block.priority = 1;
-        }
-        for block in blocks2.iter_mut() {
-            block.priority = 1;
-        }
+    #[tokio::test]
+    async fn test_probe_fast() {
+        let pool = AvailableBlocks::new().await;
+        assert!(pool.probe_fast(&[1]).is_err());
 
-        // record hash of blocks 2
-        // insert blocks 2, then blocks 1
-        // update priority of blocks 2 to 2 using the update api
-        // pull 4 blocks and test order
+        let config = AvailableBlocksConfig::default().with_fast_probe();
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        pool.fence().await.unwrap();
 
-        let block_hashes = blocks2
-            .iter()
-            .map(|b| b.token_block.sequence_hash())
-            .collect::<Vec<_>>();
+        let probed =
+            |pool: &AvailableBlocks| pool.probe_fast(&[hashes[0], hashes[1], 1001]).unwrap();
+        assert_eq!(probed(&pool), vec![true, true, false]);
 
-        // Insert Sequence 2
-        for block in blocks2.into_iter().rev() {
-            pool.insert(block).await.unwrap();
-        }
+        // a matched block is checked out until it is returned
+        let matched = pool.match_blocks(vec![hashes[0]]).await.unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(probed(&pool), vec![false, true, false]);
+        drop(matched);
+        pool.fence_returns().await.unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(probed(&pool), vec![true, true, false]);
 
-        // Insert Sequence 1
-        for block in blocks1.into_iter().rev() {
-            pool.insert(block).await.unwrap();
+        // evictions leave the index once fenced
+        let taken = pool.take_blocks(2).await.unwrap();
+        pool.fence().await.unwrap();
+        assert_eq!(probed(&pool), vec![false, false, false]);
+        drop(taken);
+        pool.fence_returns().await.unwrap();
+        assert!(!pool.is_fully_cached(hashes.clone()).await.unwrap());
+        assert_eq!(probed(&pool), vec![false, false, false]);
+    }
+
+    #[tokio::test]
+    async fn test_blocks_from_tokens() {
+        let tokens: Vec<Token> = (1..=7).collect();
+        let blocks = KvBlock::from_tokens(&tokens, 2, 3);
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks.iter().all(|block| block.score() == 3));
+
+        // the hashes are those of the same tokens blocked by a sequence
+        let (expected, _) = Tokens::from(tokens.clone()).into_sequence(2).into_parts();
+        let hashes: Vec<SequenceHash> =
+            expected.iter().map(|block| block.sequence_hash()).collect();
+        let built = block_hashes(&blocks);
+        assert_eq!(built, hashes);
+
+        let pool = AvailableBlocks::new().await;
+        pool.insert_many(blocks).await.unwrap();
+        let matched = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(matched.len(), 3);
+        for (block, hash) in matched.iter().zip(&hashes) {
+            assert_eq!(block.token_block.sequence_hash(), *hash);
         }
+    }
 
+    #[tokio::test]
+    async fn test_capacity_change_events() {
+        let pool = AvailableBlocks::new().await;
+        let mut events = pool.subscribe_pool_events();
+
+        // a batch is confirmed by a single event
+        pool.insert_many((0..4).map(|_| KvBlock::default()).collect())
+            .await
+            .unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+        let held = pool.take_blocks(1).await.unwrap();
+        pool.shrink(3).await.unwrap();
+
+        // the block checked out during the reconcile is removed once returned
+        let report = pool.reconcile(|_| BlockFate::Remove).await.unwrap();
+        assert_eq!(report.removed, 2);
+        drop(held);
+        pool.fence_returns().await.unwrap();
         pool.fence().await.unwrap();
+        assert_eq!(pool.total_blocks(), 0);
+
+        let expected = [
+            (4, 4, CapacityChangeReason::Insert),
+            (1, 5, CapacityChangeReason::Insert),
+            (-2, 3, CapacityChangeReason::Shrink),
+            (-2, 1, CapacityChangeReason::Reconcile),
+            (-1, 0, CapacityChangeReason::Reconcile),
+        ];
+        for (delta, new_total, reason) in expected {
+            assert_eq!(
+                events.try_recv().unwrap(),
+                PoolEvent::CapacityChanged {
+                    delta,
+                    new_total,
+                    reason
+                }
+            );
+        }
+        assert!(events.try_recv().is_err());
 
-        // Update priority of blocks 2 to 2
-        pool.update_multiple(
-            block_hashes
-                .into_iter()
-                .map(|h| UpdateBlock {
-                    hash: h,
-                    priority: Some(2),
-                })
-                .collect(),
-        )
-        .await
-        .unwrap();
+        // the stats keep the same changes in order
+        let log = pool.stats().await.unwrap().capacity_log;
+        let logged: Vec<_> = log
+            .iter()
+            .map(|change| (change.delta, change.new_total, change.reason))
+            .collect();
+        assert_eq!(logged, expected);
+        assert!(log.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    }
 
+    #[tokio::test]
+    async fn test_capacity_log_is_bounded() {
+        let pool = AvailableBlocks::new().await;
+        for _ in 0..CAPACITY_LOG_LEN + 8 {
+            pool.insert(KvBlock::default()).await.unwrap();
+        }
         pool.fence().await.unwrap();
 
-        let blocks = pool.take_blocks(4).await.unwrap();
-        assert_eq!(blocks.len(), 4);
+        let log = pool.stats().await.unwrap().capacity_log;
+        assert_eq!(log.len(), CAPACITY_LOG_LEN);
+        assert_eq!(log[0].new_total, 9);
+        assert_eq!(log.last().unwrap().new_total, (CAPACITY_LOG_LEN + 8) as u64);
+    }
 
-        // Validate the blocks are in the correct order
-        assert_eq!(blocks[0].token_block.tokens()[0], 3);
-        assert_eq!(blocks[1].token_block.tokens()[0], 1);
-        assert_eq!(blocks[2].token_block.tokens()[0], 7);
-        assert_eq!(blocks[3].token_block.tokens()[0], 5);
+    fn is_invalid_request(err: &anyhow::Error) -> bool {
+        matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::InvalidRequest(_))
+        )
     }
 
     #[tokio::test]
-    async fn test_reset_all() {
+    async fn test_match_rejects_duplicate_hashes() {
         let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let duplicated = vec![hashes[0], hashes[1], hashes[0]];
+        let err = pool.match_blocks(duplicated.clone()).await.unwrap_err();
+        assert!(is_invalid_request(&err), "{err}");
+        let err = pool.allocate(duplicated.clone()).await.unwrap_err();
+        assert!(is_invalid_request(&err), "{err}");
+
+        // every other match rejects them too
+        let options = MatchOptions::default();
+        let errs = [
+            pool.match_blocks_stream(duplicated.clone()).await.err(),
+            pool.match_blocks_tiered(duplicated.clone()).await.err(),
+            pool.match_blocks_detailed(duplicated.clone()).await.err(),
+            pool.match_blocks_with_options(duplicated.clone(), options)
+                .await
+                .err(),
+            pool.match_prefix(duplicated.clone(), options).await.err(),
+            pool.acquire_and_demote(duplicated.clone(), Vec::new(), 0)
+                .await
+                .err(),
+            pool.allocate_batch(
+                vec![AllocateSpec {
+                    hashes: duplicated.clone(),
+                }],
+                BatchMode::AllOrNothing,
+            )
+            .await
+            .err(),
+        ];
+        for err in errs {
+            let err = err.unwrap();
+            assert!(is_invalid_request(&err), "{err}");
+        }
+        assert_eq!(pool.available_blocks(), 3);
+
+        // the same hash in a later request is allowed and misses while checked out
+        let held = pool.match_blocks(hashes.clone()).await.unwrap();
+        assert_eq!(held.len(), 2);
+        assert!(pool.match_blocks(vec![hashes[0]]).await.unwrap().is_empty());
+        drop(held);
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.match_blocks(vec![hashes[0]]).await.unwrap().len(), 1);
+    }
 
-        // Create two sequences with different priorities
-        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
-        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
-
-        let mut blocks1 = create_blocks(seq1, 2);
-        let mut blocks2 = create_blocks(seq2, 2);
+    #[tokio::test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "take of zero blocks"))]
+    async fn test_take_zero_blocks_is_invalid() {
+        let pool = AvailableBlocks::new().await;
+        pool.insert(KvBlock::default()).await.unwrap();
+        assert_eq!(pool.take_blocks(1).await.unwrap().len(), 1);
 
-        for block in blocks1.iter_mut() {
-            block.priority = 1;
-        }
+        let err = pool.take_blocks(0).await.unwrap_err();
+        assert!(is_invalid_request(&err), "{err}");
+    }
 
-        for block in blocks2.iter_mut() {
-            block.priority = 1;
+    #[tokio::test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "take of zero blocks"))]
+    async fn test_every_take_rejects_zero_blocks() {
+        let pool = AvailableBlocks::new().await;
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let errs = [
+            pool.take_blocks_emergency(0).await.err(),
+            pool.take_blocks_with_options(0, TakeOptions::default())
+                .await
+                .err(),
+            pool.take_blocks_with_policy(0, UnderSupply::Fail)
+                .await
+                .err(),
+            pool.take_blocks_reporting_evictions(0).await.err(),
+            pool.take_blocks_for(CallerId(1), 0).await.err(),
+            pool.take_grouped(0).await.err(),
+        ];
+        for err in errs {
+            let err = err.unwrap();
+            assert!(is_invalid_request(&err), "{err}");
         }
+        assert_eq!(pool.available_blocks(), 1);
+    }
 
-        // record hash of blocks 2
-        let block_hashes = blocks2
-            .iter()
-            .map(|b| b.token_block.sequence_hash())
-            .collect::<Vec<_>>();
+    #[tokio::test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "reset of no hashes"))]
+    async fn test_reset_no_hashes_is_invalid() {
+        let pool = AvailableBlocks::new().await;
+        pool.reset(vec![1]).await.unwrap();
 
-        // Insert Sequence 2
-        for block in blocks2.into_iter().rev() {
-            pool.insert(block).await.unwrap();
-        }
+        let err = pool.reset(Vec::new()).await.unwrap_err();
+        assert!(is_invalid_request(&err), "{err}");
+    }
 
-        // Insert Sequence 1
-        for block in blocks1.into_iter().rev() {
-            pool.insert(block).await.unwrap();
-        }
+    #[tokio::test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "has a sequence hash but no tokens")
+    )]
+    async fn test_insert_block_without_tokens_is_invalid() {
+        let pool = AvailableBlocks::new().await;
 
-        // Reset All
-        pool.reset_all().await.unwrap();
-        pool.fence().await.unwrap();
+        // blank blocks have no tokens and no hash
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let hash = crate::kv_router::indexer::compute_hash(&[]);
+        let empty = || {
+            KvBlock::new(TokenBlock::with_precomputed_hash(
+                Tokens::default(),
+                hash,
+                None,
+            ))
+        };
+        let err = pool
+            .insert_many(vec![KvBlock::default(), empty()])
+            .await
+            .unwrap_err();
+        assert!(is_invalid_request(&err), "{err}");
+        assert_eq!(pool.total_blocks(), 1);
+    }
 
-        // Try to match from block 2 hashes, expect no matches
-        let matched = pool.match_blocks(block_hashes).await.unwrap();
-        assert_eq!(matched.len(), 0);
+    #[tokio::test]
+    async fn test_touch_keeps_block_resident() {
+        let pool = AvailableBlocks::new().await;
+        let mut blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        blocks.extend(create_blocks(create_token_sequence(&[3, 4]), 2));
+        let hashes = block_hashes(&blocks);
+
+        // the first block is the oldest and would be evicted first
+        pool.insert_many(blocks).await.unwrap();
+        assert_eq!(pool.touch(vec![hashes[0], 42]).await.unwrap(), 1);
+
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        assert!(pool.match_blocks(vec![hashes[1]]).await.unwrap().is_empty());
+
+        // checked out blocks are not touched
+        let held = pool.match_blocks(vec![hashes[0]]).await.unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(pool.touch(vec![hashes[0]]).await.unwrap(), 0);
+        drop(held);
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.touch(vec![hashes[0]]).await.unwrap(), 1);
+        let report = pool.verify_integrity().await.unwrap();
+        assert!(report.is_consistent(), "{report:?}");
     }
 
     #[tokio::test]
-    async fn test_reset_block2() {
+    async fn test_evict_chains() {
         let pool = AvailableBlocks::new().await;
+        let chain = |tokens: &[u32]| create_blocks(create_token_sequence(tokens), 2);
+        let hashes = |blocks: &[KvBlock]| -> Vec<SequenceHash> {
+            blocks
+                .iter()
+                .map(|block| block.token_block.sequence_hash())
+                .collect()
+        };
+        let hot = chain(&[1, 2, 3, 4, 5, 6]);
+        let cold = chain(&[11, 12, 13, 14, 15, 16]);
+        let colder = chain(&[21, 22, 23, 24]);
+        let (hot_hashes, cold_hashes, colder_hashes) =
+            (hashes(&hot), hashes(&cold), hashes(&colder));
+
+        pool.insert_many(colder).await.unwrap();
+        pool.insert_many(hot).await.unwrap();
+        pool.insert_many(cold).await.unwrap();
+
+        // touching the root makes the whole hot chain hotter than the cold one, although its
+        // leaves are older
+        pool.touch(vec![hot_hashes[0]]).await.unwrap();
+        assert_eq!(
+            pool.evict_chains(0).await.unwrap(),
+            EvictionReport::default()
+        );
 
-        // Create two sequences with different priorities
-        let seq1 = create_token_sequence(&[1, 2, 3, 4]);
-        let seq2 = create_token_sequence(&[5, 6, 7, 8]);
+        let report = pool.evict_chains(4).await.unwrap();
+        assert_eq!(
+            report,
+            EvictionReport {
+                chains: 2,
+                blocks: 5
+            }
+        );
+        assert_eq!(pool.cached_prefix_len(hot_hashes).await.unwrap(), 3);
+        assert_eq!(pool.cached_prefix_len(cold_hashes).await.unwrap(), 0);
+        assert_eq!(pool.cached_prefix_len(colder_hashes).await.unwrap(), 0);
+        assert_eq!(pool.available_blocks(), 8);
+        assert_eq!(pool.blank_set_stats().await.unwrap().blocks, 5);
+
+        let report = pool.verify_integrity().await.unwrap();
+        assert!(report.is_consistent(), "{report:?}");
+    }
 
-        let mut blocks1 = create_blocks(seq1, 2);
-        let mut blocks2 = create_blocks(seq2, 2);
+    #[tokio::test]
+    async fn test_evict_chains_follows_checkouts() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4, 5, 6]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        // with the root checked out, its children head a chain of their own
+        let root = pool.match_one(hashes[0]).await.unwrap().unwrap();
+        assert_eq!(
+            pool.evict_chains(1).await.unwrap(),
+            EvictionReport {
+                chains: 1,
+                blocks: 2
+            }
+        );
 
-        for block in blocks1.iter_mut() {
-            block.priority = 1;
+        drop(root);
+        pool.fence_returns().await.unwrap();
+        assert_eq!(pool.cached_prefix_len(hashes).await.unwrap(), 1);
+        assert_eq!(
+            pool.evict_chains(1).await.unwrap(),
+            EvictionReport {
+                chains: 1,
+                blocks: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_in_use_caps_every_take_path() {
+        let config = AvailableBlocksConfig::default().with_max_in_use(3);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..6 {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
 
-        for block in blocks2.iter_mut() {
-            block.priority = 1;
+        let mut checked_out = pool.take_blocks_for(CallerId(1), 2).await.unwrap();
+        assert_eq!(checked_out.len(), 2);
+        checked_out.extend(
+            pool.take_blocks_with_options(4, TakeOptions { hits: 1 })
+                .await
+                .unwrap(),
+        );
+        assert_eq!(checked_out.len(), 3);
+
+        // once the cap is reached, every take path fails rather than coming back empty
+        let in_use_limit = |err: anyhow::Error| {
+            matches!(
+                err.downcast_ref::<KvPoolError>(),
+                Some(KvPoolError::InUseLimitReached { max: 3 })
+            )
+        };
+        assert!(in_use_limit(
+            pool.take_blocks_for(CallerId(1), 1).await.unwrap_err()
+        ));
+        assert!(in_use_limit(
+            pool.take_blocks_with_options(1, TakeOptions { hits: 1 })
+                .await
+                .unwrap_err()
+        ));
+        assert!(in_use_limit(pool.take_grouped(1).await.unwrap_err()));
+        assert!(in_use_limit(
+            pool.take_one_at_priority(0).await.unwrap_err()
+        ));
+        assert!(in_use_limit(pool.promote_cold(7).await.unwrap_err()));
+        assert!(in_use_limit(pool.allocate(vec![7]).await.unwrap_err()));
+        assert_eq!(pool.available_blocks(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_headroom_holds_for_every_take_path() {
+        let config = AvailableBlocksConfig::default().with_reserved_headroom(2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..4 {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
 
-        // record hash of blocks 2
-        let block2_hashes = blocks2
-            .iter()
-            .map(|b| b.token_block.sequence_hash())
-            .collect::<Vec<_>>();
+        let taken = pool.take_blocks_for(CallerId(1), 4).await.unwrap();
+        assert_eq!(taken.len(), 2);
+        assert!(pool
+            .take_blocks_with_options(1, TakeOptions { hits: 1 })
+            .await
+            .unwrap()
+            .is_empty());
 
-        let block1_hashes = blocks1
-            .iter()
-            .map(|b| b.token_block.sequence_hash())
-            .collect::<Vec<_>>();
+        let urgent = pool.take_blocks_emergency(2).await.unwrap();
+        assert_eq!(urgent.len(), 2);
+    }
 
-        // Insert Sequence 2
-        for block in blocks2.into_iter().rev() {
-            pool.insert(block).await.unwrap();
+    #[tokio::test]
+    async fn test_take_reporting_evictions_follows_take() {
+        let config = AvailableBlocksConfig::default()
+            .with_reserved_headroom(1)
+            .with_max_in_use(2);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..4 {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
 
-        // Insert Sequence 1
-        for block in blocks1.into_iter().rev() {
-            pool.insert(block).await.unwrap();
+        let outcome = pool.take_blocks_reporting_evictions(4).await.unwrap();
+        assert_eq!(outcome.taken.len(), 2);
+        assert!(outcome.shortfall.is_some());
+
+        let err = pool.take_blocks_reporting_evictions(1).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::InUseLimitReached { max: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_allocate_batch_within_take_limits() {
+        let config = AvailableBlocksConfig::default()
+            .with_max_in_use(6)
+            .with_hit_reserve(0.25);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        for _ in 0..8 {
+            pool.insert(KvBlock::default()).await.unwrap();
         }
 
-        // Reset Block 2
-        pool.reset(block2_hashes.clone()).await.unwrap();
-        pool.fence().await.unwrap();
+        let request = |tokens: &[u32]| AllocateSpec {
+            hashes: create_blocks(create_token_sequence(tokens), 2)
+                .iter()
+                .map(|block| block.token_block.sequence_hash())
+                .collect(),
+        };
+        // the second request would dip into the hit reserve and cross the in-use cap
+        let batch = vec![
+            request(&[1, 2, 3, 4, 5, 6, 7, 8]),
+            request(&[9, 10, 11, 12, 13, 14]),
+        ];
+        let allocations = pool
+            .allocate_batch(batch, BatchMode::BestEffortRanked)
+            .await
+            .unwrap();
+        assert_eq!(allocations[0].as_ref().unwrap().taken.len(), 4);
+        assert!(allocations[1].is_none());
+    }
 
-        // Try to match from block 2 hashes, expect no matches
-        let matched = pool.match_blocks(block2_hashes).await.unwrap();
-        assert_eq!(matched.len(), 0);
+    #[tokio::test]
+    async fn test_corrupt_reports_need_a_block() {
+        let config = AvailableBlocksConfig::default().with_checksum_verifier(first_token_checksum);
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+
+        // nothing is quarantined for a hash without a block
+        pool.report_corrupt(hashes[0]).await.unwrap();
+        pool.report_corrupt(0).await.unwrap();
+        pool.insert_many(blocks).await.unwrap();
+        drop(pool.match_blocks(hashes.clone()).await.unwrap());
+        pool.fence().await.unwrap();
+        assert_eq!(pool.drain_counters().await.unwrap().corrupt_blocks, 0);
 
-        let matched = pool.match_blocks(block1_hashes).await.unwrap();
+        let mut matched = pool.match_blocks(hashes.clone()).await.unwrap();
         assert_eq!(matched.len(), 2);
+        matched[1].set_checksum(Some(99));
+        drop(matched);
+        pool.fence().await.unwrap();
+
+        // match_one and allocations verify checksums as well
+        assert!(pool.match_one(hashes[1]).await.unwrap().is_none());
+        pool.fence().await.unwrap();
+        let allocation = pool.allocate(hashes.clone()).await.unwrap();
+        assert_eq!(allocation.matched.len(), 1);
+        assert!(!allocation.truncated);
+        assert_eq!(allocation.taken.len(), 1);
+        assert_eq!(pool.drain_counters().await.unwrap().corrupt_blocks, 1);
+    }
+
+    #[tokio::test]
+    async fn test_pending_priorities_need_a_checked_out_block() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes: Vec<_> = blocks.iter().map(|block| block.lookup_key()).collect();
+        pool.insert_many(blocks).await.unwrap();
+        pool.insert(KvBlock::default()).await.unwrap();
+
+        let blank = pool.take_blocks(1).await.unwrap();
+        let err = blank[0]
+            .pool_ops()
+            .unwrap()
+            .set_priority(3)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KvPoolError>(),
+            Some(KvPoolError::ZeroHash)
+        ));
+
+        // a reset block is gone; the priority does not wait for a block inserted later
+        let matched = pool.match_blocks(hashes[..1].to_vec()).await.unwrap();
+        let ops = matched[0].pool_ops().unwrap();
+        drop(matched);
+        pool.fence_returns().await.unwrap();
+        pool.reset(hashes[..1].to_vec()).await.unwrap();
+        ops.set_priority(5).await.unwrap();
+        pool.insert_many(create_blocks(create_token_sequence(&[1, 2]), 2))
+            .await
+            .unwrap();
+        let matched = pool.match_blocks(hashes[..1].to_vec()).await.unwrap();
+        assert_ne!(matched[0].priority(), BlockPriority::new(0, 5));
+        drop(matched);
+
+        // a reset drops the priorities pending for checked out blocks
+        let matched = pool.match_one(hashes[1]).await.unwrap().unwrap();
+        matched.pool_ops().unwrap().set_priority(6).await.unwrap();
+        pool.reset_all().await.unwrap();
+        drop(matched);
+        pool.fence_returns().await.unwrap();
+        let matched = pool.match_one(hashes[1]).await.unwrap().unwrap();
+        assert_ne!(matched.priority(), BlockPriority::new(0, 6));
+    }
+
+    #[tokio::test]
+    async fn test_released_hold_keeps_shield() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2, 3, 4]), 2);
+        let hashes = block_hashes(&blocks);
+        pool.insert_many(blocks).await.unwrap();
+
+        let ttl = Duration::from_secs(60);
+        pool.expect(vec![hashes[0]], ttl).await.unwrap();
+        assert_eq!(pool.peek_hold(7, hashes.clone(), ttl / 2).await.unwrap(), 2);
+        assert_eq!(pool.peek_hold(7, hashes.clone(), ttl / 4).await.unwrap(), 2);
+        assert_eq!(pool.release_holds(7).await.unwrap(), 2);
+
+        // the hold over the shield fell back to it; the other hold is gone
+        assert_eq!(pool.stats().await.unwrap().active_shields, 1);
+        let taken = pool.take_blocks(1).await.unwrap();
+        assert_eq!(taken[0].token_block.sequence_hash(), hashes[1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expect_keeps_longer_hold() {
+        let pool = AvailableBlocks::new().await;
+        let blocks = create_blocks(create_token_sequence(&[1, 2]), 2);
+        let hash = blocks[0].token_block.sequence_hash();
+        pool.insert_many(blocks).await.unwrap();
+
+        // a short expect over a long hold does not cut the hold short
+        assert_eq!(
+            pool.peek_hold(1, vec![hash], Duration::from_secs(60))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            pool.expect(vec![hash], Duration::from_secs(1))
+                .await
+                .unwrap(),
+            1
+        );
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        let options = MatchOptions {
+            holder: Some(2),
+            on_soft_hold: OnSoftHold::Miss,
+            max_blocks: None,
+        };
+        let matched = pool
+            .match_blocks_with_options(vec![hash], options)
+            .await
+            .unwrap();
+        assert!(matched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_follows_config() {
+        let pool = AvailableBlocks::new().await;
+        assert_eq!(pool.manifest(), CompatManifest::default());
+
+        let hash_config = HashConfig {
+            algorithm: "xxh3_64".to_string(),
+            seed: 7,
+        };
+        let config = AvailableBlocksConfig::default()
+            .with_block_size(16)
+            .with_hash_config(hash_config.clone());
+        let pool = AvailableBlocks::new_with_config(config).await;
+        let manifest = pool.manifest();
+        assert_eq!(manifest.hash_config, hash_config);
+        assert_eq!(manifest.block_size, Some(16));
+        assert!(manifest.features.is_empty());
+    }
+
+    #[test]
+    fn test_priority_set_counts_bands() {
+        let score = |score| BlockPriority::new(0, score);
+        let key = |priority, return_tick| PriorityKey {
+            priority: score(priority),
+            sticky: false,
+            affinity_tick: 0,
+            return_tick,
+            sequence_hash: return_tick,
+        };
+        for uniform in [false, true] {
+            let mut set = PrioritySet::new(uniform, vec![score(0)..=score(0), score(5)..=score(9)]);
+            set.insert(key(0, 1), 1);
+            set.insert(key(0, 2), 2);
+            set.insert(key(7, 3), 3);
+            set.insert(key(12, 4), 4);
+            assert_eq!((set.band_len(0), set.band_len(1)), (2, 1));
+
+            // a replaced key is not counted twice; a missing key is not uncounted
+            set.insert(key(7, 3), 3);
+            assert!(set.remove(&key(7, 9)).is_none());
+            assert_eq!(set.band_len(1), 1);
+
+            set.pop_first();
+            set.remove(&key(7, 3));
+            assert_eq!((set.band_len(0), set.band_len(1)), (1, 0));
+
+            let rebuilt = set.rebuilt([(key(6, 5), 5), (key(8, 6), 6)].into_iter());
+            assert_eq!((rebuilt.band_len(0), rebuilt.band_len(1)), (0, 2));
+        }
     }
 }
@@ -0,0 +1,532 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Warm Standby
+//!
+//! A standby worker mirrors the cache index of a primary so its cache is not cold after a
+//! failover. [ShadowPrimary] diffs the [manifest][AvailableBlocks::export_manifest] of the primary
+//! pool against what it shipped last and sends [ShadowMessage]s over a [ShadowTransport].
+//! [ShadowStandby] applies them to the standby pool as cold entries through
+//! [AvailableBlocks::mirror_cold], so [AvailableBlocks::match_blocks_tiered] on the standby reports
+//! the blocks cached by the primary.
+//!
+//! The transport may drop and reorder messages. Every message carries the epoch of the primary and
+//! a sequence number: the standby buffers deltas which arrive early, ignores stale messages and
+//! asks for a resync once a gap outlasts its reorder window, which the primary answers with a full
+//! snapshot. A restarted primary uses a new epoch and starts with a snapshot.
+//!
+//! Only the index is mirrored; the KV data of a mirrored block is restored from the [ColdLocation]
+//! of the primary or recomputed. Snapshots carry the [CompatManifest] of the primary, and a standby
+//! which can not consume its blocks refuses them rather than mirroring hashes it will never match.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use dynamo_runtime::Result;
+
+use super::compat::CompatManifest;
+use super::reuse::{AvailableBlocks, ColdHit, ColdLocation};
+use crate::tokens::SequenceHash;
+
+/// Deltas a [ShadowStandby] buffers while waiting for a missing message, unless set by
+/// [ShadowStandby::with_reorder_window]
+pub const DEFAULT_REORDER_WINDOW: usize = 8;
+
+/// Message shipped from a [ShadowPrimary] to a [ShadowStandby]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShadowMessage {
+    /// Every reusable block of the primary, in eviction order; replaces the mirrored index
+    Snapshot {
+        epoch: u64,
+        seq: u64,
+        manifest: CompatManifest,
+        hashes: Vec<SequenceHash>,
+    },
+
+    /// Blocks added to and removed from the primary since the message numbered `seq - 1`
+    ///
+    /// Deltas are sent even if nothing changed, so a standby notices a lost last message.
+    Delta {
+        epoch: u64,
+        seq: u64,
+        added: Vec<SequenceHash>,
+        removed: Vec<SequenceHash>,
+    },
+}
+
+impl ShadowMessage {
+    pub fn epoch(&self) -> u64 {
+        match self {
+            ShadowMessage::Snapshot { epoch, .. } | ShadowMessage::Delta { epoch, .. } => *epoch,
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        match self {
+            ShadowMessage::Snapshot { seq, .. } | ShadowMessage::Delta { seq, .. } => *seq,
+        }
+    }
+}
+
+/// Carries [ShadowMessage]s to the standby; delivery may be lossy and out of order
+#[async_trait]
+pub trait ShadowTransport: Send + Sync {
+    async fn send(&self, message: ShadowMessage) -> Result<()>;
+}
+
+#[async_trait]
+impl<T: ShadowTransport + ?Sized> ShadowTransport for Arc<T> {
+    async fn send(&self, message: ShadowMessage) -> Result<()> {
+        (**self).send(message).await
+    }
+}
+
+/// Primary side of a warm standby pair
+pub struct ShadowPrimary<T> {
+    transport: T,
+    epoch: u64,
+    seq: u64,
+    shipped: HashSet<SequenceHash>,
+    resync: bool,
+}
+
+impl<T: ShadowTransport> ShadowPrimary<T> {
+    /// Ship over `transport` as `epoch`, which must be larger than the epoch of any previous
+    /// primary of the standby, e.g. the start time of the worker
+    pub fn new(transport: T, epoch: u64) -> Self {
+        Self {
+            transport,
+            epoch,
+            seq: 0,
+            shipped: HashSet::new(),
+            resync: true,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Send a snapshot on the next [ShadowPrimary::sync]; call when the standby answers
+    /// [ShadowAck::Resync]
+    pub fn request_resync(&mut self) {
+        self.resync = true;
+    }
+
+    /// Ship the changes to `pool` since the last sync; the first sync and the first sync after a
+    /// resync request send a snapshot
+    pub async fn sync(&mut self, pool: &AvailableBlocks) -> Result<()> {
+        let manifest = pool.export_manifest().await?;
+        let current: HashSet<SequenceHash> = manifest
+            .blocks
+            .iter()
+            .map(|block| block.sequence_hash)
+            .collect();
+
+        self.seq += 1;
+        let message = if self.resync {
+            ShadowMessage::Snapshot {
+                epoch: self.epoch,
+                seq: self.seq,
+                manifest: manifest.compat.clone(),
+                hashes: manifest
+                    .blocks
+                    .iter()
+                    .map(|block| block.sequence_hash)
+                    .collect(),
+            }
+        } else {
+            let added = manifest
+                .blocks
+                .iter()
+                .map(|block| block.sequence_hash)
+                .filter(|hash| !self.shipped.contains(hash))
+                .collect();
+            let mut removed: Vec<SequenceHash> =
+                self.shipped.difference(&current).copied().collect();
+            removed.sort_unstable();
+            ShadowMessage::Delta {
+                epoch: self.epoch,
+                seq: self.seq,
+                added,
+                removed,
+            }
+        };
+
+        // a message lost in transit shows up as a gap on the standby
+        self.shipped = current;
+        self.resync = false;
+        self.transport.send(message).await
+    }
+}
+
+/// Outcome of [ShadowStandby::apply]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowAck {
+    /// The message, and any buffered deltas following it, were applied
+    Applied,
+
+    /// The message arrived ahead of a missing one and was buffered
+    Buffered,
+
+    /// The message is older than the mirrored state and was ignored
+    Stale,
+
+    /// A message was lost; the primary should [resync][ShadowPrimary::request_resync]
+    Resync,
+}
+
+/// What the standby does with the mirrored entries on [ShadowStandby::promote]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailoverPolicy {
+    /// Keep the mirrored entries, so probes report them as cold hits at the primary's location
+    /// until the blocks are restored or recomputed
+    #[default]
+    KeepCold,
+
+    /// Drop the mirrored entries, e.g. when the data of the primary is lost with it
+    Discard,
+}
+
+type PendingDelta = (Vec<SequenceHash>, Vec<SequenceHash>);
+
+/// Standby side of a warm standby pair
+pub struct ShadowStandby {
+    location: ColdLocation,
+    reorder_window: usize,
+    epoch: Option<u64>,
+    next_seq: u64,
+    synced: bool,
+    pending: BTreeMap<u64, PendingDelta>,
+}
+
+impl ShadowStandby {
+    /// Mirror a primary whose blocks are reported at `location`
+    pub fn new(location: ColdLocation) -> Self {
+        Self {
+            location,
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            epoch: None,
+            next_seq: 0,
+            synced: false,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Set how many deltas are buffered while waiting for a missing message before asking for a
+    /// resync
+    pub fn with_reorder_window(mut self, reorder_window: usize) -> Self {
+        self.reorder_window = reorder_window;
+        self
+    }
+
+    /// Epoch of the primary being mirrored, if any message was received
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+
+    /// Whether the mirrored index reflects every message of the current epoch received so far
+    pub fn is_synced(&self) -> bool {
+        self.synced && self.pending.is_empty()
+    }
+
+    /// Apply a message received from the primary to `pool`
+    ///
+    /// Fails with the [IncompatibilityReason][super::compat::IncompatibilityReason] of a snapshot
+    /// whose manifest `pool` is not compatible with, leaving the mirrored index as it was.
+    pub async fn apply(
+        &mut self,
+        pool: &AvailableBlocks,
+        message: ShadowMessage,
+    ) -> Result<ShadowAck> {
+        if let ShadowMessage::Snapshot { manifest, .. } = &message {
+            pool.manifest().compatible_with(manifest)?;
+        }
+
+        let (epoch, seq) = (message.epoch(), message.seq());
+        match self.epoch {
+            Some(current) if epoch < current => return Ok(ShadowAck::Stale),
+            Some(current) if epoch == current => {
+                if seq < self.next_seq {
+                    return Ok(ShadowAck::Stale);
+                }
+            }
+            // a new primary; keep the old index until its snapshot arrives
+            _ => {
+                self.epoch = Some(epoch);
+                self.next_seq = 0;
+                self.synced = false;
+                self.pending.clear();
+            }
+        }
+
+        match message {
+            ShadowMessage::Snapshot { hashes, .. } => {
+                pool.mirror_cold(self.hits(hashes), Vec::new(), true)
+                    .await?;
+                self.next_seq = seq + 1;
+                self.synced = true;
+                self.pending = self.pending.split_off(&self.next_seq);
+                self.drain(pool).await?;
+                Ok(ShadowAck::Applied)
+            }
+            ShadowMessage::Delta { added, removed, .. } => {
+                if self.synced && seq == self.next_seq {
+                    self.apply_delta(pool, (added, removed)).await?;
+                    self.drain(pool).await?;
+                    return Ok(ShadowAck::Applied);
+                }
+
+                self.pending.insert(seq, (added, removed));
+                if self.pending.len() > self.reorder_window {
+                    // deltas pile up until the snapshot answering the resync arrives
+                    self.synced = false;
+                    self.pending.clear();
+                    return Ok(ShadowAck::Resync);
+                }
+                Ok(ShadowAck::Buffered)
+            }
+        }
+    }
+
+    /// Stop mirroring and handle the mirrored entries of `pool` as set by `policy`
+    pub async fn promote(self, pool: &AvailableBlocks, policy: FailoverPolicy) -> Result<()> {
+        match policy {
+            FailoverPolicy::KeepCold => Ok(()),
+            FailoverPolicy::Discard => pool.mirror_cold(Vec::new(), Vec::new(), true).await,
+        }
+    }
+
+    async fn drain(&mut self, pool: &AvailableBlocks) -> Result<()> {
+        while let Some(delta) = self.pending.remove(&self.next_seq) {
+            self.apply_delta(pool, delta).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply_delta(
+        &mut self,
+        pool: &AvailableBlocks,
+        (added, removed): PendingDelta,
+    ) -> Result<()> {
+        pool.mirror_cold(self.hits(added), removed, false).await?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    fn hits(&self, hashes: Vec<SequenceHash>) -> Vec<ColdHit> {
+        hashes
+            .into_iter()
+            .map(|sequence_hash| ColdHit {
+                sequence_hash,
+                location: self.location,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::kv::reuse::tests::{block_hashes, create_blocks, create_token_sequence};
+    use crate::kv::KvBlock;
+
+    #[derive(Default)]
+    struct FakeTransport {
+        sent: Mutex<Vec<ShadowMessage>>,
+    }
+
+    #[async_trait]
+    impl ShadowTransport for FakeTransport {
+        async fn send(&self, message: ShadowMessage) -> Result<()> {
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    impl FakeTransport {
+        // Drain the sent messages, dropping about one in four and swapping neighbours when lossy
+        fn deliver(&self, rng: &mut u64, lossy: bool) -> Vec<ShadowMessage> {
+            let mut messages = std::mem::take(&mut *self.sent.lock().unwrap());
+            if !lossy {
+                return messages;
+            }
+            let mut next = || {
+                *rng = rng
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                *rng >> 33
+            };
+            messages.retain(|_| next() % 4 != 0);
+            for i in 1..messages.len() {
+                if next() % 2 == 0 {
+                    messages.swap(i - 1, i);
+                }
+            }
+            messages
+        }
+    }
+
+    async fn mirrored(standby: &AvailableBlocks, candidates: &[SequenceHash]) -> Vec<SequenceHash> {
+        let mut mirrored = Vec::new();
+        for &hash in candidates {
+            let tiered = standby.match_blocks_tiered(vec![hash]).await.unwrap();
+            if !tiered.cold.is_empty() {
+                mirrored.push(hash);
+            }
+        }
+        mirrored.sort_unstable();
+        mirrored
+    }
+
+    async fn primary_hashes(primary: &AvailableBlocks) -> Vec<SequenceHash> {
+        let mut hashes: Vec<SequenceHash> = primary
+            .export_manifest()
+            .await
+            .unwrap()
+            .blocks
+            .iter()
+            .map(|block| block.sequence_hash)
+            .collect();
+        hashes.sort_unstable();
+        hashes
+    }
+
+    async fn deliver(
+        transport: &FakeTransport,
+        primary: &mut ShadowPrimary<Arc<FakeTransport>>,
+        standby: &mut ShadowStandby,
+        pool: &AvailableBlocks,
+        rng: &mut u64,
+        lossy: bool,
+    ) {
+        for message in transport.deliver(rng, lossy) {
+            if standby.apply(pool, message).await.unwrap() == ShadowAck::Resync {
+                primary.request_resync();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_standby_converges_over_lossy_transport() {
+        let primary_pool = AvailableBlocks::new().await;
+        let standby_pool = AvailableBlocks::new().await;
+        for _ in 0..4 {
+            standby_pool.insert(KvBlock::default()).await.unwrap();
+        }
+        standby_pool.fence().await.unwrap();
+
+        let transport = Arc::new(FakeTransport::default());
+        let mut primary = ShadowPrimary::new(transport.clone(), 1);
+        let mut standby = ShadowStandby::new(ColdLocation(7)).with_reorder_window(2);
+        let mut rng = 42;
+
+        let mut all = Vec::new();
+        for round in 0..24u32 {
+            let blocks = create_blocks(create_token_sequence(&[round, round + 1, 100, 101]), 2);
+            all.extend(block_hashes(&blocks));
+            primary_pool.insert_many(blocks).await.unwrap();
+            if round % 3 == 2 {
+                primary_pool
+                    .reset(all[all.len() - 6..][..2].to_vec())
+                    .await
+                    .unwrap();
+            }
+            primary_pool.fence().await.unwrap();
+
+            primary.sync(&primary_pool).await.unwrap();
+            if round % 2 == 1 {
+                deliver(
+                    &transport,
+                    &mut primary,
+                    &mut standby,
+                    &standby_pool,
+                    &mut rng,
+                    true,
+                )
+                .await;
+            }
+        }
+
+        // once the transport behaves, the standby catches up within a few heartbeats
+        for _ in 0..6 {
+            primary.sync(&primary_pool).await.unwrap();
+            deliver(
+                &transport,
+                &mut primary,
+                &mut standby,
+                &standby_pool,
+                &mut rng,
+                false,
+            )
+            .await;
+        }
+        assert!(standby.is_synced());
+        let expected = primary_hashes(&primary_pool).await;
+        assert!(!expected.is_empty());
+        assert_eq!(mirrored(&standby_pool, &all).await, expected);
+
+        // mirrored entries carry no tokens and can not be promoted
+        assert!(standby_pool
+            .promote_cold(expected[0])
+            .await
+            .unwrap()
+            .is_none());
+
+        // a restarted primary resyncs the standby under a new epoch
+        primary_pool.reset_all().await.unwrap();
+        let blocks = create_blocks(create_token_sequence(&[50, 51, 52, 53]), 2);
+        all.extend(block_hashes(&blocks));
+        primary_pool.insert_many(blocks).await.unwrap();
+        primary_pool.fence().await.unwrap();
+
+        let mut primary = ShadowPrimary::new(transport.clone(), 2);
+        primary.sync(&primary_pool).await.unwrap();
+        primary.sync(&primary_pool).await.unwrap();
+        let mut sent = std::mem::take(&mut *transport.sent.lock().unwrap());
+        sent.reverse();
+        for message in sent {
+            standby.apply(&standby_pool, message).await.unwrap();
+        }
+
+        // a late message of the old primary is ignored
+        let stale = ShadowMessage::Delta {
+            epoch: 1,
+            seq: 1000,
+            added: vec![all[0]],
+            removed: Vec::new(),
+        };
+        assert_eq!(
+            standby.apply(&standby_pool, stale).await.unwrap(),
+            ShadowAck::Stale
+        );
+
+        assert_eq!(standby.epoch(), Some(2));
+        assert!(standby.is_synced());
+        assert_eq!(
+            mirrored(&standby_pool, &all).await,
+            primary_hashes(&primary_pool).await
+        );
+
+        standby
+            .promote(&standby_pool, FailoverPolicy::Discard)
+            .await
+            .unwrap();
+        assert!(mirrored(&standby_pool, &all).await.is_empty());
+    }
+}
@@ -0,0 +1,498 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # KV Eviction Policy Simulator
+//!
+//! Replays a recorded [Trace] against a fixed capacity cache to compare [EvictionPolicy]s offline.
+//!
+//! Each request is modelled the way the [KvStorageManager][super::manager::KvStorageManager] drives
+//! the [AvailableBlocks][super::reuse::AvailableBlocks] pool:
+//!
+//! - the longest cached prefix of the request is matched, stopping at the first miss;
+//! - a block is taken for every remaining hash, evicting the policy's victim when there is no
+//!   free capacity left;
+//! - once the request completes, all of its blocks are returned from tail to root.
+//!
+//! Blocks in use by a request are never evicted to make room for that same request, so only the
+//! first `capacity` blocks of a longer request are simulated. A returned block whose hash is
+//! already cached is not cached twice; its slot becomes free capacity.
+//!
+//! The simulation is fully deterministic: the same trace, policy and capacity always produce the
+//! same [PolicyReport].
+//!
+//! The policies are modelled rather than run on the pool, which only implements
+//! [EvictionPolicy::PriorityFifo]. [simulate_pool] replays a trace through a real
+//! [AvailableBlocks] instead, so the model of that policy can be checked against the pool.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+
+use dynamo_runtime::Result;
+use serde::{Deserialize, Serialize};
+
+use super::reuse::{Allocation, AvailableBlocks};
+use super::KvBlock;
+use crate::tokens::{SequenceHash, Token, Tokens};
+
+/// Fraction of the capacity reserved for the protected segment of [EvictionPolicy::Slru]
+const SLRU_PROTECTED_FRACTION: f64 = 0.8;
+
+/// A single request in a [Trace]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Arrival time of the request in milliseconds since the start of the trace
+    pub arrival_ms: u64,
+
+    /// Sequence hashes of the complete blocks of the request, root first
+    pub hashes: Vec<SequenceHash>,
+}
+
+/// A recorded sequence of requests, ordered by arrival time
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trace {
+    pub records: Vec<TraceRecord>,
+}
+
+impl Trace {
+    /// Parse a trace from JSON lines, one [TraceRecord] per line; blank lines are skipped.
+    ///
+    /// Records are ordered by arrival time; records with the same arrival time keep their order.
+    pub fn from_jsonl(reader: impl BufRead) -> Result<Self> {
+        let mut records: Vec<TraceRecord> = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+        records.sort_by_key(|record| record.arrival_ms);
+        Ok(Self { records })
+    }
+
+    /// Write the trace as JSON lines, one [TraceRecord] per line
+    pub fn to_jsonl(&self, mut writer: impl Write) -> Result<()> {
+        for record in &self.records {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Eviction policies supported by the simulator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Lowest priority first, then oldest return first; the ordering used by the available pool
+    PriorityFifo,
+
+    /// Least recently used, where a block is used when it is matched or taken
+    Lru,
+
+    /// Segmented LRU; blocks are promoted from the probationary to the protected segment when
+    /// they are re-used, and the probationary segment is always evicted first
+    Slru,
+
+    /// Least frequently used, ties broken by least recently used
+    Lfu,
+}
+
+impl EvictionPolicy {
+    pub const ALL: [EvictionPolicy; 4] = [
+        EvictionPolicy::PriorityFifo,
+        EvictionPolicy::Lru,
+        EvictionPolicy::Slru,
+        EvictionPolicy::Lfu,
+    ];
+}
+
+/// Outcome of replaying a [Trace] under a single [EvictionPolicy]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PolicyReport {
+    pub policy: EvictionPolicy,
+    pub capacity: usize,
+    pub requests: u64,
+
+    /// Blocks requested across all requests, after truncating requests to the capacity
+    pub blocks_requested: u64,
+
+    /// Blocks served from the cache
+    pub blocks_hit: u64,
+
+    /// Blocks which had to be recomputed; always `blocks_requested - blocks_hit`
+    pub blocks_recomputed: u64,
+
+    /// Cached blocks evicted to make room for new content
+    pub evictions: u64,
+
+    /// `blocks_hit / blocks_requested`, or 0 for an empty trace
+    pub hit_rate: f64,
+
+    /// 99th percentile of the matched prefix length, in blocks
+    pub p99_matched_prefix: usize,
+}
+
+/// Replay the trace under the given policy with a cache of `capacity` blocks
+pub fn simulate(trace: &Trace, policy: EvictionPolicy, capacity: usize) -> PolicyReport {
+    let mut cache = SimCache::new(policy, capacity);
+    let mut matched_prefixes = Vec::with_capacity(trace.records.len());
+    let mut blocks_requested = 0;
+    let mut blocks_hit = 0;
+
+    for record in &trace.records {
+        let hashes = &record.hashes[..record.hashes.len().min(capacity)];
+        let matched = cache.run_request(hashes);
+
+        blocks_requested += hashes.len() as u64;
+        blocks_hit += matched as u64;
+        matched_prefixes.push(matched);
+    }
+
+    let hit_rate = match blocks_requested {
+        0 => 0.0,
+        requested => blocks_hit as f64 / requested as f64,
+    };
+
+    PolicyReport {
+        policy,
+        capacity,
+        requests: trace.records.len() as u64,
+        blocks_requested,
+        blocks_hit,
+        blocks_recomputed: blocks_requested - blocks_hit,
+        evictions: cache.evictions,
+        hit_rate,
+        p99_matched_prefix: percentile(&mut matched_prefixes, 0.99),
+    }
+}
+
+/// Replay the trace through an [AvailableBlocks] pool of `capacity` blank blocks
+///
+/// Each request allocates its blocks, fills the blocks taken and returns them all from tail to
+/// root, as [simulate] models it. The pool hashes tokens itself, so each distinct trace hash is
+/// given a token of its own and the requests are replayed as sequences of single token blocks;
+/// requests sharing a prefix of trace hashes share the same prefix of pool hashes. The report is
+/// for [EvictionPolicy::PriorityFifo], the policy of the pool.
+pub async fn simulate_pool(trace: &Trace, capacity: usize) -> Result<PolicyReport> {
+    let pool = AvailableBlocks::new().await;
+    pool.insert_many((0..capacity).map(|_| KvBlock::default()).collect())
+        .await?;
+
+    let mut tokens: HashMap<SequenceHash, Token> = HashMap::new();
+    let mut matched_prefixes = Vec::with_capacity(trace.records.len());
+    let mut blocks_requested = 0;
+    let mut blocks_hit = 0;
+
+    for record in &trace.records {
+        let hashes = &record.hashes[..record.hashes.len().min(capacity)];
+        let request: Vec<Token> = hashes
+            .iter()
+            .map(|hash| {
+                let next = tokens.len() as Token;
+                *tokens.entry(*hash).or_insert(next)
+            })
+            .collect();
+        let (blocks, _) = Tokens::from(request).into_sequence_fast(1).into_parts();
+
+        let matched = if blocks.is_empty() {
+            0
+        } else {
+            let Allocation {
+                matched, mut taken, ..
+            } = pool
+                .allocate(blocks.iter().map(|block| block.sequence_hash()).collect())
+                .await?;
+            for (block, token_block) in taken.iter_mut().zip(&blocks[matched.len()..]) {
+                block.token_block = token_block.clone();
+            }
+
+            let hits = matched.len();
+            for block in matched.into_iter().chain(taken).rev() {
+                drop(block);
+            }
+            pool.fence_returns().await?;
+            hits
+        };
+
+        blocks_requested += hashes.len() as u64;
+        blocks_hit += matched as u64;
+        matched_prefixes.push(matched);
+    }
+
+    let hit_rate = match blocks_requested {
+        0 => 0.0,
+        requested => blocks_hit as f64 / requested as f64,
+    };
+
+    Ok(PolicyReport {
+        policy: EvictionPolicy::PriorityFifo,
+        capacity,
+        requests: trace.records.len() as u64,
+        blocks_requested,
+        blocks_hit,
+        blocks_recomputed: blocks_requested - blocks_hit,
+        evictions: pool.drain_counters().await?.evictions,
+        hit_rate,
+        p99_matched_prefix: percentile(&mut matched_prefixes, 0.99),
+    })
+}
+
+/// Replay the trace under every [EvictionPolicy] with the same capacity
+pub fn compare(trace: &Trace, capacity: usize) -> Vec<PolicyReport> {
+    EvictionPolicy::ALL
+        .iter()
+        .map(|policy| simulate(trace, *policy, capacity))
+        .collect()
+}
+
+fn percentile(values: &mut [usize], quantile: f64) -> usize {
+    if values.is_empty() {
+        return 0;
+    }
+    values.sort_unstable();
+    let rank = (quantile * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+/// Position of an idle block in the eviction order; the smallest key is evicted first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SimKey {
+    rank: u64,
+    tick: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SimEntry {
+    key: SimKey,
+    frequency: u64,
+    protected: bool,
+}
+
+struct SimCache {
+    policy: EvictionPolicy,
+    capacity: usize,
+    protected_capacity: usize,
+    order: BTreeMap<SimKey, SequenceHash>,
+    idle: HashMap<SequenceHash, SimEntry>,
+    protected_len: usize,
+    tick: u64,
+    evictions: u64,
+}
+
+impl SimCache {
+    fn new(policy: EvictionPolicy, capacity: usize) -> Self {
+        Self {
+            policy,
+            capacity,
+            protected_capacity: (capacity as f64 * SLRU_PROTECTED_FRACTION) as usize,
+            order: BTreeMap::new(),
+            idle: HashMap::new(),
+            protected_len: 0,
+            tick: 0,
+            evictions: 0,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Simulate a single request, returning the length of the matched prefix
+    fn run_request(&mut self, hashes: &[SequenceHash]) -> usize {
+        // match the prefix; matched blocks are in use and leave the eviction order
+        let mut in_use = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            match self.check_out(*hash) {
+                Some(entry) => in_use.push((*hash, Some(entry))),
+                None => break,
+            }
+        }
+        let matched = in_use.len();
+
+        // take a slot for each remaining block, evicting if there is no free capacity
+        for hash in &hashes[matched..] {
+            if self.idle.len() + in_use.len() >= self.capacity {
+                self.evict();
+            }
+            in_use.push((*hash, None));
+        }
+
+        // LRU-style policies record use in access order, before the request completes
+        if matches!(self.policy, EvictionPolicy::Lru | EvictionPolicy::Lfu) {
+            let ticks: Vec<u64> = in_use.iter().map(|_| self.next_tick()).collect();
+            for ((hash, previous), tick) in in_use.into_iter().zip(ticks) {
+                self.check_in(hash, previous, tick);
+            }
+        } else {
+            for (hash, previous) in in_use.into_iter().rev() {
+                let tick = self.next_tick();
+                self.check_in(hash, previous, tick);
+            }
+        }
+
+        matched
+    }
+
+    fn check_out(&mut self, hash: SequenceHash) -> Option<SimEntry> {
+        let entry = self.idle.remove(&hash)?;
+        self.order.remove(&entry.key);
+        if entry.protected {
+            self.protected_len -= 1;
+        }
+        Some(entry)
+    }
+
+    fn check_in(&mut self, hash: SequenceHash, previous: Option<SimEntry>, tick: u64) {
+        if self.idle.contains_key(&hash) {
+            // the content is already cached; the duplicate slot becomes free capacity
+            return;
+        }
+
+        let frequency = previous.map_or(1, |entry| entry.frequency + 1);
+        let protected = self.policy == EvictionPolicy::Slru && previous.is_some();
+
+        let rank = match self.policy {
+            EvictionPolicy::PriorityFifo | EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => frequency,
+            EvictionPolicy::Slru => protected as u64,
+        };
+
+        let entry = SimEntry {
+            key: SimKey { rank, tick },
+            frequency,
+            protected,
+        };
+        self.order.insert(entry.key, hash);
+        self.idle.insert(hash, entry);
+
+        if protected {
+            self.protected_len += 1;
+            if self.protected_len > self.protected_capacity {
+                self.demote_protected();
+            }
+        }
+    }
+
+    /// Move the least recently used protected block to the front of the probationary segment
+    fn demote_protected(&mut self) {
+        let Some((&key, &hash)) = self.order.range(SimKey { rank: 1, tick: 0 }..).next() else {
+            return;
+        };
+        self.order.remove(&key);
+        self.protected_len -= 1;
+
+        let tick = self.next_tick();
+        let entry = self
+            .idle
+            .get_mut(&hash)
+            .expect("protected block must be idle");
+        entry.protected = false;
+        entry.key = SimKey { rank: 0, tick };
+        self.order.insert(entry.key, hash);
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, hash)) = self.order.pop_first() {
+            let entry = self
+                .idle
+                .remove(&hash)
+                .expect("block in eviction order must be idle");
+            if entry.protected {
+                self.protected_len -= 1;
+            }
+            self.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_trace() -> Trace {
+        let file = std::fs::File::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/kv/sample_trace.jsonl"
+        ))
+        .unwrap();
+        Trace::from_jsonl(std::io::BufReader::new(file)).unwrap()
+    }
+
+    #[test]
+    fn test_simulator_is_deterministic_and_consistent() {
+        let trace = sample_trace();
+        assert!(!trace.records.is_empty());
+
+        let max_request_len = trace.records.iter().map(|r| r.hashes.len()).max().unwrap();
+
+        for capacity in [8, 32, 128] {
+            let reports = compare(&trace, capacity);
+            assert_eq!(reports, compare(&trace, capacity));
+            assert_eq!(reports.len(), EvictionPolicy::ALL.len());
+
+            for report in reports {
+                assert_eq!(report.requests, trace.records.len() as u64);
+                assert_eq!(
+                    report.blocks_hit + report.blocks_recomputed,
+                    report.blocks_requested
+                );
+                assert!(report.evictions <= report.blocks_recomputed);
+                assert!(report.p99_matched_prefix <= max_request_len.min(capacity));
+                assert_eq!(
+                    report.hit_rate,
+                    report.blocks_hit as f64 / report.blocks_requested as f64
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_simulator_unbounded_capacity_only_has_compulsory_misses() {
+        let trace = sample_trace();
+        let unique = trace
+            .records
+            .iter()
+            .flat_map(|r| r.hashes.iter())
+            .collect::<std::collections::HashSet<_>>()
+            .len() as u64;
+
+        for report in compare(&trace, 1 << 20) {
+            assert_eq!(report.evictions, 0);
+            assert_eq!(report.blocks_recomputed, unique);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority_fifo_model_matches_pool() {
+        let trace = sample_trace();
+
+        for capacity in [8, 32, 128] {
+            let report = simulate_pool(&trace, capacity).await.unwrap();
+            assert_eq!(
+                report,
+                simulate(&trace, EvictionPolicy::PriorityFifo, capacity)
+            );
+        }
+    }
+
+    #[test]
+    fn test_trace_jsonl_round_trip() {
+        let trace = sample_trace();
+        let mut buffer = Vec::new();
+        trace.to_jsonl(&mut buffer).unwrap();
+        assert_eq!(Trace::from_jsonl(buffer.as_slice()).unwrap(), trace);
+    }
+}
@@ -0,0 +1,257 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Routing Test Harness
+//!
+//! Glue for tests which run [AvailableBlocks] pools behind the [KV router][crate::kv_router]
+//! without a distributed runtime. A [FakeWorker] serves requests from one pool and publishes the
+//! blocks it stores and evicts as [KvCacheEvent]s, as the
+//! [KvEventPublisher][crate::kv_router::publisher::KvEventPublisher] of a worker would. The events
+//! travel on an [InMemoryBus] straight into a [KvIndexer].
+//!
+//! A worker publishes the evictions of a request before the blocks it stored, so the indexer
+//! applies them in the order the pool made them.
+//!
+//! The harness is only built for tests and with the `test-utils` feature.
+
+use dynamo_runtime::{raise, Result};
+use tokio::sync::{broadcast, mpsc};
+
+use super::reuse::{
+    Allocation, AvailableBlocks, AvailableBlocksConfig, CounterSnapshot, EvictionEvent,
+};
+use super::KvBlock;
+use crate::kv_router::{
+    indexer::{KvIndexer, KvIndexerInterface, KvRouterError, RouterEvent, WorkerId},
+    protocols::{
+        ExternalSequenceBlockHash, KvCacheEvent, KvCacheEventData, KvCacheRemoveData,
+        KvCacheStoreData, KvCacheStoredBlockData, LocalBlockHash,
+    },
+};
+use crate::tokens::{Token, Tokens};
+
+/// Carries the events of every [FakeWorker] to a [KvIndexer]
+pub struct InMemoryBus {
+    indexer: mpsc::Sender<RouterEvent>,
+}
+
+impl InMemoryBus {
+    pub fn new(indexer: &KvIndexer) -> Self {
+        Self {
+            indexer: indexer.event_sender(),
+        }
+    }
+
+    pub async fn publish(&self, worker_id: WorkerId, event: KvCacheEvent) -> Result<()> {
+        if self
+            .indexer
+            .send(RouterEvent::new(worker_id, event))
+            .await
+            .is_err()
+        {
+            raise!("failed to publish kv event; indexer offline");
+        }
+        Ok(())
+    }
+
+    /// Wait until the indexer has received every published event
+    ///
+    /// The indexer applies an event in the turn which receives it, so a match sent after this
+    /// resolves sees every event published before the call.
+    pub async fn settle(&self) {
+        while self.indexer.capacity() < self.indexer.max_capacity() {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// Blocks of a request served by [FakeWorker::serve]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServedRequest {
+    /// Full blocks of the request
+    pub blocks: usize,
+
+    /// Blocks matched from the cache
+    pub matched: usize,
+
+    /// Blocks taken, filled and stored for the rest of the request
+    pub stored: usize,
+}
+
+/// A worker which serves requests from a single pool and publishes its cache events
+pub struct FakeWorker {
+    worker_id: WorkerId,
+    pool: AvailableBlocks,
+    block_size: usize,
+    evictions: broadcast::Receiver<EvictionEvent>,
+    next_event_id: u64,
+    expected: CounterSnapshot,
+}
+
+impl FakeWorker {
+    /// Start a worker on a pool of `capacity` empty blocks of `block_size` tokens
+    pub async fn new(
+        worker_id: WorkerId,
+        config: AvailableBlocksConfig,
+        capacity: usize,
+        block_size: usize,
+    ) -> Result<Self> {
+        let pool = AvailableBlocks::new_with_config(config.with_block_size(block_size)).await;
+        let evictions = pool.subscribe_evictions();
+        pool.insert_many((0..capacity).map(|_| KvBlock::default()).collect())
+            .await?;
+        pool.fence().await?;
+
+        Ok(Self {
+            worker_id,
+            pool,
+            block_size,
+            evictions,
+            next_event_id: 0,
+            expected: CounterSnapshot {
+                inserts: capacity as u64,
+                ..Default::default()
+            },
+        })
+    }
+
+    pub fn worker_id(&self) -> WorkerId {
+        self.worker_id
+    }
+
+    pub fn pool(&self) -> &AvailableBlocks {
+        &self.pool
+    }
+
+    /// Counters the pool should report for the requests served so far
+    ///
+    /// Only the counters a worker can account for itself are set: inserts, matches, takes,
+    /// returns and the evictions it published.
+    pub fn expected_counters(&self) -> CounterSnapshot {
+        self.expected
+    }
+
+    /// Serve a request of `tokens` and publish the resulting cache events to `bus`
+    ///
+    /// The full blocks of the request are allocated, the taken blocks are filled with the tokens
+    /// of the request and every block is returned to the pool before the events are published.
+    pub async fn serve(&mut self, bus: &InMemoryBus, tokens: &[Token]) -> Result<ServedRequest> {
+        let (blocks, _) = Tokens::from(tokens.to_vec())
+            .into_sequence(self.block_size)
+            .into_parts();
+        let hashes = blocks.iter().map(|block| block.sequence_hash()).collect();
+
+        let Allocation {
+            matched, mut taken, ..
+        } = self.pool.allocate(hashes).await?;
+        let parent_hash = matched
+            .last()
+            .map(|block| ExternalSequenceBlockHash(block.token_block.sequence_hash()));
+
+        let stored = &blocks[matched.len()..matched.len() + taken.len()];
+        for (block, token_block) in taken.iter_mut().zip(stored) {
+            block.token_block = token_block.clone();
+        }
+
+        let served = ServedRequest {
+            blocks: blocks.len(),
+            matched: matched.len(),
+            stored: taken.len(),
+        };
+        self.expected.matches += served.matched as u64;
+        self.expected.takes += served.stored as u64;
+        self.expected.returns += (served.matched + served.stored) as u64;
+
+        drop(matched);
+        drop(taken);
+        self.pool.fence_returns().await?;
+
+        self.publish_evictions(bus).await?;
+        if !stored.is_empty() {
+            let blocks = stored
+                .iter()
+                .map(|block| KvCacheStoredBlockData {
+                    block_hash: ExternalSequenceBlockHash(block.sequence_hash()),
+                    tokens_hash: LocalBlockHash(block.block_hash()),
+                })
+                .collect();
+            let data = KvCacheEventData::Stored(KvCacheStoreData {
+                parent_hash,
+                blocks,
+            });
+            self.publish(bus, data).await?;
+        }
+
+        Ok(served)
+    }
+
+    /// Publish the evictions of the pool not published yet
+    pub async fn publish_evictions(&mut self, bus: &InMemoryBus) -> Result<()> {
+        let mut block_hashes = Vec::new();
+        loop {
+            match self.evictions.try_recv() {
+                Ok(event) => block_hashes.push(ExternalSequenceBlockHash(event.sequence_hash)),
+                Err(broadcast::error::TryRecvError::Empty) => break,
+                Err(broadcast::error::TryRecvError::Lagged(missed)) => {
+                    raise!("missed {} evictions; the indexer view is stale", missed);
+                }
+                Err(broadcast::error::TryRecvError::Closed) => {
+                    raise!("eviction events closed; pool dropped");
+                }
+            }
+        }
+
+        if block_hashes.is_empty() {
+            return Ok(());
+        }
+        self.expected.evictions += block_hashes.len() as u64;
+        self.publish(
+            bus,
+            KvCacheEventData::Removed(KvCacheRemoveData { block_hashes }),
+        )
+        .await
+    }
+
+    async fn publish(&mut self, bus: &InMemoryBus, data: KvCacheEventData) -> Result<()> {
+        let event = KvCacheEvent {
+            event_id: self.next_event_id,
+            data,
+        };
+        self.next_event_id += 1;
+        bus.publish(self.worker_id, event).await
+    }
+}
+
+/// Number of leading blocks of `sequence` the indexer holds for `worker_id`
+///
+/// This is the indexer's counterpart of [AvailableBlocks::cached_prefix_len]; the scores of the
+/// indexer count every block on the matched path, so the prefix ends at the first block which
+/// does not add to the score of the worker.
+pub async fn indexed_prefix_len(
+    indexer: &KvIndexer,
+    worker_id: WorkerId,
+    sequence: &[LocalBlockHash],
+) -> Result<usize, KvRouterError> {
+    let mut score = 0;
+    for len in 1..=sequence.len() {
+        let scores = indexer.find_matches(sequence[..len].to_vec()).await?;
+        let next = scores.scores.get(&worker_id).copied().unwrap_or(0);
+        if next == score {
+            return Ok(len - 1);
+        }
+        score = next;
+    }
+    Ok(sequence.len())
+}
@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Workload Trace Recorder
+//!
+//! When a pool is configured with a trace recorder, its engine keeps a bounded ring buffer of the
+//! matches, takes and returns it handles. Exporting the trace drains the buffer as a
+//! [RecordedTrace], which converts to the [Trace] replayed by the [policy simulator][super::simulate].
+//!
+//! Only sequence hashes and counts are recorded, never tokens.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::simulate::{Trace, TraceRecord};
+use crate::tokens::SequenceHash;
+
+/// Kind of operation in a [TraceEvent]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TraceOp {
+    Match,
+    Take,
+    Return,
+}
+
+/// A single recorded operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    /// Time the engine handled the operation, in milliseconds since the recorder started
+    pub at_ms: u64,
+
+    pub op: TraceOp,
+
+    /// Hashes requested by a match or the hash of a returned block; empty for takes
+    pub hashes: Vec<SequenceHash>,
+
+    /// Blocks requested
+    pub requested: usize,
+
+    /// Blocks served; matched blocks for a match, taken blocks for a take
+    pub served: usize,
+}
+
+/// Operations drained by [AvailableBlocks::export_trace][super::reuse::AvailableBlocks::export_trace]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedTrace {
+    /// Recorded operations, oldest first
+    pub events: Vec<TraceEvent>,
+
+    /// Sampled operations dropped because the ring buffer was full
+    pub dropped: u64,
+}
+
+impl RecordedTrace {
+    /// The recorded matches as simulator requests
+    ///
+    /// The simulator derives the takes and returns of a request from its hashes, so only matches
+    /// become [TraceRecord]s.
+    pub fn to_trace(&self) -> Trace {
+        let records = self
+            .events
+            .iter()
+            .filter(|event| event.op == TraceOp::Match)
+            .map(|event| TraceRecord {
+                arrival_ms: event.at_ms,
+                hashes: event.hashes.clone(),
+            })
+            .collect();
+        Trace { records }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TraceRecorderConfig {
+    pub(crate) capacity: usize,
+    pub(crate) sample_every: u64,
+}
+
+pub(crate) struct TraceRecorder {
+    config: TraceRecorderConfig,
+    started: Instant,
+    seen: u64,
+    events: VecDeque<TraceEvent>,
+    dropped: u64,
+}
+
+impl TraceRecorder {
+    pub(crate) fn new(config: TraceRecorderConfig) -> Self {
+        Self {
+            config,
+            started: Instant::now(),
+            seen: 0,
+            events: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Whether the next operation is recorded; one in every `sample_every` operations is
+    pub(crate) fn sample(&mut self) -> bool {
+        let sampled = self.seen % self.config.sample_every == 0;
+        self.seen += 1;
+        sampled
+    }
+
+    /// Whether the operations of the request `request_id` are recorded; one in every
+    /// `sample_every` request ids is
+    pub(crate) fn sample_request(&self, request_id: u64) -> bool {
+        request_id % self.config.sample_every == 0
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        op: TraceOp,
+        hashes: Vec<SequenceHash>,
+        requested: usize,
+        served: usize,
+    ) {
+        if self.events.len() == self.config.capacity {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(TraceEvent {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            op,
+            hashes,
+            requested,
+            served,
+        });
+    }
+
+    /// Drain the recorded operations
+    pub(crate) fn export(&mut self) -> RecordedTrace {
+        RecordedTrace {
+            events: self.events.drain(..).collect(),
+            dropped: std::mem::take(&mut self.dropped),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Soak test of a single pool under concurrent producers and consumers
+//!
+//! Many tasks insert, match, take and return blocks for a fixed duration while another shrinks
+//! the pool, then the test fences and checks the engine for drift. `KV_SOAK_TASKS` and
+//! `KV_SOAK_MILLIS` set the concurrency level and the duration, for example to soak for minutes
+//! rather than the default half second.
+
+#![cfg(feature = "cuda_kv")]
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+
+use dynamo_llm::kv::reuse::AvailableBlocks;
+use dynamo_llm::kv::KvBlock;
+use dynamo_llm::tokens::{SequenceHash, Tokens};
+use dynamo_runtime::Result;
+use tokio::time::{Duration, Instant};
+
+const SEED_BLOCKS: u64 = 64;
+const MIN_BLOCKS: u64 = 48;
+const MAX_HELD: usize = 8;
+
+struct SoakConfig {
+    tasks: u64,
+    duration: Duration,
+
+    // drop the receiver of one in three requests after sending it
+    drop_receivers: bool,
+}
+
+impl SoakConfig {
+    fn from_env(drop_receivers: bool) -> Self {
+        let var = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            tasks: var("KV_SOAK_TASKS", 16),
+            duration: Duration::from_millis(var("KV_SOAK_MILLIS", 500)),
+            drop_receivers,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    inserted: AtomicU64,
+    drained: AtomicU64,
+    dropped_receivers: AtomicU64,
+}
+
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+// run the request to completion, or drop it once it was sent when `drop_receiver` is set;
+// every request is sent on its first poll
+async fn request<T>(
+    request: impl Future<Output = Result<T>>,
+    drop_receiver: bool,
+    tally: &Tally,
+) -> Option<T> {
+    if !drop_receiver {
+        return Some(request.await.unwrap());
+    }
+    let mut request = pin!(request);
+    match futures::poll!(request.as_mut()) {
+        Poll::Ready(result) => Some(result.unwrap()),
+        Poll::Pending => {
+            tally.dropped_receivers.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+async fn producer_consumer(
+    pool: Arc<AvailableBlocks>,
+    tally: Arc<Tally>,
+    task: u64,
+    deadline: Instant,
+    drop_receivers: bool,
+) {
+    let mut rng = Lcg(task);
+    let mut known: Vec<Vec<SequenceHash>> = Vec::new();
+    let mut held = Vec::new();
+    let mut step = 0u32;
+
+    while Instant::now() < deadline {
+        let drop_receiver = drop_receivers && rng.next() % 3 == 0;
+        match rng.next() % 4 {
+            0 => {
+                let first = ((task as u32) << 20) | (step << 2);
+                let (blocks, _) = Tokens::from((first..first + 4).collect::<Vec<_>>())
+                    .into_sequence(2)
+                    .into_parts();
+                known.push(blocks.iter().map(|block| block.sequence_hash()).collect());
+                let blocks: Vec<_> = blocks.into_iter().map(KvBlock::new).collect();
+
+                // a dropped insert was still sent, so it is applied
+                tally
+                    .inserted
+                    .fetch_add(blocks.len() as u64, Ordering::Relaxed);
+                request(pool.insert_many(blocks), drop_receiver, &tally).await;
+            }
+            1 if !known.is_empty() => {
+                let hashes = known[rng.next() as usize % known.len()].clone();
+                let matched = request(pool.match_blocks(hashes), drop_receiver, &tally).await;
+                held.extend(matched.into_iter().flatten());
+            }
+            2 => {
+                let count = (rng.next() % 4 + 1) as u32;
+                let taken = request(pool.take_blocks(count), drop_receiver, &tally).await;
+                held.extend(taken.into_iter().flatten());
+            }
+            _ => held.clear(),
+        }
+
+        if held.len() > MAX_HELD {
+            held.clear();
+        }
+        step += 1;
+        tokio::task::yield_now().await;
+    }
+}
+
+async fn shrinker(pool: Arc<AvailableBlocks>, tally: Arc<Tally>, deadline: Instant) {
+    while Instant::now() < deadline {
+        let total = pool.total_blocks();
+        if total > MIN_BLOCKS {
+            let report = pool.shrink(total - 1).await.unwrap();
+            tally.drained.fetch_add(
+                report.removed_idle + report.retired_in_flight,
+                Ordering::Relaxed,
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+async fn soak(config: SoakConfig) {
+    let pool = Arc::new(AvailableBlocks::new().await);
+    let tally = Arc::new(Tally::default());
+    pool.insert_many((0..SEED_BLOCKS).map(|_| KvBlock::default()).collect())
+        .await
+        .unwrap();
+    tally.inserted.fetch_add(SEED_BLOCKS, Ordering::Relaxed);
+
+    let deadline = Instant::now() + config.duration;
+    let mut handles: Vec<_> = (0..config.tasks)
+        .map(|task| {
+            tokio::spawn(producer_consumer(
+                pool.clone(),
+                tally.clone(),
+                task,
+                deadline,
+                config.drop_receivers,
+            ))
+        })
+        .collect();
+    handles.push(tokio::spawn(shrinker(
+        pool.clone(),
+        tally.clone(),
+        deadline,
+    )));
+
+    // a panic in a task fails its join
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    pool.fence_returns().await.unwrap();
+    pool.fence().await.unwrap();
+    assert!(pool.is_active(), "the engine stopped");
+
+    let report = pool.verify_integrity().await.unwrap();
+    assert!(report.is_consistent(), "{report:?}");
+
+    let inserted = tally.inserted.load(Ordering::Relaxed);
+    let drained = tally.drained.load(Ordering::Relaxed);
+    assert_eq!(pool.total_blocks(), inserted - drained);
+    assert_eq!(pool.available_blocks(), pool.total_blocks());
+
+    let counters = pool.drain_counters().await.unwrap();
+    assert_eq!(counters.inserts, inserted);
+    assert_eq!(counters.returns, counters.matches + counters.takes);
+    assert_eq!(counters.integrity_repairs, 0);
+
+    if config.drop_receivers {
+        assert!(tally.dropped_receivers.load(Ordering::Relaxed) > 0);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_soak() {
+    soak(SoakConfig::from_env(false)).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_soak_dropping_receivers() {
+    soak(SoakConfig::from_env(true)).await;
+}
@@ -0,0 +1,154 @@
+// SPDX-FileCopyrightText: Copyright (c) 2024-2025 NVIDIA CORPORATION & AFFILIATES. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Routes a synthetic workload across two pools through the event bus and the indexer, the way
+//! a deployment routes requests across workers.
+
+#![cfg(feature = "cuda_kv")]
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+use dynamo_llm::kv::reuse::AvailableBlocksConfig;
+use dynamo_llm::kv::testing::{indexed_prefix_len, FakeWorker, InMemoryBus};
+use dynamo_llm::kv_router::indexer::{compute_block_hash_for_seq, KvIndexer, KvIndexerInterface};
+use dynamo_llm::tokens::Tokens;
+use tokio_util::sync::CancellationToken;
+
+const BLOCK_SIZE: usize = 4;
+const CAPACITY: usize = 24;
+const FAMILIES: u32 = 4;
+const PREFIX_TOKENS: u32 = 16;
+const SUFFIX_TOKENS: u32 = 8;
+const REQUESTS: u32 = 64;
+
+// a shared prompt of one of the families followed by tokens unique to the request
+fn request_tokens(index: u32, seed: &mut u64) -> Vec<u32> {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    let family = ((*seed >> 33) % FAMILIES as u64) as u32;
+
+    let prefix = (0..PREFIX_TOKENS).map(|token| family * 100 + token);
+    let suffix = (0..SUFFIX_TOKENS).map(|token| 10_000 + index * SUFFIX_TOKENS + token);
+    prefix.chain(suffix).collect()
+}
+
+#[tokio::test]
+async fn test_route_across_pools() {
+    let mut indexer = KvIndexer::new(CancellationToken::new(), BLOCK_SIZE);
+    let bus = InMemoryBus::new(&indexer);
+
+    let mut workers = Vec::new();
+    for worker_id in 0..2 {
+        let worker = FakeWorker::new(
+            worker_id,
+            AvailableBlocksConfig::default(),
+            CAPACITY,
+            BLOCK_SIZE,
+        )
+        .await
+        .unwrap();
+        workers.push(worker);
+    }
+
+    let mut routed = vec![0usize; workers.len()];
+    let mut requests = BTreeSet::new();
+    let (mut total_blocks, mut matched_blocks) = (0, 0);
+    let mut seed = 7;
+
+    for index in 0..REQUESTS {
+        let tokens = request_tokens(index, &mut seed);
+
+        // the worker with the best overlap, the least loaded one on a tie
+        bus.settle().await;
+        let overlaps = indexer.find_matches_for_request(&tokens).await.unwrap();
+        let best = (0..workers.len())
+            .max_by_key(|&id| {
+                let score = overlaps.scores.get(&(id as i64)).copied().unwrap_or(0);
+                (score, Reverse(routed[id]), Reverse(id))
+            })
+            .unwrap();
+        routed[best] += 1;
+
+        let served = workers[best].serve(&bus, &tokens).await.unwrap();
+        assert_eq!(served.blocks, tokens.len() / BLOCK_SIZE);
+        assert_eq!(served.matched + served.stored, served.blocks);
+        total_blocks += served.blocks;
+        matched_blocks += served.matched;
+
+        requests.insert(tokens);
+    }
+
+    // every family but its first request hits its shared prompt
+    let hit_rate = matched_blocks as f64 / total_blocks as f64;
+    assert!(hit_rate > 0.5, "hit rate {hit_rate} too low");
+    assert!(routed.iter().all(|&count| count > 0), "{routed:?}");
+
+    for worker in workers.iter_mut() {
+        worker.pool().fence().await.unwrap();
+        worker.publish_evictions(&bus).await.unwrap();
+    }
+    bus.settle().await;
+
+    // the indexer holds the same prefix of every request as the pool which served it
+    for tokens in &requests {
+        let sequence = compute_block_hash_for_seq(tokens, BLOCK_SIZE);
+        let (blocks, _) = Tokens::from(tokens.clone())
+            .into_sequence(BLOCK_SIZE)
+            .into_parts();
+        let hashes: Vec<_> = blocks.iter().map(|block| block.sequence_hash()).collect();
+
+        for worker in &workers {
+            let indexed = indexed_prefix_len(&indexer, worker.worker_id(), &sequence)
+                .await
+                .unwrap();
+            let cached = worker
+                .pool()
+                .cached_prefix_len(hashes.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                indexed,
+                cached,
+                "worker {} disagrees with the indexer on {tokens:?}",
+                worker.worker_id()
+            );
+        }
+    }
+
+    for worker in &workers {
+        let pool = worker.pool();
+        assert_eq!(pool.available_blocks(), pool.total_blocks());
+
+        let counters = pool.drain_counters().await.unwrap();
+        let expected = worker.expected_counters();
+        assert_eq!(counters.inserts, expected.inserts);
+        assert_eq!(counters.matches, expected.matches);
+        assert_eq!(counters.takes, expected.takes);
+        assert_eq!(counters.returns, expected.returns);
+        assert_eq!(counters.evictions, expected.evictions);
+        assert_eq!(counters.integrity_repairs, 0);
+    }
+
+    // the suffixes alone outgrow the pools
+    let evictions: u64 = workers
+        .iter()
+        .map(|worker| worker.expected_counters().evictions)
+        .sum();
+    assert!(evictions > 0);
+
+    indexer.shutdown();
+}